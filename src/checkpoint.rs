@@ -0,0 +1,70 @@
+// Serialization of sampler state, so long runs can be resumed.
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+use super::sampler::transcripts::CellIndex;
+use super::sampler::voxelsampler::{ProposalState, Voxel};
+use super::sampler::{IterationDiagnostics, ModelParams, UncertaintyTracker};
+
+// Owned checkpoint, as read by `--resume`.
+#[derive(Deserialize)]
+pub struct Checkpoint {
+    pub schedule: Vec<usize>,
+    pub phase: usize,
+    pub phase_iteration: usize,
+    pub recorded_iterations: usize,
+    pub burnin_finished: bool,
+    pub total_steps: usize,
+    pub phase_diagnostics: Vec<IterationDiagnostics>,
+    pub params: ModelParams,
+    pub voxel_cells: Vec<(Voxel, CellIndex)>,
+    pub uncertainty: UncertaintyTracker,
+    pub proposal_state: ProposalState,
+    pub rng_state: (u64, u64),
+}
+
+// Borrowed checkpoint, with identical layout, used for writing.
+#[derive(Serialize)]
+pub struct CheckpointRef<'a> {
+    pub schedule: &'a [usize],
+    pub phase: usize,
+    pub phase_iteration: usize,
+    pub recorded_iterations: usize,
+    pub burnin_finished: bool,
+    pub total_steps: usize,
+    pub phase_diagnostics: &'a [IterationDiagnostics],
+    pub params: &'a ModelParams,
+    pub voxel_cells: Vec<(Voxel, CellIndex)>,
+    pub uncertainty: &'a UncertaintyTracker,
+    pub proposal_state: ProposalState,
+    pub rng_state: (u64, u64),
+}
+
+impl CheckpointRef<'_> {
+    pub fn save(&self, filename: &str) {
+        // Write to a temporary file and rename, so we never leave a truncated
+        // checkpoint if interrupted mid-write.
+        let tmp_filename = format!("{}.tmp", filename);
+        let file = File::create(&tmp_filename)
+            .unwrap_or_else(|err| panic!("Unable to create checkpoint '{}': {}", tmp_filename, err));
+        let mut writer = BufWriter::new(file);
+        bincode::serialize_into(&mut writer, self)
+            .and_then(|_| writer.flush().map_err(|err| err.into()))
+            .unwrap_or_else(|err| panic!("Unable to write checkpoint '{}': {}", tmp_filename, err));
+        std::fs::rename(&tmp_filename, filename)
+            .unwrap_or_else(|err| panic!("Unable to write checkpoint '{}': {}", filename, err));
+    }
+}
+
+impl Checkpoint {
+    pub fn load(filename: &str) -> Checkpoint {
+        let file = File::open(filename)
+            .unwrap_or_else(|err| panic!("Unable to open checkpoint '{}': {}", filename, err));
+        let mut checkpoint: Checkpoint = bincode::deserialize_from(BufReader::new(file))
+            .unwrap_or_else(|err| panic!("Unable to read checkpoint '{}': {}", filename, err));
+        checkpoint.params.rebuild_caches();
+        checkpoint
+    }
+}
@@ -0,0 +1,373 @@
+// Comparison of proseg's assignments against another segmentation, run with
+// `proseg compare`. Cells are matched one-to-one by shared transcripts, and
+// agreement is summarized per transcript and per matched cell.
+
+use arrow::array::{Array, StringArray, UInt64Array};
+use arrow::datatypes::DataType;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use std::collections::HashMap;
+use std::fs::File;
+
+use super::error::{Error, Result};
+use super::output::{infer_format_from_filename, open_decompressed, OutputFormat};
+use super::sampler::transcripts::{
+    find_column, find_parquet_column, parquet_column, parse_field, CellIndex, BACKGROUND_CELL,
+};
+
+// Transcripts from one segmentation, with cells and genes numbered in order of
+// appearance.
+pub struct Segmentation {
+    pub transcript_ids: Vec<u64>,
+    pub cells: Vec<CellIndex>,
+    pub cell_names: Vec<String>,
+    pub genes: Vec<u32>,
+    pub gene_names: Vec<String>,
+}
+
+pub struct SegmentationColumns<'a> {
+    pub transcript_id: &'a str,
+    pub cell: &'a str,
+    pub unassigned: &'a str,
+    pub gene: Option<&'a str>,
+}
+
+// A pair of cells matched between the two segmentations.
+pub struct CellMatch {
+    pub cell: CellIndex,
+    pub truth_cell: CellIndex,
+    pub count: usize,
+    pub truth_count: usize,
+    pub shared: usize,
+    pub iou: f32,
+    // Pearson correlation of gene counts, or NaN if either is constant
+    pub correlation: f32,
+}
+
+pub struct Comparison {
+    // transcripts present in both segmentations
+    pub ntranscripts: usize,
+    // fraction of transcripts that are background in both, or assigned to
+    // matched cells
+    pub agreement: f32,
+    pub ncells: usize,
+    pub truth_ncells: usize,
+    pub matches: Vec<CellMatch>,
+    pub mutual_information: f32,
+    pub normalized_mutual_information: f32,
+}
+
+pub fn read_segmentation(
+    path: &str,
+    fmt: OutputFormat,
+    columns: &SegmentationColumns,
+) -> Result<Segmentation> {
+    let fmt = match fmt {
+        OutputFormat::Infer => infer_format_from_filename(path),
+        _ => fmt,
+    };
+
+    let mut builder = SegmentationBuilder::default();
+    match fmt {
+        OutputFormat::Csv | OutputFormat::CsvGz | OutputFormat::CsvZst => {
+            let file = open_decompressed(path).map_err(|source| Error::Io {
+                path: path.to_string(),
+                source,
+            })?;
+            let rdr = csv::Reader::from_reader(file);
+            read_segmentation_csv(path, rdr, columns, &mut builder)?;
+        }
+        OutputFormat::Parquet => read_segmentation_parquet(path, columns, &mut builder)?,
+        _ => {
+            return Err(Error::UnsupportedFormat {
+                path: path.to_string(),
+                format: format!("{:?}", fmt).to_lowercase(),
+            })
+        }
+    }
+    Ok(builder.finish())
+}
+
+#[derive(Default)]
+struct SegmentationBuilder {
+    transcript_ids: Vec<u64>,
+    cells: Vec<CellIndex>,
+    genes: Vec<u32>,
+    cell_map: HashMap<String, CellIndex>,
+    cell_names: Vec<String>,
+    gene_map: HashMap<String, u32>,
+    gene_names: Vec<String>,
+}
+
+impl SegmentationBuilder {
+    fn push(&mut self, transcript_id: u64, cell: Option<&str>, gene: Option<&str>) {
+        self.transcript_ids.push(transcript_id);
+        let cell = match cell {
+            Some(cell) => match self.cell_map.get(cell) {
+                Some(&index) => index,
+                None => {
+                    let index = self.cell_names.len() as CellIndex;
+                    self.cell_map.insert(cell.to_string(), index);
+                    self.cell_names.push(cell.to_string());
+                    index
+                }
+            },
+            None => BACKGROUND_CELL,
+        };
+        self.cells.push(cell);
+        if let Some(gene) = gene {
+            let gene = match self.gene_map.get(gene) {
+                Some(&index) => index,
+                None => {
+                    let index = self.gene_names.len() as u32;
+                    self.gene_map.insert(gene.to_string(), index);
+                    self.gene_names.push(gene.to_string());
+                    index
+                }
+            };
+            self.genes.push(gene);
+        }
+    }
+
+    fn finish(self) -> Segmentation {
+        Segmentation {
+            transcript_ids: self.transcript_ids,
+            cells: self.cells,
+            cell_names: self.cell_names,
+            genes: self.genes,
+            gene_names: self.gene_names,
+        }
+    }
+}
+
+fn read_segmentation_csv<T: std::io::Read>(
+    path: &str,
+    mut rdr: csv::Reader<T>,
+    columns: &SegmentationColumns,
+    builder: &mut SegmentationBuilder,
+) -> Result<()> {
+    let csv_error = |source| Error::Csv {
+        path: path.to_string(),
+        source,
+    };
+    let headers = rdr.headers().map_err(csv_error)?.clone();
+    let id_col = find_column(path, &headers, columns.transcript_id)?;
+    let cell_col = find_column(path, &headers, columns.cell)?;
+    let gene_col = columns
+        .gene
+        .map(|gene| find_column(path, &headers, gene))
+        .transpose()?;
+
+    let mut row = csv::StringRecord::new();
+    while rdr.read_record(&mut row).map_err(csv_error)? {
+        let transcript_id =
+            parse_field::<u64>(path, &headers, &row, id_col, "an integer transcript ID")?;
+        let cell = &row[cell_col];
+        builder.push(
+            transcript_id,
+            if cell == columns.unassigned || cell.is_empty() {
+                None
+            } else {
+                Some(cell)
+            },
+            gene_col.map(|gene_col| &row[gene_col]),
+        );
+    }
+    Ok(())
+}
+
+fn read_segmentation_parquet(
+    path: &str,
+    columns: &SegmentationColumns,
+    builder: &mut SegmentationBuilder,
+) -> Result<()> {
+    let parquet_error = |source| Error::Parquet {
+        path: path.to_string(),
+        source,
+    };
+    let file = File::open(path).map_err(|source| Error::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    let builder_ = ParquetRecordBatchReaderBuilder::try_new(file).map_err(parquet_error)?;
+    let schema = builder_.schema().as_ref().clone();
+    let id_col_idx = find_parquet_column(path, &schema, columns.transcript_id)?;
+    let cell_col_idx = find_parquet_column(path, &schema, columns.cell)?;
+    let gene_col_idx = columns
+        .gene
+        .map(|gene| find_parquet_column(path, &schema, gene))
+        .transpose()?;
+    let rdr = builder_.build().map_err(parquet_error)?;
+
+    for rec_batch in rdr {
+        let rec_batch = rec_batch.map_err(|source| Error::Arrow {
+            path: path.to_string(),
+            source,
+        })?;
+        let id_col: UInt64Array = parquet_column(path, &rec_batch, id_col_idx, &DataType::UInt64)?;
+        let cell_col: StringArray =
+            parquet_column(path, &rec_batch, cell_col_idx, &DataType::Utf8)?;
+        let gene_col: Option<StringArray> = gene_col_idx
+            .map(|idx| parquet_column(path, &rec_batch, idx, &DataType::Utf8))
+            .transpose()?;
+
+        for i in 0..rec_batch.num_rows() {
+            let cell = if cell_col.is_null(i) {
+                None
+            } else {
+                Some(cell_col.value(i))
+                    .filter(|cell| *cell != columns.unassigned && !cell.is_empty())
+            };
+            builder.push(
+                id_col.value(i),
+                cell,
+                gene_col.as_ref().map(|gene_col| gene_col.value(i)),
+            );
+        }
+    }
+    Ok(())
+}
+
+// Compare two segmentations of the same transcripts, matched on transcript
+// id. Genes are taken from `seg`, which must have them.
+pub fn compare_segmentations(seg: &Segmentation, truth: &Segmentation) -> Comparison {
+    let truth_index: HashMap<u64, usize> = truth
+        .transcript_ids
+        .iter()
+        .enumerate()
+        .map(|(i, &id)| (id, i))
+        .collect();
+
+    // joint counts of (cell, truth cell), including background
+    let mut joint: HashMap<(CellIndex, CellIndex), usize> = HashMap::new();
+    let mut cell_genes: HashMap<(CellIndex, u32), u32> = HashMap::new();
+    let mut truth_cell_genes: HashMap<(CellIndex, u32), u32> = HashMap::new();
+    let mut ntranscripts = 0;
+    for (i, id) in seg.transcript_ids.iter().enumerate() {
+        if let Some(&j) = truth_index.get(id) {
+            let (a, b) = (seg.cells[i], truth.cells[j]);
+            *joint.entry((a, b)).or_insert(0) += 1;
+            ntranscripts += 1;
+
+            let gene = seg.genes[i];
+            if a != BACKGROUND_CELL {
+                *cell_genes.entry((a, gene)).or_insert(0) += 1;
+            }
+            if b != BACKGROUND_CELL {
+                *truth_cell_genes.entry((b, gene)).or_insert(0) += 1;
+            }
+        }
+    }
+
+    let mut cell_counts: HashMap<CellIndex, usize> = HashMap::new();
+    let mut truth_cell_counts: HashMap<CellIndex, usize> = HashMap::new();
+    for (&(a, b), &count) in joint.iter() {
+        *cell_counts.entry(a).or_insert(0) += count;
+        *truth_cell_counts.entry(b).or_insert(0) += count;
+    }
+
+    // Greedily match cells one-to-one, in order of shared transcripts.
+    let mut pairs: Vec<((CellIndex, CellIndex), usize)> = joint
+        .iter()
+        .filter(|((a, b), _)| *a != BACKGROUND_CELL && *b != BACKGROUND_CELL)
+        .map(|(&pair, &count)| (pair, count))
+        .collect();
+    pairs.sort_unstable_by(|x, y| y.1.cmp(&x.1).then(x.0.cmp(&y.0)));
+
+    let ngenes = seg.gene_names.len();
+    let gene_counts = |counts: &HashMap<(CellIndex, u32), u32>, cell: CellIndex| -> Vec<f32> {
+        (0..ngenes as u32)
+            .map(|gene| *counts.get(&(cell, gene)).unwrap_or(&0) as f32)
+            .collect()
+    };
+
+    let mut matched_cells: HashMap<CellIndex, CellIndex> = HashMap::new();
+    let mut matched_truth_cells: HashMap<CellIndex, CellIndex> = HashMap::new();
+    let mut matches = Vec::new();
+    for ((a, b), shared) in pairs {
+        if matched_cells.contains_key(&a) || matched_truth_cells.contains_key(&b) {
+            continue;
+        }
+        matched_cells.insert(a, b);
+        matched_truth_cells.insert(b, a);
+
+        let count = cell_counts[&a];
+        let truth_count = truth_cell_counts[&b];
+        matches.push(CellMatch {
+            cell: a,
+            truth_cell: b,
+            count,
+            truth_count,
+            shared,
+            iou: shared as f32 / (count + truth_count - shared) as f32,
+            correlation: pearson_correlation(
+                &gene_counts(&cell_genes, a),
+                &gene_counts(&truth_cell_genes, b),
+            ),
+        });
+    }
+    matches.sort_unstable_by_key(|m| m.cell);
+
+    let agreed: usize = joint
+        .iter()
+        .filter(|((a, b), _)| {
+            (*a == BACKGROUND_CELL && *b == BACKGROUND_CELL) || matched_cells.get(a) == Some(b)
+        })
+        .map(|(_, &count)| count)
+        .sum();
+
+    // mutual information between assignments, treating background as a label
+    let n = ntranscripts.max(1) as f64;
+    let entropy = |counts: &HashMap<CellIndex, usize>| -> f64 {
+        counts
+            .values()
+            .map(|&count| {
+                let p = count as f64 / n;
+                -p * p.ln()
+            })
+            .sum()
+    };
+    let mutual_information: f64 = joint
+        .iter()
+        .map(|(&(a, b), &count)| {
+            let p = count as f64 / n;
+            let pa = cell_counts[&a] as f64 / n;
+            let pb = truth_cell_counts[&b] as f64 / n;
+            p * (p / (pa * pb)).ln()
+        })
+        .sum();
+    let h = entropy(&cell_counts) + entropy(&truth_cell_counts);
+    let normalized_mutual_information = if h > 0.0 {
+        2.0 * mutual_information / h
+    } else {
+        1.0
+    };
+
+    Comparison {
+        ntranscripts,
+        agreement: agreed as f32 / ntranscripts.max(1) as f32,
+        ncells: cell_counts
+            .keys()
+            .filter(|&&a| a != BACKGROUND_CELL)
+            .count(),
+        truth_ncells: truth_cell_counts
+            .keys()
+            .filter(|&&b| b != BACKGROUND_CELL)
+            .count(),
+        matches,
+        mutual_information: mutual_information as f32,
+        normalized_mutual_information: normalized_mutual_information as f32,
+    }
+}
+
+fn pearson_correlation(xs: &[f32], ys: &[f32]) -> f32 {
+    let n = xs.len() as f32;
+    let mx = xs.iter().sum::<f32>() / n;
+    let my = ys.iter().sum::<f32>() / n;
+    let (mut sxy, mut sxx, mut syy) = (0.0, 0.0, 0.0);
+    for (x, y) in xs.iter().zip(ys) {
+        sxy += (x - mx) * (y - my);
+        sxx += (x - mx) * (x - mx);
+        syy += (y - my) * (y - my);
+    }
+    sxy / (sxx * syy).sqrt()
+}
@@ -0,0 +1,141 @@
+// Errors reading input data.
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Csv {
+        path: String,
+        source: csv::Error,
+    },
+    Parquet {
+        path: String,
+        source: parquet::errors::ParquetError,
+    },
+    Arrow {
+        path: String,
+        source: arrow::error::ArrowError,
+    },
+    Image {
+        path: String,
+        message: String,
+    },
+    GeoJson {
+        path: String,
+        message: String,
+    },
+    ExpressionPrior {
+        path: String,
+        message: String,
+    },
+    GenePanel {
+        path: String,
+        message: String,
+    },
+    Pbin {
+        path: String,
+        message: String,
+    },
+    Transform {
+        path: String,
+        message: String,
+    },
+    UnknownFormat {
+        path: String,
+    },
+    UnsupportedFormat {
+        path: String,
+        format: String,
+    },
+    MissingColumn {
+        path: String,
+        column: String,
+    },
+    MissingNuclearValue {
+        path: String,
+        column: String,
+    },
+    ColumnType {
+        path: String,
+        column: String,
+        data_type: String,
+    },
+    InvalidValue {
+        path: String,
+        row: u64,
+        column: String,
+        value: String,
+        expected: &'static str,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io { path, source } => write!(f, "{}: {}", path, source),
+            Error::Csv { path, source } => write!(f, "{}: {}", path, source),
+            Error::Parquet { path, source } => write!(f, "{}: {}", path, source),
+            Error::Arrow { path, source } => write!(f, "{}: {}", path, source),
+            Error::Image { path, message } => write!(f, "{}: {}", path, message),
+            Error::GeoJson { path, message } => write!(f, "{}: {}", path, message),
+            Error::ExpressionPrior { path, message } => write!(f, "{}: {}", path, message),
+            Error::GenePanel { path, message } => write!(f, "{}: {}", path, message),
+            Error::Pbin { path, message } => write!(f, "{}: {}", path, message),
+            Error::Transform { path, message } => write!(f, "{}: {}", path, message),
+            Error::UnknownFormat { path } => write!(
+                f,
+                "{}: could not infer file format from the extension, use --format to specify it",
+                path
+            ),
+            Error::UnsupportedFormat { path, format } => {
+                write!(f, "{}: transcripts can not be read from {} files", path, format)
+            }
+            Error::MissingColumn { path, column } => {
+                write!(f, "{}: column '{}' not found", path, column)
+            }
+            Error::MissingNuclearValue { path, column } => write!(
+                f,
+                "{}: compartment column '{}' found, but no --compartment-nuclear value was given to identify nuclear transcripts",
+                path, column
+            ),
+            Error::ColumnType {
+                path,
+                column,
+                data_type,
+            } => write!(
+                f,
+                "{}: column '{}' can not be converted to {}",
+                path, column, data_type
+            ),
+            Error::InvalidValue {
+                path,
+                row,
+                column,
+                value,
+                expected,
+            } => write!(
+                f,
+                "{}:{}: expected {} in column '{}', found '{}'",
+                path, row, expected, column, value
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            Error::Csv { source, .. } => Some(source),
+            Error::Parquet { source, .. } => Some(source),
+            Error::Arrow { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
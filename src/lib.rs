@@ -0,0 +1,1547 @@
+#![allow(confusable_idents)]
+
+//! Proseg: probabilistic cell segmentation for in situ spatial transcriptomics.
+//!
+//! The `proseg` binary is a thin command line wrapper around this library. To
+//! embed the segmentation engine, read a transcript table, set up priors, and
+//! run the sampler with [`Proseg`]:
+//!
+//! ```no_run
+//! # use proseg::Proseg;
+//! # use proseg::sampler::ModelPriors;
+//! # use proseg::sampler::transcripts::TranscriptDataset;
+//! # fn example(dataset: &TranscriptDataset, priors: ModelPriors) -> Result<(), proseg::ProsegError> {
+//! let result = Proseg::new(dataset, priors, 1000.0, 1.0)
+//!     .ncomponents(10)
+//!     .schedule(vec![150, 150, 300])
+//!     .run()?;
+//! let cell_centroids = result.sampler.cell_centroids();
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod checkpoint;
+pub mod compare;
+pub mod error;
+pub mod memory;
+pub mod output;
+pub mod profile;
+pub mod sampler;
+pub mod schemas;
+pub mod status;
+pub mod validate;
+#[cfg(feature = "wasm32")]
+pub mod wasm;
+
+use checkpoint::{Checkpoint, CheckpointRef};
+use indicatif::{ProgressBar, ProgressStyle};
+use output::write_cell_layered_multipolygons;
+use profile::Profiler;
+use status::RunStatus;
+use sampler::boundary::BoundaryPrior;
+use sampler::chunks::ChunkLayout;
+use sampler::expression_prior::ExpressionPrior;
+use sampler::roi::Roi;
+use sampler::transcripts::{coordinate_span, Transcript, TranscriptDataset};
+use sampler::voxelsampler::VoxelSampler;
+use sampler::{
+    AcceptanceRates, IterationDiagnostics, ModelParams, ModelPriors, ProposalStats, Sampler,
+    UncertaintyTracker,
+};
+use rand::Rng;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Called with the current state every so many iterations (see
+/// [`Proseg::intermediate_output`]).
+pub type IntermediateOutput<'a> = Box<dyn Fn(&TranscriptDataset, &ModelParams, &VoxelSampler) + Send + Sync + 'a>;
+
+/// Called with the phase (from 0) and the sampler at the end of every phase
+/// but the last, before the voxel resolution is doubled (see
+/// [`Proseg::phase_output`]).
+pub type PhaseOutput<'a> = Box<dyn Fn(usize, &VoxelSampler) + Send + Sync + 'a>;
+
+/// Configuration of a segmentation run. Construct with [`Proseg::new`], adjust
+/// settings with the builder methods, and call [`Proseg::run`].
+pub struct Proseg<'a> {
+    dataset: &'a TranscriptDataset,
+    priors: ModelPriors,
+    full_layer_volume: f32,
+    layer_depth: f32,
+    ncomponents: usize,
+    nbglayers: usize,
+    voxel_layers: usize,
+    initial_voxel_size: f32,
+    cells_per_chunk: usize,
+    density_chunks: bool,
+    adaptive_chunks: bool,
+    chunk_shift_interval: usize,
+    boundary: Option<Arc<BoundaryPrior>>,
+    editable_region: Option<Arc<Roi>>,
+    expression_prior: Option<ExpressionPrior>,
+    prior_source_confidence: Vec<(String, f32)>,
+    schedule: Vec<usize>,
+    temperature_schedule: Vec<f32>,
+    recorded_samples: usize,
+    morphology_steps_per_iter: usize,
+    split_merge_moves: usize,
+    birth_death_moves: usize,
+    ncells_ratio_bounds: (Option<f32>, Option<f32>),
+    fill_holes: bool,
+    double_z_layers: bool,
+    check_consistency: bool,
+    monitor_cell_polygons: Option<String>,
+    monitor_cell_polygons_freq: usize,
+    checkpoint: Option<String>,
+    checkpoint_interval: usize,
+    intermediate_outputs: Vec<(usize, IntermediateOutput<'a>)>,
+    phase_output: Option<PhaseOutput<'a>>,
+    resume: Option<String>,
+    interrupt: Option<Arc<AtomicBool>>,
+    convergence_eps: Option<f32>,
+    convergence_window: usize,
+    nchains: usize,
+    spill_chains: bool,
+    progress: bool,
+    profiler: Option<Arc<Profiler>>,
+    status: Option<Arc<RunStatus>>,
+    seed: Option<u64>,
+}
+
+// Position in the sampling schedule, tracked so checkpoints can resume mid-phase.
+struct SchedulePosition {
+    chain: usize,
+    phase: usize,
+    // iterations run so far in the current phase, of which `recorded_iterations`
+    // recorded samples
+    phase_iteration: usize,
+    recorded_iterations: usize,
+    // set once the phase's burn-in has run its course or converged
+    burnin_finished: bool,
+    total_steps: usize,
+    // set when resuming mid-phase, where global parameters were already
+    // sampled for the current iteration
+    resumed: bool,
+}
+
+/// State of the sampler at the end of a run, from which all outputs are derived.
+pub struct ProsegResult {
+    pub params: ModelParams,
+    pub sampler: VoxelSampler,
+    pub uncertainty: UncertaintyTracker,
+    /// Summary statistics recorded after every iteration, of every chain.
+    pub diagnostics: Vec<IterationDiagnostics>,
+    /// Agreement between chains, when more than one was run.
+    pub chains: Option<ChainSummary>,
+}
+
+/// Reasons [`Proseg::run`] or [`Proseg::run_deterministic`] can fail.
+#[derive(Debug)]
+pub enum ProsegError {
+    /// Settings that are invalid, or can't be used together.
+    InvalidOptions(String),
+    /// A checkpoint being resumed from that doesn't match the run.
+    Checkpoint { path: String, message: String },
+    /// A deterministic run whose repeat gave a different result.
+    NotReproducible(String),
+}
+
+impl std::fmt::Display for ProsegError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProsegError::InvalidOptions(message) => write!(f, "{}", message),
+            ProsegError::Checkpoint { path, message } => write!(f, "Checkpoint '{}' {}", path, message),
+            ProsegError::NotReproducible(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for ProsegError {}
+
+/// Comparison of independent chains run with [`Proseg::nchains`].
+pub struct ChainSummary {
+    /// Fraction of chains whose maximum posterior assignment of each
+    /// transcript matches the consensus assignment.
+    pub agreement: Vec<f32>,
+    /// Potential scale reduction factor (R-hat) of summary statistics over the
+    /// recorded samples, as (statistic, R-hat) pairs. Values near 1 indicate
+    /// the chains mixed.
+    pub rhat: Vec<(&'static str, f32)>,
+}
+
+impl<'a> Proseg<'a> {
+    /// `full_layer_volume` is the estimated volume of tissue in each background
+    /// layer, and `layer_depth` the z-depth of those layers.
+    pub fn new(
+        dataset: &'a TranscriptDataset,
+        priors: ModelPriors,
+        full_layer_volume: f32,
+        layer_depth: f32,
+    ) -> Self {
+        Proseg {
+            dataset,
+            priors,
+            full_layer_volume,
+            layer_depth,
+            ncomponents: 10,
+            nbglayers: 4,
+            voxel_layers: 1,
+            initial_voxel_size: 4.0,
+            cells_per_chunk: 100,
+            density_chunks: false,
+            adaptive_chunks: false,
+            chunk_shift_interval: 10,
+            boundary: None,
+            editable_region: None,
+            expression_prior: None,
+            prior_source_confidence: Vec::new(),
+            schedule: vec![150, 150, 300],
+            temperature_schedule: Vec::new(),
+            recorded_samples: 100,
+            morphology_steps_per_iter: 1000,
+            split_merge_moves: 0,
+            birth_death_moves: 0,
+            ncells_ratio_bounds: (None, None),
+            fill_holes: false,
+            double_z_layers: true,
+            check_consistency: false,
+            monitor_cell_polygons: None,
+            monitor_cell_polygons_freq: 10,
+            checkpoint: None,
+            checkpoint_interval: 100,
+            intermediate_outputs: Vec::new(),
+            phase_output: None,
+            resume: None,
+            interrupt: None,
+            convergence_eps: None,
+            convergence_window: 20,
+            nchains: 1,
+            spill_chains: false,
+            progress: true,
+            profiler: None,
+            status: None,
+            seed: None,
+        }
+    }
+
+    /// Number of components in the mixture model of cellular gene expression.
+    pub fn ncomponents(mut self, ncomponents: usize) -> Self {
+        self.ncomponents = ncomponents;
+        self
+    }
+
+    /// Number of z-axis layers used to model background expression.
+    pub fn nbglayers(mut self, nbglayers: usize) -> Self {
+        self.nbglayers = nbglayers;
+        self
+    }
+
+    /// Number of layers of voxels in the z-axis used for segmentation.
+    pub fn voxel_layers(mut self, voxel_layers: usize) -> Self {
+        self.voxel_layers = voxel_layers;
+        self
+    }
+
+    /// Initial x/y size of voxels.
+    pub fn initial_voxel_size(mut self, initial_voxel_size: f32) -> Self {
+        self.initial_voxel_size = initial_voxel_size;
+        self
+    }
+
+    /// Target number of cells per chunk in the parallelization scheme.
+    pub fn cells_per_chunk(mut self, cells_per_chunk: usize) -> Self {
+        self.cells_per_chunk = cells_per_chunk;
+        self
+    }
+
+    /// Divide the data into chunks with equal numbers of transcripts, rather
+    /// than on a regular grid. This balances work between threads when tissue
+    /// is irregularly shaped or has large empty regions.
+    pub fn density_chunks(mut self, density_chunks: bool) -> Self {
+        self.density_chunks = density_chunks;
+        self
+    }
+
+    /// Start with density balanced chunks (see [`Proseg::density_chunks`]), and
+    /// rebuild them each time the voxel resolution is doubled, balancing the
+    /// number of voxels on cell boundaries rather than transcripts, with chunks
+    /// allowed to shrink along with voxels. This keeps threads busy in the
+    /// fine phases, where work is concentrated on the boundaries of cells.
+    pub fn adaptive_chunks(mut self, adaptive_chunks: bool) -> Self {
+        self.adaptive_chunks = adaptive_chunks;
+        self
+    }
+
+    /// Shift chunks by a random offset every `interval` iterations, so cells
+    /// on the seams between chunks are sampled as well as those elsewhere.
+    /// Shifting has some cost, and is disabled with an interval of 0.
+    pub fn chunk_shift_interval(mut self, interval: usize) -> Self {
+        self.chunk_shift_interval = interval;
+        self
+    }
+
+    /// Number of iterations between each doubling of resolution.
+    pub fn schedule(mut self, schedule: Vec<usize>) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Temperature of cell boundary proposals in each phase of the schedule.
+    /// The change in log posterior of a proposal is divided by the temperature
+    /// before accepting or rejecting it, so temperatures above 1 help escape a
+    /// poor initialization, and a temperature of 0 accepts only proposals that
+    /// improve the posterior, for a crisp maximum a posteriori segmentation.
+    /// At temperature 0, transcript positions and global parameters are also
+    /// updated greedily. Phases without a temperature are run at 1, sampling
+    /// the posterior.
+    pub fn temperature_schedule(mut self, temperatures: Vec<f32>) -> Self {
+        self.temperature_schedule = temperatures;
+        self
+    }
+
+    /// Number of samples at the end of the schedule used to compute
+    /// expectations and uncertainty.
+    pub fn recorded_samples(mut self, recorded_samples: usize) -> Self {
+        self.recorded_samples = recorded_samples;
+        self
+    }
+
+    /// Number of sub-iterations sampling cell morphology per overall iteration.
+    pub fn morphology_steps_per_iter(mut self, morphology_steps_per_iter: usize) -> Self {
+        self.morphology_steps_per_iter = morphology_steps_per_iter;
+        self
+    }
+
+    /// Number of moves per iteration that propose splitting a cell in two, or
+    /// merging it into a neighbor, letting the number of cells change. These
+    /// are made only before samples are recorded. Cells merged away are left
+    /// with no voxels (see `VoxelSampler::empty_cells`), and cells created by
+    /// splits are numbered after the initial ones.
+    pub fn split_merge_moves(mut self, split_merge_moves: usize) -> Self {
+        self.split_merge_moves = split_merge_moves;
+        self
+    }
+
+    /// Number of moves per iteration that propose creating a cell from a
+    /// region of unassigned transcripts, or returning a cell to the background,
+    /// recovering cells whose nuclei were missed. New cells' rates have the
+    /// gamma prior given by their component's dispersion, which keeps sparse
+    /// background from forming spurious cells. Like split and merge moves,
+    /// these are made only before samples are recorded.
+    pub fn birth_death_moves(mut self, birth_death_moves: usize) -> Self {
+        self.birth_death_moves = birth_death_moves;
+        self
+    }
+
+    /// Bound the number of cells that split, merge, birth, and death moves can
+    /// leave, as multiples of the initial number of cells, so noisy data can't
+    /// make the number of cells collapse or explode. Moves past either bound
+    /// aren't proposed. By default the number of cells is unbounded.
+    pub fn ncells_ratio_bounds(mut self, min_ratio: Option<f32>, max_ratio: Option<f32>) -> Self {
+        self.ncells_ratio_bounds = (min_ratio, max_ratio);
+        self
+    }
+
+    // Bounds on the number of non-empty cells.
+    fn ncells_bounds(&self) -> (usize, usize) {
+        let ncells = self.dataset.nucleus_population.len() as f32;
+        let (min_ratio, max_ratio) = self.ncells_ratio_bounds;
+        (
+            min_ratio.map_or(0, |ratio| (ratio * ncells).ceil() as usize),
+            max_ratio.map_or(usize::MAX, |ratio| (ratio * ncells).floor() as usize),
+        )
+    }
+
+    /// After sampling, assign unassigned voxels enclosed by a single cell, and
+    /// their transcripts, to that cell.
+    pub fn fill_holes(mut self, fill_holes: bool) -> Self {
+        self.fill_holes = fill_holes;
+        self
+    }
+
+    /// Whether to double the z-layers when doubling resolution.
+    pub fn double_z_layers(mut self, double_z_layers: bool) -> Self {
+        self.double_z_layers = double_z_layers;
+        self
+    }
+
+    /// Run (slow) consistency checks between schedule phases.
+    pub fn check_consistency(mut self, check_consistency: bool) -> Self {
+        self.check_consistency = check_consistency;
+        self
+    }
+
+    /// Write cell polygons to `{basename}-{step}.geojson.gz` every `freq` iterations.
+    pub fn monitor_cell_polygons(mut self, basename: Option<String>, freq: usize) -> Self {
+        self.monitor_cell_polygons = basename;
+        self.monitor_cell_polygons_freq = freq;
+        self
+    }
+
+    /// Save sampler state to `filename` every `interval` iterations.
+    pub fn checkpoint(mut self, filename: Option<String>, interval: usize) -> Self {
+        self.checkpoint = filename;
+        self.checkpoint_interval = interval;
+        self
+    }
+
+    /// Call `output` with the current state every `interval` iterations, e.g.
+    /// to write intermediate results that can be inspected before the run
+    /// finishes. With several chains, it's called for each in turn. Can be
+    /// given more than once, with different intervals.
+    pub fn intermediate_output(mut self, interval: usize, output: IntermediateOutput<'a>) -> Self {
+        self.intermediate_outputs.push((interval, output));
+        self
+    }
+
+    /// Call `output` at the end of each phase of the schedule but the last,
+    /// e.g. to record the state at each voxel resolution.
+    pub fn phase_output(mut self, output: PhaseOutput<'a>) -> Self {
+        self.phase_output = Some(output);
+        self
+    }
+
+    /// Penalize cells for covering cell boundaries in a boundary stain image
+    /// (see [`sampler::boundary`]).
+    pub fn boundary_prior(mut self, boundary: Option<BoundaryPrior>) -> Self {
+        self.boundary = boundary.map(Arc::new);
+        self
+    }
+
+    /// Only change cell assignments of voxels within `region`, keeping the
+    /// initial segmentation everywhere else, as when refining part of a
+    /// previous run.
+    pub fn editable_region(mut self, region: Option<Roi>) -> Self {
+        self.editable_region = region.map(Arc::new);
+        self
+    }
+
+    /// Use reference cell type expression as a prior on mixture components (see
+    /// [`sampler::expression_prior`]). This sets the number of components to the
+    /// number of cell types.
+    pub fn expression_prior(mut self, expression_prior: Option<ExpressionPrior>) -> Self {
+        if let Some(expression_prior) = &expression_prior {
+            self.ncomponents = expression_prior.ncomponents();
+        }
+        self.expression_prior = expression_prior;
+        self
+    }
+
+    /// Probability that prior cell assignments are correct, for each named
+    /// source in the dataset's `prior_source_names`. Sources not listed use the
+    /// priors' `prior_seg_reassignment_log_prob`.
+    pub fn prior_source_confidence(mut self, confidence: Vec<(String, f32)>) -> Self {
+        self.prior_source_confidence = confidence;
+        self
+    }
+
+    /// Resume sampling from a checkpoint written with the same data and schedule.
+    pub fn resume(mut self, filename: Option<String>) -> Self {
+        self.resume = filename;
+        self
+    }
+
+    /// Stop sampling early, after the current iteration, once `flag` is set
+    /// (e.g. from a SIGINT handler). The run then returns the current state.
+    pub fn interrupt(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.interrupt = Some(flag);
+        self
+    }
+
+    /// End each phase of the schedule early once the sampler has plateaued:
+    /// when the mean log likelihood over the last `window` iterations changes
+    /// by a relative amount less than `eps` from the `window` iterations before,
+    /// and the fraction of unassigned transcripts by less than `eps`. Recorded
+    /// samples are always run in full.
+    pub fn convergence(mut self, eps: Option<f32>, window: usize) -> Self {
+        self.convergence_eps = eps;
+        self.convergence_window = window;
+        self
+    }
+
+    /// Run `nchains` independent chains and merge them. The posterior over
+    /// transcript assignments pools the recorded samples of every chain, while
+    /// cell shapes and parameters are taken from the chain with the highest
+    /// final log likelihood.
+    pub fn nchains(mut self, nchains: usize) -> Self {
+        self.nchains = nchains;
+        self
+    }
+
+    /// With several chains, write the recorded samples of each finished chain
+    /// to a temporary file until chains are merged, rather than keeping them
+    /// in memory, so that memory use doesn't grow with the number of chains.
+    pub fn spill_chains(mut self, spill_chains: bool) -> Self {
+        self.spill_chains = spill_chains;
+        self
+    }
+
+    /// Seed the random number generator, making runs reproducible. Each chain
+    /// is seeded with `seed` plus its index. By default a random seed is used.
+    pub fn seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Show a progress bar on the terminal while sampling (on by default).
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Record the time spent in each phase of sampling, and in local proposals
+    /// in each chunk.
+    pub fn profiler(mut self, profiler: Option<Arc<Profiler>>) -> Self {
+        self.profiler = profiler;
+        self
+    }
+
+    /// Report the phase, log likelihood, and other diagnostics of each
+    /// iteration to `status`, e.g. to serve them with [`RunStatus::serve`].
+    pub fn status(mut self, status: Option<Arc<RunStatus>>) -> Self {
+        self.status = status;
+        self
+    }
+
+    // Run `f`, recording its time under `phase` if profiling.
+    fn timed<T>(&self, phase: &str, f: impl FnOnce() -> T) -> T {
+        match &self.profiler {
+            Some(profiler) => profiler.time(phase, f),
+            None => f(),
+        }
+    }
+
+    // Check whether the trace of the current phase has plateaued.
+    fn converged(&self, phase_diagnostics: &[IterationDiagnostics]) -> bool {
+        let eps = match self.convergence_eps {
+            Some(eps) => eps,
+            None => return false,
+        };
+
+        let window = self.convergence_window;
+        if window == 0 || phase_diagnostics.len() < 2 * window {
+            return false;
+        }
+
+        let n = phase_diagnostics.len();
+        let mean = |ds: &[IterationDiagnostics], f: fn(&IterationDiagnostics) -> f32| {
+            ds.iter().map(f).sum::<f32>() / ds.len() as f32
+        };
+        let (prev, last) = (&phase_diagnostics[n - 2 * window..n - window], &phase_diagnostics[n - window..]);
+
+        let ll_prev = mean(prev, |d| d.log_likelihood);
+        let ll_last = mean(last, |d| d.log_likelihood);
+        let unassigned_prev = mean(prev, |d| d.unassigned_fraction);
+        let unassigned_last = mean(last, |d| d.unassigned_fraction);
+
+        (ll_last - ll_prev).abs() < eps * ll_prev.abs() && (unassigned_last - unassigned_prev).abs() < eps
+    }
+
+    fn interrupted(&self) -> bool {
+        self.interrupt
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    // Whether (x, y) is in the part of the sample that's sampled: everywhere,
+    // or when refining, the bounds of the region plus a margin as wide as the
+    // smallest chunks, so chunks reach past the voxels along its border.
+    fn sampled(&self, x: f32, y: f32) -> bool {
+        self.editable_region.as_ref().is_none_or(|region| {
+            let bounds = region.bounds();
+            let margin = min_chunk_size(self.initial_voxel_size);
+            x >= bounds.min().x - margin
+                && x <= bounds.max().x + margin
+                && y >= bounds.min().y - margin
+                && y <= bounds.max().y + margin
+        })
+    }
+
+    // Find a reasonable way to chunk the data. When refining, only the region
+    // being refined is chunked, since no proposals are made elsewhere.
+    fn chunk_layout(&self) -> ChunkLayout {
+        let transcripts: Cow<[Transcript]> = if self.editable_region.is_some() {
+            Cow::Owned(
+                self.dataset
+                    .transcripts
+                    .iter()
+                    .filter(|t| self.sampled(t.x, t.y))
+                    .cloned()
+                    .collect(),
+            )
+        } else {
+            Cow::Borrowed(&self.dataset.transcripts)
+        };
+
+        // cells in the sampled part, supposing they're spread like transcripts
+        let ncells = (self.dataset.nucleus_population.len() * transcripts.len())
+            .div_ceil(self.dataset.transcripts.len());
+
+        if self.density_chunks || self.adaptive_chunks {
+            let nchunks = ncells.div_ceil(self.cells_per_chunk);
+            let min_chunk_size = min_chunk_size(self.initial_voxel_size);
+            let chunks = ChunkLayout::balanced(&transcripts, nchunks, min_chunk_size);
+            println!("Using density balanced chunks. Chunks: {}", chunks.nchunks());
+            return chunks;
+        }
+
+        let (xmin, xmax, ymin, ymax, _zmin, _zmax) = coordinate_span(&transcripts);
+        let (xspan, yspan) = (xmax - xmin, ymax - ymin);
+        let area = xspan * yspan;
+
+        let cell_density = ncells as f32 / area;
+        let chunk_size = (self.cells_per_chunk as f32 / cell_density).sqrt();
+
+        let chunks = ChunkLayout::grid(xmin, xmax, ymin, ymax, chunk_size);
+        println!("Using grid size {}. Chunks: {}", chunk_size, chunks.nchunks());
+
+        chunks
+    }
+
+    // Chunks balanced by the number of cell boundary voxels in each, if there
+    // are any.
+    fn rebalanced_chunk_layout(&self, sampler: &VoxelSampler) -> Option<ChunkLayout> {
+        let positions = sampler.boundary_positions();
+        let npositions = positions.len();
+        let positions: Vec<(f32, f32)> = positions
+            .into_iter()
+            .filter(|&(x, y)| self.sampled(x, y))
+            .collect();
+        if positions.is_empty() {
+            return None;
+        }
+        let nchunks = (self.dataset.nucleus_population.len() * positions.len())
+            .div_ceil(npositions * self.cells_per_chunk);
+        Some(ChunkLayout::balanced_points(
+            positions,
+            nchunks,
+            min_chunk_size(sampler.voxel_size()),
+        ))
+    }
+
+    /// Run the sampler through the full schedule.
+    pub fn run(&self) -> Result<ProsegResult, ProsegError> {
+        self.check_options()?;
+        let seed = self.seed.unwrap_or_else(|| rand::thread_rng().gen());
+        self.run_chains(seed)
+    }
+
+    /// Run the sampler through the full schedule on a single thread, so chunks
+    /// are always visited in the same order and floating point sums are taken
+    /// in the same order, then repeat the run and return an error describing
+    /// the first difference if the results differ. Without a seed, 0 is used.
+    /// This doubles the cost of sampling, and is intended for regression tests
+    /// of the sampler on small datasets.
+    pub fn run_deterministic(&self) -> Result<ProsegResult, ProsegError> {
+        self.check_options()?;
+        let seed = self.seed.unwrap_or(0);
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+
+        let result = pool.install(|| self.run_chains(seed))?;
+        if self.interrupted() {
+            return Ok(result);
+        }
+
+        println!("Repeating run to check that it's reproducible");
+        let repeat = pool.install(|| self.run_chains(seed))?;
+        if !self.interrupted() {
+            check_reproducible(&result, &repeat).map_err(ProsegError::NotReproducible)?;
+        }
+        Ok(result)
+    }
+
+    fn check_options(&self) -> Result<(), ProsegError> {
+        let invalid = |message: &str| Err(ProsegError::InvalidOptions(message.to_string()));
+        if self.ncomponents == 0 {
+            return invalid("ncomponents must be at least 1");
+        }
+        if self.voxel_layers == 0 {
+            return invalid("voxel-layers must be at least 1");
+        }
+        if self.nchains == 0 {
+            return invalid("nchains must be at least 1");
+        }
+        match self.schedule.last() {
+            None => return invalid("schedule must have at least one entry"),
+            Some(&last) if self.recorded_samples > last => {
+                return invalid("recorded-samples must be <= the last entry in the schedule");
+            }
+            _ => {}
+        }
+        if self.nchains > 1 && (self.checkpoint.is_some() || self.resume.is_some()) {
+            return invalid("Checkpointing is not supported when running multiple chains");
+        }
+        let changes_ncells = self.split_merge_moves > 0 || self.birth_death_moves > 0;
+        if changes_ncells && self.nchains > 1 {
+            return invalid(
+                "Moves that change the number of cells are not supported when running multiple chains",
+            );
+        }
+        if changes_ncells && (self.checkpoint.is_some() || self.resume.is_some()) {
+            return invalid("Checkpointing is not supported with moves that change the number of cells");
+        }
+        if let Some(region) = &self.editable_region {
+            if !self.dataset.transcripts.iter().any(|t| region.contains(t.x, t.y)) {
+                return invalid("No transcripts are in the region being refined");
+            }
+        }
+        Ok(())
+    }
+
+    fn run_chains(&self, seed: u64) -> Result<ProsegResult, ProsegError> {
+        if self.nchains == 1 {
+            return self.run_chain(0, seed);
+        }
+
+        // Only the chain with the highest final log likelihood so far is kept
+        // whole, for its cell shapes. Of the others, just what's needed to merge
+        // them is kept.
+        let mut best: Option<(usize, ProsegResult)> = None;
+        let mut chains = Vec::new();
+        for chain in 0..self.nchains {
+            if self.interrupted() {
+                break;
+            }
+            println!("Running chain {} of {}", chain + 1, self.nchains);
+            let result = self.run_chain(chain, seed.wrapping_add(chain as u64))?;
+            chains.push(FinishedChain {
+                diagnostics: result.diagnostics.clone(),
+                assignments: result.uncertainty.max_posterior_cell_assignments(&result.params),
+                time: result.params.time(),
+                uncertainty: None,
+            });
+
+            // (ties going to the later chain)
+            let is_best = best.as_ref().is_none_or(|(_, best)| {
+                final_log_likelihood(&result).partial_cmp(&final_log_likelihood(best))
+                    != Some(std::cmp::Ordering::Less)
+            });
+            let other = if is_best {
+                best.replace((chain, result))
+            } else {
+                Some((chain, result))
+            };
+            if let Some((other_chain, other)) = other {
+                chains[other_chain].uncertainty = Some(self.set_aside(other.uncertainty));
+            }
+        }
+
+        Ok(self.merge_chains(best.unwrap(), chains))
+    }
+
+    // Keep a chain's recorded samples until chains are merged, in a temporary
+    // file with `spill_chains`.
+    fn set_aside(&self, uncertainty: UncertaintyTracker) -> SetAside {
+        if !self.spill_chains {
+            return SetAside::Memory(uncertainty);
+        }
+
+        // (several runs may spill at once, when FOVs are segmented in parallel)
+        static NSPILLED: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "proseg-chain-{}-{}.bin",
+            std::process::id(),
+            NSPILLED.fetch_add(1, Ordering::SeqCst)
+        ));
+        let file = File::create(&path)
+            .unwrap_or_else(|err| panic!("Unable to create '{}': {}", path.display(), err));
+        let mut writer = BufWriter::new(file);
+        bincode::serialize_into(&mut writer, &uncertainty)
+            .and_then(|_| writer.flush().map_err(|err| err.into()))
+            .unwrap_or_else(|err| panic!("Unable to write '{}': {}", path.display(), err));
+        SetAside::File(path)
+    }
+
+    // Pool the recorded samples of several chains into the best one. Chains all
+    // start from the same nuclei, so cell indices correspond between them.
+    fn merge_chains(&self, best: (usize, ProsegResult), mut chains: Vec<FinishedChain>) -> ProsegResult {
+        let nchains = chains.len();
+        let chain_diagnostics: Vec<&[IterationDiagnostics]> =
+            chains.iter().map(|chain| chain.diagnostics.as_slice()).collect();
+        let rhat = chain_rhat(&chain_diagnostics, self.recorded_samples);
+        let diagnostics = chain_diagnostics.concat();
+
+        let (best, mut result) = best;
+        for (chain, finished) in chains.iter_mut().enumerate() {
+            if let Some(uncertainty) = finished.uncertainty.take() {
+                assert!(chain != best);
+                result.uncertainty.merge(uncertainty.restore(), finished.time);
+            }
+        }
+
+        let consensus = result.uncertainty.max_posterior_cell_assignments(&result.params);
+        let agreement = consensus
+            .iter()
+            .enumerate()
+            .map(|(i, (j, _))| {
+                let nagree = chains
+                    .iter()
+                    .filter(|chain| chain.assignments[i].0 == *j)
+                    .count();
+                nagree as f32 / nchains as f32
+            })
+            .collect();
+
+        println!("Chain R-hat (using chain {} for cell shapes):", best + 1);
+        for (statistic, value) in &rhat {
+            println!("  {}: {:.3}", statistic, value);
+        }
+
+        result.diagnostics = diagnostics;
+        result.chains = Some(ChainSummary { agreement, rhat });
+        result
+    }
+
+    fn run_chain(&self, chain: usize, seed: u64) -> Result<ProsegResult, ProsegError> {
+        sampler::rng::set_seed(seed);
+
+        let dataset = self.dataset;
+        let priors = &self.priors;
+        let ngenes = dataset.transcript_names.len();
+        let ncells = dataset.nucleus_population.len();
+        let chunks = self.chunk_layout();
+
+        let mut params = self.timed("sampling;initialization", || ModelParams::new(
+            priors,
+            self.full_layer_volume,
+            priors.zmin,
+            self.layer_depth,
+            &dataset.transcripts,
+            &dataset.nucleus_assignments,
+            &dataset.nucleus_population,
+            &dataset.cell_assignments,
+            self.ncomponents,
+            self.nbglayers,
+            ncells,
+            ngenes,
+        ));
+        params.set_expression_prior(self.expression_prior.clone());
+        if !self.prior_source_confidence.is_empty() {
+            let log_probs = dataset
+                .prior_source_names
+                .iter()
+                .map(|name| {
+                    match self.prior_source_confidence.iter().find(|(source, _)| source == name) {
+                        Some(&(_, confidence)) => ((1.0 - confidence).ln(), confidence.ln()),
+                        None => (
+                            priors.prior_seg_reassignment_log_prob,
+                            priors.prior_seg_reassignment_1mlog_prob,
+                        ),
+                    }
+                })
+                .collect();
+            params.set_prior_seg_sources(&dataset.prior_sources, log_probs);
+        }
+
+        let total_iterations = self.schedule.iter().sum::<usize>();
+        let mut prog = if self.progress {
+            ProgressBar::new(total_iterations as u64)
+        } else {
+            ProgressBar::hidden()
+        };
+        prog.set_style(
+            ProgressStyle::with_template("{eta_precise} {bar:60} | {msg}")
+                .unwrap()
+                .progress_chars("##-"),
+        );
+
+        let mut uncertainty = UncertaintyTracker::new();
+
+        let mut sampler = self.timed("sampling;graph_construction", || {
+            let mut sampler = VoxelSampler::new(
+                priors,
+                &mut params,
+                &dataset.transcripts,
+                ngenes,
+                self.voxel_layers,
+                self.nbglayers,
+                priors.zmin,
+                self.layer_depth,
+                self.initial_voxel_size,
+                chunks,
+            );
+            sampler.set_boundary(self.boundary.clone());
+            sampler.set_editable_region(self.editable_region.clone());
+            if priors.enforce_connectivity {
+                let nrepaired = sampler.repair_connectivity(priors, &mut params);
+                if nrepaired > 0 {
+                    println!("Repaired {} initially disconnected cells", nrepaired);
+                }
+            }
+            sampler.initialize(priors, &mut params);
+            sampler
+        });
+
+        let mut checkpoint = self
+            .resume
+            .as_ref()
+            .map(|filename| {
+                let checkpoint = Checkpoint::load(filename);
+                let mismatch = |message: &str| {
+                    Err(ProsegError::Checkpoint {
+                        path: filename.clone(),
+                        message: message.to_string(),
+                    })
+                };
+                if checkpoint.schedule != self.schedule {
+                    return mismatch("was written with a different schedule");
+                }
+                if checkpoint.params.cell_assignments.len() != dataset.transcripts.len()
+                    || checkpoint.params.ncells() != ncells
+                {
+                    return mismatch("does not match the input data");
+                }
+                Ok(checkpoint)
+            })
+            .transpose()?;
+        let start_phase = checkpoint.as_ref().map_or(0, |checkpoint| checkpoint.phase);
+
+        let mut diagnostics = Vec::new();
+        let mut position = SchedulePosition {
+            chain,
+            phase: 0,
+            phase_iteration: 0,
+            recorded_iterations: 0,
+            burnin_finished: false,
+            total_steps: 0,
+            resumed: false,
+        };
+        let nphases = self.schedule.len();
+        for (phase, &niter) in self.schedule.iter().enumerate() {
+            if self.interrupted() {
+                break;
+            }
+
+            if phase > 0 {
+                if self.check_consistency && phase > start_phase {
+                    sampler.check_consistency(priors, &mut params);
+                }
+                if let Some(output) = self.phase_output.as_ref().filter(|_| phase > start_phase) {
+                    output(phase - 1, &sampler);
+                }
+                sampler = self.timed("sampling;resolution_doubling", || {
+                    sampler.double_resolution(&params, self.double_z_layers)
+                });
+                if self.adaptive_chunks {
+                    if let Some(chunks) = self.rebalanced_chunk_layout(&sampler) {
+                        sampler.set_chunks(chunks);
+                    }
+                }
+            }
+
+            // When resuming, earlier phases are only replayed to arrive at the
+            // right voxel resolution.
+            if phase < start_phase {
+                continue;
+            }
+
+            let nrecorded = if phase + 1 < nphases { 0 } else { self.recorded_samples };
+            let nunrecorded = niter - nrecorded;
+
+            position.phase = phase;
+            position.phase_iteration = 0;
+            position.recorded_iterations = 0;
+            position.burnin_finished = false;
+            if let Some(checkpoint) = checkpoint.take() {
+                params = checkpoint.params;
+                sampler.restore_voxel_cells(&params, &checkpoint.voxel_cells);
+                sampler.restore_proposal_state(checkpoint.proposal_state);
+                uncertainty = checkpoint.uncertainty;
+                position.phase_iteration = checkpoint.phase_iteration;
+                position.recorded_iterations = checkpoint.recorded_iterations;
+                position.burnin_finished = checkpoint.burnin_finished;
+                position.total_steps = checkpoint.total_steps;
+                diagnostics.extend(checkpoint.phase_diagnostics);
+                sampler::rng::restore_state(checkpoint.rng_state);
+
+                // A checkpoint written as the burn-in finished resumes at the
+                // start of the recorded samples (or the next phase), which,
+                // like any other, begins by sampling global parameters.
+                position.resumed = !position.burnin_finished || position.recorded_iterations > 0;
+
+                // Burn-in iterations skipped by converging before the checkpoint.
+                let nburnin = position.phase_iteration - position.recorded_iterations;
+                if position.burnin_finished && nburnin < nunrecorded {
+                    prog.set_length(prog.length().unwrap_or(0) - (nunrecorded - nburnin) as u64);
+                }
+                prog.set_position(position.total_steps as u64);
+                println!("Resuming from iteration {}", position.total_steps);
+            }
+
+            // Convergence can cut burn-in short, so the iterations left are
+            // taken from the counts actually run rather than the schedule.
+            let burnin_left = if position.burnin_finished {
+                0
+            } else {
+                nunrecorded - position.phase_iteration
+            };
+            self.run_hexbin_sampler(
+                &mut prog,
+                &mut sampler,
+                &mut params,
+                burnin_left,
+                None,
+                &mut position,
+                &mut diagnostics,
+                phase + 1 < nphases,
+            );
+            position.burnin_finished = true;
+
+            self.run_hexbin_sampler(
+                &mut prog,
+                &mut sampler,
+                &mut params,
+                nrecorded - position.recorded_iterations,
+                Some(&mut uncertainty),
+                &mut position,
+                &mut diagnostics,
+                false,
+            );
+        }
+
+        if self.interrupted() {
+            prog.abandon();
+            println!(
+                "Interrupted after {} of {} iterations. Writing output from the current state.",
+                position.total_steps, total_iterations
+            );
+        } else {
+            prog.finish();
+        }
+
+        if self.check_consistency {
+            sampler.check_consistency(priors, &mut params);
+        }
+
+        uncertainty.finish(&params);
+
+        if self.fill_holes {
+            let nfilled = sampler.fill_holes(priors, &mut params, &mut uncertainty);
+            if nfilled > 0 {
+                println!("Filled {} voxels in holes within cells", nfilled);
+            }
+        }
+
+        Ok(ProsegResult {
+            params,
+            sampler,
+            uncertainty,
+            diagnostics,
+            chains: None,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn run_hexbin_sampler(
+        &self,
+        prog: &mut ProgressBar,
+        sampler: &mut VoxelSampler,
+        params: &mut ModelParams,
+        niter: usize,
+        mut uncertainty: Option<&mut UncertaintyTracker>,
+        position: &mut SchedulePosition,
+        diagnostics: &mut Vec<IterationDiagnostics>,
+        burnin: bool,
+    ) {
+        let priors = &self.priors;
+        let transcripts: &Vec<Transcript> = &self.dataset.transcripts;
+        let temperature = self
+            .temperature_schedule
+            .get(position.phase)
+            .copied()
+            .unwrap_or(1.0);
+        // At temperature 0 global parameters and transcript positions are also
+        // updated greedily.
+        let greedy = temperature == 0.0;
+
+        if niter == 0 || self.interrupted() {
+            return;
+        }
+
+        if !std::mem::take(&mut position.resumed) {
+            self.timed("sampling;global_params", || {
+                sampler.sample_global_params(priors, params, transcripts, &mut uncertainty, burnin, greedy)
+            });
+        }
+        let mut proposal_stats = ProposalStats::new();
+        let mut phase_stats = ProposalStats::new();
+        // Convergence is judged over the whole phase, including any iterations
+        // run before resuming from a checkpoint.
+        let diagnostics_start = diagnostics
+            .iter()
+            .position(|d| d.phase == position.phase)
+            .unwrap_or(diagnostics.len());
+
+        for iter in 0..niter {
+            if self.interrupted() {
+                break;
+            }
+
+            // sampler.check_perimeter_bounds(priors);
+
+            // (counting from the start of the phase, so resumed runs shift
+            // chunks when uninterrupted ones do)
+            if self.chunk_shift_interval > 0
+                && position.phase_iteration.is_multiple_of(self.chunk_shift_interval)
+            {
+                sampler.shift_chunks();
+            }
+            if priors.max_cell_radius.is_some() {
+                sampler.update_cell_anchors();
+            }
+
+            self.timed("sampling;local_proposals", || {
+                for _ in 0..self.morphology_steps_per_iter {
+                    sampler.sample_cell_regions(
+                        priors,
+                        params,
+                        &mut proposal_stats,
+                        transcripts,
+                        temperature,
+                        &mut uncertainty,
+                        self.profiler.as_deref(),
+                    );
+                }
+            });
+
+            // Moves that change the number of cells are only made before
+            // samples are recorded, which are tallied by cell.
+            if uncertainty.is_none() && (self.split_merge_moves > 0 || self.birth_death_moves > 0) {
+                self.timed("sampling;cell_moves", || {
+                    if self.split_merge_moves > 0 {
+                        sampler.sample_split_merge(
+                            priors,
+                            params,
+                            &mut proposal_stats,
+                            self.split_merge_moves,
+                            self.ncells_bounds(),
+                        );
+                    }
+                    if self.birth_death_moves > 0 {
+                        sampler.sample_birth_death(
+                            priors,
+                            params,
+                            &mut proposal_stats,
+                            self.birth_death_moves,
+                            self.ncells_bounds(),
+                        );
+                    }
+                });
+            }
+
+            self.timed("sampling;global_params", || {
+                sampler.sample_global_params(priors, params, transcripts, &mut uncertainty, burnin, greedy)
+            });
+
+            if let Some(uncertainty) = uncertainty.as_deref_mut() {
+                self.timed("sampling;record_samples", || {
+                    uncertainty.record_positions(params, transcripts);
+                    uncertainty.record_components(params);
+                });
+            }
+
+            let nassigned = params.nassigned();
+            let nforeground = params.nforeground();
+            let log_likelihood = self.timed("sampling;diagnostics", || params.log_likelihood(priors));
+            prog.inc(1);
+            prog.set_message(format!(
+                "log-likelihood: {ll} | assigned: {nassigned} / {n} ({perc_assigned:.2}%) | non-background: ({perc_foreground:.2}%)",
+                ll = log_likelihood,
+                nassigned = nassigned,
+                n = transcripts.len(),
+                perc_assigned = 100.0 * (nassigned as f32) / (transcripts.len() as f32),
+                perc_foreground = 100.0 * (nforeground as f32) / (transcripts.len() as f32),
+            ));
+
+            diagnostics.push(IterationDiagnostics {
+                chain: position.chain,
+                iteration: position.total_steps,
+                phase: position.phase,
+                log_likelihood,
+                ncells: params.cell_population.iter().filter(|&&p| p > 0).count(),
+                unassigned_fraction: 1.0 - (nassigned as f32) / (transcripts.len() as f32),
+                background_fraction: 1.0 - (nforeground as f32) / (transcripts.len() as f32),
+                mean_cell_area: params.mean_cell_area(),
+                mean_compactness: sampler.mean_compactness(priors),
+                acceptance: proposal_stats.acceptance_rates(),
+            });
+            if let Some(status) = &self.status {
+                status.record_iteration(
+                    diagnostics.last().unwrap(),
+                    self.schedule.len(),
+                    self.schedule.iter().sum(),
+                );
+            }
+
+            phase_stats.add(&proposal_stats);
+            proposal_stats.reset();
+
+            if position.total_steps.is_multiple_of(self.monitor_cell_polygons_freq) {
+                if let Some(basename) = &self.monitor_cell_polygons {
+                    let filename = format!("{}-{:04}.geojson.gz", basename, position.total_steps);
+                    let (cell_polygons, _cell_flattened_polygons) = sampler.cell_polygons();
+                    write_cell_layered_multipolygons(&Some(filename), cell_polygons);
+                }
+            }
+
+            position.total_steps += 1;
+            position.phase_iteration += 1;
+            if uncertainty.is_some() {
+                position.recorded_iterations += 1;
+            }
+
+            // Recorded samples are never cut short, since they determine the output.
+            let converged = uncertainty.is_none() && self.converged(&diagnostics[diagnostics_start..]);
+            if uncertainty.is_none() && (converged || iter + 1 == niter) {
+                position.burnin_finished = true;
+            }
+
+            if let Some(filename) = &self.checkpoint {
+                // Always checkpoint when interrupted, so the run can be resumed.
+                if position.total_steps.is_multiple_of(self.checkpoint_interval) || self.interrupted() {
+                    let empty_uncertainty = UncertaintyTracker::new();
+                    self.timed("output;checkpoint", || {
+                        CheckpointRef {
+                            schedule: &self.schedule,
+                            phase: position.phase,
+                            phase_iteration: position.phase_iteration,
+                            recorded_iterations: position.recorded_iterations,
+                            burnin_finished: position.burnin_finished,
+                            total_steps: position.total_steps,
+                            phase_diagnostics: &diagnostics[diagnostics_start..],
+                            params,
+                            voxel_cells: sampler.voxel_cell_assignments(),
+                            uncertainty: uncertainty.as_deref().unwrap_or(&empty_uncertainty),
+                            proposal_state: sampler.proposal_state(),
+                            rng_state: sampler::rng::state(),
+                        }
+                        .save(filename)
+                    });
+                }
+            }
+
+            for (interval, output) in &self.intermediate_outputs {
+                if position.total_steps.is_multiple_of(*interval) {
+                    self.timed("output;intermediate", || output(self.dataset, params, sampler));
+                }
+            }
+
+            if converged {
+                let skipped = (niter - iter - 1) as u64;
+                prog.set_length(prog.length().unwrap_or(0) - skipped);
+                prog.println(format!(
+                    "Converged after {} iterations of phase {}",
+                    position.phase_iteration,
+                    position.phase + 1
+                ));
+                break;
+            }
+        }
+
+        // Acceptance rates that drop close to zero mean the phase is mixing poorly.
+        let rates = format_acceptance_rates(&phase_stats.acceptance_rates());
+        if !rates.is_empty() {
+            let chain = if self.nchains > 1 {
+                format!("Chain {}, phase", position.chain + 1)
+            } else {
+                String::from("Phase")
+            };
+            prog.println(format!("{} {} acceptance: {}", chain, position.phase + 1, rates));
+        }
+    }
+}
+
+// Keep quadrants several voxels wide, so parallel updates can't touch.
+fn min_chunk_size(voxel_size: f32) -> f32 {
+    8.0 * voxel_size
+}
+
+// Acceptance rates of each kind of move that was proposed at all.
+fn format_acceptance_rates(rates: &AcceptanceRates) -> String {
+    [
+        ("grow", rates.background_to_cell),
+        ("shrink", rates.cell_to_background),
+        ("cell-to-cell", rates.cell_to_cell),
+        ("split", rates.split),
+        ("merge", rates.merge),
+        ("birth", rates.birth),
+        ("death", rates.death),
+    ]
+    .iter()
+    .filter(|(_, rate)| rate.is_finite())
+    .map(|(name, rate)| format!("{} {:.2}%", name, 100.0 * rate))
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+// What's kept of a finished chain to merge it with the others.
+struct FinishedChain {
+    diagnostics: Vec<IterationDiagnostics>,
+    // maximum posterior assignment of each transcript
+    assignments: Vec<(u32, f32)>,
+    // sampler time the chain spanned
+    time: u32,
+    // recorded samples, unless this is the best chain
+    uncertainty: Option<SetAside>,
+}
+
+// Recorded samples of a chain other than the best, kept until chains are merged.
+enum SetAside {
+    Memory(UncertaintyTracker),
+    File(PathBuf),
+}
+
+impl SetAside {
+    fn restore(self) -> UncertaintyTracker {
+        match self {
+            SetAside::Memory(uncertainty) => uncertainty,
+            SetAside::File(path) => {
+                let file = File::open(&path)
+                    .unwrap_or_else(|err| panic!("Unable to open '{}': {}", path.display(), err));
+                let uncertainty = bincode::deserialize_from(BufReader::new(file))
+                    .unwrap_or_else(|err| panic!("Unable to read '{}': {}", path.display(), err));
+                let _ = std::fs::remove_file(&path);
+                uncertainty
+            }
+        }
+    }
+}
+
+fn final_log_likelihood(result: &ProsegResult) -> f32 {
+    result
+        .diagnostics
+        .last()
+        .map_or(f32::NEG_INFINITY, |d| d.log_likelihood)
+}
+
+// Gelman-Rubin potential scale reduction factor of summary statistics over the
+// last `nsamples` iterations of each chain.
+
+fn chain_rhat(chains: &[&[IterationDiagnostics]], nsamples: usize) -> Vec<(&'static str, f32)> {
+    type Statistic = (&'static str, fn(&IterationDiagnostics) -> f64);
+    let statistics: [Statistic; 5] = [
+        ("log_likelihood", |d| d.log_likelihood as f64),
+        ("ncells", |d| d.ncells as f64),
+        ("unassigned_fraction", |d| d.unassigned_fraction as f64),
+        ("mean_cell_area", |d| d.mean_cell_area as f64),
+        ("mean_compactness", |d| d.mean_compactness as f64),
+    ];
+
+    let n = chains
+        .iter()
+        .map(|diagnostics| diagnostics.len())
+        .min()
+        .unwrap_or(0)
+        .min(nsamples);
+    let m = chains.len();
+
+    statistics
+        .iter()
+        .map(|(name, f)| {
+            if n < 2 || m < 2 {
+                return (*name, f32::NAN);
+            }
+
+            let traces: Vec<Vec<f64>> = chains
+                .iter()
+                .map(|ds| ds[ds.len() - n..].iter().map(f).collect())
+                .collect();
+
+            let means: Vec<f64> = traces
+                .iter()
+                .map(|trace| trace.iter().sum::<f64>() / n as f64)
+                .collect();
+            let mean = means.iter().sum::<f64>() / m as f64;
+
+            let b = n as f64 * means.iter().map(|mu| (mu - mean).powi(2)).sum::<f64>()
+                / (m - 1) as f64;
+            let w = traces
+                .iter()
+                .zip(&means)
+                .map(|(trace, mu)| {
+                    trace.iter().map(|x| (x - mu).powi(2)).sum::<f64>() / (n - 1) as f64
+                })
+                .sum::<f64>()
+                / m as f64;
+
+            let rhat = if w > 0.0 {
+                let var = (n - 1) as f64 / n as f64 * w + b / n as f64;
+                (var / w).sqrt()
+            } else if b > 0.0 {
+                f64::INFINITY
+            } else {
+                1.0
+            };
+
+            (*name, rhat as f32)
+        })
+        .collect()
+}
+
+// Describe the first difference between two runs that should be identical.
+fn check_reproducible(a: &ProsegResult, b: &ProsegResult) -> Result<(), String> {
+    for (da, db) in a.diagnostics.iter().zip(&b.diagnostics) {
+        if da.log_likelihood.to_bits() != db.log_likelihood.to_bits() || da.ncells != db.ncells {
+            return Err(format!(
+                "Runs diverged at iteration {} of chain {}: log likelihood {} vs {}",
+                da.iteration, da.chain, da.log_likelihood, db.log_likelihood
+            ));
+        }
+    }
+    if a.diagnostics.len() != b.diagnostics.len() {
+        return Err(format!(
+            "Runs diverged: {} vs {} iterations",
+            a.diagnostics.len(),
+            b.diagnostics.len()
+        ));
+    }
+    if let Some(i) = a
+        .params
+        .cell_assignments
+        .iter()
+        .zip(&b.params.cell_assignments)
+        .position(|(x, y)| x != y)
+    {
+        return Err(format!("Runs diverged in the assignment of transcript {}", i));
+    }
+    if a.params.λ.iter().zip(b.params.λ.iter()).any(|(x, y)| x.to_bits() != y.to_bits()) {
+        return Err(String::from("Runs diverged in expression rates"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sampler::transcripts::{postprocess_cell_assignments, CellIndex, BACKGROUND_CELL};
+
+    // Four square cells of transcripts on a 2x2 grid, with the transcripts
+    // near each center in its nucleus.
+    fn grid_dataset() -> TranscriptDataset {
+        let mut transcripts = Vec::new();
+        let mut nucleus_assignments = Vec::new();
+        for cell in 0..4 {
+            let (cx, cy) = (5.0 + 10.0 * (cell % 2) as f32, 5.0 + 10.0 * (cell / 2) as f32);
+            for i in 0..64 {
+                let (dx, dy) = ((i % 8) as f32 - 3.5, (i / 8) as f32 - 3.5);
+                transcripts.push(Transcript {
+                    transcript_id: transcripts.len() as u64,
+                    x: cx + 0.8 * dx,
+                    y: cy + 0.8 * dy,
+                    z: (i % 3) as f32,
+                    gene: (i + cell) % 3,
+                    fov: 0,
+                });
+                let nuclear = dx.abs() < 2.0 && dy.abs() < 2.0;
+                nucleus_assignments.push(if nuclear { cell } else { BACKGROUND_CELL });
+            }
+        }
+        let ntranscripts = transcripts.len();
+        let mut cell_assignments = nucleus_assignments.clone();
+        let nucleus_population =
+            postprocess_cell_assignments(&mut nucleus_assignments, &mut cell_assignments);
+
+        TranscriptDataset {
+            transcript_names: vec![String::from("a"), String::from("b"), String::from("c")],
+            transcripts,
+            nucleus_assignments,
+            cell_assignments,
+            nucleus_population,
+            fovs: vec![0; ntranscripts],
+            qvs: vec![f32::INFINITY; ntranscripts],
+            fov_names: vec![String::from("0")],
+            sample_names: vec![String::from("0")],
+            fov_samples: vec![0],
+            nuclear: vec![false; ntranscripts],
+            original_cell_ids: Vec::new(),
+            original_cell_assignments: vec![BACKGROUND_CELL; ntranscripts],
+            prior_source_names: vec![String::new()],
+            prior_sources: vec![0; ntranscripts],
+        }
+    }
+
+    #[test]
+    fn deterministic_run_is_reproducible() {
+        let dataset = grid_dataset();
+        let priors = ModelPriors::new(10.0, 0.0, 2.0);
+        let result = Proseg::new(&dataset, priors, 400.0, 2.02)
+            .ncomponents(2)
+            .nbglayers(1)
+            .initial_voxel_size(1.0)
+            .schedule(vec![4, 4])
+            .recorded_samples(2)
+            .seed(Some(1))
+            .progress(false)
+            .run_deterministic()
+            .unwrap();
+
+        assert_eq!(result.diagnostics.len(), 8);
+        assert_eq!(result.params.ncells(), 4);
+    }
+
+    #[test]
+    fn refining_keeps_cells_outside_region() {
+        let dataset = grid_dataset();
+        let region = || Roi::rect(0.0, 0.0, 10.0, 10.0);
+
+        // Voxels assigned to cells, as (inside the region, cell, coordinates),
+        // after `niter` iterations refining the region.
+        let refined_voxels = |niter: usize| {
+            let result = sampler::rng::thread_pool(1).install(|| {
+                Proseg::new(&dataset, ModelPriors::new(10.0, 0.0, 2.0), 400.0, 2.02)
+                    .ncomponents(2)
+                    .nbglayers(1)
+                    .initial_voxel_size(1.0)
+                    .editable_region(Some(region()))
+                    .schedule(vec![niter])
+                    .recorded_samples(0)
+                    .seed(Some(1))
+                    .progress(false)
+                    .run()
+                    .unwrap()
+            });
+            let mut voxels = result
+                .sampler
+                .voxels()
+                .map(|(cell, (x0, y0, _, x1, y1, _))| {
+                    let inside = region().contains((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+                    (inside, cell, (x0, y0))
+                })
+                .collect::<Vec<_>>();
+            voxels.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            voxels
+        };
+
+        let initial = refined_voxels(0);
+        let refined = refined_voxels(8);
+        let outside = |voxels: &[(bool, CellIndex, (f32, f32))]| {
+            voxels.iter().filter(|(inside, _, _)| !inside).cloned().collect::<Vec<_>>()
+        };
+        assert!(!outside(&initial).is_empty());
+        assert_eq!(outside(&initial), outside(&refined));
+        assert_ne!(initial, refined);
+    }
+
+
+    #[test]
+    fn invalid_options_are_errors() {
+        let dataset = grid_dataset();
+        let proseg = || Proseg::new(&dataset, ModelPriors::new(10.0, 0.0, 2.0), 400.0, 2.02).progress(false);
+
+        let result = proseg().schedule(vec![4]).recorded_samples(5).run();
+        assert!(matches!(result, Err(ProsegError::InvalidOptions(_))));
+
+        let result = proseg()
+            .editable_region(Some(Roi::rect(100.0, 100.0, 110.0, 110.0)))
+            .run();
+        assert!(matches!(result, Err(ProsegError::InvalidOptions(_))));
+    }
+
+}
@@ -1,25 +1,102 @@
 #![allow(confusable_idents)]
 
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 
-mod output;
-mod sampler;
-mod schemas;
-
-use indicatif::{ProgressBar, ProgressStyle};
 use itertools::Itertools;
-use rayon::current_num_threads;
-use sampler::hull::compute_cell_areas;
-use sampler::transcripts::{
-    coordinate_span, estimate_full_area, filter_cellfree_transcripts, read_transcripts_csv, Transcript
+use proseg::compare::{compare_segmentations, read_segmentation, SegmentationColumns};
+use proseg::output::*;
+use proseg::sampler::boundary::read_boundary_prior;
+use proseg::sampler::controls::{extract_negative_controls, NegativeControlSummary};
+use proseg::sampler::dedup::{dedup_transcripts, DedupAction};
+use proseg::sampler::downsample::downsample_transcripts;
+use proseg::sampler::expression_prior::read_expression_prior;
+use proseg::sampler::genefilter::{filter_genes, GeneFilter};
+use proseg::sampler::genepanel::{rename_genes, GenePanel};
+use proseg::sampler::pbin::{is_pbin_filename, read_transcripts_pbin, write_transcripts_pbin};
+use proseg::sampler::hull::compute_cell_areas;
+use proseg::sampler::mask::{assign_transcripts_from_mask, read_label_mask};
+use proseg::sampler::refine::assign_transcripts_from_previous;
+use proseg::sampler::roi::{PolygonIndex, Roi};
+use proseg::sampler::rowfilter::RowFilter;
+use proseg::sampler::smoothing::{smooth_cell_polygons, PolygonSmoothing, PolygonSmoothingParams};
+use proseg::sampler::tissue::TissueMask;
+use proseg::sampler::transform::{AffineTransform, PolygonInverseTransform};
+use proseg::sampler::transcripts::{
+    assign_transcripts_to_nuclei, coordinate_span,
+    concatenate_datasets, filter_cellfree_transcripts, partition_by_fov, read_nuclei_csv,
+    read_transcripts_csv, CellIndex, Transcript, TranscriptDataset, BACKGROUND_CELL,
 };
-use sampler::voxelsampler::{filter_sparse_cells, VoxelSampler};
-use sampler::{ModelParams, ModelPriors, ProposalStats, Sampler, UncertaintyTracker};
+use proseg::sampler::voxelsampler::{filter_sparse_cells, CellPolygon, CellPolygonLayers, CellShape};
+use proseg::memory::{available_memory, format_memory_size, parse_memory_size, MemoryEstimate};
+use proseg::sampler::{rng, ModelPriors, NeighborhoodMethod, Sampler, TranscriptState};
+use proseg::validate::{
+    check_transcripts, overlapping_cells, read_cell_polygons, read_transcript_assignments,
+    self_intersecting_cells,
+};
+use proseg::profile::Profiler;
+use proseg::status::RunStatus;
+use proseg::{IntermediateOutput, Proseg, ProsegResult};
+use rand::Rng;
+use rayon::current_num_threads;
+use rayon::prelude::*;
+use regex::Regex;
+use serde::Serialize;
 use core::f32;
-use std::cell::RefCell;
+use ndarray::{Array1, Array2, Axis};
 use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Preset {
+    Xenium,
+    Cosmx,
+    CosmxMicron,
+    Merscope,
+    Merfish,
+}
+
+// Whether to sample the posterior, or greedily find a single maximum a
+// posteriori segmentation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum RunMode {
+    Sample,
+    Map,
+}
+
+// How to divide per-cell and per-transcript outputs into separate files.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum SplitOutputBy {
+    Sample,
+}
+
+// Either a fixed number of mixture components, or `auto` to infer how many
+// are occupied.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum NComponents {
+    Fixed(usize),
+    Auto,
+}
+
+impl std::str::FromStr for NComponents {
+    type Err = String;
 
-use output::*;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(NComponents::Auto);
+        }
+        match s.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(NComponents::Fixed(n)),
+            _ => Err(String::from("expected a positive number or 'auto'")),
+        }
+    }
+}
 
 #[derive(Parser)]
 #[command(version)]
@@ -28,11 +105,68 @@ use output::*;
 #[command(
     about = "High-speed cell segmentation of transcript-resolution spatial transcriptomics data."
 )]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Segment cells from transcript positions. This is the default when no
+    /// subcommand is given.
+    Run(Args),
+
+    /// Resume sampling from a checkpoint written with --checkpoint, given the
+    /// same inputs and options as the original run.
+    Resume(ResumeArgs),
+
+    /// Re-segment a region of a previous run, given the same inputs and
+    /// --roi or --roi-geojson, keeping cells outside it as they were.
+    Refine(RefineArgs),
+
+    /// Convert a table or GeoJSON file written by proseg to another format or
+    /// compression, e.g. transcript-metadata.csv.gz to transcript-metadata.parquet.
+    Convert(ConvertArgs),
+
+    /// Check proseg cell polygons for self-intersections and overlaps, and
+    /// optionally for transcripts outside their assigned cell.
+    #[command(alias = "validate-output")]
+    Validate(ValidateArgs),
+
+    /// Compare proseg's transcript assignments to those of another segmentation.
+    Compare(CompareArgs),
+}
+
+#[derive(clap::Args, Serialize)]
 struct Args {
-    /// CSV with transcript information. How this is interpreted is determined
+    /// CSV or Parquet file with transcript information. How this is interpreted is determined
     /// either by using a preset (`--xenium`, `--cosmx`, `--cosmx-micron`, `--merfish`)
     /// or by manually setting column names using (`--x-column`, `--transcript-column`, etc).
-    transcript_csv: String,
+    /// Several files (or a quoted glob pattern, e.g. "sections/*.parquet") may be given,
+    /// in which case they are concatenated, and outputs record which file, or `sample`,
+    /// each transcript and cell came from.
+    #[arg(required = true, num_args = 1..)]
+    transcript_csv: Vec<String>,
+
+    /// TOML file setting any of the long options below, e.g. `schedule = [150, 150, 300]`
+    /// or `recorded_samples = 100`. Tables are flattened, so options can be grouped into
+    /// sections. Options given on the command line take precedence.
+    #[arg(long, default_value = None)]
+    config: Option<String>,
+
+    /// Format of the transcript table. By default this is inferred from the file extension.
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    format: OutputFormat,
+
+    /// Field delimiter of a csv transcript table, e.g. "tab" for tab delimited
+    /// files. By default it's guessed from the header line.
+    #[arg(long, value_parser = parse_delimiter)]
+    delimiter: Option<u8>,
+
+    /// Platform preset. Equivalent to passing `--xenium`, `--cosmx`, `--cosmx-micron`,
+    /// or `--merscope`.
+    #[arg(long, value_enum, default_value = None)]
+    preset: Option<Preset>,
 
     /// Preset for 10X Xenium data
     #[arg(long, default_value_t = false)]
@@ -91,6 +225,24 @@ struct Args {
     #[arg(long, default_value = None)]
     fov_column: Option<String>,
 
+    /// Segment each field of view (from --fov-column) independently, and merge
+    /// the results with cells numbered consecutively across FOVs
+    #[arg(long, default_value_t = false)]
+    partition_fovs: bool,
+
+    /// With --partition-fovs, the number of FOVs to segment at once, sharing
+    /// the threads between them (default: as many as there are threads)
+    #[arg(long, default_value = None)]
+    parallel_fovs: Option<usize>,
+
+    /// Memory limit, e.g. `64G`. If peak memory use is predicted to exceed it,
+    /// finished chains are kept on disk (as with --spill-chains), and with
+    /// --partition-fovs fewer FOVs are segmented at once, if that would fit.
+    /// Otherwise proseg exits before sampling rather than running out of memory
+    /// partway through
+    #[arg(long, value_parser = parse_memory_size, default_value = None)]
+    max_memory: Option<u64>,
+
     /// Column indicating whether a transcript is assigned to a cell
     #[arg(long, default_value = None)]
     cell_assignment_column: Option<String>,
@@ -111,6 +263,116 @@ struct Args {
     #[arg(long, default_value = None)]
     qv_column: Option<String>,
 
+    /// CSV file of nucleus centroids (e.g. from DAPI segmentation) used to
+    /// initialize cells, in place of any cell assignments in the transcript file
+    #[arg(long, default_value = None)]
+    nuclei_csv: Option<String>,
+
+    /// Name of column containing the x coordinate in the nuclei CSV
+    #[arg(long, default_value = "x")]
+    nuclei_x_column: String,
+
+    /// Name of column containing the y coordinate in the nuclei CSV
+    #[arg(long, default_value = "y")]
+    nuclei_y_column: String,
+
+    /// Transcripts within this distance of a nucleus centroid are initially
+    /// assigned to it
+    #[arg(long, default_value_t = 4.0_f32)]
+    nuclei_radius: f32,
+
+    /// Labeled cell or nucleus mask image (TIFF or PNG, e.g. from Cellpose) used to
+    /// initialize cells, in place of any cell assignments in the transcript file
+    #[arg(long, default_value = None)]
+    init_mask: Option<String>,
+
+    /// Affine transform a,b,c,d,e,f from transcript coordinates to mask pixels,
+    /// giving pixel (a*x + b*y + c, d*x + e*y + f)
+    #[arg(long, num_args=1.., value_delimiter=',', allow_negative_numbers=true, default_values_t=[1.0, 0.0, 0.0, 0.0, 1.0, 0.0])]
+    init_mask_transform: Vec<f32>,
+
+    /// Transcript metadata written by a previous run (--output-transcript-metadata),
+    /// or the directory it was written to, used to initialize cells, so that runs
+    /// with different settings can start from a converged segmentation
+    #[arg(long, default_value = None)]
+    init_from_proseg: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    init_from_proseg_fmt: OutputFormat,
+
+    /// Boundary stain image (TIFF or PNG, e.g. a cell boundary probability map
+    /// from Xenium's multimodal segmentation) giving the probability of a cell
+    /// membrane at each pixel. Cells are penalized for growing across boundaries.
+    #[arg(long, default_value = None)]
+    boundary_image: Option<String>,
+
+    /// Affine transform a,b,c,d,e,f from transcript coordinates to boundary image
+    /// pixels, giving pixel (a*x + b*y + c, d*x + e*y + f)
+    #[arg(long, num_args=1.., value_delimiter=',', allow_negative_numbers=true, default_values_t=[1.0, 0.0, 0.0, 0.0, 1.0, 0.0])]
+    boundary_image_transform: Vec<f32>,
+
+    /// Log probability penalty for each pair of neighboring voxels in the same
+    /// cell separated by a boundary, scaled by the boundary probability
+    #[arg(long, default_value_t = 1.0_f32)]
+    boundary_weight: f32,
+
+    /// Only segment transcripts within this rectangle, given as xmin,ymin,xmax,ymax
+    /// in output coordinates (i.e. after --coordinate-scale)
+    #[arg(long, num_args=1.., value_delimiter=',', allow_negative_numbers=true, conflicts_with="roi_geojson")]
+    roi: Option<Vec<f32>>,
+
+    /// Only segment transcripts within the polygons in this GeoJSON file, in
+    /// output coordinates (i.e. after --coordinate-scale)
+    #[arg(long, default_value=None)]
+    roi_geojson: Option<String>,
+
+    /// Drop transcripts of genes matching this regular expression (e.g.
+    /// '^(NegControl|BLANK_)' for negative control probes) as the input is read
+    #[arg(long, default_value=None)]
+    exclude_genes: Option<String>,
+
+    /// Negative control probes, as a regular expression matching their names
+    /// (e.g. '^(NegControl|BLANK_)'). Their transcripts are set aside rather
+    /// than modeled, and used to estimate the fraction of false transcripts in
+    /// each cell and overall.
+    #[arg(long, default_value=None)]
+    negative_controls: Option<String>,
+
+    /// Gene panel manifest (Xenium's gene_panel.json), used to rename genes given
+    /// by probe id to their symbol, and to add Ensembl ids and feature types
+    /// (gene or control probe) to gene metadata and AnnData outputs
+    #[arg(long, default_value=None)]
+    gene_panel: Option<String>,
+
+    /// Only keep transcripts of genes listed in this file, one per line
+    #[arg(long, default_value=None)]
+    include_genes: Option<String>,
+
+    /// Keep only this fraction of each gene's transcripts, chosen at random, for
+    /// quick trial runs
+    #[arg(long, default_value = None)]
+    downsample_fraction: Option<f32>,
+
+    /// Seed for choosing transcripts with --downsample-fraction
+    #[arg(long, default_value_t = 0)]
+    downsample_seed: u64,
+
+    /// Treat transcripts of the same gene in different FOVs within this
+    /// distance of each other as one molecule decoded twice where FOVs
+    /// overlap, and handle them as given by --dedup-action
+    #[arg(long, default_value = None)]
+    dedup_radius: Option<f32>,
+
+    /// What to do with transcripts duplicated across FOV seams: merge them
+    /// into one at their mean position, or drop every copy
+    #[arg(long, value_enum, default_value_t = DedupAction::Merge)]
+    dedup_action: DedupAction,
+
+    /// GeoJSON file of nucleus polygons. Transcripts inside them are labeled
+    /// nuclear, in place of any --compartment-column
+    #[arg(long, default_value=None)]
+    nucleus_polygons: Option<String>,
+
     /// Ignore the z coordinate, flattening the data to 2D
     #[arg(long, default_value_t = false)]
     ignore_z_coord: bool,
@@ -119,15 +381,81 @@ struct Args {
     #[arg(long, default_value_t = 0.0_f32)]
     min_qv: f32,
 
+    /// Only read transcripts for which this expression holds, e.g.
+    /// "qv >= 20 && fov != 12". Columns can be compared to each other or to
+    /// literals using ==, !=, <, <=, >, >=, and combined with &&, ||, !, and
+    /// parentheses.
+    #[arg(long, default_value=None)]
+    filter: Option<String>,
+
     /// Target number of cells per chunk in the parallelization scheme
     /// Smaller number enabled more parallelization, but too small a number
     /// risks inconsistent updates.
     #[arg(long, default_value_t = 100)]
     cells_per_chunk: usize,
 
-    /// Number of components in the mixture model of cellular gene expression
+    /// Divide the data into chunks with roughly equal numbers of transcripts,
+    /// instead of a regular grid. Balances work across threads on irregularly
+    /// shaped sections or ones with large empty regions.
+    #[arg(long, default_value_t = false)]
+    density_chunks: bool,
+
+    /// Start with --density-chunks, and rebuild chunks each time the voxel
+    /// resolution doubles, balancing the number of voxels on cell boundaries,
+    /// so threads aren't left idle in the finer phases
+    #[arg(long, default_value_t = false)]
+    adaptive_chunks: bool,
+
+    /// Move chunks by a random offset every this many iterations, so that cells
+    /// on the seams between chunks aren't sampled less than others. 0 disables.
     #[arg(long, default_value_t = 10)]
-    ncomponents: usize,
+    chunk_shift_interval: usize,
+
+    /// Number of components in the mixture model of cellular gene expression,
+    /// or `auto` to infer the number under a Dirichlet process prior, up to
+    /// --max-components
+    #[arg(long, default_value = "10")]
+    ncomponents: NComponents,
+
+    /// Maximum number of components with `--ncomponents auto`
+    #[arg(long, default_value_t = 50)]
+    max_components: usize,
+
+    /// Concentration of the Dirichlet process prior with `--ncomponents auto`.
+    /// Larger values favor more components.
+    #[arg(long, default_value_t = 1.0_f32)]
+    dp_concentration: f32,
+
+    /// Favor neighboring cells being assigned the same component, with this
+    /// strength (0 disables). This reduces scattered component labels within
+    /// homogeneous regions of tissue. Values around 1 to 5 are reasonable.
+    #[arg(long, default_value_t = 0.0_f32)]
+    component_smoothness: f32,
+
+    /// Distance over which the influence of neighboring cells falls off with
+    /// --component-smoothness, in the same units as the output (by default,
+    /// twice the expected cell diameter)
+    #[arg(long, default_value = None)]
+    component_smoothness_length: Option<f32>,
+
+    /// How neighboring cells are found for --component-smoothness: `radius` for
+    /// cells within three length scales, or `delaunay` for cells adjacent in a
+    /// triangulation of cell centroids, which holds up better when cell density
+    /// varies widely (requires building with `--features delaunay`)
+    #[arg(long, value_enum, default_value_t = NeighborhoodMethod::Radius)]
+    neighborhood_method: NeighborhoodMethod,
+
+    /// Table of reference mean expression (genes in rows, cell types in columns,
+    /// with gene names in the first column), e.g. from a scRNA-seq atlas, used as
+    /// a prior on components so that each corresponds to a cell type. Overrides
+    /// --ncomponents.
+    #[arg(long, default_value = None)]
+    expression_prior: Option<String>,
+
+    /// Precision of the expression prior on each component's log expression
+    /// rates. Larger values keep components closer to the reference.
+    #[arg(long, default_value_t = 1.0_f32)]
+    expression_prior_strength: f32,
 
     /// Number of z-axis layers used to model background expression
     #[arg(long, default_value_t = 4)]
@@ -142,13 +470,62 @@ struct Args {
     voxel_layers: usize,
 
     /// Sampler schedule, indicating the number of iterations between doubling resolution.
-    #[arg(long, num_args=1.., value_delimiter=',', default_values_t=[150, 150, 300])]
+    #[arg(long, alias = "iters-per-phase", num_args=1.., value_delimiter=',', default_values_t=[150, 150, 300])]
     schedule: Vec<usize>,
 
+    /// Voxel x/y size in each phase of the schedule, e.g. `4,2,1,0.5`, which
+    /// must halve from one phase to the next. Sets --initial-voxel-size.
+    #[arg(long, num_args=1.., value_delimiter=',')]
+    voxel_size_schedule: Vec<f32>,
+
+    /// `sample` to sample the posterior, or `map` for a fast greedy segmentation that
+    /// only accepts cell boundary changes improving the posterior, stops each phase
+    /// once it converges, and records a single sample
+    #[arg(long, value_enum, default_value_t = RunMode::Sample)]
+    mode: RunMode,
+
+    /// Temperature of cell boundary proposals in each phase of the schedule, e.g.
+    /// `2,1,0` to anneal from a hot first phase to a greedy final phase. Changes in
+    /// log posterior are divided by the temperature, and 0 accepts only
+    /// improvements. By default every phase runs at 1.
+    #[arg(long, num_args=1.., value_delimiter=',')]
+    temperature_schedule: Vec<f32>,
+
     /// Whether to double the z-layers when doubling resolution
     #[arg(long, default_value_t = true)]
     double_z_layers: bool,
 
+    /// Move on to the next phase of the schedule early once the relative change in
+    /// mean log likelihood (and change in the unassigned fraction) between
+    /// consecutive windows of iterations falls below this
+    #[arg(long, default_value=None)]
+    convergence_eps: Option<f32>,
+
+    /// Number of iterations in each window used to detect convergence
+    #[arg(long, default_value_t = 20)]
+    convergence_window: usize,
+
+    /// Run this many independent chains and pool their samples. Cell shapes are
+    /// taken from the chain with the highest final log likelihood.
+    #[arg(long, default_value_t = 1)]
+    nchains: usize,
+
+    /// With --nchains, write each finished chain's recorded samples to a
+    /// temporary file until chains are merged, rather than keeping them in memory
+    #[arg(long, default_value_t = false)]
+    spill_chains: bool,
+
+    /// Seed for the random number generator. Runs with the same seed, input, and
+    /// arguments give identical results. By default a random seed is used.
+    #[arg(long, default_value=None)]
+    seed: Option<u64>,
+
+    /// Sample on a single thread, with a seed of 0 unless --seed is given,
+    /// then repeat the run and fail if the results differ. Intended for
+    /// regression tests on small datasets, as it doubles the sampling time.
+    #[arg(long, default_value_t = false)]
+    deterministic: bool,
+
     /// Number of samples at the end of the schedule used to compute
     /// expectations and uncertainty
     #[arg(long, default_value_t = 100)]
@@ -158,16 +535,79 @@ struct Args {
     #[arg(short = 't', long, default_value=None)]
     nthreads: Option<usize>,
 
+    /// Record time spent reading input, constructing the voxel graph, making
+    /// local proposals (per chunk), sampling global parameters, and writing
+    /// output, and write a report to this file. This is JSON, or folded stacks
+    /// for flamegraph tools if the filename ends in ".folded".
+    #[arg(long, default_value = None)]
+    profile: Option<String>,
+
+    /// Serve the progress of the run as JSON over HTTP on this port: the
+    /// current phase and iteration, log likelihood, fraction of transcripts
+    /// unassigned, and estimated time remaining
+    #[arg(long, default_value = None)]
+    status_port: Option<u16>,
+
+    /// Address to serve the run status on with --status-port. Only this machine
+    /// can connect by default; use 0.0.0.0 to accept connections from anywhere.
+    #[arg(long, default_value = "127.0.0.1")]
+    status_address: IpAddr,
+
+    /// Write a JSON record of the run to this file: the proseg version, command
+    /// line, every option after presets are applied, the seed, estimated priors,
+    /// the size and CRC32 of each input file, and the time taken, so results can
+    /// be traced back to exactly what produced them.
+    #[arg(long, default_value = None)]
+    output_run_manifest: Option<String>,
+
     /// Number of sub-iterations sampling cell morphology per overall iteration
     #[arg(short, long, default_value_t = 1000)]
     morphology_steps_per_iter: usize,
 
+    /// Number of moves per iteration proposing to split a cell in two, or merge
+    /// it into a neighboring cell, which can fix over- or under-segmentation in
+    /// the initial cells. Made only before samples are recorded.
+    #[arg(long, default_value_t = 0)]
+    split_merge_moves: usize,
+
+    /// Number of moves per iteration proposing to create a cell from a region
+    /// of dense unassigned transcripts, or return a cell to the background,
+    /// which can recover cells whose nuclei were missed. Made only before
+    /// samples are recorded.
+    #[arg(long, default_value_t = 0)]
+    birth_death_moves: usize,
+
+    /// Don't let --split-merge-moves or --birth-death-moves take the number of
+    /// cells below this multiple of the initial number
+    #[arg(long, default_value = None)]
+    min_cells_ratio: Option<f32>,
+
+    /// Don't let --split-merge-moves or --birth-death-moves take the number of
+    /// cells above this multiple of the initial number (e.g. 1.5)
+    #[arg(long, default_value = None)]
+    max_new_cells_ratio: Option<f32>,
+
     #[arg(long, default_value_t = 0.1)]
     count_pr_cutoff: f32,
 
     #[arg(long, default_value_t = 0.9)]
     foreground_pr_cutoff: f32,
 
+    /// Drop cells with fewer than this many transcripts (by maximum posterior
+    /// assignment) from every output, reporting their transcripts as unassigned.
+    #[arg(long, default_value_t = 0)]
+    min_transcripts_per_cell: usize,
+
+    /// After sampling, assign unassigned voxels that are enclosed by a single
+    /// cell, along with their transcripts, to that cell
+    #[arg(long, default_value_t = false)]
+    fill_holes: bool,
+
+    /// Drop cells with a smaller area than this (volume over the full depth of the data,
+    /// as in the anndata `area` column) from every output.
+    #[arg(long, default_value_t = 0.0)]
+    min_cell_area: f32,
+
     #[arg(long, default_value_t = 1.3_f32)]
     perimeter_bound: f32,
 
@@ -177,13 +617,123 @@ struct Args {
     #[arg(long, default_value_t = 5e-1_f32)]
     prior_seg_reassignment_prob: f32,
 
+    /// Treat the transcript table's prior cell assignments (cytoplasmic as well
+    /// as nuclear) as evidence, each being correct with this probability.
+    /// Overrides --prior-seg-reassignment-prob.
+    #[arg(long, default_value = None, conflicts_with = "prior_seg_reassignment_prob")]
+    prior_seg_confidence: Option<f32>,
+
+    /// Column giving the source of each transcript's prior cell assignment (e.g.
+    /// nucleus or boundary stain segmentation), so sources can be trusted to
+    /// different degrees with --prior-confidence
+    #[arg(long, default_value = None)]
+    prior_assignment_column: Option<String>,
+
+    /// Probability that prior cell assignments from each source in
+    /// --prior-assignment-column are correct, e.g. `nucleus=0.95,cell=0.6`.
+    /// Sources not listed use --prior-seg-reassignment-prob.
+    #[arg(long, value_delimiter = ',', value_parser = parse_prior_confidence)]
+    prior_confidence: Vec<(String, f32)>,
+
+    /// Prior mean cell volume. By default, twice the mean nucleus area times
+    /// the z-span of the data. Each mixture component has its own log-normal
+    /// distribution of cell volume, with this as the prior on its mean.
+    #[arg(long, default_value=None)]
+    cell_volume_prior_mean: Option<f32>,
+
+    /// Prior standard deviation of the log mean cell volume
+    #[arg(long, default_value_t = 3.0_f32)]
+    cell_volume_prior_sigma: f32,
+
+    /// Shape parameter of the inverse-gamma prior on log cell volume variance
+    #[arg(long, default_value_t = 0.1_f32)]
+    cell_volume_variance_prior_shape: f32,
+
+    /// Scale parameter of the inverse-gamma prior on log cell volume variance
+    #[arg(long, default_value_t = 0.1_f32)]
+    cell_volume_variance_prior_scale: f32,
+
+    /// Minimum cell volume. By default, 1e-6 times the mean nucleus area times
+    /// the z-span of the data.
+    #[arg(long, default_value=None)]
+    min_cell_volume: Option<f32>,
+
+    /// Shape parameter of the gamma prior on gene expression dispersion
+    #[arg(long, default_value_t = 1.0_f32)]
+    dispersion_prior_shape: f32,
+
+    /// Shape parameter of the gamma hyperprior on the dispersion prior's rate
+    #[arg(long, default_value_t = 1.0_f32)]
+    dispersion_rate_prior_shape: f32,
+
+    /// Rate parameter of the gamma hyperprior on the dispersion prior's rate
+    #[arg(long, default_value_t = 1.0_f32)]
+    dispersion_rate_prior_rate: f32,
+
+    /// Shape parameter of the gamma prior on background expression rates
+    #[arg(long, default_value_t = 1.0_f32)]
+    background_rate_prior_shape: f32,
+
+    /// Rate parameter of the gamma prior on background expression rates
+    #[arg(long, default_value_t = 1.0_f32)]
+    background_rate_prior_rate: f32,
+
+    /// Let the background rate vary across the slide (e.g. with
+    /// autofluorescent regions), scaling it by a factor for each bin of a grid
+    /// with bins of this size, in the same units as the output
+    #[arg(long, default_value = None)]
+    background_grid_size: Option<f32>,
+
+    /// Shape and rate of the gamma prior on background grid scale factors.
+    /// Larger values keep the background rate closer to uniform.
+    #[arg(long, default_value_t = 4.0_f32)]
+    background_grid_prior: f32,
+
+    /// Scaling factor applied to the perimeter of a circle covering a cell's
+    /// voxels, used to bound cell perimeters
+    #[arg(long, default_value_t = 5.3_f32)]
+    perimeter_eta: f32,
+
+    /// Penalty, in log probability per unit of cell boundary length in each
+    /// layer, favoring compact cells over ones with long protrusions. Boundary
+    /// between two cells counts against both.
+    #[arg(long, default_value_t = 0.0_f32)]
+    perimeter_penalty: f32,
+
+    /// Don't let any part of a cell extend further than this from the cell's
+    /// centroid, in the same units as the output.
+    #[arg(long, default_value = None)]
+    max_cell_radius: Option<f32>,
+
+    /// Factor converting z distances to the units of x and y (1 if they already
+    /// agree). When given, vertical and lateral voxel neighbors are weighted by
+    /// distance, and the cell neighborhood graph uses 3D distances. Without it,
+    /// every voxel neighbor counts the same regardless of voxel shape.
+    #[arg(long, default_value = None)]
+    z_scale: Option<f32>,
+
     /// Scale transcript coordinates by this factor to arrive at microns
     #[arg(long, default_value=None)]
     coordinate_scale: Option<f32>,
 
-    /// Initial size x/y size of voxels.
-    #[arg(long, default_value_t = 4.0_f32)]
-    initial_voxel_size: f32,
+    /// CSV file with a 3x3 (x and y) or 4x4 (x, y, and z) affine matrix applied to
+    /// transcript coordinates after reading (and after --coordinate-scale). Given once,
+    /// it applies to every transcript file, or it can be given once per file, e.g. to
+    /// register serial sections. Cell polygons are written in the input coordinates.
+    #[arg(long, num_args = 1..)]
+    transform: Vec<String>,
+
+    /// Initial size x/y size of voxels. By default, 4, or 0.4 times
+    /// --expected-cell-diameter if given.
+    #[arg(long, default_value = None)]
+    initial_voxel_size: Option<f32>,
+
+    /// Typical cell diameter, in the same units as the output. Sets the prior
+    /// mean cell volume, minimum cell volume, and initial voxel size in place of
+    /// estimates from nucleus sizes, which are unreliable in sparse panels.
+    /// Explicitly given values for those options still take precedence.
+    #[arg(long, default_value = None)]
+    expected_cell_diameter: Option<f32>,
 
     /// Exclude transcripts that are more than this distance from any nucleus
     #[arg(long, default_value_t = 60_f32)]
@@ -209,6 +759,12 @@ struct Args {
     #[arg(long, default_value_t = 4.0)]
     diffusion_sigma_far: f32,
 
+    /// Infer a separate z offset and spread for repositioning each gene's
+    /// transcripts, so genes concentrated at different depths (e.g. nuclear or
+    /// membrane) inform assignment accordingly
+    #[arg(long, default_value_t = false)]
+    gene_z_offsets: bool,
+
     /// Allow dispersion parameter to vary during burn-in
     #[arg(long, default_value_t = false)]
     variable_burnin_dispersion: bool,
@@ -225,17 +781,72 @@ struct Args {
     #[arg(long, default_value_t = false)]
     check_consistency: bool,
 
-    /// Output a point estimate of transcript counts per cell
+    /// Compression of CSV and GeoJSON outputs, replacing the compression
+    /// suffix of their filenames (e.g. "zstd" writes cell-metadata.csv.zst in
+    /// place of cell-metadata.csv.gz). By default it's inferred from each filename.
+    #[arg(long, value_enum, default_value = None)]
+    output_compression: Option<OutputCompression>,
+
+    /// With several transcript files, write expected and maximum posterior
+    /// counts, cell and transcript metadata, and cell polygons separately for
+    /// each, into a directory named by the sample next to each output file.
+    /// Genes are in the same order for every sample, and cells are numbered
+    /// from 0 within each.
+    #[arg(long, value_enum, default_value = None)]
+    split_output_by: Option<SplitOutputBy>,
+
+    /// Output a matrix of expected counts per cell of only the transcripts
+    /// observed in a nucleus (see --compartment-column and --nucleus-polygons)
+    #[arg(long, default_value = None)]
+    output_nuclear_expected_counts: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_nuclear_expected_counts_fmt: OutputFormat,
+
+    /// Output a matrix of expected counts per cell of only the transcripts
+    /// observed outside of nuclei
+    #[arg(long, default_value = None)]
+    output_cytoplasmic_expected_counts: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_cytoplasmic_expected_counts_fmt: OutputFormat,
+
+    /// Output integer transcript counts per cell, assigning each transcript to
+    /// the cell it was most often assigned to over the recorded samples
     #[arg(long, default_value = None)]
     output_maxpost_counts: Option<String>,
 
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
     output_maxpost_counts_fmt: OutputFormat,
 
-    /// Output a matrix of expected transcript counts per cell
+    /// Output integer counts per cell (as with --output-maxpost-counts) of only the
+    /// transcripts assigned with probability at least --high-confidence-threshold
+    #[arg(long, default_value = None)]
+    output_high_confidence_counts: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_high_confidence_counts_fmt: OutputFormat,
+
+    /// Output integer counts per cell of the remaining transcripts, assigned with
+    /// probability below --high-confidence-threshold
+    #[arg(long, default_value = None)]
+    output_low_confidence_counts: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_low_confidence_counts_fmt: OutputFormat,
+
+    /// Assignment probability dividing high from low confidence counts
+    #[arg(long, default_value_t = 0.9_f32)]
+    high_confidence_threshold: f32,
+
+    /// Output a matrix of expected transcript counts per cell: the probability
+    /// of each transcript being assigned to the cell, summed over transcripts
     #[arg(long, default_value = "expected-counts.csv.gz")]
     output_expected_counts: Option<String>,
 
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_expected_counts_fmt: OutputFormat,
+
     /// Output a matrix of estimated Poisson expression rates per cell
     #[arg(long, default_value = None)]
     output_rates: Option<String>,
@@ -250,13 +861,34 @@ struct Args {
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
     output_component_params_fmt: OutputFormat,
 
+    /// Output the weight, number of cells, and volume distribution of each
+    /// component
+    #[arg(long, default_value = None)]
+    output_component_metadata: Option<String>,
+
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
-    output_expected_counts_fmt: OutputFormat,
+    output_component_metadata_fmt: OutputFormat,
+
+    /// Output the posterior probability of each cell belonging to each
+    /// component, over the recorded samples
+    #[arg(long, default_value = None)]
+    output_component_probabilities: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_component_probabilities_fmt: OutputFormat,
 
     /// Output cell convex hulls
     #[arg(long, default_value = None)]
     output_cell_hulls: Option<String>,
 
+    /// Output pairs of cells that share a boundary, with the length of boundary
+    /// they share
+    #[arg(long, default_value = None)]
+    output_cell_graph: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_cell_graph_fmt: OutputFormat,
+
     /// Output cell metadata
     #[arg(long, default_value = "cell-metadata.csv.gz")]
     output_cell_metadata: Option<String>,
@@ -264,6 +896,25 @@ struct Args {
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
     output_cell_metadata_fmt: OutputFormat,
 
+    /// Add a `cell_id` column to cell metadata naming cells in a way that's stable
+    /// between runs: `centroid` for the sample and a z-order code of the cell's
+    /// centroid quantized to --cell-id-resolution, or `original` for the input
+    /// cell id the cell has most transcripts from (falling back to `centroid`)
+    #[arg(long, value_enum, default_value_t = CellIdScheme::Index)]
+    cell_id_scheme: CellIdScheme,
+
+    /// Grid size centroids are quantized to with --cell-id-scheme centroid
+    #[arg(long, default_value_t = 1.0_f32)]
+    cell_id_resolution: f32,
+
+    /// Output a table relating cells to the input cell ids they contain
+    /// transcripts from, with the number of transcripts from each
+    #[arg(long, default_value = None)]
+    output_cell_id_map: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_cell_id_map_fmt: OutputFormat,
+
     /// Output transcript metadata
     #[arg(long, default_value = "transcript-metadata.csv.gz")]
     output_transcript_metadata: Option<String>,
@@ -271,6 +922,30 @@ struct Args {
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
     output_transcript_metadata_fmt: OutputFormat,
 
+    /// Output posterior probabilities of every cell each transcript was assigned to
+    /// during the recorded samples
+    #[arg(long, default_value=None)]
+    output_transcript_posterior: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_transcript_posterior_fmt: OutputFormat,
+
+    /// Output the fraction of chains agreeing with the consensus assignment of
+    /// each transcript, when running with --nchains
+    #[arg(long, default_value=None)]
+    output_chain_agreement: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_chain_agreement_fmt: OutputFormat,
+
+    /// Output per-iteration diagnostics (log likelihood, cell counts, proposal
+    /// acceptance rates, etc) for assessing convergence
+    #[arg(long, default_value=None)]
+    output_diagnostics: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_diagnostics_fmt: OutputFormat,
+
     /// Output gene metadata
     #[arg(long, default_value=None)]
     output_gene_metadata: Option<String>,
@@ -278,6 +953,75 @@ struct Args {
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
     output_gene_metadata_fmt: OutputFormat,
 
+    /// Output, for each transcript, the distance it diffused in the model, and the
+    /// signed distance from its observed position to the boundary of its cell's
+    /// polygon (positive outside the cell)
+    #[arg(long, default_value=None)]
+    output_transcript_diffusion: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_transcript_diffusion_fmt: OutputFormat,
+
+    /// Output per-gene summaries of transcript diffusion, for finding genes whose
+    /// transcripts are often observed outside the cells they're assigned to
+    #[arg(long, default_value=None)]
+    output_gene_diffusion: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_gene_diffusion_fmt: OutputFormat,
+
+    /// Output per-gene background and confusion rates, with expected and observed
+    /// noise transcript counts
+    #[arg(long, default_value=None)]
+    output_noise_report: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_noise_report_fmt: OutputFormat,
+
+    /// Output the ambient expression profile estimated from transcripts assigned
+    /// to the background
+    #[arg(long, default_value=None)]
+    output_ambient_profile: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_ambient_profile_fmt: OutputFormat,
+
+    /// Output expected counts less the ambient counts expected in each cell's
+    /// volume, clipped at zero
+    #[arg(long, default_value=None)]
+    output_corrected_counts: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_corrected_counts_fmt: OutputFormat,
+
+    /// Output the estimated tissue region, whose area the background rates are
+    /// spread over, as GeoJSON polygons
+    #[arg(long, default_value=None)]
+    output_tissue_mask: Option<String>,
+
+    /// Output the fitted background rate in each bin of --background-grid-size
+    #[arg(long, default_value=None)]
+    output_background_map: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_background_map_fmt: OutputFormat,
+
+    /// Output per-gene counts of transcripts dropped by --exclude-genes or
+    /// --include-genes
+    #[arg(long, default_value=None)]
+    output_excluded_genes: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_excluded_genes_fmt: OutputFormat,
+
+    /// Output posterior mean transcript positions under the diffusion model,
+    /// averaged over the recorded samples
+    #[arg(long, default_value=None)]
+    output_transcript_positions: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_transcript_positions_fmt: OutputFormat,
+
     /// Output a table of each voxel in each cell
     #[arg(long, default_value=None)]
     output_cell_voxels: Option<String>,
@@ -285,11 +1029,32 @@ struct Args {
     #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
     output_cell_voxels_fmt: OutputFormat,
 
+    /// Output a table of the voxels the sampler operates on: every voxel
+    /// assigned to a cell or containing transcripts, with its center, size,
+    /// cell, and transcript count
+    #[arg(long, default_value=None)]
+    output_hexes: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_hexes_fmt: OutputFormat,
+
+    /// Also write --output-hexes at the end of each earlier phase of the
+    /// schedule, at that phase's resolution, adding the phase number to the
+    /// filename (e.g. hexes-phase1.parquet)
+    #[arg(long, default_value_t = false)]
+    output_hexes_phases: bool,
+
     /// Output consensus non-overlapping 2D polygons, formed by taking the
     /// dominant cell at each x/y location.
     #[arg(long, default_value = "cell-polygons.geojson.gz")]
     output_cell_polygons: Option<String>,
 
+    /// Format of --output-cell-polygons. `geoparquet` writes polygons as WKB
+    /// along with the cell metadata columns, replacing a .geojson filename
+    /// suffix with .parquet.
+    #[arg(long, value_enum, default_value_t = PolygonFormat::Infer)]
+    output_cell_polygons_fmt: PolygonFormat,
+
     /// Output cell polygons flattened (unioned) to 2D
     #[arg(long, default_value = "union-cell-polygons.geojson.gz")]
     output_union_cell_polygons: Option<String>,
@@ -298,25 +1063,415 @@ struct Args {
     #[arg(long, default_value = "cell-polygons-layers.geojson.gz")]
     output_cell_polygon_layers: Option<String>,
 
+    /// Smooth consensus cell polygons, which otherwise follow voxel edges
+    #[arg(long, value_enum, default_value_t = PolygonSmoothing::None)]
+    polygon_smoothing: PolygonSmoothing,
+
+    /// Number of rounds of corner cutting with --polygon-smoothing chaikin
+    #[arg(long, default_value_t = 2)]
+    polygon_smoothing_iterations: usize,
+
+    /// Kernel standard deviation with --polygon-smoothing gaussian
+    #[arg(long, default_value_t = 1.0)]
+    polygon_smoothing_sigma: f32,
+
+    /// Simplify consensus cell polygons with the Douglas-Peucker algorithm,
+    /// removing vertices that are within this distance of the simplified outline
+    #[arg(long, default_value_t = 0.0)]
+    polygon_simplify_tolerance: f32,
+
+    /// Output cell-by-gene expected counts and cell metadata as an AnnData (h5ad) file.
+    /// Requires proseg to be built with the `hdf5` feature.
+    #[arg(long, default_value = None)]
+    output_anndata: Option<String>,
+
+    /// Output transcripts, cell polygons, and expected counts as a SpatialData zarr store
+    #[arg(long, default_value = None)]
+    output_spatialdata: Option<String>,
+
     /// Output cell polygons repeatedly during sampling
     #[arg(long, default_value = None)]
     monitor_cell_polygons: Option<String>,
 
     /// How frequently to output cell polygons during monitoring
-    #[arg(long, default_value_t = 10)]
+    #[arg(long, value_parser = parse_interval, default_value_t = 10)]
     monitor_cell_polygons_freq: usize,
 
-    /// Use connectivity checks to prevent cells from having any disconnected voxels
-    #[arg(long, default_value_t = true)]
-    enforce_connectivity: bool,
-}
+    /// Periodically save sampler state to this file, so the run can be resumed
+    #[arg(long, default_value = None)]
+    checkpoint: Option<String>,
 
-fn set_xenium_presets(args: &mut Args) {
-    args.gene_column.get_or_insert(String::from("feature_name"));
-    args.transcript_id_column
-        .get_or_insert(String::from("transcript_id"));
-    args.x_column.get_or_insert(String::from("x_location"));
-    args.y_column.get_or_insert(String::from("y_location"));
+    /// How frequently (in iterations) to save a checkpoint
+    #[arg(long, value_parser = parse_interval, default_value_t = 100)]
+    checkpoint_interval: usize,
+
+    /// Rewrite counts, transcript metadata, cell polygons, and hulls from the
+    /// current state every this many iterations, so intermediate results can be
+    /// inspected, or salvaged from a run that doesn't finish
+    #[arg(long, value_parser = parse_interval, default_value = None)]
+    output_interval: Option<usize>,
+
+    /// Write PNG images of the current segmentation to this directory every
+    /// --render-interval iterations, as a quick visual check on convergence
+    #[arg(long, default_value = None)]
+    render_snapshots: Option<String>,
+
+    /// How frequently (in iterations) to write snapshots
+    #[arg(long, value_parser = parse_interval, default_value_t = 200)]
+    render_interval: usize,
+
+    /// Region to draw in snapshots, as xmin,ymin,xmax,ymax. By default,
+    /// everything.
+    #[arg(long, num_args=1.., value_delimiter=',', allow_negative_numbers=true)]
+    render_roi: Option<Vec<f32>>,
+
+    /// Color each cell in snapshots differently, or by its mixture component
+    #[arg(long, value_enum, default_value_t = SnapshotColor::Cell)]
+    render_color: SnapshotColor,
+
+    /// Width of snapshots in pixels
+    #[arg(long, default_value_t = 1000)]
+    render_width: usize,
+
+    /// Resume sampling from a checkpoint written by a previous run with the same inputs
+    #[arg(long, default_value = None)]
+    resume: Option<String>,
+
+    /// Use connectivity checks to prevent cells from having any disconnected voxels
+    #[arg(long, default_value_t = true)]
+    enforce_connectivity: bool,
+
+    // Transcript metadata of a previous run, set by `proseg refine`
+    #[arg(skip)]
+    previous: Option<(String, OutputFormat)>,
+}
+
+#[derive(clap::Args)]
+struct ResumeArgs {
+    /// Checkpoint file written by a previous run
+    #[arg(value_name = "CHECKPOINT")]
+    resume_checkpoint: String,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(clap::Args)]
+struct RefineArgs {
+    /// Transcript metadata written by the previous run (--output-transcript-metadata),
+    /// or the directory it was written to
+    #[arg(long)]
+    previous: String,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    previous_fmt: OutputFormat,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(clap::Args)]
+struct ConvertArgs {
+    /// Table (CSV, TSV, or Parquet) or GeoJSON file written by proseg, or
+    /// transcripts to convert to a .pbin file
+    input: String,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    input_fmt: OutputFormat,
+
+    /// Output filename, with the format determined by its extension unless
+    /// --output-fmt is given
+    output: String,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_fmt: OutputFormat,
+
+    /// Options for reading transcripts, as given to `proseg run`, when the
+    /// output is a .pbin file (e.g. `-- --xenium`)
+    #[arg(last = true)]
+    read_args: Vec<String>,
+}
+
+#[derive(clap::Args)]
+struct ValidateArgs {
+    /// Cell polygons GeoJSON, as written by --output-cell-polygons
+    cell_polygons: String,
+
+    /// Transcript metadata, as written by --output-transcript-metadata
+    #[arg(long, default_value = None)]
+    transcript_metadata: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    transcript_metadata_fmt: OutputFormat,
+
+    /// Ignore overlaps smaller than this area, which can arise from rounding
+    #[arg(long, default_value_t = 1e-3)]
+    min_overlap_area: f32,
+}
+
+#[derive(clap::Args)]
+struct CompareArgs {
+    /// Transcript assignments from another segmentation, e.g. ground truth or
+    /// another method, with a transcript id column matching proseg's input.
+    #[arg(long)]
+    truth: String,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    truth_fmt: OutputFormat,
+
+    #[arg(long, default_value = "transcript_id")]
+    truth_transcript_id_column: String,
+
+    #[arg(long, default_value = "cell_id")]
+    truth_cell_column: String,
+
+    /// Value in the truth cell column indicating a transcript is unassigned.
+    /// Empty values are always treated as unassigned.
+    #[arg(long, default_value = "UNASSIGNED")]
+    truth_unassigned: String,
+
+    /// Transcript metadata, as written by --output-transcript-metadata
+    #[arg(long, default_value = "transcript-metadata.csv.gz")]
+    transcript_metadata: String,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    transcript_metadata_fmt: OutputFormat,
+
+    /// Output a table of matched cells, with their shared transcripts,
+    /// intersection over union, and correlation of gene counts
+    #[arg(long, default_value = "comparison.csv.gz")]
+    output: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Infer)]
+    output_fmt: OutputFormat,
+}
+
+fn compare(args: CompareArgs) {
+    let background = BACKGROUND_CELL.to_string();
+    let seg = read_segmentation(
+        &args.transcript_metadata,
+        args.transcript_metadata_fmt,
+        &SegmentationColumns {
+            transcript_id: "transcript_id",
+            cell: "assignment",
+            unassigned: &background,
+            gene: Some("gene"),
+        },
+    )
+    .unwrap_or_else(|err| {
+        eprintln!("Error reading transcript metadata: {}", err);
+        std::process::exit(1);
+    });
+    let truth = read_segmentation(
+        &args.truth,
+        args.truth_fmt,
+        &SegmentationColumns {
+            transcript_id: &args.truth_transcript_id_column,
+            cell: &args.truth_cell_column,
+            unassigned: &args.truth_unassigned,
+            gene: None,
+        },
+    )
+    .unwrap_or_else(|err| {
+        eprintln!("Error reading truth assignments: {}", err);
+        std::process::exit(1);
+    });
+
+    let comparison = compare_segmentations(&seg, &truth);
+    if comparison.ntranscripts == 0 {
+        eprintln!("Error: no transcript ids in common between the two segmentations");
+        std::process::exit(1);
+    }
+
+    let median = |mut xs: Vec<f32>| -> f32 {
+        xs.retain(|x| !x.is_nan());
+        if xs.is_empty() {
+            return f32::NAN;
+        }
+        xs.sort_unstable_by(|a, b| a.total_cmp(b));
+        xs[xs.len() / 2]
+    };
+
+    println!(
+        "Compared {} transcripts present in both ({} in proseg's output, {} in the truth)",
+        comparison.ntranscripts,
+        seg.transcript_ids.len(),
+        truth.transcript_ids.len()
+    );
+    println!(
+        "  Cells: {} proseg, {} truth, {} matched",
+        comparison.ncells,
+        comparison.truth_ncells,
+        comparison.matches.len()
+    );
+    println!("  Transcript agreement: {:.4}", comparison.agreement);
+    println!(
+        "  Median IoU of matched cells: {:.4}",
+        median(comparison.matches.iter().map(|m| m.iou).collect())
+    );
+    println!(
+        "  Median count correlation of matched cells: {:.4}",
+        median(comparison.matches.iter().map(|m| m.correlation).collect())
+    );
+    println!(
+        "  Mutual information: {:.4} (normalized: {:.4})",
+        comparison.mutual_information, comparison.normalized_mutual_information
+    );
+
+    write_comparison(&args.output, args.output_fmt, &comparison, &seg, &truth);
+}
+
+fn is_geojson_filename(filename: &str) -> bool {
+    [".geojson", ".geojson.gz", ".geojson.zst"]
+        .iter()
+        .any(|suffix| filename.ends_with(suffix))
+}
+
+// Rewrite an output file in another format. GeoJSON files can only have their
+// compression changed.
+fn convert(args: ConvertArgs) {
+    if is_pbin_filename(&args.output) {
+        convert_transcripts_pbin(args);
+        return;
+    }
+    if is_pbin_filename(&args.input) {
+        eprintln!("Error: .pbin files can only be read by `proseg run`");
+        std::process::exit(1);
+    }
+
+    if is_geojson_filename(&args.input) || is_geojson_filename(&args.output) {
+        if !(is_geojson_filename(&args.input) && is_geojson_filename(&args.output)) {
+            eprintln!("Error: GeoJSON files can only be converted to other GeoJSON files");
+            std::process::exit(1);
+        }
+        let mut input = open_decompressed(&args.input).unwrap_or_else(|err| {
+            eprintln!("Error reading {}: {}", args.input, err);
+            std::process::exit(1);
+        });
+        let mut output = geojson_writer(&args.output);
+        std::io::copy(&mut input, &mut output)
+            .and_then(|_| output.flush())
+            .unwrap_or_else(|err| panic!("Unable to write '{}': {}", args.output, err));
+        return;
+    }
+
+    let output_fmt = match args.output_fmt {
+        OutputFormat::Infer => infer_format_from_filename(&args.output),
+        fmt => fmt,
+    };
+    if output_fmt == OutputFormat::Mtx || output_fmt == OutputFormat::Loom {
+        eprintln!("Error: the mtx and loom formats are only written for count matrices by `proseg run`");
+        std::process::exit(1);
+    }
+
+    let table = read_table(&args.input, args.input_fmt).unwrap_or_else(|err| {
+        eprintln!("Error reading {}: {}", args.input, err);
+        std::process::exit(1);
+    });
+    write_table(&args.output, output_fmt, &table);
+    println!(
+        "Wrote {} rows and {} columns to {}",
+        table.num_rows(),
+        table.num_columns(),
+        args.output
+    );
+}
+
+// Read transcripts as `proseg run` would, with the options after `--`, and
+// save them in the binary format it can load directly.
+fn convert_transcripts_pbin(args: ConvertArgs) {
+    let mut cli_args = vec![String::from("proseg"), String::from("run"), args.input.clone()];
+    cli_args.extend(args.read_args.iter().cloned());
+    let mut run_args = match Cli::parse_from(cli_args).command {
+        Command::Run(run_args) => run_args,
+        _ => unreachable!(),
+    };
+    apply_presets(&mut run_args);
+    if run_args.use_cell_initialization {
+        run_args.compartment_column = None;
+        run_args.compartment_nuclear = None;
+    }
+
+    let roi = read_roi(&run_args);
+    let filter = run_args.filter.as_ref().map(|filter| {
+        RowFilter::parse(filter).unwrap_or_else(|err| {
+            eprintln!("Error: invalid --filter expression: {}", err);
+            std::process::exit(1);
+        })
+    });
+    let dataset = read_transcripts(&run_args, roi.as_ref(), filter.as_ref());
+
+    write_transcripts_pbin(&args.output, &dataset)
+        .unwrap_or_else(|err| panic!("Unable to write '{}': {}", args.output, err));
+    println!(
+        "Wrote {} transcripts of {} genes to {}",
+        dataset.transcripts.len(),
+        dataset.transcript_names.len(),
+        args.output
+    );
+}
+
+// Report problems with output polygons, exiting with an error if any polygons
+// self-intersect or overlap.
+fn validate_output(args: ValidateArgs) {
+    let polygons = read_cell_polygons(&args.cell_polygons).unwrap_or_else(|err| {
+        eprintln!("Error reading cell polygons: {}", err);
+        std::process::exit(1);
+    });
+    println!("Checked {} cell polygons", polygons.cells.len());
+
+    let self_intersecting = self_intersecting_cells(&polygons);
+    println!("  {} self-intersecting", self_intersecting.len());
+    for cell in self_intersecting.iter().take(10) {
+        println!("    cell {}", cell);
+    }
+
+    let overlaps = overlapping_cells(&polygons, &self_intersecting, args.min_overlap_area);
+    println!("  {} overlapping pairs", overlaps.len());
+    for overlap in overlaps.iter().take(10) {
+        println!(
+            "    cells {} and {} (area {})",
+            overlap.cell_a, overlap.cell_b, overlap.area
+        );
+    }
+
+    if let Some(transcript_metadata) = &args.transcript_metadata {
+        let transcripts =
+            read_transcript_assignments(transcript_metadata, args.transcript_metadata_fmt)
+                .unwrap_or_else(|err| {
+                    eprintln!("Error reading transcript metadata: {}", err);
+                    std::process::exit(1);
+                });
+        let check = check_transcripts(&polygons, &transcripts);
+        println!("Checked {} assigned transcripts", check.assigned);
+        println!(
+            "  {} ({:.2}%) outside their cell's polygon",
+            check.outside,
+            100.0 * check.outside as f32 / check.assigned.max(1) as f32
+        );
+        println!("  {} in cells with no polygon", check.missing);
+    }
+
+    if !self_intersecting.is_empty() || !overlaps.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+fn polygon_smoothing_params(args: &Args) -> PolygonSmoothingParams {
+    PolygonSmoothingParams {
+        method: args.polygon_smoothing,
+        iterations: args.polygon_smoothing_iterations,
+        sigma: args.polygon_smoothing_sigma,
+        simplify_tolerance: args.polygon_simplify_tolerance,
+    }
+}
+
+fn set_xenium_presets(args: &mut Args) {
+    args.gene_column.get_or_insert(String::from("feature_name"));
+    args.transcript_id_column
+        .get_or_insert(String::from("transcript_id"));
+    args.x_column.get_or_insert(String::from("x_location"));
+    args.y_column.get_or_insert(String::from("y_location"));
     args.z_column.get_or_insert(String::from("z_location"));
     args.compartment_column
         .get_or_insert(String::from("overlaps_nucleus"));
@@ -329,7 +1484,7 @@ fn set_xenium_presets(args: &mut Args) {
     // newer xenium data does have a fov column
     args.fov_column.get_or_insert(String::from("fov_name"));
 
-    args.initial_voxel_size = 4.0;
+    args.initial_voxel_size.get_or_insert(4.0);
 }
 
 fn set_cosmx_presets(args: &mut Args) {
@@ -350,7 +1505,7 @@ fn set_cosmx_presets(args: &mut Args) {
     // CosMx reports values in pixels and pixel size appears to always be 0.12 microns.
     args.coordinate_scale.get_or_insert(0.12);
 
-    args.initial_voxel_size = 4.0;
+    args.initial_voxel_size.get_or_insert(4.0);
 }
 
 fn set_cosmx_micron_presets(args: &mut Args) {
@@ -366,7 +1521,7 @@ fn set_cosmx_micron_presets(args: &mut Args) {
     args.cell_id_column.get_or_insert(String::from("cell_ID"));
     args.cell_id_unassigned.get_or_insert(String::from("0"));
 
-    args.initial_voxel_size = 4.0;
+    args.initial_voxel_size.get_or_insert(4.0);
 }
 
 fn set_merfish_presets(args: &mut Args) {
@@ -377,7 +1532,112 @@ fn set_merfish_presets(args: &mut Args) {
     args.cell_id_column.get_or_insert(String::from("cell"));
     args.cell_id_unassigned.get_or_insert(String::from("NA"));
     // args.cell_id_unassigned.get_or_insert(String::from("0"));
-    args.initial_voxel_size = 4.0;
+    args.initial_voxel_size.get_or_insert(4.0);
+}
+
+// Check --voxel-size-schedule against the schedule and the doubling of
+// resolution between phases, and set the initial voxel size from it.
+fn apply_voxel_size_schedule(args: &mut Args) {
+    let sizes = &args.voxel_size_schedule;
+    if sizes.is_empty() {
+        return;
+    }
+    if sizes.len() != args.schedule.len() {
+        eprintln!(
+            "Error: --voxel-size-schedule has {} entries, but --schedule has {} phases",
+            sizes.len(),
+            args.schedule.len()
+        );
+        std::process::exit(1);
+    }
+    if sizes.iter().any(|size| !size.is_finite() || *size <= 0.0) {
+        eprintln!("Error: --voxel-size-schedule entries must be positive");
+        std::process::exit(1);
+    }
+    if let Some(k) = (1..sizes.len()).find(|&k| (sizes[k] - sizes[k - 1] / 2.0).abs() > 1e-3 * sizes[k]) {
+        eprintln!(
+            "Error: --voxel-size-schedule must halve each phase, since voxels are split in four, but phase {} has size {} after {}",
+            k + 1,
+            sizes[k],
+            sizes[k - 1]
+        );
+        std::process::exit(1);
+    }
+    if args.initial_voxel_size.is_some_and(|size| size != sizes[0]) {
+        eprintln!("Error: --initial-voxel-size and --voxel-size-schedule disagree");
+        std::process::exit(1);
+    }
+    args.initial_voxel_size = Some(sizes[0]);
+}
+
+// Column names and other settings for each platform, and the voxel size from
+// --expected-cell-diameter.
+fn apply_presets(args: &mut Args) {
+    match args.preset {
+        Some(Preset::Xenium) => args.xenium = true,
+        Some(Preset::Cosmx) => args.cosmx = true,
+        Some(Preset::CosmxMicron) => args.cosmx_micron = true,
+        Some(Preset::Merscope) => args.merscope = true,
+        Some(Preset::Merfish) => args.merfish = true,
+        None => {}
+    }
+
+    if (args.xenium as u8)
+        + (args.cosmx as u8)
+        + (args.cosmx_micron as u8)
+        + (args.merfish as u8)
+        + (args.merscope as u8)
+        > 1
+    {
+        panic!(
+            "At most one of --xenium, --cosmx, --cosmx-micron, --merfish, --merscope can be set"
+        );
+    }
+
+    // Applied before presets, which would otherwise set the voxel size.
+    if let Some(diameter) = args.expected_cell_diameter {
+        if diameter <= 0.0 {
+            eprintln!("Error: --expected-cell-diameter must be positive");
+            std::process::exit(1);
+        }
+        args.initial_voxel_size.get_or_insert(0.4 * diameter);
+    }
+
+    if args.xenium {
+        set_xenium_presets(args);
+    }
+
+    if args.cosmx {
+        set_cosmx_presets(args);
+    }
+
+    if args.cosmx_micron {
+        set_cosmx_micron_presets(args);
+    }
+
+    if args.merfish {
+        println!("WARNING: --merfish is deprecated, use --merscope instead");
+        set_merfish_presets(args);
+    }
+
+    if args.merscope {
+        set_merscope_presets(args);
+    }
+}
+
+// Greedy (iterated conditional modes) updates of cell boundaries, transcript
+// positions, and global parameters in every phase, moving on once each phase
+// converges. Expectations over samples would add nothing, so only one is
+// recorded. Runs are reproducible, using seed 0 unless another is given.
+fn set_map_mode(args: &mut Args) {
+    if !args.temperature_schedule.is_empty() {
+        eprintln!("Error: --temperature-schedule can't be used with --mode map");
+        std::process::exit(1);
+    }
+    args.temperature_schedule = vec![0.0; args.schedule.len()];
+    args.recorded_samples = 1;
+    args.convergence_eps.get_or_insert(1e-4);
+    args.seed.get_or_insert(0);
 }
 
 fn set_merscope_presets(args: &mut Args) {
@@ -388,7 +1648,142 @@ fn set_merscope_presets(args: &mut Args) {
     args.fov_column.get_or_insert(String::from("fov"));
     args.cell_id_column.get_or_insert(String::from("cell_id"));
     args.cell_id_unassigned.get_or_insert(String::from("-1"));
-    args.initial_voxel_size = 4.0;
+    args.initial_voxel_size.get_or_insert(4.0);
+}
+
+// Convert a TOML config file into command line arguments, skipping any options
+// that are also given on the command line.
+fn config_file_args(filename: &str, cli_args: &[String]) -> Result<Vec<String>, String> {
+    let content = std::fs::read_to_string(filename)
+        .map_err(|err| format!("unable to read config file '{}': {}", filename, err))?;
+    let table = content
+        .parse::<toml::Table>()
+        .map_err(|err| format!("unable to parse config file '{}': {}", filename, err))?;
+
+    fn value_string(key: &str, value: &toml::Value) -> Result<String, String> {
+        match value {
+            toml::Value::String(value) => Ok(value.clone()),
+            toml::Value::Integer(value) => Ok(value.to_string()),
+            toml::Value::Float(value) => Ok(value.to_string()),
+            _ => Err(format!("unsupported value for '{}' in config file", key)),
+        }
+    }
+
+    // Each value is given as a single `--flag=value` argument, so options with
+    // several values can't take any arguments that follow as theirs.
+    fn push_args(table: &toml::Table, cli_args: &[String], args: &mut Vec<String>) -> Result<(), String> {
+        for (key, value) in table {
+            if let toml::Value::Table(table) = value {
+                push_args(table, cli_args, args)?;
+                continue;
+            }
+
+            let flag = format!("--{}", key.replace('_', "-"));
+            let on_cli = cli_args
+                .iter()
+                .any(|arg| *arg == flag || arg.starts_with(&format!("{}=", flag)));
+            if on_cli || flag == "--config" {
+                continue;
+            }
+
+            match value {
+                toml::Value::Boolean(true) => args.push(flag),
+                toml::Value::Boolean(false) => {}
+                toml::Value::Array(values) => {
+                    for value in values {
+                        args.push(format!("{}={}", flag, value_string(key, value)?));
+                    }
+                }
+                _ => args.push(format!("{}={}", flag, value_string(key, value)?)),
+            }
+        }
+        Ok(())
+    }
+
+    let mut args = Vec::new();
+    push_args(&table, cli_args, &mut args)?;
+    Ok(args)
+}
+
+// Command line arguments with those from the config file inserted right after
+// the subcommand.
+fn with_config_args(cli_args: &[String], config: &str) -> Result<Vec<String>, String> {
+    let mut args = cli_args[..2].to_vec();
+    args.extend(config_file_args(config, &cli_args[2..])?);
+    args.extend(cli_args[2..].iter().cloned());
+    Ok(args)
+}
+
+fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s {
+        "tab" | "\\t" => Ok(b'\t'),
+        _ if s.len() == 1 && s.is_ascii() => Ok(s.as_bytes()[0]),
+        _ => Err(format!("expected a single character or \"tab\", found \"{}\"", s)),
+    }
+}
+
+// A number of iterations between periodic outputs, which must be positive.
+fn parse_interval(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(interval) if interval > 0 => Ok(interval),
+        _ => Err(format!("expected a positive number of iterations, found \"{}\"", s)),
+    }
+}
+
+fn parse_prior_confidence(s: &str) -> Result<(String, f32), String> {
+    let (source, confidence) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected source=probability, found \"{}\"", s))?;
+    match confidence.parse::<f32>() {
+        Ok(confidence) if confidence > 0.0 && confidence < 1.0 => {
+            Ok((source.to_string(), confidence))
+        }
+        _ => Err(format!("expected a probability between 0 and 1, found \"{}\"", confidence)),
+    }
+}
+
+// Give every CSV and GeoJSON output filename the suffix for `compression`.
+fn set_output_compression(args: &mut Args, compression: OutputCompression) {
+    for filename in [
+        &mut args.output_nuclear_expected_counts,
+        &mut args.output_cytoplasmic_expected_counts,
+        &mut args.output_maxpost_counts,
+        &mut args.output_high_confidence_counts,
+        &mut args.output_low_confidence_counts,
+        &mut args.output_expected_counts,
+        &mut args.output_rates,
+        &mut args.output_component_params,
+        &mut args.output_component_metadata,
+        &mut args.output_component_probabilities,
+        &mut args.output_cell_hulls,
+        &mut args.output_cell_graph,
+        &mut args.output_cell_metadata,
+        &mut args.output_cell_id_map,
+        &mut args.output_transcript_metadata,
+        &mut args.output_transcript_posterior,
+        &mut args.output_chain_agreement,
+        &mut args.output_diagnostics,
+        &mut args.output_gene_metadata,
+        &mut args.output_noise_report,
+        &mut args.output_ambient_profile,
+        &mut args.output_corrected_counts,
+        &mut args.output_background_map,
+        &mut args.output_transcript_diffusion,
+        &mut args.output_gene_diffusion,
+        &mut args.output_excluded_genes,
+        &mut args.output_transcript_positions,
+        &mut args.output_cell_voxels,
+        &mut args.output_hexes,
+        &mut args.output_cell_polygons,
+        &mut args.output_union_cell_polygons,
+        &mut args.output_cell_polygon_layers,
+        &mut args.output_tissue_mask,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        *filename = with_compression(filename, compression);
+    }
 }
 
 fn main() {
@@ -409,8 +1804,63 @@ fn main() {
     //     panic!();
     // }
 
-    let mut args = Args::parse();
+    // Invocations without a subcommand, as in earlier versions, run the sampler.
+    let mut cli_args = std::env::args().collect::<Vec<_>>();
+    if cli_args.get(1).is_some_and(|arg| {
+        !matches!(arg.as_str(), "help" | "-h" | "--help" | "-V" | "--version")
+            && Cli::command().find_subcommand(arg).is_none()
+    }) {
+        cli_args.insert(1, String::from("run"));
+    }
+
+    let mut cli = Cli::parse_from(&cli_args);
+
+    // Options from a config file go right after the subcommand, so any given
+    // on the command line take precedence.
+    let config = match &cli.command {
+        Command::Run(args)
+        | Command::Resume(ResumeArgs { args, .. })
+        | Command::Refine(RefineArgs { args, .. }) => args.config.clone(),
+        _ => None,
+    };
+    if let Some(config) = config {
+        let config_args = with_config_args(&cli_args, &config).unwrap_or_else(|err| {
+            Cli::command()
+                .error(clap::error::ErrorKind::InvalidValue, err)
+                .exit()
+        });
+        cli = Cli::parse_from(config_args);
+    }
+
+    match cli.command {
+        Command::Run(args) => run(args),
+        Command::Resume(ResumeArgs {
+            resume_checkpoint,
+            mut args,
+        }) => {
+            args.resume = Some(resume_checkpoint);
+            run(args);
+        }
+        Command::Refine(RefineArgs {
+            previous,
+            previous_fmt,
+            mut args,
+        }) => {
+            args.previous = Some((previous, previous_fmt));
+            run(args);
+        }
+        Command::Convert(args) => convert(args),
+        Command::Validate(args) => validate_output(args),
+        Command::Compare(args) => compare(args),
+    }
+}
 
+fn run(mut args: Args) {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let run_start = Instant::now();
     if let Some(nthreads) = args.nthreads {
         rayon::ThreadPoolBuilder::new()
             .num_threads(nthreads)
@@ -419,95 +1869,1228 @@ fn main() {
     }
     let nthreads = current_num_threads();
     println!("Using {} threads", nthreads);
+    let profiler = args.profile.as_ref().map(|_| Arc::new(Profiler::new()));
+    let status = args.status_port.map(|port| {
+        let status = Arc::new(RunStatus::new());
+        status.serve(args.status_address, port).unwrap_or_else(|err| {
+            eprintln!(
+                "Error: unable to serve status on {}:{}: {}",
+                args.status_address, port, err
+            );
+            std::process::exit(1);
+        });
+        println!("Serving run status on {}:{}", args.status_address, port);
+        status
+    });
 
-    if (args.xenium as u8)
-        + (args.cosmx as u8)
-        + (args.cosmx_micron as u8)
-        + (args.merfish as u8)
-        + (args.merscope as u8)
-        > 1
-    {
-        panic!(
-            "At most one of --xenium, --cosmx, --cosmx-micron, --merfish, --merscope can be set"
-        );
+    // before presets, which would otherwise set an initial voxel size
+    apply_voxel_size_schedule(&mut args);
+    apply_presets(&mut args);
+
+    if let Some(compression) = args.output_compression {
+        set_output_compression(&mut args, compression);
     }
 
-    if args.xenium {
-        set_xenium_presets(&mut args);
+    // the default filename is for GeoJSON
+    if args.output_cell_polygons_fmt == PolygonFormat::Geoparquet {
+        if let Some(filename) = &mut args.output_cell_polygons {
+            let base = filename.trim_end_matches(".gz").trim_end_matches(".zst");
+            if let Some(stem) = base.strip_suffix(".geojson") {
+                *filename = format!("{}.parquet", stem);
+            }
+        }
     }
 
-    if args.cosmx {
-        set_cosmx_presets(&mut args);
+    if args.mode == RunMode::Map {
+        set_map_mode(&mut args);
     }
 
-    if args.cosmx_micron {
-        set_cosmx_micron_presets(&mut args);
+    // pick the seed here, rather than in the sampler, so it can be recorded
+    if args.output_run_manifest.is_some() && args.seed.is_none() {
+        args.seed = Some(if args.deterministic { 0 } else { rand::thread_rng().gen() });
     }
 
-    if args.merfish {
-        println!("WARNING: --merfish is deprecated, use --merscope instead");
-        set_merfish_presets(&mut args);
+    if args.recorded_samples > *args.schedule.last().unwrap() {
+        eprintln!("Error: --recorded-samples must be <= the last entry in the schedule");
+        std::process::exit(1);
+    }
+
+    if !args.temperature_schedule.is_empty() {
+        if args.temperature_schedule.len() != args.schedule.len() {
+            eprintln!(
+                "Error: --temperature-schedule has {} entries, but --schedule has {} phases",
+                args.temperature_schedule.len(),
+                args.schedule.len()
+            );
+            std::process::exit(1);
+        }
+        if args.temperature_schedule.iter().any(|t| !t.is_finite() || *t < 0.0) {
+            eprintln!("Error: --temperature-schedule entries must be non-negative");
+            std::process::exit(1);
+        }
+        if *args.temperature_schedule.last().unwrap() != 1.0 && args.mode != RunMode::Map {
+            println!("WARNING: The final phase isn't run at temperature 1, so recorded samples don't reflect posterior uncertainty.");
+        }
+    }
+
+    if args.use_cell_initialization {
+        args.compartment_column = None;
+        args.compartment_nuclear = None;
+    }
+
+    if let Some(confidence) = args.prior_seg_confidence {
+        if !(confidence > 0.0 && confidence < 1.0) {
+            eprintln!("Error: --prior-seg-confidence must be between 0 and 1");
+            std::process::exit(1);
+        }
+        args.prior_seg_reassignment_prob = 1.0 - confidence;
+    }
+
+    if !args.prior_confidence.is_empty() && args.prior_assignment_column.is_none() {
+        eprintln!("Error: --prior-confidence requires --prior-assignment-column");
+        std::process::exit(1);
+    }
+
+    if args.ncomponents == NComponents::Auto
+        && (args.max_components == 0 || args.dp_concentration <= 0.0)
+    {
+        eprintln!("Error: --max-components and --dp-concentration must be positive");
+        std::process::exit(1);
+    }
+
+    if args.component_smoothness < 0.0
+        || args.component_smoothness_length.is_some_and(|length| length <= 0.0)
+    {
+        eprintln!("Error: --component-smoothness must be non-negative and --component-smoothness-length positive");
+        std::process::exit(1);
+    }
+
+    if args.neighborhood_method == NeighborhoodMethod::Delaunay {
+        if !cfg!(feature = "delaunay") {
+            eprintln!("Error: --neighborhood-method delaunay requires building proseg with `--features delaunay`");
+            std::process::exit(1);
+        }
+        if args.component_smoothness == 0.0 {
+            println!("WARNING: --neighborhood-method has no effect without --component-smoothness");
+        }
     }
 
-    if args.merscope {
-        set_merscope_presets(&mut args);
+    if args.expression_prior.is_some() {
+        if args.ncomponents == NComponents::Auto {
+            eprintln!("Error: --expression-prior can't be used with --ncomponents auto");
+            std::process::exit(1);
+        }
+        if args.expression_prior_strength <= 0.0 {
+            eprintln!("Error: --expression-prior-strength must be positive");
+            std::process::exit(1);
+        }
+    }
+
+    if args.ignore_z_coord && args.voxel_layers > 1 {
+        println!("WARNING: --voxel-layers has no effect with --ignore-z-coord, since all transcripts lie in one z-layer.");
+    }
+
+    if args.gene_z_offsets && args.no_diffusion {
+        println!("WARNING: --gene-z-offsets has no effect with --no-diffusion.");
+    }
+
+    if args.background_grid_size.is_some_and(|size| size <= 0.0) || args.background_grid_prior <= 0.0 {
+        eprintln!("Error: --background-grid-size and --background-grid-prior must be positive");
+        std::process::exit(1);
+    }
+
+    if args.output_background_map.is_some() && args.background_grid_size.is_none() {
+        println!("WARNING: --output-background-map has no effect without --background-grid-size.");
+    }
+
+    if args.output_hexes_phases && args.output_hexes.is_none() {
+        println!("WARNING: --output-hexes-phases has no effect without --output-hexes.");
+    }
+
+    /* let (transcript_names,
+    mut transcripts,
+    mut nucleus_assignments,
+    mut cell_assignments,
+    mut nucleus_population) = */
+
+    if args.partition_fovs {
+        if args.fov_column.is_none() {
+            eprintln!("Error: --partition-fovs requires --fov-column");
+            std::process::exit(1);
+        }
+        if args.checkpoint.is_some() || args.resume.is_some() {
+            eprintln!("Error: --partition-fovs can't be used with --checkpoint or --resume");
+            std::process::exit(1);
+        }
+    }
+
+    for (argname, nmoves) in [
+        ("split-merge-moves", args.split_merge_moves),
+        ("birth-death-moves", args.birth_death_moves),
+    ] {
+        if nmoves == 0 {
+            continue;
+        }
+        if args.nchains > 1 {
+            eprintln!("Error: --{} can't be used with --nchains", argname);
+            std::process::exit(1);
+        }
+        if args.checkpoint.is_some() || args.resume.is_some() {
+            eprintln!("Error: --{} can't be used with --checkpoint or --resume", argname);
+            std::process::exit(1);
+        }
+    }
+    if args.min_cells_ratio.is_some_and(|ratio| ratio > 1.0) {
+        eprintln!("Error: --min-cells-ratio must be at most 1");
+        std::process::exit(1);
+    }
+    if args.max_new_cells_ratio.is_some_and(|ratio| ratio < 1.0) {
+        eprintln!("Error: --max-new-cells-ratio must be at least 1");
+        std::process::exit(1);
+    }
+
+    // When refining, the ROI is the region to re-segment rather than a crop,
+    // and every transcript is read.
+    let roi = read_roi(&args);
+    if args.previous.is_some() {
+        if roi.is_none() {
+            eprintln!("Error: refine requires --roi or --roi-geojson");
+            std::process::exit(1);
+        }
+        if args.partition_fovs {
+            eprintln!("Error: refine can't be used with --partition-fovs");
+            std::process::exit(1);
+        }
+        if args.split_merge_moves > 0 || args.birth_death_moves > 0 {
+            eprintln!("Error: refine can't be used with --split-merge-moves or --birth-death-moves");
+            std::process::exit(1);
+        }
+        if args.init_from_proseg.is_some() {
+            eprintln!("Error: refine can't be used with --init-from-proseg");
+            std::process::exit(1);
+        }
+    }
+    let roi = roi.filter(|_| args.previous.is_none());
+
+    let filter = args.filter.as_ref().map(|filter| {
+        RowFilter::parse(filter).unwrap_or_else(|err| {
+            eprintln!("Error: invalid --filter expression: {}", err);
+            std::process::exit(1);
+        })
+    });
+
+    let t0 = Instant::now();
+    let mut dataset = read_transcripts(&args, roi.as_ref(), filter.as_ref());
+    if let Some(profiler) = &profiler {
+        profiler.record("io;read_transcripts", t0.elapsed());
+    }
+
+    for (source, _) in &args.prior_confidence {
+        if !dataset.prior_source_names.contains(source) {
+            println!("WARNING: --prior-confidence source '{}' doesn't occur in --prior-assignment-column", source);
+        }
+    }
+
+    let gene_panel = args.gene_panel.as_ref().map(|gene_panel| {
+        let panel = GenePanel::read(gene_panel).unwrap_or_else(|err| {
+            eprintln!("Error reading gene panel: {}", err);
+            std::process::exit(1);
+        });
+        let nrenamed = rename_genes(&mut dataset, &panel);
+        let ncontrol = dataset
+            .transcripts
+            .iter()
+            .filter(|t| panel.is_control(&dataset.transcript_names[t.gene as usize]))
+            .count();
+        println!(
+            "Renamed {} genes from the gene panel, which marks {} transcripts as controls",
+            nrenamed, ncontrol
+        );
+        panel
+    });
+
+    let negative_controls = args.negative_controls.as_ref().map(|pattern| {
+        let pattern = Regex::new(pattern).unwrap_or_else(|err| {
+            eprintln!("Error: invalid --negative-controls pattern: {}", err);
+            std::process::exit(1);
+        });
+        let controls = extract_negative_controls(&mut dataset, &pattern);
+        println!(
+            "Set aside {} transcripts of {} negative control probes",
+            controls.positions.len(),
+            controls.nprobes
+        );
+        controls
+    });
+
+    if args.exclude_genes.is_some() || args.include_genes.is_some() {
+        let exclude = args.exclude_genes.as_ref().map(|pattern| {
+            Regex::new(pattern).unwrap_or_else(|err| {
+                eprintln!("Error: invalid --exclude-genes pattern: {}", err);
+                std::process::exit(1);
+            })
+        });
+        let include = args.include_genes.as_ref().map(|include_genes| {
+            GeneFilter::read_gene_list(include_genes).unwrap_or_else(|err| {
+                eprintln!("Error reading gene list: {}", err);
+                std::process::exit(1);
+            })
+        });
+        let excluded_genes = filter_genes(&mut dataset, &GeneFilter::new(exclude, include));
+        println!(
+            "Excluded {} transcripts of {} genes",
+            excluded_genes.iter().map(|g| g.count).sum::<usize>(),
+            excluded_genes.len()
+        );
+        write_excluded_genes(
+            &args.output_excluded_genes,
+            args.output_excluded_genes_fmt,
+            &excluded_genes,
+        );
+    } else if args.output_excluded_genes.is_some() {
+        println!("WARNING: --output-excluded-genes has no effect without --exclude-genes or --include-genes.");
+    }
+
+    if let Some(fraction) = args.downsample_fraction {
+        if !(fraction > 0.0 && fraction <= 1.0) {
+            eprintln!("Error: --downsample-fraction must be in (0, 1]");
+            std::process::exit(1);
+        }
+        let nremoved = downsample_transcripts(&mut dataset, fraction, args.downsample_seed);
+        println!(
+            "Downsampled to {} transcripts ({} removed)",
+            dataset.transcripts.len(),
+            nremoved
+        );
+    }
+
+    if let Some(radius) = args.dedup_radius {
+        if !(radius > 0.0) {
+            eprintln!("Error: --dedup-radius must be positive");
+            std::process::exit(1);
+        }
+        if dataset.fov_names.len() < 2 {
+            println!("WARNING: --dedup-radius has no effect without multiple FOVs (see --fov-column).");
+        } else {
+            let stats = dedup_transcripts(&mut dataset, radius, args.dedup_action);
+            println!(
+                "Found {} transcripts duplicated across FOV seams ({} removed)",
+                stats.nduplicated, stats.nremoved
+            );
+        }
+    }
+
+    if let Some(nucleus_polygons) = &args.nucleus_polygons {
+        let nuclei = PolygonIndex::from_geojson(nucleus_polygons).unwrap_or_else(|err| {
+            eprintln!("Error reading nucleus polygons: {}", err);
+            std::process::exit(1);
+        });
+        dataset.nuclear = dataset
+            .transcripts
+            .par_iter()
+            .map(|t| nuclei.contains(t.x, t.y))
+            .collect();
+        println!(
+            "Labeled {} transcripts as nuclear from {} nucleus polygons",
+            dataset.nuclear.iter().filter(|&&nuclear| nuclear).count(),
+            nuclei.len()
+        );
+    }
+
+    if (args.output_nuclear_expected_counts.is_some()
+        || args.output_cytoplasmic_expected_counts.is_some())
+        && !dataset.nuclear.iter().any(|&nuclear| nuclear)
+    {
+        println!("WARNING: No transcripts are labeled nuclear. Use --compartment-column or --nucleus-polygons to split counts by compartment.");
+    }
+
+    if let Some(nuclei_csv) = &args.nuclei_csv {
+        let mut centroids = read_nuclei_csv(
+            nuclei_csv,
+            &args.nuclei_x_column,
+            &args.nuclei_y_column,
+            args.coordinate_scale.unwrap_or(1.0),
+        )
+        .unwrap_or_else(|err| {
+            eprintln!("Error reading nuclei: {}", err);
+            std::process::exit(1);
+        });
+        // Nuclei are in the coordinates of the transcript files, so can only be
+        // transformed along with them if they share a transform.
+        if args.transform.len() > 1 {
+            eprintln!("Error: --nuclei-csv can't be used with a separate --transform for each transcript file");
+            std::process::exit(1);
+        }
+        let transform = read_transforms(&args.transform, 1)[0];
+        for (x, y) in centroids.iter_mut() {
+            (*x, *y, _) = transform.apply(*x, *y, 0.0);
+        }
+        assign_transcripts_to_nuclei(&mut dataset, &centroids, args.nuclei_radius);
+        println!(
+            "Initialized {} cells from {} nucleus centroids",
+            dataset.nucleus_population.len(),
+            centroids.len()
+        );
+    }
+
+    if let Some(init_mask) = &args.init_mask {
+        let mask = read_label_mask(init_mask).unwrap_or_else(|err| {
+            eprintln!("Error reading mask: {}", err);
+            std::process::exit(1);
+        });
+        let transform: [f32; 6] = args.init_mask_transform.clone().try_into().unwrap_or_else(|_| {
+            eprintln!("Error: --init-mask-transform must have exactly 6 values");
+            std::process::exit(1);
+        });
+        assign_transcripts_from_mask(&mut dataset, &mask, &transform);
+        println!(
+            "Initialized {} cells from mask {}",
+            dataset.nucleus_population.len(),
+            init_mask
+        );
+    }
+
+    let previous = args.previous.clone().or_else(|| {
+        args.init_from_proseg
+            .clone()
+            .map(|previous| (previous, args.init_from_proseg_fmt))
+    });
+    if let Some((previous, previous_fmt)) = &previous {
+        let previous = previous_transcript_metadata(previous, &args.output_transcript_metadata);
+        let background = BACKGROUND_CELL.to_string();
+        let segmentation = read_segmentation(
+            &previous,
+            *previous_fmt,
+            &SegmentationColumns {
+                transcript_id: "transcript_id",
+                cell: "assignment",
+                unassigned: &background,
+                gene: None,
+            },
+        )
+        .unwrap_or_else(|err| {
+            eprintln!("Error reading previous transcript metadata: {}", err);
+            std::process::exit(1);
+        });
+        let nmatched = assign_transcripts_from_previous(&mut dataset, &segmentation);
+        if nmatched == 0 {
+            eprintln!("Error: no transcript ids match those in {}", previous);
+            std::process::exit(1);
+        }
+        println!(
+            "Initialized {} cells from {} ({} of {} transcripts matched)",
+            dataset.nucleus_population.len(),
+            previous,
+            nmatched,
+            dataset.transcripts.len()
+        );
+    }
+
+    if dataset.nucleus_population.is_empty() {
+        eprintln!("Error: No transcripts are initially assigned to cells. Check --cell-id-column and --compartment-column, or use --nuclei-csv or --init-mask to initialize cells.");
+        std::process::exit(1);
+    }
+
+    // Warn if any nucleus has extremely high population, which is likely
+    // an error interpreting the file.
+    dataset.nucleus_population.iter().for_each(|&p| {
+        if p > 10000 {
+            eprintln!("Warning: nucleus with population {}", p);
+        }
+    });
+
+    /* let transcripts = &mut transcript_dataset.transcripts;
+    let transcript_names = &transcript_dataset.transcript_names;
+    let cell_assignments = &mut transcript_dataset.cell_assignments;
+    let nucleus_assignments = &mut transcript_dataset.nucleus_assignments;
+    let nucleus_population = &transcript_dataset.nucleus_population; */
+
+    // Stop sampling on Ctrl-C, but still write output. A second Ctrl-C exits
+    // immediately.
+    let interrupted = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register_conditional_shutdown(
+        signal_hook::consts::SIGINT,
+        1,
+        Arc::clone(&interrupted),
+    )
+    .unwrap();
+    signal_hook::flag::register(signal_hook::consts::SIGINT, Arc::clone(&interrupted)).unwrap();
+
+    check_memory(&mut args, &dataset);
+
+    let reading_time = run_start.elapsed().as_secs_f64();
+    if args.partition_fovs {
+        segment_fovs(&args, dataset, interrupted, &profiler, &status);
+        write_profile(&args, &profiler);
+        let timing = RunTiming {
+            started,
+            reading: reading_time,
+            sampling: run_start.elapsed().as_secs_f64() - reading_time,
+            output: 0.0,
+            total: run_start.elapsed().as_secs_f64(),
+        };
+        write_manifest(&args, None, None, timing);
+        return;
+    }
+
+    let (
+        ProsegResult {
+            params,
+            sampler,
+            uncertainty,
+            diagnostics,
+            chains,
+        },
+        priors,
+        _,
+    ) = segment(&args, &mut dataset, interrupted, &profiler, &status, true);
+    if let Some(status) = &status {
+        status.set_stage("output");
+    }
+    let output_start = Instant::now();
+    let sampling_time = run_start.elapsed().as_secs_f64() - reading_time;
+    let gene_features = gene_panel
+        .as_ref()
+        .map(|panel| panel.features(&dataset.transcript_names));
+
+    let (counts, sampled_cell_assignments) = uncertainty
+        .max_posterior_transcript_counts_assignments(
+            &params,
+            &dataset.transcripts,
+            args.count_pr_cutoff,
+            args.foreground_pr_cutoff,
+        );
+
+    // Every output is of the cells that pass QC, renumbered.
+    let qc = CellQc::new(
+        &sampler.empty_cells(),
+        &counts,
+        &params.cell_areas(),
+        args.min_transcripts_per_cell,
+        args.min_cell_area,
+    );
+    qc.report();
+    let counts = qc.select_columns(&counts);
+    let cell_assignments = qc.cell_assignments(&sampled_cell_assignments);
+    let ecounts = qc.select_columns(&uncertainty.expected_counts(&params, &dataset.transcripts));
+    let cell_centroids = qc.select(&sampler.cell_centroids());
+
+    let sample_splits = match args.split_output_by {
+        Some(SplitOutputBy::Sample) if dataset.sample_names.len() > 1 => Some(split_by_sample(
+            cell_centroids.len(),
+            &cell_assignments,
+            &dataset.fovs,
+            &dataset.fov_samples,
+            &dataset.sample_names,
+        )),
+        Some(SplitOutputBy::Sample) => {
+            println!("WARNING: --split-output-by sample has no effect with a single transcript file.");
+            None
+        }
+        None => None,
+    };
+    // outputs that are written for each sample instead, when split
+    let merged_output = |output: &Option<String>| {
+        if sample_splits.is_some() {
+            None
+        } else {
+            output.clone()
+        }
+    };
+
+    write_expected_counts(
+        &merged_output(&args.output_expected_counts),
+        args.output_expected_counts_fmt,
+        &dataset.transcript_names,
+        &ecounts,
+        &cell_centroids,
+    );
+    write_counts(
+        &merged_output(&args.output_maxpost_counts),
+        args.output_maxpost_counts_fmt,
+        &dataset.transcript_names,
+        &counts,
+        &cell_centroids,
+    );
+    for split in sample_splits.iter().flatten() {
+        let split_centroids = split.select(&cell_centroids);
+        write_expected_counts(
+            &split.output_path(&args.output_expected_counts),
+            args.output_expected_counts_fmt,
+            &dataset.transcript_names,
+            &split.select_columns(&ecounts),
+            &split_centroids,
+        );
+        write_counts(
+            &split.output_path(&args.output_maxpost_counts),
+            args.output_maxpost_counts_fmt,
+            &dataset.transcript_names,
+            &split.select_columns(&counts),
+            &split_centroids,
+        );
+    }
+    write_confidence_counts(
+        &args,
+        &dataset.transcript_names,
+        &dataset.transcripts,
+        &cell_assignments,
+        &cell_centroids,
+    );
+    if args.output_nuclear_expected_counts.is_some() {
+        write_expected_counts(
+            &args.output_nuclear_expected_counts,
+            args.output_nuclear_expected_counts_fmt,
+            &dataset.transcript_names,
+            &qc.select_columns(&uncertainty.compartment_expected_counts(
+                &params,
+                &dataset.transcripts,
+                &dataset.nuclear,
+                true,
+            )),
+            &cell_centroids,
+        );
+    }
+    if args.output_cytoplasmic_expected_counts.is_some() {
+        write_expected_counts(
+            &args.output_cytoplasmic_expected_counts,
+            args.output_cytoplasmic_expected_counts_fmt,
+            &dataset.transcript_names,
+            &qc.select_columns(&uncertainty.compartment_expected_counts(
+                &params,
+                &dataset.transcripts,
+                &dataset.nuclear,
+                false,
+            )),
+            &cell_centroids,
+        );
+    }
+    write_rates(
+        &args.output_rates,
+        args.output_rates_fmt,
+        &qc.select_columns(&params.λ),
+        &dataset.transcript_names,
+    );
+    write_component_params(
+        &args.output_component_params,
+        args.output_component_params_fmt,
+        &params,
+        &dataset.transcript_names,
+    );
+    write_component_metadata(
+        &args.output_component_metadata,
+        args.output_component_metadata_fmt,
+        &params,
+    );
+    write_component_probabilities(
+        &args.output_component_probabilities,
+        args.output_component_probabilities_fmt,
+        &qc.select_columns(&uncertainty.component_probabilities(&params)),
+        params
+            .expression_prior
+            .as_ref()
+            .map(|expression_prior| expression_prior.cell_types.as_slice()),
+    );
+    let cell_metadata = cell_metadata_table(
+        &qc.select_array(&params.z),
+        &qc.select_array(&params.cell_volume),
+        &qc.select(&params.cell_population),
+        &cell_centroids,
+        &qc.select(&sampler.cell_shapes()),
+        &cell_assignments,
+        &dataset.fovs,
+        &dataset.fov_names,
+        &dataset.fov_samples,
+        &dataset.sample_names,
+        &dataset.original_cell_ids,
+        &dataset.original_cell_assignments,
+        params
+            .expression_prior
+            .as_ref()
+            .map(|expression_prior| expression_prior.cell_types.as_slice()),
+        args.cell_id_scheme,
+        args.cell_id_resolution,
+    );
+    let mut negative_control_summary = None;
+    let cell_metadata = match &negative_controls {
+        Some(controls) => {
+            let control_cells: Vec<u32> = controls
+                .positions
+                .iter()
+                .map(|&position| qc.renumber(sampler.cell_at_position(position)))
+                .collect();
+            let cell_counts = ecounts.sum_axis(Axis(0)).to_vec();
+            let ngenes = dataset.transcript_names.len();
+            let (control_counts, false_discovery_rates) =
+                controls.cell_false_discovery_rates(&control_cells, &cell_counts, ngenes);
+            let summary = controls.summary(&control_cells, &cell_counts, ngenes);
+            println!(
+                "Negative controls: {} of {} transcripts in cells, estimated false discovery rate {:.2}%",
+                summary.assigned_control_count,
+                summary.control_count,
+                100.0 * summary.false_discovery_rate
+            );
+            negative_control_summary = Some(summary);
+            add_negative_control_columns(&cell_metadata, &control_counts, &false_discovery_rates)
+        }
+        None => cell_metadata,
+    };
+    write_cell_metadata(
+        &merged_output(&args.output_cell_metadata),
+        args.output_cell_metadata_fmt,
+        &cell_metadata,
+    );
+    for split in sample_splits.iter().flatten() {
+        write_cell_metadata(
+            &split.output_path(&args.output_cell_metadata),
+            args.output_cell_metadata_fmt,
+            &split.select_rows(&cell_metadata),
+        );
+    }
+    write_cell_id_map(
+        &args.output_cell_id_map,
+        args.output_cell_id_map_fmt,
+        &cell_assignments,
+        &dataset.original_cell_ids,
+        &dataset.original_cell_assignments,
+    );
+    if args.output_cell_graph.is_some() {
+        write_cell_graph(
+            &args.output_cell_graph,
+            args.output_cell_graph_fmt,
+            &sampler.cell_adjacency(),
+            &qc,
+        );
+    }
+    if args.output_transcript_metadata.is_some() {
+        let noise_probabilities = params.noise_probabilities(
+            &dataset.transcripts,
+            &qc.unassign_removed(&sampled_cell_assignments),
+        );
+        let transcript_flags = qc.transcript_flags(&sampled_cell_assignments);
+        write_transcript_metadata(
+            &merged_output(&args.output_transcript_metadata),
+            args.output_transcript_metadata_fmt,
+            &dataset.transcripts,
+            &params.transcript_positions,
+            &dataset.transcript_names,
+            &cell_assignments,
+            &params.transcript_state,
+            &noise_probabilities,
+            &dataset.qvs,
+            &dataset.fovs,
+            &dataset.fov_names,
+            &dataset.fov_samples,
+            &dataset.sample_names,
+            &dataset.nuclear,
+            &transcript_flags,
+        );
+        for split in sample_splits.iter().flatten() {
+            write_transcript_metadata(
+                &split.output_path(&args.output_transcript_metadata),
+                args.output_transcript_metadata_fmt,
+                &split.select_transcripts(&dataset.transcripts),
+                &split.select_transcripts(&params.transcript_positions),
+                &dataset.transcript_names,
+                &split.cell_assignments(&cell_assignments),
+                &split.select_transcripts_array(&params.transcript_state),
+                &split.select_transcripts(&noise_probabilities),
+                &split.select_transcripts(&dataset.qvs),
+                &split.select_transcripts(&dataset.fovs),
+                &dataset.fov_names,
+                &dataset.fov_samples,
+                &dataset.sample_names,
+                &split.select_transcripts(&dataset.nuclear),
+                &split.select_transcripts(&transcript_flags),
+            );
+        }
+    }
+    write_transcript_positions(
+        &args.output_transcript_positions,
+        args.output_transcript_positions_fmt,
+        &dataset.transcripts,
+        &uncertainty.mean_transcript_positions(&dataset.transcripts),
+        &dataset.transcript_names,
+        &cell_assignments,
+    );
+    if args.output_transcript_posterior.is_some() {
+        write_transcript_posterior(
+            &args.output_transcript_posterior,
+            args.output_transcript_posterior_fmt,
+            &dataset.transcripts,
+            &qc.posterior(&uncertainty.posterior_cell_assignments()),
+        );
+    }
+    if let Some(chains) = &chains {
+        write_chain_agreement(
+            &args.output_chain_agreement,
+            args.output_chain_agreement_fmt,
+            &dataset.transcripts,
+            &cell_assignments,
+            &chains.agreement,
+        );
+    } else if args.output_chain_agreement.is_some() {
+        println!("WARNING: --output-chain-agreement has no effect with a single chain.");
+    }
+    write_diagnostics(
+        &args.output_diagnostics,
+        args.output_diagnostics_fmt,
+        &diagnostics,
+    );
+    write_gene_metadata(
+        &args.output_gene_metadata,
+        args.output_gene_metadata_fmt,
+        &params,
+        &dataset.transcript_names,
+        gene_features.as_ref(),
+        &ecounts,
+    );
+    write_noise_report(
+        &args.output_noise_report,
+        args.output_noise_report_fmt,
+        &params,
+        &dataset.transcript_names,
+    );
+    write_ambient_profile(
+        &args.output_ambient_profile,
+        args.output_ambient_profile_fmt,
+        &params,
+        &dataset.transcript_names,
+    );
+    if args.output_corrected_counts.is_some() {
+        write_expected_counts(
+            &args.output_corrected_counts,
+            args.output_corrected_counts_fmt,
+            &dataset.transcript_names,
+            &ambient_corrected_counts(&params, &ecounts, &qc.select_array(&params.cell_volume)),
+            &cell_centroids,
+        );
+    }
+    write_background_map(
+        &args.output_background_map,
+        args.output_background_map_fmt,
+        &params,
+    );
+    write_anndata(
+        &args.output_anndata,
+        &params,
+        &dataset.transcript_names,
+        gene_features.as_ref(),
+        &ecounts,
+        &cell_centroids,
+        &qc,
+    );
+    write_voxels(
+        &args.output_cell_voxels,
+        args.output_cell_voxels_fmt,
+        &sampler,
+        Some(&qc),
+    );
+    write_hexes(&args.output_hexes, args.output_hexes_fmt, &sampler, Some(&qc));
+
+    let inverse_transform = polygon_inverse_transform(
+        &args.transform,
+        &dataset.transcripts,
+        &dataset.fov_samples,
+        dataset.sample_names.len(),
+        cell_centroids.len(),
+        cell_assignments.iter().map(|(cell, _)| *cell),
+    );
+
+    if args.output_cell_polygon_layers.is_some() || args.output_union_cell_polygons.is_some() {
+        let (cell_polygons, cell_flattened_polygons) = sampler.cell_polygons();
+        let mut cell_polygons = qc.select(&cell_polygons);
+        let mut cell_flattened_polygons = qc.select(&cell_flattened_polygons);
+        if let Some(inverse_transform) = &inverse_transform {
+            cell_polygons = inverse_transform.apply_layered(cell_polygons);
+            cell_flattened_polygons = inverse_transform.apply(cell_flattened_polygons);
+        }
+        write_cell_multipolygons(&args.output_union_cell_polygons, cell_flattened_polygons);
+        write_cell_layered_multipolygons(&args.output_cell_polygon_layers, cell_polygons);
+    }
+
+    if args.output_cell_polygons.is_some()
+        || args.output_spatialdata.is_some()
+        || args.output_transcript_diffusion.is_some()
+        || args.output_gene_diffusion.is_some()
+    {
+        let consensus_cell_polygons = smooth_cell_polygons(
+            &qc.select(&sampler.consensus_cell_polygons()),
+            &polygon_smoothing_params(&args),
+        );
+        if args.output_transcript_diffusion.is_some() || args.output_gene_diffusion.is_some() {
+            let diffusion = transcript_diffusion(
+                &dataset.transcripts,
+                &uncertainty.mean_transcript_positions(&dataset.transcripts),
+                &cell_assignments,
+                &consensus_cell_polygons,
+            );
+            write_transcript_diffusion(
+                &args.output_transcript_diffusion,
+                args.output_transcript_diffusion_fmt,
+                &dataset.transcripts,
+                &dataset.transcript_names,
+                &cell_assignments,
+                &diffusion,
+            );
+            write_gene_diffusion(
+                &args.output_gene_diffusion,
+                args.output_gene_diffusion_fmt,
+                &dataset.transcripts,
+                &dataset.transcript_names,
+                &cell_assignments,
+                &diffusion,
+            );
+        }
+        write_spatialdata(
+            &args.output_spatialdata,
+            &params,
+            &dataset.transcripts,
+            &dataset.transcript_names,
+            gene_features.as_ref(),
+            &cell_assignments,
+            &ecounts,
+            &cell_centroids,
+            &consensus_cell_polygons,
+            &qc,
+        );
+        let consensus_cell_polygons = match &inverse_transform {
+            Some(inverse_transform) => inverse_transform.apply(consensus_cell_polygons),
+            None => consensus_cell_polygons,
+        };
+        for split in sample_splits.iter().flatten() {
+            write_cell_polygons(
+                &split.output_path(&args.output_cell_polygons),
+                args.output_cell_polygons_fmt,
+                split.select(&consensus_cell_polygons),
+                Some(&split.select_rows(&cell_metadata)),
+            );
+        }
+        write_cell_polygons(
+            &merged_output(&args.output_cell_polygons),
+            args.output_cell_polygons_fmt,
+            consensus_cell_polygons,
+            Some(&cell_metadata),
+        );
+    }
+
+    if let Some(output_cell_hulls) = &args.output_cell_hulls {
+        match &inverse_transform {
+            Some(inverse_transform) => params.write_cell_hulls(
+                &inverse_transform.inverse_transcripts(&dataset.transcripts, &dataset.fov_samples),
+                &counts,
+                output_cell_hulls,
+                Some(&qc),
+            ),
+            None => params.write_cell_hulls(
+                &dataset.transcripts,
+                &counts,
+                output_cell_hulls,
+                Some(&qc),
+            ),
+        }
+    }
+
+    if let Some(profiler) = &profiler {
+        profiler.record("output", output_start.elapsed());
+    }
+    write_profile(&args, &profiler);
+    let timing = RunTiming {
+        started,
+        reading: reading_time,
+        sampling: sampling_time,
+        output: output_start.elapsed().as_secs_f64(),
+        total: run_start.elapsed().as_secs_f64(),
+    };
+    write_manifest(&args, Some(&priors), negative_control_summary.as_ref(), timing);
+}
+
+// Transform of each transcript file, from --transform, which is either given
+// once for all files or once for each.
+fn read_transforms(transform_paths: &[String], nfiles: usize) -> Vec<AffineTransform> {
+    let transforms = transform_paths
+        .iter()
+        .map(|path| {
+            AffineTransform::read(path).unwrap_or_else(|err| {
+                eprintln!("Error reading transform: {}", err);
+                std::process::exit(1);
+            })
+        })
+        .collect::<Vec<_>>();
+    match transforms.len() {
+        0 => vec![AffineTransform::identity(); nfiles],
+        1 => vec![transforms[0]; nfiles],
+        n if n == nfiles => transforms,
+        n => {
+            eprintln!(
+                "Error: {} transforms were given for {} transcript files. Give --transform once, or once for each file.",
+                n, nfiles
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+// With --transform, maps cell polygons back to the input coordinates, given
+// the cell each transcript is assigned to.
+fn polygon_inverse_transform(
+    transform_paths: &[String],
+    transcripts: &[Transcript],
+    fov_samples: &[u32],
+    nsamples: usize,
+    ncells: usize,
+    cells: impl Iterator<Item = CellIndex>,
+) -> Option<PolygonInverseTransform> {
+    if transform_paths.is_empty() {
+        return None;
+    }
+    Some(PolygonInverseTransform::new(
+        &read_transforms(transform_paths, nsamples),
+        ncells,
+        transcripts,
+        cells,
+        fov_samples,
+    ))
+}
+
+// Expand any glob patterns among the transcript files given on the command
+// line, keeping the order they were given in.
+fn expand_transcript_paths(patterns: &[String]) -> Vec<String> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        if !pattern.contains(['*', '?', '[']) {
+            paths.push(pattern.clone());
+            continue;
+        }
+        let matches = glob::glob(pattern).unwrap_or_else(|err| {
+            eprintln!("Error: invalid transcript file pattern '{}': {}", pattern, err);
+            std::process::exit(1);
+        });
+        let mut matched = matches
+            .filter_map(|path| path.ok())
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        if matched.is_empty() {
+            eprintln!("Error: no transcript files match '{}'", pattern);
+            std::process::exit(1);
+        }
+        matched.sort();
+        paths.extend(matched);
+    }
+
+    let mut seen = HashSet::new();
+    for path in &paths {
+        if !seen.insert(path) {
+            eprintln!("Error: transcript file '{}' was given more than once", path);
+            std::process::exit(1);
+        }
+    }
+    paths
+}
+
+// Name samples by their file names, without directory or extensions (e.g.
+// "section1" for "data/section1.csv.gz"), falling back to the full paths if
+// that would make two names the same.
+fn sample_names(paths: &[String]) -> Vec<String> {
+    let names = paths
+        .iter()
+        .map(|path| {
+            let file_name = std::path::Path::new(path)
+                .file_name()
+                .map_or(path.clone(), |name| name.to_string_lossy().into_owned());
+            match file_name.split_once('.') {
+                Some((stem, _)) if !stem.is_empty() => stem.to_string(),
+                _ => file_name,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if names.iter().collect::<HashSet<_>>().len() == names.len() {
+        names
+    } else {
+        paths.to_vec()
     }
+}
 
-    if args.recorded_samples > *args.schedule.last().unwrap() {
-        panic!("recorded-samples must be <= the last entry in the schedule");
+fn write_profile(args: &Args, profiler: &Option<Arc<Profiler>>) {
+    if let (Some(filename), Some(profiler)) = (&args.profile, profiler) {
+        profiler.write(filename, current_num_threads());
+        println!("Wrote profile to {}", filename);
     }
+}
 
-    if args.use_cell_initialization {
-        args.compartment_column = None;
-        args.compartment_nuclear = None;
+fn write_manifest(
+    args: &Args,
+    priors: Option<&ModelPriors>,
+    negative_controls: Option<&NegativeControlSummary>,
+    timing: RunTiming,
+) {
+    let Some(filename) = &args.output_run_manifest else {
+        return;
+    };
+
+    let mut input_paths = expand_transcript_paths(&args.transcript_csv);
+    input_paths.extend(
+        [
+            &args.config,
+            &args.nuclei_csv,
+            &args.init_mask,
+            &args.init_from_proseg,
+            &args.boundary_image,
+            &args.roi_geojson,
+            &args.gene_panel,
+            &args.include_genes,
+            &args.nucleus_polygons,
+            &args.expression_prior,
+            &args.resume,
+        ]
+        .into_iter()
+        .flatten()
+        .cloned(),
+    );
+    input_paths.extend(args.transform.iter().cloned());
+    if let Some((previous, _)) = &args.previous {
+        input_paths.push(previous.clone());
     }
 
-    assert!(args.ncomponents > 0);
+    let inputs = input_paths
+        .iter()
+        .map(|path| InputFile::read(path))
+        .collect::<proseg::error::Result<Vec<_>>>()
+        .unwrap_or_else(|err| {
+            eprintln!("Error writing run manifest: {}", err);
+            std::process::exit(1);
+        });
+
+    let manifest = RunManifest {
+        proseg_version: env!("CARGO_PKG_VERSION"),
+        command_line: std::env::args().collect(),
+        arguments: args,
+        seed: args.seed,
+        schedule: &args.schedule,
+        priors,
+        negative_controls,
+        inputs,
+        timing,
+    };
+    write_run_manifest(filename, &manifest).unwrap_or_else(|err| {
+        eprintln!("Error writing run manifest: {}", err);
+        std::process::exit(1);
+    });
+    println!("Wrote run manifest to {}", filename);
+}
 
-    fn expect_arg<T>(arg: Option<T>, argname: &str) -> T {
-        arg.unwrap_or_else(|| panic!("Missing required argument: --{}", argname))
-    }
+fn expect_arg<T>(arg: Option<T>, argname: &str) -> T {
+    arg.unwrap_or_else(|| {
+        eprintln!("Error: missing required argument: --{}", argname);
+        std::process::exit(1);
+    })
+}
 
-    /* let (transcript_names,
-    mut transcripts,
-    mut nucleus_assignments,
-    mut cell_assignments,
-    mut nucleus_population) = */
+// Read and concatenate the transcript files given to `proseg run`, or load a
+// single .pbin file written by `proseg convert`, which was already read with
+// the column, filtering, and transform options given then.
 
-    let mut dataset = read_transcripts_csv(
-        &args.transcript_csv,
-        &expect_arg(args.gene_column, "transcript-column"),
-        args.transcript_id_column,
-        args.compartment_column,
-        args.compartment_nuclear,
-        args.fov_column,
-        args.cell_assignment_column,
-        args.cell_assignment_unassigned,
-        &expect_arg(args.cell_id_column, "cell-id-column"),
-        &expect_arg(args.cell_id_unassigned, "cell-id-unassigned"),
-        args.qv_column,
-        &expect_arg(args.x_column, "x-column"),
-        &expect_arg(args.y_column, "y-column"),
-        &expect_arg(args.z_column, "z-column"),
-        args.min_qv,
-        args.ignore_z_coord,
-        args.coordinate_scale.unwrap_or(1.0),
-    );
+fn read_transcripts(args: &Args, roi: Option<&Roi>, filter: Option<&RowFilter>) -> TranscriptDataset {
+    let transcript_paths = expand_transcript_paths(&args.transcript_csv);
+    if transcript_paths.iter().any(|path| is_pbin_filename(path)) {
+        if transcript_paths.len() > 1 {
+            eprintln!("Error: a .pbin transcript file can't be combined with other transcript files");
+            std::process::exit(1);
+        }
+        return read_transcripts_pbin(&transcript_paths[0]).unwrap_or_else(|err| {
+            eprintln!("Error reading transcripts: {}", err);
+            std::process::exit(1);
+        });
+    }
 
-    // Warn if any nucleus has extremely high population, which is likely
-    // an error interpreting the file.
-    dataset.nucleus_population.iter().for_each(|&p| {
-        if p > 10000 {
-            eprintln!("Warning: nucleus with population {}", p);
+    let transforms = read_transforms(&args.transform, transcript_paths.len());
+    let datasets = transcript_paths
+        .iter()
+        .zip(&transforms)
+        .map(|(path, transform)| {
+            let mut dataset = read_transcripts_csv(
+                path,
+                args.format,
+                args.delimiter,
+                &expect_arg(args.gene_column.clone(), "transcript-column"),
+                args.transcript_id_column.clone(),
+                args.compartment_column.clone(),
+                args.compartment_nuclear.clone(),
+                args.fov_column.clone(),
+                args.cell_assignment_column.clone(),
+                args.cell_assignment_unassigned.clone(),
+                args.cell_id_column.clone(),
+                args.cell_id_unassigned.clone(),
+                args.qv_column.clone(),
+                args.prior_assignment_column.clone(),
+                &expect_arg(args.x_column.clone(), "x-column"),
+                &expect_arg(args.y_column.clone(), "y-column"),
+                &expect_arg(args.z_column.clone(), "z-column"),
+                args.min_qv,
+                args.ignore_z_coord,
+                args.coordinate_scale.unwrap_or(1.0),
+                roi,
+                filter,
+            )
+            .unwrap_or_else(|err| {
+                eprintln!("Error reading transcripts: {}", err);
+                std::process::exit(1);
+            });
+            transform.apply_to_transcripts(&mut dataset.transcripts);
+            dataset
+        })
+        .collect::<Vec<_>>();
+    if datasets.len() > 1 {
+        println!("Concatenating transcripts from {} files", datasets.len());
+    }
+    let mut dataset = concatenate_datasets(datasets);
+    dataset.sample_names = sample_names(&transcript_paths);
+    dataset
+}
+
+// The region given by --roi or --roi-geojson, if any.
+fn read_roi(args: &Args) -> Option<Roi> {
+    if let Some(bounds) = &args.roi {
+        if bounds.len() != 4 {
+            eprintln!("Error: --roi must have exactly 4 values: xmin,ymin,xmax,ymax");
+            std::process::exit(1);
         }
-    });
+        Some(Roi::rect(bounds[0], bounds[1], bounds[2], bounds[3]))
+    } else {
+        args.roi_geojson.as_ref().map(|roi_geojson| {
+            Roi::from_geojson(roi_geojson).unwrap_or_else(|err| {
+                eprintln!("Error reading ROI: {}", err);
+                std::process::exit(1);
+            })
+        })
+    }
+}
 
-    /* let transcripts = &mut transcript_dataset.transcripts;
-    let transcript_names = &transcript_dataset.transcript_names;
-    let cell_assignments = &mut transcript_dataset.cell_assignments;
-    let nucleus_assignments = &mut transcript_dataset.nucleus_assignments;
-    let nucleus_population = &transcript_dataset.nucleus_population; */
+// Transcript metadata of the run being refined or initialized from, which may
+// be given as the directory it was written to, where it's looked for under the name of
+// --output-transcript-metadata.
+fn previous_transcript_metadata(previous: &str, output_transcript_metadata: &Option<String>) -> String {
+    let path = std::path::Path::new(previous);
+    if !path.is_dir() {
+        return previous.to_string();
+    }
+    let filename = output_transcript_metadata
+        .as_deref()
+        .unwrap_or("transcript-metadata.csv.gz");
+    path.join(filename).to_string_lossy().into_owned()
+}
 
+// Estimate priors from the dataset and run the sampler on it, returning the
+// result along with the priors used, and the indices of the transcripts that
+// were kept, those far from any cell being dropped.
+fn segment(
+    args: &Args,
+    dataset: &mut TranscriptDataset,
+    interrupted: Arc<AtomicBool>,
+    profiler: &Option<Arc<Profiler>>,
+    status: &Option<Arc<RunStatus>>,
+    progress: bool,
+) -> (ProsegResult, ModelPriors, Vec<usize>) {
     // Clamp transcript depth
     // This is we get some reasonable depth slices when we step up to
     // 3d sampling.
@@ -525,15 +3108,17 @@ fn main() {
         t.z = t.z.max(zmin).min(zmax);
     }
 
+    let initial_voxel_size = args.initial_voxel_size.unwrap_or(4.0);
+
     let mut ncells = dataset.nucleus_population.len();
-    filter_cellfree_transcripts(&mut dataset, ncells, args.max_transcript_nucleus_distance);
+    let kept = filter_cellfree_transcripts(dataset, ncells, args.max_transcript_nucleus_distance);
 
     // keep removing cells until we can initialize with every cell having at least one voxel
     loop {
         let prev_ncells = ncells;
 
         filter_sparse_cells(
-            args.initial_voxel_size,
+            initial_voxel_size,
             args.voxel_layers,
             &dataset.transcripts,
             &mut dataset.nucleus_assignments,
@@ -552,9 +3137,23 @@ fn main() {
 
     let nucleus_areas =
         compute_cell_areas(ncells, &dataset.transcripts, &dataset.nucleus_assignments);
-    let mean_nucleus_area = nucleus_areas.iter().sum::<f32>()
-        / nucleus_areas.iter().filter(|a| **a > 0.0).count() as f32;
+    let mean_nucleus_area = match args.expected_cell_diameter {
+        // taking the nucleus to be half the area of a circular cell
+        Some(diameter) => {
+            let mean_nucleus_area = 0.5 * std::f32::consts::PI * (0.5 * diameter).powi(2);
+            println!(
+                "Using expected cell diameter {} (mean nucleus area {})",
+                diameter, mean_nucleus_area
+            );
+            mean_nucleus_area
+        }
+        None => {
+            nucleus_areas.iter().sum::<f32>()
+                / nucleus_areas.iter().filter(|a| **a > 0.0).count() as f32
+        }
+    };
 
+    let mut nbglayers = args.nbglayers;
     if args.detect_layers {
         const MAX_ZLAYERS: usize = 30;
         let mut undetectable = false;
@@ -569,12 +3168,12 @@ fn main() {
         }
 
         if !undetectable && zlayers.len() <= MAX_ZLAYERS {
-            args.nbglayers = zlayers.len();
-            println!("Detected {} z-layers", args.nbglayers);
+            nbglayers = zlayers.len();
+            println!("Detected {} z-layers", nbglayers);
         }
     }
 
-    let mut layer_depth = 1.01 * (zmax - zmin) / (args.nbglayers as f32);
+    let mut layer_depth = 1.01 * (zmax - zmin) / (nbglayers as f32);
     if layer_depth == 0.0 {
         layer_depth = 1.0;
     }
@@ -583,38 +3182,25 @@ fn main() {
     println!("     {} cells", ncells);
     println!("     {} genes", ngenes);
 
-    let (xmin, xmax, ymin, ymax, zmin, zmax) = coordinate_span(&dataset.transcripts);
-    let (xspan, yspan, mut zspan) = (xmax - xmin, ymax - ymin, zmax - zmin);
+    let (_xmin, _xmax, _ymin, _ymax, zmin, zmax) = coordinate_span(&dataset.transcripts);
+    let mut zspan = zmax - zmin;
     if zspan == 0.0 {
         zspan = 1.0;
     }
 
-    let full_area = estimate_full_area(&dataset.transcripts, mean_nucleus_area);
+    let tissue_mask = TissueMask::estimate(&dataset.transcripts, mean_nucleus_area);
+    let full_area = tissue_mask.area();
     println!("Estimated full area: {}", full_area);
+    // With --partition-fovs, each FOV's mask would overwrite the last.
+    if !args.partition_fovs {
+        write_tissue_mask(&args.output_tissue_mask, &tissue_mask.polygons());
+    }
     let full_volume = full_area * zspan;
 
-    let full_layer_volume = full_volume / (args.nbglayers as f32);
+    let full_layer_volume = full_volume / (nbglayers as f32);
     println!("Full volume: {}", full_volume);
 
-    // Find a reasonable grid size to use to chunk the data
-    let area = (xmax - xmin) * (ymax - ymin);
-
-    let cell_density = ncells as f32 / area;
-    let chunk_size = (args.cells_per_chunk as f32 / cell_density).sqrt();
-
-    let nchunks = |chunk_size: f32, xspan: f32, yspan: f32| {
-        ((xspan / chunk_size).ceil() as usize) * ((yspan / chunk_size).ceil() as usize)
-    };
-
-    println!(
-        "Using grid size {}. Chunks: {}",
-        chunk_size,
-        nchunks(chunk_size, xspan, yspan)
-    );
-
-    let min_cell_volume = 1e-6 * mean_nucleus_area * zspan;
-
-    let priors = ModelPriors {
+    let mut priors = ModelPriors {
         dispersion: args.dispersion,
         burnin_dispersion: if args.variable_burnin_dispersion {
             None
@@ -622,28 +3208,21 @@ fn main() {
             Some(args.burnin_dispersion)
         },
 
-        min_cell_volume,
-
-        μ_μ_volume: (2.0 * mean_nucleus_area * zspan).ln(),
-        σ_μ_volume: 3.0_f32,
-        α_σ_volume: 0.1,
-        β_σ_volume: 0.1,
+        σ_μ_volume: args.cell_volume_prior_sigma,
+        α_σ_volume: args.cell_volume_variance_prior_shape,
+        β_σ_volume: args.cell_volume_variance_prior_scale,
 
-        e_r: 1.0,
+        e_r: args.dispersion_prior_shape,
 
-        e_h: 1.0,
-        f_h: 1.0,
+        e_h: args.dispersion_rate_prior_shape,
+        f_h: args.dispersion_rate_prior_rate,
 
-        γ: 1.0,
+        α_bg: args.background_rate_prior_shape,
+        β_bg: args.background_rate_prior_rate,
 
-        α_bg: 1.0,
-        β_bg: 1.0,
-
-        α_c: 1.0,
-        β_c: 1.0,
-
-        perimeter_eta: 5.3,
+        perimeter_eta: args.perimeter_eta,
         perimeter_bound: args.perimeter_bound,
+        perimeter_penalty: args.perimeter_penalty,
 
         nuclear_reassignment_log_prob: args.nuclear_reassignment_prob.ln(),
         nuclear_reassignment_1mlog_prob: (1.0 - args.nuclear_reassignment_prob).ln(),
@@ -657,305 +3236,1052 @@ fn main() {
         σ_diffusion_near: args.diffusion_sigma_near,
         σ_diffusion_far: args.diffusion_sigma_far,
 
-        σ_z_diffusion_proposal: 0.2 * zspan,
-        σ_z_diffusion: 0.2 * zspan,
-
-        zmin,
-        zmax,
+        use_gene_z_offsets: args.gene_z_offsets,
 
         enforce_connectivity: args.enforce_connectivity,
+        max_cell_radius: args.max_cell_radius,
+
+        dp_concentration: match args.ncomponents {
+            NComponents::Fixed(_) => None,
+            NComponents::Auto => Some(args.dp_concentration),
+        },
+
+        background_grid_size: args.background_grid_size,
+        α_bg_scale: args.background_grid_prior,
+
+        z_scale: args.z_scale,
+
+        component_smoothness: Some(args.component_smoothness).filter(|&smoothness| smoothness > 0.0),
+        neighborhood_method: args.neighborhood_method,
+
+        ..ModelPriors::new(mean_nucleus_area, zmin, zmax)
     };
+    if let Some(min_cell_volume) = args.min_cell_volume {
+        priors.min_cell_volume = min_cell_volume;
+    }
+    if let Some(cell_volume_prior_mean) = args.cell_volume_prior_mean {
+        priors.μ_μ_volume = cell_volume_prior_mean.ln();
+    }
+    if let Some(length) = args.component_smoothness_length {
+        priors.component_smoothness_length = length;
+    }
 
-    let mut params = ModelParams::new(
-        &priors,
-        full_layer_volume,
-        zmin,
-        layer_depth,
-        &dataset.transcripts,
-        &dataset.nucleus_assignments,
-        &dataset.nucleus_population,
-        &dataset.cell_assignments,
-        args.ncomponents,
-        args.nbglayers,
-        ncells,
-        ngenes,
-    );
+    let ncomponents = match args.ncomponents {
+        NComponents::Fixed(ncomponents) => ncomponents,
+        NComponents::Auto => args.max_components,
+    };
 
-    let total_iterations = args.schedule.iter().sum::<usize>();
-    let mut prog = ProgressBar::new(total_iterations as u64);
-    prog.set_style(
-        ProgressStyle::with_template("{eta_precise} {bar:60} | {msg}")
-            .unwrap()
-            .progress_chars("##-"),
-    );
+    let boundary = args.boundary_image.as_ref().map(|boundary_image| {
+        let transform: [f32; 6] = args.boundary_image_transform.clone().try_into().unwrap_or_else(|_| {
+            eprintln!("Error: --boundary-image-transform must have exactly 6 values");
+            std::process::exit(1);
+        });
+        read_boundary_prior(boundary_image, transform, args.boundary_weight).unwrap_or_else(|err| {
+            eprintln!("Error reading boundary image: {}", err);
+            std::process::exit(1);
+        })
+    });
 
-    let mut uncertainty = UncertaintyTracker::new();
+    let expression_prior = args.expression_prior.as_ref().map(|expression_prior| {
+        read_expression_prior(
+            expression_prior,
+            &dataset.transcript_names,
+            args.expression_prior_strength,
+        )
+        .unwrap_or_else(|err| {
+            eprintln!("Error reading expression prior: {}", err);
+            std::process::exit(1);
+        })
+    });
 
-    let mut sampler = RefCell::new(VoxelSampler::new(
-        &priors,
-        &mut params,
-        &dataset.transcripts,
-        ngenes,
-        args.voxel_layers,
-        args.nbglayers,
-        zmin,
-        layer_depth,
-        args.initial_voxel_size,
-        chunk_size,
-    ));
-    sampler.borrow_mut().initialize(&priors, &mut params);
-
-    let mut total_steps = 0;
-
-    if args.schedule.len() > 1 {
-        run_hexbin_sampler(
-            &mut prog,
-            sampler.get_mut(),
-            &priors,
-            &mut params,
+    let mut proseg = Proseg::new(dataset, priors, full_layer_volume, layer_depth)
+        .ncomponents(ncomponents)
+        .expression_prior(expression_prior)
+        .nbglayers(nbglayers)
+        .voxel_layers(args.voxel_layers)
+        .initial_voxel_size(initial_voxel_size)
+        .cells_per_chunk(args.cells_per_chunk)
+        .density_chunks(args.density_chunks)
+        .adaptive_chunks(args.adaptive_chunks)
+        .chunk_shift_interval(args.chunk_shift_interval)
+        .boundary_prior(boundary)
+        .prior_source_confidence(args.prior_confidence.clone())
+        .editable_region(read_roi(args).filter(|_| args.previous.is_some()))
+        .schedule(args.schedule.clone())
+        .temperature_schedule(args.temperature_schedule.clone())
+        .recorded_samples(args.recorded_samples)
+        .morphology_steps_per_iter(args.morphology_steps_per_iter)
+        .split_merge_moves(args.split_merge_moves)
+        .birth_death_moves(args.birth_death_moves)
+        .ncells_ratio_bounds(args.min_cells_ratio, args.max_new_cells_ratio)
+        .fill_holes(args.fill_holes)
+        .double_z_layers(args.double_z_layers)
+        .check_consistency(args.check_consistency)
+        .monitor_cell_polygons(args.monitor_cell_polygons.clone(), args.monitor_cell_polygons_freq)
+        .checkpoint(args.checkpoint.clone(), args.checkpoint_interval)
+        .resume(args.resume.clone())
+        .convergence(args.convergence_eps, args.convergence_window)
+        .nchains(args.nchains)
+        .spill_chains(args.spill_chains)
+        .seed(args.seed)
+        .profiler(profiler.clone())
+        .status(status.clone())
+        .progress(progress)
+        .interrupt(interrupted);
+
+    // Intermediate output from one section would be overwritten by the next.
+    if let Some(interval) = args.output_interval.filter(|_| !args.partition_fovs) {
+        proseg = proseg.intermediate_output(interval, intermediate_output(args));
+    }
+    if args.render_snapshots.is_some() && !args.partition_fovs {
+        proseg = proseg.intermediate_output(args.render_interval, snapshot_output(args, dataset));
+    }
+    if let Some(output_hexes) = args
+        .output_hexes
+        .clone()
+        .filter(|_| args.output_hexes_phases && !args.partition_fovs)
+    {
+        let output_hexes_fmt = args.output_hexes_fmt;
+        proseg = proseg.phase_output(Box::new(move |phase, sampler| {
+            write_hexes(
+                &Some(phase_filename(&output_hexes, phase + 1)),
+                output_hexes_fmt,
+                sampler,
+                None,
+            );
+        }));
+    }
+
+    let result = if args.deterministic {
+        proseg.run_deterministic()
+    } else {
+        proseg.run()
+    };
+    let result = result.unwrap_or_else(|err| {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    });
+
+    if args.ncomponents == NComponents::Auto {
+        let noccupied = result
+            .params
+            .component_population
+            .iter()
+            .filter(|&&population| population > 0)
+            .count();
+        println!("Occupied components: {} of {}", noccupied, ncomponents);
+    }
+
+    (result, priors, kept)
+}
+
+// Render the sampler's current state to numbered PNG files during a run.
+fn snapshot_output(args: &Args, dataset: &TranscriptDataset) -> IntermediateOutput<'static> {
+    let dir = args.render_snapshots.clone().unwrap();
+    std::fs::create_dir_all(&dir).unwrap_or_else(|err| {
+        eprintln!("Error creating snapshot directory '{}': {}", dir, err);
+        std::process::exit(1);
+    });
+    let bounds = match &args.render_roi {
+        Some(roi) if roi.len() != 4 => {
+            eprintln!("Error: --render-roi must have exactly 4 values: xmin,ymin,xmax,ymax");
+            std::process::exit(1);
+        }
+        Some(roi) => (roi[0], roi[1], roi[2], roi[3]),
+        None => {
+            let (xmin, xmax, ymin, ymax, _, _) = coordinate_span(&dataset.transcripts);
+            (xmin, ymin, xmax, ymax)
+        }
+    };
+    if !(bounds.2 > bounds.0 && bounds.3 > bounds.1) || args.render_width == 0 {
+        eprintln!("Error: snapshots must have a non-empty --render-roi and --render-width");
+        std::process::exit(1);
+    }
+    let width = args.render_width;
+    let color = args.render_color;
+    let nsnapshots = AtomicUsize::new(0);
+
+    Box::new(move |_dataset, params, sampler| {
+        let n = nsnapshots.fetch_add(1, Ordering::Relaxed) + 1;
+        let filename = std::path::Path::new(&dir).join(format!("snapshot-{:04}.png", n));
+        let filename = filename.to_string_lossy();
+        if let Err(err) = render_snapshot(&filename, sampler, params, bounds, width, color) {
+            println!("WARNING: unable to write snapshot: {}", err);
+        }
+    })
+}
+
+// Write outputs from the sampler's current state during a run. Recorded samples
+// aren't available yet, so each transcript is counted in the cell it's
+// currently assigned to.
+fn intermediate_output(args: &Args) -> IntermediateOutput<'static> {
+    let output_expected_counts = args.output_expected_counts.clone();
+    let output_expected_counts_fmt = args.output_expected_counts_fmt;
+    let output_maxpost_counts = args.output_maxpost_counts.clone();
+    let output_maxpost_counts_fmt = args.output_maxpost_counts_fmt;
+    let output_transcript_metadata = args.output_transcript_metadata.clone();
+    let output_transcript_metadata_fmt = args.output_transcript_metadata_fmt;
+    let output_cell_polygons = args.output_cell_polygons.clone();
+    let output_cell_polygons_fmt = args.output_cell_polygons_fmt;
+    let output_cell_hulls = args.output_cell_hulls.clone();
+    let smoothing_params = polygon_smoothing_params(args);
+    let transform_paths = args.transform.clone();
+
+    Box::new(move |dataset, params, sampler| {
+        let counts = params.counts.map(|&c| c as u32).sum_axis(Axis(2));
+        let cell_assignments: Vec<(u32, f32)> = params
+            .cell_assignments
+            .iter()
+            .map(|&cell| (cell, 1.0))
+            .collect();
+        let cell_centroids = sampler.cell_centroids();
+
+        write_in_place(&output_expected_counts, |path| {
+            write_expected_counts(
+                path,
+                output_expected_counts_fmt,
+                &dataset.transcript_names,
+                &counts.map(|&c| c as f32),
+                &cell_centroids,
+            )
+        });
+        write_in_place(&output_maxpost_counts, |path| {
+            write_counts(
+                path,
+                output_maxpost_counts_fmt,
+                &dataset.transcript_names,
+                &counts,
+                &cell_centroids,
+            )
+        });
+        write_in_place(&output_transcript_metadata, |path| {
+            write_transcript_metadata(
+                path,
+                output_transcript_metadata_fmt,
+                &dataset.transcripts,
+                &params.transcript_positions,
+                &dataset.transcript_names,
+                &cell_assignments,
+                &params.transcript_state,
+                &params.noise_probabilities(&dataset.transcripts, &cell_assignments),
+                &dataset.qvs,
+                &dataset.fovs,
+                &dataset.fov_names,
+                &dataset.fov_samples,
+                &dataset.sample_names,
+                &dataset.nuclear,
+                &vec![None; dataset.transcripts.len()],
+            )
+        });
+        let inverse_transform = polygon_inverse_transform(
+            &transform_paths,
             &dataset.transcripts,
-            args.schedule[0],
-            args.morphology_steps_per_iter,
-            None,
-            &mut total_steps,
-            &args.monitor_cell_polygons,
-            args.monitor_cell_polygons_freq,
-            true,
-            true,
-            false,
-        );
-
-        for &niter in args.schedule[1..args.schedule.len() - 1].iter() {
-            if args.check_consistency {
-                sampler.borrow_mut().check_consistency(&priors, &mut params);
+            &dataset.fov_samples,
+            dataset.sample_names.len(),
+            cell_centroids.len(),
+            params.cell_assignments.iter().cloned(),
+        );
+        if output_cell_polygons.is_some() {
+            let polygons =
+                smooth_cell_polygons(&sampler.consensus_cell_polygons(), &smoothing_params);
+            let polygons = match &inverse_transform {
+                Some(inverse_transform) => inverse_transform.apply(polygons),
+                None => polygons,
+            };
+            write_in_place(&output_cell_polygons, |path| {
+                write_cell_polygons(path, output_cell_polygons_fmt, polygons, None)
+            });
+        }
+        write_in_place(&output_cell_hulls, |path| {
+            let path = path.as_ref().unwrap();
+            match &inverse_transform {
+                Some(inverse_transform) => params.write_cell_hulls(
+                    &inverse_transform.inverse_transcripts(&dataset.transcripts, &dataset.fov_samples),
+                    &counts,
+                    path,
+                    None,
+                ),
+                None => params.write_cell_hulls(&dataset.transcripts, &counts, path, None),
             }
+        });
+    })
+}
 
-            sampler
-                .replace_with(|sampler| sampler.double_resolution(&params, args.double_z_layers));
-            run_hexbin_sampler(
-                &mut prog,
-                sampler.get_mut(),
-                &priors,
-                &mut params,
-                &dataset.transcripts,
-                niter,
-                args.morphology_steps_per_iter,
-                None,
-                &mut total_steps,
-                &args.monitor_cell_polygons,
-                args.monitor_cell_polygons_freq,
-                true,
-                true,
-                false,
+// Write an output to a temporary file in the same directory, then rename it
+// into place, so that something reading it during a run (e.g. an intermediate
+// output) sees either the last complete version or the new one, never a
+// partial file. The temporary name keeps the extension, which can determine
+// the format.
+fn write_in_place(path: &Option<String>, write: impl FnOnce(&Option<String>)) {
+    let path = match path {
+        Some(path) => std::path::Path::new(path),
+        None => return,
+    };
+    let name = path.file_name().unwrap().to_string_lossy();
+    let partial = path.with_file_name(format!(".partial.{}", name));
+    write(&Some(partial.to_string_lossy().into_owned()));
+
+    // mtx outputs are directories, which can't be renamed over one another
+    let previous = path.with_file_name(format!(".previous.{}", name));
+    let replace_dir = partial.is_dir() && path.is_dir();
+    let result = if replace_dir {
+        std::fs::rename(path, &previous)
+            .and_then(|_| std::fs::rename(&partial, path))
+            .and_then(|_| std::fs::remove_dir_all(&previous))
+    } else {
+        std::fs::rename(&partial, path)
+    };
+    if let Err(err) = result {
+        eprintln!("Error: unable to write {}: {}", path.display(), err);
+        std::process::exit(1);
+    }
+}
+
+// Report predicted peak memory, warn if it's more than is available, and with
+// --max-memory, fall back to keeping finished chains on disk or segmenting
+// fewer FOVs at once, or give up, if it's more than allowed.
+fn check_memory(args: &mut Args, dataset: &TranscriptDataset) {
+    let (xmin, xmax, ymin, ymax, _, _) = coordinate_span(&dataset.transcripts);
+    let ndoublings = args.schedule.len() as i32 - 1;
+    let voxel_size = match args.voxel_size_schedule.last() {
+        Some(&voxel_size) => voxel_size,
+        None => args.initial_voxel_size.unwrap_or(4.0) / 2.0_f32.powi(ndoublings),
+    };
+    let voxel_layers = if args.double_z_layers {
+        args.voxel_layers << ndoublings
+    } else {
+        args.voxel_layers
+    };
+    let ncomponents = match args.ncomponents {
+        NComponents::Fixed(ncomponents) => ncomponents,
+        NComponents::Auto => args.max_components,
+    };
+
+    // Segmenting FOVs several at a time, the largest of them determine peak
+    // memory. Transcripts, cells, and area are all taken to scale with their
+    // share of transcripts, though the whole dataset is kept in memory throughout.
+    let dataset_memory = |fraction: f32, spill_chains: bool| {
+        MemoryEstimate::new(
+            (fraction * dataset.transcripts.len() as f32).ceil() as usize,
+            dataset.transcript_names.len(),
+            (fraction * dataset.nucleus_population.len() as f32).ceil() as usize,
+            ncomponents,
+            fraction * (xmax - xmin) * (ymax - ymin),
+            voxel_size,
+            voxel_layers,
+            args.nchains,
+            spill_chains,
+        )
+    };
+    let estimate = |fraction: f32, spill_chains: bool| MemoryEstimate {
+        transcripts: dataset_memory(1.0, spill_chains).transcripts,
+        ..dataset_memory(fraction, spill_chains)
+    };
+    let mut fov_counts = vec![0_usize; dataset.fov_names.len()];
+    for t in &dataset.transcripts {
+        fov_counts[t.fov as usize] += 1;
+    }
+    fov_counts.sort_unstable_by(|a, b| b.cmp(a));
+    let largest_fovs_fraction = |nfovs: usize| {
+        fov_counts.iter().take(nfovs).sum::<usize>() as f32 / dataset.transcripts.len().max(1) as f32
+    };
+    let nparallel = args
+        .parallel_fovs
+        .unwrap_or(current_num_threads())
+        .clamp(1, fov_counts.len().max(1));
+    let fraction = if args.partition_fovs {
+        largest_fovs_fraction(nparallel)
+    } else {
+        1.0
+    };
+
+    let memory = estimate(fraction, args.spill_chains);
+    memory.report();
+
+    if let Some(available) = available_memory() {
+        if memory.total() > available {
+            println!(
+                "WARNING: Predicted peak memory ({}) is more than is currently available ({}).",
+                format_memory_size(memory.total()),
+                format_memory_size(available)
             );
         }
-        if args.check_consistency {
-            sampler.borrow_mut().check_consistency(&priors, &mut params);
+    }
+
+    if let Some(max_memory) = args.max_memory {
+        if memory.total() <= max_memory {
+            return;
+        }
+
+        // Keeping finished chains on disk doesn't change the result, so try
+        // that first.
+        if args.nchains > 1 && !args.spill_chains {
+            args.spill_chains = true;
+            if estimate(fraction, true).total() <= max_memory {
+                println!(
+                    "Predicted peak memory ({}) exceeds --max-memory, so finished chains will be kept in temporary files.",
+                    format_memory_size(memory.total()),
+                );
+                return;
+            }
+        }
+
+        // Nor does segmenting fewer FOVs at once, if they're segmented separately.
+        if args.partition_fovs {
+            let fitting_nparallel = (1..nparallel)
+                .rev()
+                .find(|&nfovs| estimate(largest_fovs_fraction(nfovs), args.spill_chains).total() <= max_memory);
+            if let Some(fitting_nparallel) = fitting_nparallel {
+                println!(
+                    "Predicted peak memory ({}) exceeds --max-memory, so only {} FOVs will be segmented at a time.",
+                    format_memory_size(memory.total()),
+                    fitting_nparallel
+                );
+                args.parallel_fovs = Some(fitting_nparallel);
+                return;
+            }
         }
-        sampler.replace_with(|sampler| sampler.double_resolution(&params, args.double_z_layers));
+
+        eprintln!(
+            "Error: Predicted peak memory ({}) exceeds --max-memory ({}). Consider --partition-fovs (with --fov-column), fewer --voxel-layers, a larger final voxel size, or fewer --nchains.",
+            format_memory_size(memory.total()),
+            format_memory_size(max_memory)
+        );
+        std::process::exit(1);
     }
+}
 
-    run_hexbin_sampler(
-        &mut prog,
-        sampler.get_mut(),
-        &priors,
-        &mut params,
-        &dataset.transcripts,
-        *args.schedule.last().unwrap() - args.recorded_samples,
-        args.morphology_steps_per_iter,
-        None,
-        &mut total_steps,
-        &args.monitor_cell_polygons,
-        args.monitor_cell_polygons_freq,
-        true,
-        false,
-        false,
-    );
-
-    run_hexbin_sampler(
-        &mut prog,
-        sampler.get_mut(),
-        &priors,
-        &mut params,
-        &dataset.transcripts,
-        args.recorded_samples,
-        args.morphology_steps_per_iter,
-        Some(&mut uncertainty),
-        &mut total_steps,
-        &args.monitor_cell_polygons,
-        args.monitor_cell_polygons_freq,
-        true,
-        false,
-        false,
+// Split maximum posterior counts into those of transcripts assigned with
+// high confidence and the rest, e.g. to check that downstream results don't
+// hinge on uncertain assignments near cell boundaries.
+fn write_confidence_counts(
+    args: &Args,
+    transcript_names: &[String],
+    transcripts: &[Transcript],
+    cell_assignments: &[(CellIndex, f32)],
+    cell_centroids: &[(f32, f32, f32)],
+) {
+    if args.output_high_confidence_counts.is_none() && args.output_low_confidence_counts.is_none() {
+        return;
+    }
+
+    let shape = (transcript_names.len(), cell_centroids.len());
+    let mut high_counts = Array2::<u32>::zeros(shape);
+    let mut low_counts = Array2::<u32>::zeros(shape);
+    for (t, &(cell, pr)) in transcripts.iter().zip(cell_assignments) {
+        if cell == BACKGROUND_CELL || pr <= args.count_pr_cutoff {
+            continue;
+        }
+        let counts = if pr >= args.high_confidence_threshold {
+            &mut high_counts
+        } else {
+            &mut low_counts
+        };
+        counts[[t.gene as usize, cell as usize]] += 1;
+    }
+
+    write_counts(
+        &args.output_high_confidence_counts,
+        args.output_high_confidence_counts_fmt,
+        transcript_names,
+        &high_counts,
+        cell_centroids,
+    );
+    write_counts(
+        &args.output_low_confidence_counts,
+        args.output_low_confidence_counts_fmt,
+        transcript_names,
+        &low_counts,
+        cell_centroids,
     );
+}
+
+// Outputs of segmenting one FOV, with cells numbered within it, to be merged
+// with those of the others.
+struct FovSegmentation {
+    // index into the input of each transcript, some of which segmentation may
+    // have dropped
+    rows: Vec<usize>,
+    transcripts: Vec<Transcript>,
+    qvs: Vec<f32>,
+    fovs: Vec<u32>,
+    nuclear: Vec<bool>,
+    original_cell_assignments: Vec<CellIndex>,
+    cell_assignments: Vec<(CellIndex, f32)>,
+    noise_probabilities: Vec<f32>,
+    transcript_positions: Vec<(f32, f32, f32)>,
+    mean_transcript_positions: Vec<(f32, f32, f32)>,
+    transcript_state: Vec<TranscriptState>,
+    counts: Array2<u32>,
+    ecounts: Array2<f32>,
+    nuclear_ecounts: Array2<f32>,
+    cytoplasmic_ecounts: Array2<f32>,
+    λ: Array2<f32>,
+    z: Vec<u32>,
+    cell_types: Option<Vec<String>>,
+    cell_volume: Vec<f32>,
+    cell_areas: Vec<f32>,
+    empty_cells: Vec<bool>,
+    cell_population: Vec<usize>,
+    cell_centroids: Vec<(f32, f32, f32)>,
+    cell_shapes: Vec<CellShape>,
+    cell_graph: Vec<(CellIndex, CellIndex, f32)>,
+    cell_polygons: Vec<CellPolygonLayers>,
+    cell_flattened_polygons: Vec<CellPolygon>,
+    consensus_cell_polygons: Vec<CellPolygon>,
+}
 
-    if args.check_consistency {
-        sampler.borrow_mut().check_consistency(&priors, &mut params);
+impl FovSegmentation {
+    // An FOV that wasn't segmented, with no cells and every transcript unassigned.
+    fn unassigned(rows: Vec<usize>, part: TranscriptDataset) -> Self {
+        let ngenes = part.transcript_names.len();
+        let ntranscripts = part.transcripts.len();
+        let positions: Vec<(f32, f32, f32)> = part.transcripts.iter().map(|t| (t.x, t.y, t.z)).collect();
+        FovSegmentation {
+            rows,
+            transcripts: part.transcripts,
+            qvs: part.qvs,
+            fovs: part.fovs,
+            nuclear: part.nuclear,
+            original_cell_assignments: part.original_cell_assignments,
+            cell_assignments: vec![(BACKGROUND_CELL, 1.0); ntranscripts],
+            noise_probabilities: vec![1.0; ntranscripts],
+            transcript_positions: positions.clone(),
+            mean_transcript_positions: positions,
+            transcript_state: vec![TranscriptState::Background; ntranscripts],
+            counts: Array2::zeros((ngenes, 0)),
+            ecounts: Array2::zeros((ngenes, 0)),
+            nuclear_ecounts: Array2::zeros((ngenes, 0)),
+            cytoplasmic_ecounts: Array2::zeros((ngenes, 0)),
+            λ: Array2::zeros((ngenes, 0)),
+            z: Vec::new(),
+            cell_types: None,
+            cell_volume: Vec::new(),
+            cell_areas: Vec::new(),
+            empty_cells: Vec::new(),
+            cell_population: Vec::new(),
+            cell_centroids: Vec::new(),
+            cell_shapes: Vec::new(),
+            cell_graph: Vec::new(),
+            cell_polygons: Vec::new(),
+            cell_flattened_polygons: Vec::new(),
+            consensus_cell_polygons: Vec::new(),
+        }
     }
-    prog.finish();
+}
+
+fn segment_fov(
+    args: &Args,
+    rows: Vec<usize>,
+    mut part: TranscriptDataset,
+    progress: bool,
+    interrupted: &Arc<AtomicBool>,
+    profiler: &Option<Arc<Profiler>>,
+    status: &Option<Arc<RunStatus>>,
+) -> FovSegmentation {
+    // Segmentation drops transcripts far from any cell, so only the rows of
+    // those kept are passed on.
+    let (
+        ProsegResult {
+            params,
+            sampler,
+            uncertainty,
+            ..
+        },
+        _,
+        kept,
+    ) = segment(args, &mut part, Arc::clone(interrupted), profiler, status, progress);
+    let rows = kept.into_iter().map(|i| rows[i]).collect();
 
-    uncertainty.finish(&params);
     let (counts, cell_assignments) = uncertainty.max_posterior_transcript_counts_assignments(
         &params,
-        &dataset.transcripts,
+        &part.transcripts,
         args.count_pr_cutoff,
         args.foreground_pr_cutoff,
     );
+    let ngenes = part.transcript_names.len();
+    let split_compartments = args.output_nuclear_expected_counts.is_some()
+        || args.output_cytoplasmic_expected_counts.is_some();
+    let (nuclear_ecounts, cytoplasmic_ecounts) = if split_compartments {
+        (
+            uncertainty.compartment_expected_counts(&params, &part.transcripts, &part.nuclear, true),
+            uncertainty.compartment_expected_counts(&params, &part.transcripts, &part.nuclear, false),
+        )
+    } else {
+        (Array2::zeros((ngenes, 0)), Array2::zeros((ngenes, 0)))
+    };
+    let (cell_polygons, cell_flattened_polygons) =
+        if args.output_cell_polygon_layers.is_some() || args.output_union_cell_polygons.is_some() {
+            sampler.cell_polygons()
+        } else {
+            (Vec::new(), Vec::new())
+        };
+
+    FovSegmentation {
+        rows,
+        noise_probabilities: params.noise_probabilities(&part.transcripts, &cell_assignments),
+        mean_transcript_positions: uncertainty.mean_transcript_positions(&part.transcripts),
+        ecounts: uncertainty.expected_counts(&params, &part.transcripts),
+        transcripts: part.transcripts,
+        qvs: part.qvs,
+        fovs: part.fovs,
+        nuclear: part.nuclear,
+        original_cell_assignments: part.original_cell_assignments,
+        cell_assignments,
+        transcript_positions: params.transcript_positions.clone(),
+        transcript_state: params.transcript_state.to_vec(),
+        counts,
+        nuclear_ecounts,
+        cytoplasmic_ecounts,
+        λ: params.λ.clone(),
+        z: params.z.to_vec(),
+        cell_types: params
+            .expression_prior
+            .as_ref()
+            .map(|expression_prior| expression_prior.cell_types.clone()),
+        cell_volume: params.cell_volume.to_vec(),
+        cell_areas: params.cell_areas(),
+        empty_cells: sampler.empty_cells(),
+        cell_population: params.cell_population.clone(),
+        cell_centroids: sampler.cell_centroids(),
+        cell_shapes: sampler.cell_shapes(),
+        cell_graph: if args.output_cell_graph.is_some() {
+            sampler.cell_adjacency()
+        } else {
+            Vec::new()
+        },
+        cell_polygons,
+        cell_flattened_polygons,
+        consensus_cell_polygons: if args.output_cell_polygons.is_some() {
+            sampler.consensus_cell_polygons()
+        } else {
+            Vec::new()
+        },
+    }
+}
+
+fn reorder<T: Copy>(values: &[T], order: &[usize]) -> Vec<T> {
+    order.iter().map(|&i| values[i]).collect()
+}
+
+// Segment each FOV in turn, and write outputs merged across FOVs. Outputs that
+// describe the model as a whole, rather than cells or transcripts, can't be
+// merged, so aren't written.
+fn segment_fovs(
+    args: &Args,
+    dataset: TranscriptDataset,
+    interrupted: Arc<AtomicBool>,
+    profiler: &Option<Arc<Profiler>>,
+    status: &Option<Arc<RunStatus>>,
+) {
+    let unsupported_outputs = [
+        ("--output-component-params", &args.output_component_params),
+        ("--output-component-metadata", &args.output_component_metadata),
+        ("--output-component-probabilities", &args.output_component_probabilities),
+        ("--output-transcript-posterior", &args.output_transcript_posterior),
+        ("--output-chain-agreement", &args.output_chain_agreement),
+        ("--output-diagnostics", &args.output_diagnostics),
+        ("--output-gene-metadata", &args.output_gene_metadata),
+        ("--output-noise-report", &args.output_noise_report),
+        ("--output-ambient-profile", &args.output_ambient_profile),
+        ("--output-tissue-mask", &args.output_tissue_mask),
+        ("--output-corrected-counts", &args.output_corrected_counts),
+        ("--output-background-map", &args.output_background_map),
+        ("--output-transcript-diffusion", &args.output_transcript_diffusion),
+        ("--output-gene-diffusion", &args.output_gene_diffusion),
+        ("--output-anndata", &args.output_anndata),
+        ("--output-spatialdata", &args.output_spatialdata),
+        ("--output-cell-voxels", &args.output_cell_voxels),
+        ("--output-hexes", &args.output_hexes),
+        ("--output-cell-hulls", &args.output_cell_hulls),
+    ];
+    for (arg, output) in unsupported_outputs {
+        if output.is_some() {
+            println!("WARNING: {} is not supported with --partition-fovs, and will not be written", arg);
+        }
+    }
+    if args.output_interval.is_some() {
+        println!("WARNING: --output-interval is not supported with --partition-fovs");
+    }
+    if args.split_output_by.is_some() {
+        println!("WARNING: --split-output-by is not supported with --partition-fovs");
+    }
+    if args.render_snapshots.is_some() {
+        println!("WARNING: --render-snapshots is not supported with --partition-fovs");
+    }
+    if args.negative_controls.is_some() {
+        println!("WARNING: false discovery rates from --negative-controls are not estimated with --partition-fovs");
+    }
+
+    let transcript_names = dataset.transcript_names.clone();
+    let fov_names = dataset.fov_names.clone();
+    let fov_samples = dataset.fov_samples.clone();
+    let sample_names = dataset.sample_names.clone();
+    let original_cell_ids = dataset.original_cell_ids.clone();
+    let ngenes = transcript_names.len();
+
+    let parts = partition_by_fov(dataset);
+    let nparts = parts.len();
+
+    // Several FOVs are segmented at once, each in its own thread pool with an
+    // equal share of the threads (and its own random streams, so results
+    // don't depend on which FOVs happen to run together).
+    let nthreads = current_num_threads();
+    let nparallel = args.parallel_fovs.unwrap_or(nthreads).clamp(1, nparts.max(1));
+    let fov_threads = (nthreads / nparallel).max(1);
+    if nparallel > 1 {
+        println!("Segmenting {} FOVs at a time, with {} threads each", nparallel, fov_threads);
+    }
+
+    let queue = Mutex::new(parts.into_iter().enumerate());
+    let mut segmentations: Vec<(usize, FovSegmentation)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..nparallel)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut segmentations = Vec::new();
+                    loop {
+                        let next = queue.lock().unwrap().next();
+                        let Some((i, (rows, part))) = next else {
+                            break;
+                        };
+                        let fov_name = &fov_names[part.fovs[0] as usize];
+                        let segmentation = if interrupted.load(Ordering::SeqCst) {
+                            println!("WARNING: interrupted, leaving transcripts in FOV {} unassigned", fov_name);
+                            FovSegmentation::unassigned(rows, part)
+                        } else if part.nucleus_population.is_empty() {
+                            println!(
+                                "WARNING: FOV {} has no transcripts initially assigned to cells, so its transcripts are left unassigned",
+                                fov_name
+                            );
+                            FovSegmentation::unassigned(rows, part)
+                        } else {
+                            println!("Segmenting FOV {} ({} of {})", fov_name, i + 1, nparts);
+                            rng::thread_pool(fov_threads).install(|| {
+                                segment_fov(args, rows, part, nparallel == 1, &interrupted, profiler, status)
+                            })
+                        };
+                        segmentations.push((i, segmentation));
+                    }
+                    segmentations
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+    segmentations.sort_by_key(|(i, _)| *i);
+
+    let mut rows = Vec::new();
+    let mut transcripts = Vec::new();
+    let mut qvs = Vec::new();
+    let mut fovs = Vec::new();
+    let mut nuclear = Vec::new();
+    let mut original_cell_assignments = Vec::new();
+    let mut cell_assignments = Vec::new();
+    let mut transcript_positions = Vec::new();
+    let mut mean_transcript_positions = Vec::new();
+    let mut transcript_state = Vec::new();
+    let mut noise_probabilities = Vec::new();
+    let mut counts = Array2::<u32>::zeros((ngenes, 0));
+    let mut ecounts = Array2::<f32>::zeros((ngenes, 0));
+    let mut nuclear_ecounts = Array2::<f32>::zeros((ngenes, 0));
+    let mut cytoplasmic_ecounts = Array2::<f32>::zeros((ngenes, 0));
+    let mut λ = Array2::<f32>::zeros((ngenes, 0));
+    let mut z = Vec::new();
+    let mut cell_types = None;
+    let mut cell_volume = Vec::new();
+    let mut cell_areas = Vec::new();
+    let mut empty_cells = Vec::new();
+    let mut cell_population = Vec::new();
+    let mut cell_centroids = Vec::new();
+    let mut cell_shapes = Vec::new();
+    let mut cell_polygons = Vec::new();
+    let mut cell_flattened_polygons = Vec::new();
+    let mut consensus_cell_polygons = Vec::new();
+    let mut cell_graph = Vec::new();
+
+    let split_compartments = args.output_nuclear_expected_counts.is_some()
+        || args.output_cytoplasmic_expected_counts.is_some();
+
+    for (_, part) in segmentations {
+        // Cells in this FOV are numbered after those in earlier FOVs.
+        let offset = cell_centroids.len() as CellIndex;
+        cell_assignments.extend(part.cell_assignments.iter().map(|&(cell, pr)| {
+            if cell == BACKGROUND_CELL {
+                (cell, pr)
+            } else {
+                (cell + offset, pr)
+            }
+        }));
+        cell_graph.extend(
+            part.cell_graph
+                .into_iter()
+                .map(|(a, b, length)| (a + offset, b + offset, length)),
+        );
+
+        counts.append(Axis(1), part.counts.view()).unwrap();
+        ecounts.append(Axis(1), part.ecounts.view()).unwrap();
+        if split_compartments {
+            nuclear_ecounts.append(Axis(1), part.nuclear_ecounts.view()).unwrap();
+            cytoplasmic_ecounts.append(Axis(1), part.cytoplasmic_ecounts.view()).unwrap();
+        }
+        λ.append(Axis(1), part.λ.view()).unwrap();
+        z.extend(part.z);
+        if part.cell_types.is_some() {
+            cell_types = part.cell_types;
+        }
+        cell_volume.extend(part.cell_volume);
+        cell_areas.extend(part.cell_areas);
+        empty_cells.extend(part.empty_cells);
+        cell_population.extend(part.cell_population);
+        cell_centroids.extend(part.cell_centroids);
+        cell_shapes.extend(part.cell_shapes);
+        cell_polygons.extend(part.cell_polygons);
+        cell_flattened_polygons.extend(part.cell_flattened_polygons);
+        consensus_cell_polygons.extend(part.consensus_cell_polygons);
+
+        rows.extend(part.rows);
+        transcripts.extend(part.transcripts);
+        qvs.extend(part.qvs);
+        fovs.extend(part.fovs);
+        nuclear.extend(part.nuclear);
+        original_cell_assignments.extend(part.original_cell_assignments);
+        noise_probabilities.extend(part.noise_probabilities);
+        transcript_positions.extend(part.transcript_positions);
+        mean_transcript_positions.extend(part.mean_transcript_positions);
+        transcript_state.extend(part.transcript_state);
+    }
 
-    let ecounts = uncertainty.expected_counts(&params, &dataset.transcripts);
-    let cell_centroids = sampler.borrow().cell_centroids();
+    // Put transcripts back in the order they were read.
+    let order: Vec<usize> = (0..rows.len()).sorted_by_key(|&i| rows[i]).collect();
+    let transcripts = reorder(&transcripts, &order);
+    let qvs = reorder(&qvs, &order);
+    let fovs = reorder(&fovs, &order);
+    let nuclear = reorder(&nuclear, &order);
+    let original_cell_assignments = reorder(&original_cell_assignments, &order);
+    let cell_assignments = reorder(&cell_assignments, &order);
+    let mut noise_probabilities = reorder(&noise_probabilities, &order);
+    let transcript_positions = reorder(&transcript_positions, &order);
+    let mean_transcript_positions = reorder(&mean_transcript_positions, &order);
+    let transcript_state = reorder(&transcript_state, &order);
+
+    println!("Segmented {} cells in {} FOVs", cell_centroids.len(), nparts);
+    let output_start = Instant::now();
+
+    let qc = CellQc::new(
+        &empty_cells,
+        &counts,
+        &cell_areas,
+        args.min_transcripts_per_cell,
+        args.min_cell_area,
+    );
+    qc.report();
+    let qc_flags = qc.transcript_flags(&cell_assignments);
+    for (noise_probability, flag) in noise_probabilities.iter_mut().zip(&qc_flags) {
+        if flag.is_some() {
+            *noise_probability = 1.0;
+        }
+    }
+    let cell_assignments = qc.cell_assignments(&cell_assignments);
+    let counts = qc.select_columns(&counts);
+    let ecounts = qc.select_columns(&ecounts);
+    if split_compartments {
+        nuclear_ecounts = qc.select_columns(&nuclear_ecounts);
+        cytoplasmic_ecounts = qc.select_columns(&cytoplasmic_ecounts);
+    }
+    let λ = qc.select_columns(&λ);
+    let z = qc.select(&z);
+    let cell_volume = qc.select(&cell_volume);
+    let cell_population = qc.select(&cell_population);
+    let cell_centroids = qc.select(&cell_centroids);
+    let cell_shapes = qc.select(&cell_shapes);
+    // polygons are only collected if they're written
+    if !cell_polygons.is_empty() {
+        cell_polygons = qc.select(&cell_polygons);
+        cell_flattened_polygons = qc.select(&cell_flattened_polygons);
+    }
+    if !consensus_cell_polygons.is_empty() {
+        consensus_cell_polygons = qc.select(&consensus_cell_polygons);
+    }
 
     write_expected_counts(
         &args.output_expected_counts,
         args.output_expected_counts_fmt,
-        &dataset.transcript_names,
+        &transcript_names,
         &ecounts,
+        &cell_centroids,
     );
     write_counts(
         &args.output_maxpost_counts,
         args.output_maxpost_counts_fmt,
-        &dataset.transcript_names,
+        &transcript_names,
         &counts,
+        &cell_centroids,
+    );
+    write_confidence_counts(
+        args,
+        &transcript_names,
+        &transcripts,
+        &cell_assignments,
+        &cell_centroids,
+    );
+    write_expected_counts(
+        &args.output_nuclear_expected_counts,
+        args.output_nuclear_expected_counts_fmt,
+        &transcript_names,
+        &nuclear_ecounts,
+        &cell_centroids,
+    );
+    write_expected_counts(
+        &args.output_cytoplasmic_expected_counts,
+        args.output_cytoplasmic_expected_counts_fmt,
+        &transcript_names,
+        &cytoplasmic_ecounts,
+        &cell_centroids,
     );
     write_rates(
         &args.output_rates,
         args.output_rates_fmt,
-        &params,
-        &dataset.transcript_names,
+        &λ,
+        &transcript_names,
     );
-    write_component_params(
-        &args.output_component_params,
-        args.output_component_params_fmt,
-        &params,
-        &dataset.transcript_names,
+    let cell_metadata = cell_metadata_table(
+        &Array1::from(z),
+        &Array1::from(cell_volume),
+        &cell_population,
+        &cell_centroids,
+        &cell_shapes,
+        &cell_assignments,
+        &fovs,
+        &fov_names,
+        &fov_samples,
+        &sample_names,
+        &original_cell_ids,
+        &original_cell_assignments,
+        cell_types.as_deref(),
+        args.cell_id_scheme,
+        args.cell_id_resolution,
     );
     write_cell_metadata(
         &args.output_cell_metadata,
         args.output_cell_metadata_fmt,
-        &params,
-        &cell_centroids,
+        &cell_metadata,
+    );
+    write_cell_id_map(
+        &args.output_cell_id_map,
+        args.output_cell_id_map_fmt,
         &cell_assignments,
-        &dataset.fovs,
-        &dataset.fov_names,
+        &original_cell_ids,
+        &original_cell_assignments,
+    );
+    write_cell_graph(
+        &args.output_cell_graph,
+        args.output_cell_graph_fmt,
+        &cell_graph,
+        &qc,
     );
     write_transcript_metadata(
         &args.output_transcript_metadata,
         args.output_transcript_metadata_fmt,
-        &dataset.transcripts,
-        &params.transcript_positions,
-        &dataset.transcript_names,
+        &transcripts,
+        &transcript_positions,
+        &transcript_names,
         &cell_assignments,
-        &params.transcript_state,
-        &dataset.qvs,
-        &dataset.fovs,
-        &dataset.fov_names,
+        &Array1::from(transcript_state),
+        &noise_probabilities,
+        &qvs,
+        &fovs,
+        &fov_names,
+        &fov_samples,
+        &sample_names,
+        &nuclear,
+        &qc_flags,
     );
-    write_gene_metadata(
-        &args.output_gene_metadata,
-        args.output_gene_metadata_fmt,
-        &params,
-        &dataset.transcript_names,
-        &ecounts,
+    write_transcript_positions(
+        &args.output_transcript_positions,
+        args.output_transcript_positions_fmt,
+        &transcripts,
+        &mean_transcript_positions,
+        &transcript_names,
+        &cell_assignments,
     );
-    write_voxels(
-        &args.output_cell_voxels,
-        args.output_cell_voxels_fmt,
-        &sampler.borrow(),
+    // Smoothing is done after combining partitions so that neighboring cells
+    // in different FOVs don't overlap.
+    let consensus_cell_polygons =
+        smooth_cell_polygons(&consensus_cell_polygons, &polygon_smoothing_params(args));
+    let (cell_polygons, cell_flattened_polygons, consensus_cell_polygons) =
+        match polygon_inverse_transform(
+            &args.transform,
+            &transcripts,
+            &fov_samples,
+            sample_names.len(),
+            cell_centroids.len(),
+            cell_assignments.iter().map(|(cell, _)| *cell),
+        ) {
+            Some(inverse_transform) => (
+                inverse_transform.apply_layered(cell_polygons),
+                inverse_transform.apply(cell_flattened_polygons),
+                inverse_transform.apply(consensus_cell_polygons),
+            ),
+            None => (cell_polygons, cell_flattened_polygons, consensus_cell_polygons),
+        };
+    write_cell_multipolygons(&args.output_union_cell_polygons, cell_flattened_polygons);
+    write_cell_layered_multipolygons(&args.output_cell_polygon_layers, cell_polygons);
+    write_cell_polygons(
+        &args.output_cell_polygons,
+        args.output_cell_polygons_fmt,
+        consensus_cell_polygons,
+        Some(&cell_metadata),
     );
 
-    if args.output_cell_polygon_layers.is_some() || args.output_union_cell_polygons.is_some() {
-        let (cell_polygons, cell_flattened_polygons) = sampler.borrow().cell_polygons();
-        write_cell_multipolygons(&args.output_union_cell_polygons, cell_flattened_polygons);
-        write_cell_layered_multipolygons(&args.output_cell_polygon_layers, cell_polygons);
+    if let Some(profiler) = profiler {
+        profiler.record("output", output_start.elapsed());
     }
+}
 
-    if args.output_cell_polygons.is_some() {
-        let consensus_cell_polygons = sampler.borrow().consensus_cell_polygons();
-        write_cell_multipolygons(
-            &args.output_cell_polygons,
-            consensus_cell_polygons,
-        );
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("proseg-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
     }
 
-    if let Some(output_cell_hulls) = args.output_cell_hulls {
-        params.write_cell_hulls(&dataset.transcripts, &counts, &output_cell_hulls);
+    #[test]
+    fn config_lists_before_positionals() {
+        let config = write_config(
+            "lists.toml",
+            "schedule = [2, 2]\nroi = [0, 0, 10.5, 10]\n[output]\nrecorded_samples = 1\n",
+        );
+        let cli_args: Vec<String> = ["proseg", "run", "t.csv", "--xenium", "--config", &config]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let args = with_config_args(&cli_args, &config);
+        std::fs::remove_file(&config).unwrap();
+
+        let args = match Cli::try_parse_from(args.unwrap()).unwrap().command {
+            Command::Run(args) => args,
+            _ => unreachable!(),
+        };
+        assert_eq!(args.transcript_csv, vec![String::from("t.csv")]);
+        assert_eq!(args.schedule, vec![2, 2]);
+        assert_eq!(args.roi, Some(vec![0.0, 0.0, 10.5, 10.0]));
+        assert_eq!(args.recorded_samples, 1);
     }
-}
 
-#[allow(clippy::too_many_arguments)]
-fn run_hexbin_sampler(
-    prog: &mut ProgressBar,
-    sampler: &mut VoxelSampler,
-    priors: &ModelPriors,
-    params: &mut ModelParams,
-    transcripts: &Vec<Transcript>,
-    niter: usize,
-    local_steps_per_iter: usize,
-    mut uncertainty: Option<&mut UncertaintyTracker>,
-    total_steps: &mut usize,
-    monitor_cell_polygons: &Option<String>,
-    monitor_cell_polygons_freq: usize,
-    sample_cell_regions: bool,
-    burnin: bool,
-    hillclimb: bool,
-) {
-    sampler.sample_global_params(priors, params, transcripts, &mut uncertainty, burnin);
-    let mut proposal_stats = ProposalStats::new();
-
-    for _ in 0..niter {
-        // sampler.check_perimeter_bounds(priors);
-
-        if sample_cell_regions {
-            // let t0 = std::time::Instant::now();
-            for _ in 0..local_steps_per_iter {
-                sampler.sample_cell_regions(
-                    priors,
-                    params,
-                    &mut proposal_stats,
-                    transcripts,
-                    hillclimb,
-                    &mut uncertainty,
-                );
-            }
-            // println!("Sample cell regions: {:?}", t0.elapsed());
-        }
-        // let t0 = std::time::Instant::now();
-        sampler.sample_global_params(priors, params, transcripts, &mut uncertainty, burnin);
-        // println!("Sample parameters: {:?}", t0.elapsed());
-
-        let nassigned = params.nassigned();
-        let nforeground = params.nforeground();
-        prog.inc(1);
-        prog.set_message(format!(
-            "log-likelihood: {ll} | assigned: {nassigned} / {n} ({perc_assigned:.2}%) | non-background: ({perc_foreground:.2}%)",
-            ll = params.log_likelihood(priors),
-            nassigned = nassigned,
-            n = transcripts.len(),
-            perc_assigned = 100.0 * (nassigned as f32) / (transcripts.len() as f32),
-            perc_foreground = 100.0 * (nforeground as f32) / (transcripts.len() as f32),
-        ));
-
-        // println!("Log likelihood: {}", params.log_likelihood());
-
-        // let empty_cell_count = params.cell_population.iter().filter(|p| **p == 0).count();
-        // println!("Empty cells: {}", empty_cell_count);
-
-        // dbg!(&proposal_stats);
-        // dbg!(sampler.mismatch_edge_stats());
-        proposal_stats.reset();
-
-        // if i % 100 == 0 {
-        //     println!("Iteration {} ({} unassigned transcripts)", i, params.nunassigned());
-        // }
-
-        if *total_steps % monitor_cell_polygons_freq == 0 {
-            if let Some(basename) = monitor_cell_polygons {
-                let filename = format!("{}-{:04}.geojson.gz", basename, *total_steps);
-                let (cell_polygons, _cell_flattened_polygons) = sampler.cell_polygons();
-                write_cell_layered_multipolygons(&Some(filename), cell_polygons);
-            }
-        }
+    #[test]
+    fn config_errors() {
+        let cli_args = vec![String::from("proseg"), String::from("run")];
+        assert!(with_config_args(&cli_args, "/nonexistent/proseg.toml").is_err());
 
-        *total_steps += 1;
+        let config = write_config("dates.toml", "schedule = 1979-05-27\n");
+        let result = with_config_args(&cli_args, &config);
+        std::fs::remove_file(&config).unwrap();
+        assert!(result.is_err());
     }
 }
@@ -0,0 +1,163 @@
+// Rough prediction of peak memory use from the size of the dataset, so that
+// runs that won't fit can be caught before spending hours sampling.
+
+use crate::sampler::transcripts::Transcript;
+
+// Approximate bytes per item, including hash table overhead, beyond the
+// transcripts themselves.
+const DATASET_BYTES_PER_TRANSCRIPT: u64 = 32;
+const PARAMS_BYTES_PER_TRANSCRIPT: u64 = 72;
+const UNCERTAINTY_BYTES_PER_TRANSCRIPT: u64 = 64;
+const BYTES_PER_VOXEL: u64 = 48;
+// counts, expected counts, rates, and intermediate [ngenes, ncells] matrices
+const CELL_GENE_MATRICES: u64 = 8;
+
+pub struct MemoryEstimate {
+    pub transcripts: u64,
+    pub params: u64,
+    pub voxels: u64,
+}
+
+impl MemoryEstimate {
+    // `area` is the area covered by transcripts, and the voxel size and number
+    // of layers are those of the final phase of the schedule. Chains are run
+    // one after another, keeping the best so far whole, and the recorded
+    // samples of the rest in memory unless `spill_chains`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        ntranscripts: usize,
+        ngenes: usize,
+        ncells: usize,
+        ncomponents: usize,
+        area: f32,
+        voxel_size: f32,
+        voxel_layers: usize,
+        nchains: usize,
+        spill_chains: bool,
+    ) -> Self {
+        let ntranscripts = ntranscripts as u64;
+        let whole_chains = nchains.min(2) as u64;
+        let set_aside_chains = if spill_chains { 0 } else { nchains as u64 - whole_chains };
+
+        let transcripts = ntranscripts
+            * (std::mem::size_of::<Transcript>() as u64 + DATASET_BYTES_PER_TRANSCRIPT);
+
+        let cell_gene = (ngenes * (ncells + ncomponents)) as u64 * 4 * CELL_GENE_MATRICES;
+        let params = whole_chains
+            * (ntranscripts * (PARAMS_BYTES_PER_TRANSCRIPT + UNCERTAINTY_BYTES_PER_TRANSCRIPT)
+                + cell_gene)
+            + set_aside_chains * ntranscripts * UNCERTAINTY_BYTES_PER_TRANSCRIPT;
+
+        // Voxels are only stored where cells or transcripts are, so the area is
+        // an upper bound, but not a loose one for densely packed tissue.
+        let nvoxels = (area as f64 / (voxel_size as f64).powi(2)).ceil() as u64 * voxel_layers as u64;
+        let voxels = whole_chains * nvoxels.max(ntranscripts) * BYTES_PER_VOXEL;
+
+        MemoryEstimate {
+            transcripts,
+            params,
+            voxels,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.transcripts + self.params + self.voxels
+    }
+
+    pub fn report(&self) {
+        println!(
+            "Estimated peak memory: {} (transcripts {}, model {}, voxels {})",
+            format_memory_size(self.total()),
+            format_memory_size(self.transcripts),
+            format_memory_size(self.params),
+            format_memory_size(self.voxels),
+        );
+    }
+}
+
+pub fn format_memory_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, UNITS[unit])
+}
+
+// Parse sizes like "512M", "16G", "1.5T", or a plain number of bytes. Units
+// are powers of 1024.
+pub fn parse_memory_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, scale) = match s.char_indices().find(|(_, c)| c.is_ascii_alphabetic()) {
+        Some((i, _)) => {
+            let scale: u64 = match s[i..].to_ascii_uppercase().trim_end_matches('B') {
+                "" => 1,
+                "K" => 1 << 10,
+                "M" => 1 << 20,
+                "G" => 1 << 30,
+                "T" => 1 << 40,
+                _ => return Err(format!("unknown unit in memory size \"{}\"", s)),
+            };
+            (&s[..i], scale)
+        }
+        None => (s, 1),
+    };
+    let number: f64 = number
+        .trim()
+        .parse()
+        .map_err(|_| format!("expected a memory size like 16G, found \"{}\"", s))?;
+    if number.is_nan() || number <= 0.0 {
+        return Err(format!("memory size must be positive, found \"{}\"", s));
+    }
+    Ok((number * scale as f64) as u64)
+}
+
+// Memory available to new processes, where the OS reports it.
+pub fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|line| line.starts_with("MemAvailable:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_sizes() {
+        assert_eq!(parse_memory_size("512"), Ok(512));
+        assert_eq!(parse_memory_size("10B"), Ok(10));
+        assert_eq!(parse_memory_size("2KB"), Ok(2 << 10));
+        assert_eq!(parse_memory_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_memory_size("16g"), Ok(16 << 30));
+        assert_eq!(parse_memory_size("16 GB"), Ok(16 << 30));
+        assert_eq!(parse_memory_size(" 1.5T "), Ok(3 << 39));
+    }
+
+    #[test]
+    fn invalid_memory_sizes() {
+        assert_eq!(
+            parse_memory_size("16X"),
+            Err(String::from("unknown unit in memory size \"16X\""))
+        );
+        assert_eq!(
+            parse_memory_size("G"),
+            Err(String::from("expected a memory size like 16G, found \"G\""))
+        );
+        assert_eq!(
+            parse_memory_size("0"),
+            Err(String::from("memory size must be positive, found \"0\""))
+        );
+        assert!(parse_memory_size("-1G").is_err());
+    }
+
+    #[test]
+    fn format_round_trip() {
+        for bytes in [100, 1 << 20, 3 << 30, 5 << 40] {
+            assert_eq!(parse_memory_size(&format_memory_size(bytes)), Ok(bytes));
+        }
+    }
+}
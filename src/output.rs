@@ -7,26 +7,62 @@ use parquet::arrow::ArrowWriter;
 use parquet::file::properties::WriterProperties;
 use parquet::basic::{Compression::ZSTD, ZstdLevel};
 use clap::ValueEnum;
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use serde::Serialize;
+use flate2::read::MultiGzDecoder;
 use geo::MultiPolygon;
+use kiddo::float::kdtree::KdTree;
+use kiddo::SquaredEuclidean;
 use ndarray::{Array1, Array2, Axis, Zip};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::Write;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::sync::Arc;
 
-use crate::schemas::transcript_metadata_schema;
+#[cfg(feature = "hdf5")]
+mod anndata;
+#[cfg(feature = "hdf5")]
+mod loom;
+mod cellids;
+mod compress;
+mod diffusion;
+mod geoparquet;
+mod manifest;
+mod qc;
+mod snapshot;
+mod spatialdata;
+mod split;
+
+pub use cellids::CellIdScheme;
+pub use compress::zstd_encoder;
+pub use diffusion::{transcript_diffusion, write_gene_diffusion, write_transcript_diffusion};
+pub use manifest::{write_run_manifest, InputFile, RunManifest, RunTiming};
+pub use qc::{CellQc, QcFlag};
+pub use snapshot::{render_snapshot, SnapshotColor};
+pub use split::{split_by_sample, SampleSplit};
+
+use compress::ParallelGzEncoder;
+use crate::schemas::{chain_agreement_schema, transcript_metadata_schema, transcript_posterior_schema};
+use super::compare::{Comparison, Segmentation};
+use super::sampler::genefilter::ExcludedGene;
+use super::sampler::genepanel::GeneFeatures;
 use super::sampler::transcripts::Transcript;
-use super::sampler::transcripts::BACKGROUND_CELL;
-use super::sampler::voxelsampler::VoxelSampler;
-use super::sampler::{ModelParams, TranscriptState};
+use super::sampler::transcripts::{CellIndex, BACKGROUND_CELL};
+use super::sampler::voxelsampler::{CellShape, VoxelSampler};
+use super::sampler::{IterationDiagnostics, ModelParams, TranscriptState};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum OutputFormat {
     Infer,
     Csv,
     CsvGz,
+    CsvZst,
     Parquet,
+    // Sparse 10x/CellRanger style directory. Only supported for count matrices.
+    Mtx,
+    // HDF5 based loom file. Only supported for count matrices, and requires
+    // the hdf5 feature.
+    Loom,
 }
 
 pub fn write_table(
@@ -43,16 +79,22 @@ pub fn write_table(
 
     match fmt {
         OutputFormat::Csv => {
-            if write_table_csv(&mut file, batch).is_err() {
+            if write_table_csv(&mut file, filename, batch).is_err() {
                 panic!("Error writing csv file: {}", filename);
             }
         }
         OutputFormat::CsvGz => {
-            let mut encoder = GzEncoder::new(file, Compression::default());
-            if write_table_csv(&mut encoder, batch).is_err() {
+            let mut encoder = ParallelGzEncoder::new(file);
+            if write_table_csv(&mut encoder, filename, batch).is_err() {
                 panic!("Error writing csv.gz file: {}", filename);
             }
         }
+        OutputFormat::CsvZst => {
+            let mut encoder = zstd_encoder(file).unwrap().auto_finish();
+            if write_table_csv(&mut encoder, filename, batch).is_err() {
+                panic!("Error writing csv.zst file: {}", filename);
+            }
+        }
         OutputFormat::Parquet => {
             if write_table_parquet(&mut file, batch).is_err() {
                 panic!("Error writing parquet file: {}", filename);
@@ -61,18 +103,146 @@ pub fn write_table(
         OutputFormat::Infer => {
             panic!("Cannot infer output format for filename: {}", filename);
         }
+        OutputFormat::Mtx => {
+            panic!("The mtx format is only supported for count matrices: {}", filename);
+        }
+        OutputFormat::Loom => {
+            panic!("The loom format is only supported for count matrices: {}", filename);
+        }
+    }
+}
+
+// Write a [ngenes, ncells] count matrix in the CellRanger layout: a directory
+// containing matrix.mtx.gz, barcodes.tsv.gz, and features.tsv.gz.
+pub(crate) fn write_counts_mtx<T>(
+    dirname: &str,
+    field: &str,
+    transcript_names: &[String],
+    counts: &Array2<T>,
+) -> std::io::Result<()>
+where
+    T: Copy + PartialEq + Default + std::fmt::Display,
+{
+    std::fs::create_dir_all(dirname)?;
+    let path = std::path::Path::new(dirname);
+
+    let (ngenes, ncells) = counts.dim();
+    let nnz = counts.iter().filter(|&&x| x != T::default()).count();
+
+    let file = File::create(path.join("matrix.mtx.gz"))?;
+    let mut encoder = std::io::BufWriter::new(ParallelGzEncoder::new(file));
+    writeln!(encoder, "%%MatrixMarket matrix coordinate {} general", field)?;
+    writeln!(encoder, "{} {} {}", ngenes, ncells, nnz)?;
+    for (j, cell_counts) in counts.columns().into_iter().enumerate() {
+        for (i, &x) in cell_counts.iter().enumerate() {
+            if x != T::default() {
+                writeln!(encoder, "{} {} {}", i + 1, j + 1, x)?;
+            }
+        }
+    }
+    encoder.into_inner()?.finish()?;
+
+    let file = File::create(path.join("barcodes.tsv.gz"))?;
+    let mut encoder = std::io::BufWriter::new(ParallelGzEncoder::new(file));
+    for j in 0..ncells {
+        writeln!(encoder, "{}", j)?;
+    }
+    encoder.into_inner()?.finish()?;
+
+    let file = File::create(path.join("features.tsv.gz"))?;
+    let mut encoder = std::io::BufWriter::new(ParallelGzEncoder::new(file));
+    for name in transcript_names {
+        writeln!(encoder, "{}\t{}\tGene Expression", name, name)?;
+    }
+    encoder.into_inner()?.finish()?;
+
+    Ok(())
+}
+
+// Write a [ngenes, ncells] count matrix as a loom file, with cell centroids as
+// column attributes.
+#[cfg(feature = "hdf5")]
+fn write_counts_loom<T: hdf5::H5Type>(
+    filename: &str,
+    transcript_names: &[String],
+    counts: &Array2<T>,
+    cell_centroids: &[(f32, f32, f32)],
+) {
+    loom::write_loom(filename, transcript_names, counts, cell_centroids)
+        .unwrap_or_else(|err| panic!("Unable to write '{}': {}", filename, err));
+}
+
+#[cfg(not(feature = "hdf5"))]
+fn write_counts_loom<T>(
+    filename: &str,
+    _transcript_names: &[String],
+    _counts: &Array2<T>,
+    _cell_centroids: &[(f32, f32, f32)],
+) {
+    panic!(
+        "Unable to write '{}': proseg was built without HDF5 support (rebuild with `--features hdf5`)",
+        filename
+    );
+}
+
+// Write a [ngenes, ncells] matrix as a delimited text table with a row per
+// cell, formatting rows straight to the (compressed) output rather than
+// building an arrow table first, since dense count matrices can be very large.
+fn write_matrix_text<T>(
+    filename: &str,
+    fmt: OutputFormat,
+    transcript_names: &[String],
+    matrix: &Array2<T>,
+) -> std::io::Result<()>
+where
+    T: Copy + std::fmt::Debug,
+{
+    let file = File::create(filename)?;
+    let mut output: Box<dyn Write> = match fmt {
+        OutputFormat::CsvGz => Box::new(ParallelGzEncoder::new(file)),
+        OutputFormat::CsvZst => Box::new(zstd_encoder(file)?.auto_finish()),
+        _ => Box::new(file),
+    };
+    let mut output = std::io::BufWriter::new(&mut output);
+    let delimiter = if is_tsv_filename(filename) { '\t' } else { ',' };
+
+    for (i, name) in transcript_names.iter().enumerate() {
+        if i > 0 {
+            write!(output, "{}", delimiter)?;
+        }
+        if name.contains([delimiter, '"', '\n']) {
+            write!(output, "\"{}\"", name.replace('"', "\"\""))?;
+        } else {
+            write!(output, "{}", name)?;
+        }
     }
+    writeln!(output)?;
+
+    for cell_values in matrix.columns() {
+        for (i, value) in cell_values.iter().enumerate() {
+            if i > 0 {
+                write!(output, "{}", delimiter)?;
+            }
+            write!(output, "{:?}", value)?;
+        }
+        writeln!(output)?;
+    }
+    output.flush()
 }
 
+// Tables are tab delimited if the filename has a ".tsv" extension.
 fn write_table_csv<W>(
     output: &mut W,
+    filename: &str,
     batch: &RecordBatch,
 ) -> Result<(), ArrowError>
 where
     W: std::io::Write,
 {
+    let delimiter = if is_tsv_filename(filename) { b'\t' } else { b',' };
     let mut writer = csv::WriterBuilder::new()
         .with_header(true)
+        .with_delimiter(delimiter)
         .build(output);
     writer.write(batch)
 }
@@ -96,13 +266,25 @@ where
     Ok(())
 }
 
+fn is_tsv_filename(filename: &str) -> bool {
+    [".tsv", ".tsv.gz", ".tsv.zst"]
+        .iter()
+        .any(|suffix| filename.ends_with(suffix))
+}
+
+// Delimited text tables may be comma or tab separated (".csv" or ".tsv"), and
+// are read and written with the same formats.
 pub fn infer_format_from_filename(filename: &str) -> OutputFormat {
-    if filename.ends_with(".csv.gz") {
+    if filename.ends_with(".csv.gz") || filename.ends_with(".tsv.gz") {
         OutputFormat::CsvGz
-    } else if filename.ends_with(".csv") {
+    } else if filename.ends_with(".csv.zst") || filename.ends_with(".tsv.zst") {
+        OutputFormat::CsvZst
+    } else if filename.ends_with(".csv") || filename.ends_with(".tsv") {
         OutputFormat::Csv
     } else if filename.ends_with(".parquet") {
         OutputFormat::Parquet
+    } else if filename.ends_with(".loom") {
+        OutputFormat::Loom
     } else {
         panic!("Unknown file format for filename: {}", filename);
     }
@@ -113,8 +295,28 @@ pub fn write_counts(
     output_counts_fmt: OutputFormat,
     transcript_names: &[String],
     counts: &Array2<u32>,
+    cell_centroids: &[(f32, f32, f32)],
 ) {
     if let Some(output_counts) = output_counts {
+        let output_counts_fmt = match output_counts_fmt {
+            OutputFormat::Infer => infer_format_from_filename(output_counts),
+            _ => output_counts_fmt,
+        };
+        if output_counts_fmt == OutputFormat::Mtx {
+            write_counts_mtx(output_counts, "integer", transcript_names, counts)
+                .unwrap_or_else(|err| panic!("Error writing mtx files to {}: {}", output_counts, err));
+            return;
+        }
+        if output_counts_fmt == OutputFormat::Loom {
+            write_counts_loom(output_counts, transcript_names, counts, cell_centroids);
+            return;
+        }
+        if output_counts_fmt != OutputFormat::Parquet {
+            write_matrix_text(output_counts, output_counts_fmt, transcript_names, counts)
+                .unwrap_or_else(|err| panic!("Error writing {}: {}", output_counts, err));
+            return;
+        }
+
         let schema = Schema::new(
             transcript_names
                 .iter()
@@ -144,8 +346,28 @@ pub fn write_expected_counts(
     output_expected_counts_fmt: OutputFormat,
     transcript_names: &[String],
     ecounts: &Array2<f32>,
+    cell_centroids: &[(f32, f32, f32)],
 ) {
     if let Some(output_expected_counts) = output_expected_counts {
+        let output_expected_counts_fmt = match output_expected_counts_fmt {
+            OutputFormat::Infer => infer_format_from_filename(output_expected_counts),
+            _ => output_expected_counts_fmt,
+        };
+        if output_expected_counts_fmt == OutputFormat::Mtx {
+            write_counts_mtx(output_expected_counts, "real", transcript_names, ecounts)
+                .unwrap_or_else(|err| panic!("Error writing mtx files to {}: {}", output_expected_counts, err));
+            return;
+        }
+        if output_expected_counts_fmt == OutputFormat::Loom {
+            write_counts_loom(output_expected_counts, transcript_names, ecounts, cell_centroids);
+            return;
+        }
+        if output_expected_counts_fmt != OutputFormat::Parquet {
+            write_matrix_text(output_expected_counts, output_expected_counts_fmt, transcript_names, ecounts)
+                .unwrap_or_else(|err| panic!("Error writing {}: {}", output_expected_counts, err));
+            return;
+        }
+
         let schema = Schema::new(
             transcript_names
                 .iter()
@@ -177,7 +399,7 @@ pub fn write_expected_counts(
 pub fn write_rates(
     output_rates: &Option<String>,
     output_rates_fmt: OutputFormat,
-    params: &ModelParams,
+    λ: &Array2<f32>,
     transcript_names: &[String],
 ) {
     if let Some(output_rates) = output_rates {
@@ -190,7 +412,7 @@ pub fn write_rates(
         );
 
         let mut columns: Vec<Arc<dyn arrow::array::Array>> = Vec::new();
-        for row in params.λ.rows() {
+        for row in λ.rows() {
             columns.push(Arc::new(
                 row.iter().cloned().collect::<arrow::array::Float32Array>(),
             ));
@@ -212,7 +434,10 @@ pub fn write_component_params(
     transcript_names: &[String],
 ) {
     if let Some(output_component_params) = output_component_params {
-        // What does this look like: rows for each gene, columns for α1, β1, α2, β2, etc.
+        // What does this look like: rows for each gene, columns for α1, β1, λ1, α2, β2, λ2, etc.
+        // Cell rates for component i are gamma distributed with shape α_i and
+        // rate β_i (per unit volume), so λ_i = α_i / β_i is the component's
+        // mean expression rate.
         let α = &params.r;
         let φ = &params.φ;
         let β = φ.map(|φ| (-φ).exp());
@@ -224,6 +449,7 @@ pub fn write_component_params(
         for i in 0..ncomponents {
             fields.push(Field::new(&format!("α_{}", i), DataType::Float32, false));
             fields.push(Field::new(&format!("β_{}", i), DataType::Float32, false));
+            fields.push(Field::new(&format!("λ_{}", i), DataType::Float32, false));
         }
         let schema = Schema::new(fields);
 
@@ -233,6 +459,9 @@ pub fn write_component_params(
         Zip::from(α.rows()).and(β.rows()).for_each(|α, β| {
             columns.push(Arc::new(α.iter().cloned().collect::<arrow::array::Float32Array>()));
             columns.push(Arc::new(β.iter().cloned().collect::<arrow::array::Float32Array>()));
+            columns.push(Arc::new(
+                α.iter().zip(β).map(|(α, β)| α / β).collect::<arrow::array::Float32Array>()
+            ));
         });
 
         let batch = RecordBatch::try_new(
@@ -248,6 +477,99 @@ pub fn write_component_params(
     }
 }
 
+// One row per mixture component, giving its mixing proportion, the number of
+// (non-empty) cells assigned to it, and its log-normal cell volume parameters.
+pub fn write_component_metadata(
+    output_component_metadata: &Option<String>,
+    output_component_metadata_fmt: OutputFormat,
+    params: &ModelParams,
+) {
+    if let Some(output_component_metadata) = output_component_metadata {
+        let ncomponents = params.ncomponents();
+
+        let mut schema_fields = vec![
+            Field::new("component", DataType::UInt32, false),
+            Field::new("weight", DataType::Float32, false),
+            Field::new("population", DataType::UInt32, false),
+            Field::new("log_volume_mean", DataType::Float32, false),
+            Field::new("log_volume_sd", DataType::Float32, false),
+        ];
+
+        let mut columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new((0..ncomponents as u32).collect::<arrow::array::UInt32Array>()),
+            Arc::new(params.π.iter().cloned().collect::<arrow::array::Float32Array>()),
+            Arc::new(
+                params
+                    .component_population
+                    .iter()
+                    .cloned()
+                    .collect::<arrow::array::UInt32Array>(),
+            ),
+            Arc::new(params.μ_volume.iter().cloned().collect::<arrow::array::Float32Array>()),
+            Arc::new(params.σ_volume.iter().cloned().collect::<arrow::array::Float32Array>()),
+        ];
+
+        if let Some(expression_prior) = &params.expression_prior {
+            schema_fields.push(Field::new("cell_type", DataType::Utf8, false));
+            columns.push(Arc::new(
+                expression_prior
+                    .cell_types
+                    .iter()
+                    .map(|cell_type| Some(cell_type.clone()))
+                    .collect::<arrow::array::StringArray>(),
+            ));
+        }
+
+        let batch = RecordBatch::try_new(Arc::new(Schema::new(schema_fields)), columns).unwrap();
+        write_table(
+            output_component_metadata,
+            output_component_metadata_fmt,
+            &batch,
+        );
+    }
+}
+
+// One row per cell and one column per component, giving the posterior
+// probability of the cell belonging to the component. Columns are named by cell
+// type with --expression-prior, and `component_{i}` otherwise.
+pub fn write_component_probabilities(
+    output_component_probabilities: &Option<String>,
+    output_component_probabilities_fmt: OutputFormat,
+    probabilities: &Array2<f32>,
+    cell_types: Option<&[String]>,
+) {
+    if let Some(output_component_probabilities) = output_component_probabilities {
+        let names: Vec<String> = match cell_types {
+            Some(cell_types) => cell_types.to_vec(),
+            None => (0..probabilities.nrows())
+                .map(|i| format!("component_{}", i))
+                .collect(),
+        };
+        let schema = Schema::new(
+            names
+                .iter()
+                .map(|name| Field::new(name, DataType::Float32, false))
+                .collect::<Vec<Field>>(),
+        );
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = probabilities
+            .rows()
+            .into_iter()
+            .map(|row| {
+                Arc::new(row.iter().cloned().collect::<arrow::array::Float32Array>())
+                    as Arc<dyn arrow::array::Array>
+            })
+            .collect();
+
+        let batch = RecordBatch::try_new(Arc::new(schema), columns).unwrap();
+        write_table(
+            output_component_probabilities,
+            output_component_probabilities_fmt,
+            &batch,
+        );
+    }
+}
+
 // Assign cells to fovs by finding the most common transcript fov of the
 // assigned transcripts.
 fn cell_fov_vote(
@@ -279,62 +601,337 @@ fn cell_fov_vote(
         .collect::<Vec<u32>>()
 }
 
-pub fn write_cell_metadata(
-    output_cell_metadata: &Option<String>,
-    output_cell_metadata_fmt: OutputFormat,
-    params: &ModelParams,
+// Number of transcripts each cell shares with each input cell, as (cell,
+// original cell, count), sorted by cell.
+fn original_cell_overlaps(
+    cell_assignments: &[(u32, f32)],
+    original_cell_assignments: &[CellIndex],
+) -> Vec<(u32, CellIndex, u32)> {
+    let mut counts: HashMap<(u32, CellIndex), u32> = HashMap::new();
+    for (&(cell, _), &original_cell) in cell_assignments.iter().zip(original_cell_assignments) {
+        if cell != BACKGROUND_CELL && original_cell != BACKGROUND_CELL {
+            *counts.entry((cell, original_cell)).or_insert(0) += 1;
+        }
+    }
+
+    let mut overlaps = counts
+        .into_iter()
+        .map(|((cell, original_cell), count)| (cell, original_cell, count))
+        .collect::<Vec<_>>();
+    overlaps.sort_unstable();
+    overlaps
+}
+
+// The input cell most of each cell's transcripts came from, or BACKGROUND_CELL
+// if none did.
+fn cell_original_id_vote(ncells: usize, overlaps: &[(u32, CellIndex, u32)]) -> Vec<CellIndex> {
+    let mut winners = vec![(BACKGROUND_CELL, 0); ncells];
+    for &(cell, original_cell, count) in overlaps {
+        let winner = &mut winners[cell as usize];
+        if count > winner.1 {
+            *winner = (original_cell, count);
+        }
+    }
+    winners.into_iter().map(|(original_cell, _)| original_cell).collect()
+}
+
+// Per-cell metadata, as written by --output-cell-metadata and alongside
+// GeoParquet cell polygons.
+#[allow(clippy::too_many_arguments)]
+pub fn cell_metadata_table(
+    z: &Array1<u32>,
+    cell_volume: &Array1<f32>,
+    cell_population: &[usize],
     cell_centroids: &[(f32, f32, f32)],
+    cell_shapes: &[CellShape],
     cell_assignments: &[(u32, f32)],
     fovs: &[u32],
     fov_names: &[String],
-) {
+    fov_samples: &[u32],
+    sample_names: &[String],
+    original_cell_ids: &[String],
+    original_cell_assignments: &[CellIndex],
+    cell_types: Option<&[String]>,
+    cell_id_scheme: CellIdScheme,
+    cell_id_resolution: f32,
+) -> RecordBatch {
     let ncells = cell_centroids.len();
     let nfovs = fov_names.len();
     let cell_fovs = cell_fov_vote(ncells, nfovs, cell_assignments, fovs);
+    let cell_original_ids = cell_original_id_vote(
+        ncells,
+        &original_cell_overlaps(cell_assignments, original_cell_assignments),
+    );
+    let cell_fov_names: Vec<Option<&str>> = cell_fovs
+        .iter()
+        .map(|&fov| (fov != u32::MAX).then(|| fov_names[fov as usize].as_str()))
+        .collect();
+    let cell_samples: Vec<Option<&str>> = cell_fovs
+        .iter()
+        .map(|&fov| {
+            (fov != u32::MAX).then(|| sample_names[fov_samples[fov as usize] as usize].as_str())
+        })
+        .collect();
+    let cell_original_ids: Vec<Option<&str>> = cell_original_ids
+        .iter()
+        .map(|&original_cell| {
+            (original_cell != BACKGROUND_CELL)
+                .then(|| original_cell_ids[original_cell as usize].as_str())
+        })
+        .collect();
+    let nearest_neighbor_distance = nearest_neighbor_distances(cell_centroids);
+
+    let mut schema_fields = vec![
+        Field::new("cell", DataType::UInt32, false),
+        Field::new("centroid_x", DataType::Float32, false),
+        Field::new("centroid_y", DataType::Float32, false),
+        Field::new("centroid_z", DataType::Float32, false),
+        Field::new("fov", DataType::Utf8, true),
+        Field::new("sample", DataType::Utf8, true),
+        Field::new("original_cell_id", DataType::Utf8, true),
+        Field::new("cluster", DataType::UInt16, false),
+        Field::new("volume", DataType::Float32, false),
+        Field::new("population", DataType::UInt64, false),
+        Field::new("elongation", DataType::Float32, false),
+        Field::new("orientation", DataType::Float32, false),
+        Field::new("solidity", DataType::Float32, false),
+        Field::new("zlayers", DataType::UInt32, false),
+        Field::new("nearest_neighbor_distance", DataType::Float32, false),
+    ];
+
+    let mut columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+
+        Arc::new((0..ncells as u32).collect::<arrow::array::UInt32Array>()),
+        Arc::new(cell_centroids.iter().map(|(x, _, _)| *x).collect::<arrow::array::Float32Array>()),
+        Arc::new(cell_centroids.iter().map(|(_, y, _)| *y).collect::<arrow::array::Float32Array>()),
+        Arc::new(cell_centroids.iter().map(|(_, _, z)| *z).collect::<arrow::array::Float32Array>()),
+        Arc::new(cell_fov_names.iter().cloned().collect::<arrow::array::StringArray>()),
+        Arc::new(cell_samples.iter().cloned().collect::<arrow::array::StringArray>()),
+        Arc::new(cell_original_ids.iter().cloned().collect::<arrow::array::StringArray>()),
+        Arc::new(z.iter().map(|&z| z as u16).collect::<arrow::array::UInt16Array>()),
+        Arc::new(cell_volume.iter().cloned().collect::<arrow::array::Float32Array>()),
+        Arc::new(cell_population.iter().map(|&p| p as u64).collect::<arrow::array::UInt64Array>()),
+        Arc::new(cell_shapes.iter().map(|s| s.elongation).collect::<arrow::array::Float32Array>()),
+        Arc::new(cell_shapes.iter().map(|s| s.orientation).collect::<arrow::array::Float32Array>()),
+        Arc::new(cell_shapes.iter().map(|s| s.solidity).collect::<arrow::array::Float32Array>()),
+        Arc::new(cell_shapes.iter().map(|s| s.zlayers).collect::<arrow::array::UInt32Array>()),
+        Arc::new(arrow::array::Float32Array::from(nearest_neighbor_distance)),
+    ];
+
+    // stable names, after the cell index
+    if let Some(ids) = cellids::cell_ids(
+        cell_id_scheme,
+        cell_id_resolution,
+        cell_centroids,
+        &cell_samples,
+        &cell_original_ids,
+    ) {
+        schema_fields.insert(1, Field::new("cell_id", DataType::Utf8, false));
+        columns.insert(1, Arc::new(arrow::array::StringArray::from(ids)));
+    }
+
+    // reference cell types of clusters, with --expression-prior
+    if let Some(cell_types) = cell_types {
+        schema_fields.push(Field::new("cell_type", DataType::Utf8, false));
+        columns.push(Arc::new(
+            z.iter()
+                .map(|&z| Some(cell_types[z as usize].clone()))
+                .collect::<arrow::array::StringArray>(),
+        ));
+    }
+
+    RecordBatch::try_new(
+        Arc::new(Schema::new(schema_fields)),
+        columns
+    ).unwrap()
+}
+
+// Cell metadata with the number of negative control transcripts in each cell,
+// and the estimated fraction of its transcripts that are false, appended.
+pub fn add_negative_control_columns(
+    cell_metadata: &RecordBatch,
+    control_counts: &[u32],
+    false_discovery_rates: &[f32],
+) -> RecordBatch {
+    let schema = cell_metadata.schema();
+    let mut schema_fields: Vec<Field> = schema.fields().iter().map(|field| field.as_ref().clone()).collect();
+    let mut columns = cell_metadata.columns().to_vec();
+
+    schema_fields.push(Field::new("negative_control_count", DataType::UInt32, false));
+    columns.push(Arc::new(arrow::array::UInt32Array::from(control_counts.to_vec())));
+    schema_fields.push(Field::new("false_discovery_rate", DataType::Float32, false));
+    columns.push(Arc::new(arrow::array::Float32Array::from(false_discovery_rates.to_vec())));
+
+    RecordBatch::try_new(
+        Arc::new(Schema::new(schema_fields)),
+        columns
+    ).unwrap()
+}
+
+// Distance in the xy-plane from each cell's centroid to that of the nearest
+// other cell, or infinity when there's only one cell.
+fn nearest_neighbor_distances(cell_centroids: &[(f32, f32, f32)]) -> Vec<f32> {
+    let mut kdtree: KdTree<f32, u32, 2, 32, u32> = KdTree::with_capacity(cell_centroids.len());
+    for (i, (x, y, _)) in cell_centroids.iter().enumerate() {
+        kdtree.add(&[*x, *y], i as u32);
+    }
+
+    cell_centroids
+        .iter()
+        .enumerate()
+        .map(|(i, (x, y, _))| {
+            kdtree
+                .nearest_n::<SquaredEuclidean>(&[*x, *y], 2)
+                .iter()
+                .find(|neighbor| neighbor.item != i as u32)
+                .map_or(f32::INFINITY, |neighbor| neighbor.distance.sqrt())
+        })
+        .collect()
+}
 
+pub fn write_cell_metadata(
+    output_cell_metadata: &Option<String>,
+    output_cell_metadata_fmt: OutputFormat,
+    cell_metadata: &RecordBatch,
+) {
     if let Some(output_cell_metadata) = output_cell_metadata {
+        write_table(
+            output_cell_metadata,
+            output_cell_metadata_fmt,
+            cell_metadata,
+        );
+    }
+}
+
+// Every pair of a cell and an input cell (from the cell id column, or
+// --nuclei-csv or --init-mask) that share transcripts, with the number they share.
+pub fn write_cell_id_map(
+    output_cell_id_map: &Option<String>,
+    output_cell_id_map_fmt: OutputFormat,
+    cell_assignments: &[(u32, f32)],
+    original_cell_ids: &[String],
+    original_cell_assignments: &[CellIndex],
+) {
+    if let Some(output_cell_id_map) = output_cell_id_map {
+        let overlaps = original_cell_overlaps(cell_assignments, original_cell_assignments);
+
         let schema = Schema::new(vec![
             Field::new("cell", DataType::UInt32, false),
-            Field::new("centroid_x", DataType::Float32, false),
-            Field::new("centroid_y", DataType::Float32, false),
-            Field::new("centroid_z", DataType::Float32, false),
-            Field::new("fov", DataType::Utf8, true),
-            Field::new("cluster", DataType::UInt16, false),
-            Field::new("volume", DataType::Float32, false),
-            Field::new("population", DataType::UInt64, false),
+            Field::new("original_cell_id", DataType::Utf8, false),
+            Field::new("transcripts", DataType::UInt32, false),
         ]);
 
         let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
-
-            Arc::new((0..params.ncells() as u32).collect::<arrow::array::UInt32Array>()),
-            Arc::new(cell_centroids.iter().map(|(x, _, _)| *x).collect::<arrow::array::Float32Array>()),
-            Arc::new(cell_centroids.iter().map(|(_, y, _)| *y).collect::<arrow::array::Float32Array>()),
-            Arc::new(cell_centroids.iter().map(|(_, _, z)| *z).collect::<arrow::array::Float32Array>()),
+            Arc::new(overlaps.iter().map(|&(cell, _, _)| cell).collect::<arrow::array::UInt32Array>()),
             Arc::new(
-                cell_fovs.iter().map(
-                    |fov| {
-                        if *fov == u32::MAX {
-                            None
-                        } else {
-                            Some(fov_names[*fov as usize].clone())
-                        }
-                    },
-                ).collect::<arrow::array::StringArray>()),
-            Arc::new(params.z.iter().map(|&z| z as u16).collect::<arrow::array::UInt16Array>()),
-            Arc::new(params.cell_volume.iter().cloned().collect::<arrow::array::Float32Array>()),
-            Arc::new(params.cell_population.iter().map(|&p| p as u64).collect::<arrow::array::UInt64Array>())
+                overlaps
+                    .iter()
+                    .map(|&(_, original_cell, _)| Some(original_cell_ids[original_cell as usize].as_str()))
+                    .collect::<arrow::array::StringArray>(),
+            ),
+            Arc::new(overlaps.iter().map(|&(_, _, count)| count).collect::<arrow::array::UInt32Array>()),
         ];
 
-        let batch = RecordBatch::try_new(
-            Arc::new(schema),
-            columns
-        ).unwrap();
+        let batch = RecordBatch::try_new(Arc::new(schema), columns).unwrap();
+        write_table(output_cell_id_map, output_cell_id_map_fmt, &batch);
+    }
+}
 
-        write_table(
-            output_cell_metadata,
-            output_cell_metadata_fmt,
-            &batch,
-        );
+// Per-gene columns included in AnnData output, when there's a gene panel.
+fn anndata_var_columns(gene_features: Option<&GeneFeatures>) -> Vec<(&'static str, Vec<String>)> {
+    match gene_features {
+        Some(features) => vec![
+            ("gene_ids", features.gene_ids.clone()),
+            ("feature_types", features.feature_types.clone()),
+        ],
+        None => Vec::new(),
+    }
+}
+
+// Per-cell columns included in AnnData output.
+#[allow(clippy::type_complexity)]
+fn anndata_obs_columns(
+    params: &ModelParams,
+    cell_centroids: &[(f32, f32, f32)],
+    qc: &CellQc,
+) -> ([(&'static str, Vec<f32>); 5], [(&'static str, Vec<u32>); 2]) {
+    let cell_volume = qc.select_array(&params.cell_volume);
+    let obs_f32_columns = [
+        ("centroid_x", cell_centroids.iter().map(|(x, _, _)| *x).collect::<Vec<f32>>()),
+        ("centroid_y", cell_centroids.iter().map(|(_, y, _)| *y).collect::<Vec<f32>>()),
+        ("centroid_z", cell_centroids.iter().map(|(_, _, z)| *z).collect::<Vec<f32>>()),
+        ("volume", cell_volume.to_vec()),
+        ("area", qc.select(&params.cell_areas())),
+    ];
+    let obs_u32_columns = [
+        ("cluster", qc.select_array(&params.z).to_vec()),
+        ("population", qc.select(&params.cell_population).iter().map(|&p| p as u32).collect::<Vec<u32>>()),
+    ];
+    (obs_f32_columns, obs_u32_columns)
+}
+
+pub fn write_anndata(
+    output_anndata: &Option<String>,
+    params: &ModelParams,
+    transcript_names: &[String],
+    gene_features: Option<&GeneFeatures>,
+    ecounts: &Array2<f32>,
+    cell_centroids: &[(f32, f32, f32)],
+    qc: &CellQc,
+) {
+    if let Some(output_anndata) = output_anndata {
+        let (obs_f32_columns, obs_u32_columns) = anndata_obs_columns(params, cell_centroids, qc);
+        let var_columns = anndata_var_columns(gene_features);
+
+        #[cfg(feature = "hdf5")]
+        anndata::write_h5ad(
+            output_anndata,
+            transcript_names,
+            &var_columns,
+            ecounts,
+            &obs_f32_columns,
+            &obs_u32_columns,
+        )
+        .unwrap_or_else(|err| panic!("Unable to write '{}': {}", output_anndata, err));
+
+        #[cfg(not(feature = "hdf5"))]
+        {
+            let _ = (transcript_names, var_columns, ecounts, obs_f32_columns, obs_u32_columns);
+            panic!(
+                "Unable to write '{}': proseg was built without HDF5 support (rebuild with `--features hdf5`)",
+                output_anndata
+            );
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn write_spatialdata(
+    output_spatialdata: &Option<String>,
+    params: &ModelParams,
+    transcripts: &[Transcript],
+    transcript_names: &[String],
+    gene_features: Option<&GeneFeatures>,
+    cell_assignments: &[(u32, f32)],
+    ecounts: &Array2<f32>,
+    cell_centroids: &[(f32, f32, f32)],
+    cell_polygons: &[MultiPolygon<f32>],
+    qc: &CellQc,
+) {
+    if let Some(output_spatialdata) = output_spatialdata {
+        let (obs_f32_columns, obs_u32_columns) = anndata_obs_columns(params, cell_centroids, qc);
+        spatialdata::write_spatialdata_zarr(
+            output_spatialdata,
+            transcripts,
+            transcript_names,
+            &anndata_var_columns(gene_features),
+            cell_assignments,
+            ecounts,
+            cell_polygons,
+            &obs_f32_columns,
+            &obs_u32_columns,
+        )
+        .unwrap_or_else(|err| panic!("Unable to write '{}': {}", output_spatialdata, err));
     }
 }
 
@@ -347,9 +944,14 @@ pub fn write_transcript_metadata(
     transcript_names: &[String],
     cell_assignments: &[(u32, f32)],
     transcript_state: &Array1<TranscriptState>,
+    noise_probabilities: &[f32],
     qvs: &[f32],
     fovs: &[u32],
     fov_names: &[String],
+    fov_samples: &[u32],
+    sample_names: &[String],
+    nuclear: &[bool],
+    qc_flags: &[Option<&str>],
 ) {
     if let Some(output_transcript_metadata) = output_transcript_metadata {
         let schema = transcript_metadata_schema();
@@ -390,6 +992,11 @@ pub fn write_transcript_metadata(
                     .map(|fov| Some(fov_names[*fov as usize].clone()))
                     .collect::<arrow::array::LargeStringArray>()
             ),
+            Arc::new(
+                fovs.iter()
+                    .map(|fov| Some(sample_names[fov_samples[*fov as usize] as usize].clone()))
+                    .collect::<arrow::array::LargeStringArray>()
+            ),
             Arc::new(
                 cell_assignments.iter().map(|(cell, _)| *cell).collect::<arrow::array::UInt32Array>()
             ),
@@ -408,6 +1015,27 @@ pub fn write_transcript_metadata(
                     .map(|&s| (s == TranscriptState::Confusion) as u8)
                     .collect::<arrow::array::UInt8Array>()
             ),
+            Arc::new(
+                noise_probabilities.iter().cloned().collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                cell_assignments
+                    .iter()
+                    .zip(nuclear)
+                    .map(|((cell, _), &nuclear)| {
+                        if *cell == BACKGROUND_CELL {
+                            None
+                        } else if nuclear {
+                            Some("nuclear")
+                        } else {
+                            Some("cytoplasmic")
+                        }
+                    })
+                    .collect::<arrow::array::LargeStringArray>()
+            ),
+            Arc::new(
+                qc_flags.iter().cloned().collect::<arrow::array::LargeStringArray>()
+            ),
         ];
 
         let batch = RecordBatch::try_new(
@@ -423,49 +1051,537 @@ pub fn write_transcript_metadata(
     }
 }
 
-pub fn write_gene_metadata(
-    output_gene_metadata: &Option<String>,
-    output_gene_metadata_fmt: OutputFormat,
-    params: &ModelParams,
+// Posterior mean position of every transcript under the diffusion model, for
+// plotting transcripts where they were inferred to come from.
+pub fn write_transcript_positions(
+    output_transcript_positions: &Option<String>,
+    output_transcript_positions_fmt: OutputFormat,
+    transcripts: &[Transcript],
+    transcript_positions: &[(f32, f32, f32)],
     transcript_names: &[String],
-    expected_counts: &Array2<f32>,
+    cell_assignments: &[(u32, f32)],
 ) {
-    if let Some(output_gene_metadata) = output_gene_metadata {
-        let mut schema_fields = vec![
-            Field::new("gene", DataType::Utf8, false),
-            Field::new("total_count", DataType::UInt64, false),
-            Field::new("expected_assigned_count", DataType::Float32, false),
-            // Field::new("dispersion", DataType::Float32, false),
-        ];
+    if let Some(output_transcript_positions) = output_transcript_positions {
+        let schema = Schema::new(vec![
+            Field::new("transcript_id", DataType::UInt64, false),
+            Field::new("gene", DataType::LargeUtf8, false),
+            Field::new("x", DataType::Float32, false),
+            Field::new("y", DataType::Float32, false),
+            Field::new("z", DataType::Float32, false),
+            Field::new("observed_x", DataType::Float32, false),
+            Field::new("observed_y", DataType::Float32, false),
+            Field::new("observed_z", DataType::Float32, false),
+            Field::new("assignment", DataType::UInt32, false),
+        ]);
 
-        let mut columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
             Arc::new(
-                transcript_names.iter().map(|s| Some(s.clone())).collect::<arrow::array::StringArray>()
+                transcripts.iter().map(|t| t.transcript_id).collect::<arrow::array::UInt64Array>()
             ),
             Arc::new(
-                params
-                    .total_gene_counts
-                    .sum_axis(Axis(1))
+                transcripts
                     .iter()
-                    .map(|x| *x as u64)
-                    .collect::<arrow::array::UInt64Array>()
+                    .map(|t| Some(transcript_names[t.gene as usize].clone()))
+                    .collect::<arrow::array::LargeStringArray>()
             ),
             Arc::new(
-                expected_counts
-                    .sum_axis(Axis(1))
-                    .iter().cloned()
-                    .collect::<arrow::array::Float32Array>()
+                transcript_positions.iter().map(|(x, _, _)| *x).collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                transcript_positions.iter().map(|(_, y, _)| *y).collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                transcript_positions.iter().map(|(_, _, z)| *z).collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                transcripts.iter().map(|t| t.x).collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                transcripts.iter().map(|t| t.y).collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                transcripts.iter().map(|t| t.z).collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                cell_assignments.iter().map(|(cell, _)| *cell).collect::<arrow::array::UInt32Array>()
             ),
-            // Arc::new(array::Float32Array::from_values(
-            //     params.r.iter().cloned(),
-            // ))
         ];
 
-        // cell type dispersions
-        for i in 0..params.ncomponents() {
-            schema_fields.push(Field::new(
-                &format!("dispersion_{}", i),
-                DataType::Float32,
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            columns
+        ).unwrap();
+
+        write_table(
+            output_transcript_positions,
+            output_transcript_positions_fmt,
+            &batch,
+        );
+    }
+}
+
+// Long format table of every cell (or background) each transcript was assigned to
+// while recording samples, with its posterior probability.
+pub fn write_transcript_posterior(
+    output_transcript_posterior: &Option<String>,
+    output_transcript_posterior_fmt: OutputFormat,
+    transcripts: &[Transcript],
+    posterior: &[(usize, u32, f32)],
+) {
+    if let Some(output_transcript_posterior) = output_transcript_posterior {
+        let schema = transcript_posterior_schema();
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(
+                posterior
+                    .iter()
+                    .map(|(i, _, _)| transcripts[*i].transcript_id)
+                    .collect::<arrow::array::UInt64Array>()
+            ),
+            Arc::new(
+                posterior.iter().map(|(_, j, _)| *j).collect::<arrow::array::UInt32Array>()
+            ),
+            Arc::new(
+                posterior.iter().map(|(_, _, pr)| *pr).collect::<arrow::array::Float32Array>()
+            ),
+        ];
+
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            columns
+        ).unwrap();
+
+        write_table(
+            output_transcript_posterior,
+            output_transcript_posterior_fmt,
+            &batch,
+        );
+    }
+}
+
+// Consensus assignment of each transcript when running multiple chains, with the
+// fraction of chains that agree with it.
+pub fn write_chain_agreement(
+    output_chain_agreement: &Option<String>,
+    output_chain_agreement_fmt: OutputFormat,
+    transcripts: &[Transcript],
+    cell_assignments: &[(u32, f32)],
+    agreement: &[f32],
+) {
+    if let Some(output_chain_agreement) = output_chain_agreement {
+        let schema = chain_agreement_schema();
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(
+                transcripts
+                    .iter()
+                    .map(|t| t.transcript_id)
+                    .collect::<arrow::array::UInt64Array>()
+            ),
+            Arc::new(
+                cell_assignments.iter().map(|(j, _)| *j).collect::<arrow::array::UInt32Array>()
+            ),
+            Arc::new(agreement.iter().cloned().collect::<arrow::array::Float32Array>()),
+        ];
+
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            columns
+        ).unwrap();
+
+        write_table(
+            output_chain_agreement,
+            output_chain_agreement_fmt,
+            &batch,
+        );
+    }
+}
+
+pub fn write_diagnostics(
+    output_diagnostics: &Option<String>,
+    output_diagnostics_fmt: OutputFormat,
+    diagnostics: &[IterationDiagnostics],
+) {
+    if let Some(output_diagnostics) = output_diagnostics {
+        let schema = Schema::new(vec![
+            Field::new("chain", DataType::UInt32, false),
+            Field::new("iteration", DataType::UInt32, false),
+            Field::new("phase", DataType::UInt32, false),
+            Field::new("log_likelihood", DataType::Float32, false),
+            Field::new("ncells", DataType::UInt32, false),
+            Field::new("unassigned_fraction", DataType::Float32, false),
+            Field::new("background_fraction", DataType::Float32, false),
+            Field::new("mean_cell_area", DataType::Float32, false),
+            Field::new("mean_compactness", DataType::Float32, false),
+            Field::new("cell_to_cell_acceptance", DataType::Float32, false),
+            Field::new("background_to_cell_acceptance", DataType::Float32, false),
+            Field::new("cell_to_background_acceptance", DataType::Float32, false),
+            Field::new("split_acceptance", DataType::Float32, false),
+            Field::new("merge_acceptance", DataType::Float32, false),
+            Field::new("birth_acceptance", DataType::Float32, false),
+            Field::new("death_acceptance", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(diagnostics.iter().map(|d| d.chain as u32).collect::<arrow::array::UInt32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.iteration as u32).collect::<arrow::array::UInt32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.phase as u32).collect::<arrow::array::UInt32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.log_likelihood).collect::<arrow::array::Float32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.ncells as u32).collect::<arrow::array::UInt32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.unassigned_fraction).collect::<arrow::array::Float32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.background_fraction).collect::<arrow::array::Float32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.mean_cell_area).collect::<arrow::array::Float32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.mean_compactness).collect::<arrow::array::Float32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.acceptance.cell_to_cell).collect::<arrow::array::Float32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.acceptance.background_to_cell).collect::<arrow::array::Float32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.acceptance.cell_to_background).collect::<arrow::array::Float32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.acceptance.split).collect::<arrow::array::Float32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.acceptance.merge).collect::<arrow::array::Float32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.acceptance.birth).collect::<arrow::array::Float32Array>()),
+            Arc::new(diagnostics.iter().map(|d| d.acceptance.death).collect::<arrow::array::Float32Array>()),
+        ];
+
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            columns
+        ).unwrap();
+
+        write_table(
+            output_diagnostics,
+            output_diagnostics_fmt,
+            &batch,
+        );
+    }
+}
+
+// Per-gene summary of the noise model: background and confusion rates, and how
+// many transcripts they account for compared to how many the model expects.
+pub fn write_noise_report(
+    output_noise_report: &Option<String>,
+    output_noise_report_fmt: OutputFormat,
+    params: &ModelParams,
+    transcript_names: &[String],
+) {
+    if let Some(output_noise_report) = output_noise_report {
+        let schema = Schema::new(vec![
+            Field::new("gene", DataType::Utf8, false),
+            Field::new("total_count", DataType::UInt64, false),
+            Field::new("background_rate", DataType::Float32, false),
+            Field::new("expected_background_count", DataType::Float32, false),
+            Field::new("observed_background_count", DataType::UInt64, false),
+            Field::new("confusion_rate", DataType::Float32, false),
+            Field::new("expected_confusion_count", DataType::Float32, false),
+            Field::new("observed_confusion_count", DataType::UInt64, false),
+            Field::new("noise_fraction", DataType::Float32, false),
+        ]);
+
+        let total_counts = params.total_gene_counts.sum_axis(Axis(1));
+        let observed_background_counts = params.background_counts.sum_axis(Axis(1));
+        let total_cell_volume = params.cell_volume.sum();
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(
+                transcript_names.iter().map(|s| Some(s.clone())).collect::<arrow::array::StringArray>()
+            ),
+            Arc::new(
+                total_counts.iter().map(|&x| x as u64).collect::<arrow::array::UInt64Array>()
+            ),
+            Arc::new(
+                params
+                    .λ_bg
+                    .mean_axis(Axis(1))
+                    .unwrap()
+                    .iter()
+                    .cloned()
+                    .collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                params
+                    .λ_bg
+                    .sum_axis(Axis(1))
+                    .iter()
+                    .map(|λ| λ * params.background_volume())
+                    .collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                observed_background_counts
+                    .iter()
+                    .map(|&x| x as u64)
+                    .collect::<arrow::array::UInt64Array>()
+            ),
+            Arc::new(
+                params.λ_c.iter().cloned().collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                params
+                    .λ_c
+                    .iter()
+                    .map(|λ| λ * total_cell_volume)
+                    .collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                params.confusion_counts.iter().map(|&x| x as u64).collect::<arrow::array::UInt64Array>()
+            ),
+            Arc::new(
+                total_counts
+                    .iter()
+                    .zip(&observed_background_counts)
+                    .zip(&params.confusion_counts)
+                    .map(|((&total, &background), &confusion)| {
+                        if total == 0 {
+                            0.0
+                        } else {
+                            (background + confusion) as f32 / total as f32
+                        }
+                    })
+                    .collect::<arrow::array::Float32Array>()
+            ),
+        ];
+
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            columns
+        ).unwrap();
+
+        write_table(
+            output_noise_report,
+            output_noise_report_fmt,
+            &batch,
+        );
+    }
+}
+
+// Transcripts of genes removed by --exclude-genes or --include-genes, e.g.
+// negative control probes, for estimating the background rate independently.
+pub fn write_excluded_genes(
+    output_excluded_genes: &Option<String>,
+    output_excluded_genes_fmt: OutputFormat,
+    excluded_genes: &[ExcludedGene],
+) {
+    if let Some(output_excluded_genes) = output_excluded_genes {
+        let schema = Schema::new(vec![
+            Field::new("gene", DataType::Utf8, false),
+            Field::new("count", DataType::UInt64, false),
+            Field::new("assigned_count", DataType::UInt64, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(
+                excluded_genes.iter().map(|g| Some(g.name.clone())).collect::<arrow::array::StringArray>()
+            ),
+            Arc::new(
+                excluded_genes.iter().map(|g| g.count as u64).collect::<arrow::array::UInt64Array>()
+            ),
+            Arc::new(
+                excluded_genes
+                    .iter()
+                    .map(|g| g.assigned_count as u64)
+                    .collect::<arrow::array::UInt64Array>()
+            ),
+        ];
+
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            columns
+        ).unwrap();
+
+        write_table(
+            output_excluded_genes,
+            output_excluded_genes_fmt,
+            &batch,
+        );
+    }
+}
+
+// Pairs of cells sharing a boundary, with the length of boundary they share.
+// Pairs involving cells removed in QC are left out.
+pub fn write_cell_graph(
+    output_cell_graph: &Option<String>,
+    output_cell_graph_fmt: OutputFormat,
+    adjacency: &[(CellIndex, CellIndex, f32)],
+    qc: &CellQc,
+) {
+    if let Some(output_cell_graph) = output_cell_graph {
+        let adjacency: Vec<(CellIndex, CellIndex, f32)> = adjacency
+            .iter()
+            .map(|&(a, b, length)| (qc.renumber(a), qc.renumber(b), length))
+            .filter(|&(a, b, _)| a != BACKGROUND_CELL && b != BACKGROUND_CELL)
+            .collect();
+
+        let schema = Schema::new(vec![
+            Field::new("cell_a", DataType::UInt32, false),
+            Field::new("cell_b", DataType::UInt32, false),
+            Field::new("boundary_length", DataType::Float32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(adjacency.iter().map(|&(a, _, _)| a).collect::<arrow::array::UInt32Array>()),
+            Arc::new(adjacency.iter().map(|&(_, b, _)| b).collect::<arrow::array::UInt32Array>()),
+            Arc::new(
+                adjacency
+                    .iter()
+                    .map(|&(_, _, length)| length)
+                    .collect::<arrow::array::Float32Array>(),
+            ),
+        ];
+
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            columns
+        ).unwrap();
+
+        write_table(output_cell_graph, output_cell_graph_fmt, &batch);
+    }
+}
+
+// Per matched cell statistics from `proseg compare`.
+pub fn write_comparison(
+    output_comparison: &Option<String>,
+    output_comparison_fmt: OutputFormat,
+    comparison: &Comparison,
+    seg: &Segmentation,
+    truth: &Segmentation,
+) {
+    if let Some(output_comparison) = output_comparison {
+        let schema = Schema::new(vec![
+            Field::new("cell", DataType::Utf8, false),
+            Field::new("truth_cell", DataType::Utf8, false),
+            Field::new("count", DataType::UInt64, false),
+            Field::new("truth_count", DataType::UInt64, false),
+            Field::new("shared", DataType::UInt64, false),
+            Field::new("iou", DataType::Float32, false),
+            Field::new("correlation", DataType::Float32, false),
+        ]);
+
+        let matches = &comparison.matches;
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(
+                matches
+                    .iter()
+                    .map(|m| Some(seg.cell_names[m.cell as usize].clone()))
+                    .collect::<arrow::array::StringArray>(),
+            ),
+            Arc::new(
+                matches
+                    .iter()
+                    .map(|m| Some(truth.cell_names[m.truth_cell as usize].clone()))
+                    .collect::<arrow::array::StringArray>(),
+            ),
+            Arc::new(
+                matches
+                    .iter()
+                    .map(|m| m.count as u64)
+                    .collect::<arrow::array::UInt64Array>(),
+            ),
+            Arc::new(
+                matches
+                    .iter()
+                    .map(|m| m.truth_count as u64)
+                    .collect::<arrow::array::UInt64Array>(),
+            ),
+            Arc::new(
+                matches
+                    .iter()
+                    .map(|m| m.shared as u64)
+                    .collect::<arrow::array::UInt64Array>(),
+            ),
+            Arc::new(
+                matches
+                    .iter()
+                    .map(|m| m.iou)
+                    .collect::<arrow::array::Float32Array>(),
+            ),
+            Arc::new(
+                matches
+                    .iter()
+                    .map(|m| m.correlation)
+                    .collect::<arrow::array::Float32Array>(),
+            ),
+        ];
+
+        let batch = RecordBatch::try_new(Arc::new(schema), columns).unwrap();
+        write_table(output_comparison, output_comparison_fmt, &batch);
+    }
+}
+
+pub fn write_gene_metadata(
+    output_gene_metadata: &Option<String>,
+    output_gene_metadata_fmt: OutputFormat,
+    params: &ModelParams,
+    transcript_names: &[String],
+    gene_features: Option<&GeneFeatures>,
+    expected_counts: &Array2<f32>,
+) {
+    if let Some(output_gene_metadata) = output_gene_metadata {
+        let mut schema_fields = vec![
+            Field::new("gene", DataType::Utf8, false),
+            Field::new("total_count", DataType::UInt64, false),
+            Field::new("expected_assigned_count", DataType::Float32, false),
+            Field::new("mean_expression", DataType::Float32, false),
+            Field::new("dispersion", DataType::Float32, false),
+            Field::new("background_fraction", DataType::Float32, false),
+        ];
+
+        // NB dispersion (r) averaged over components, weighted by the number
+        // of cells in each.
+        let component_population = params.component_population.map(|&p| p as f32);
+        let total_population = component_population.sum().max(1.0);
+        let dispersion = params.r.t().dot(&component_population) / total_population;
+
+        let background_counts = params.background_counts.sum_axis(Axis(1));
+        let total_gene_counts = params.total_gene_counts.sum_axis(Axis(1));
+
+        let mut columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(
+                transcript_names.iter().map(|s| Some(s.clone())).collect::<arrow::array::StringArray>()
+            ),
+            Arc::new(
+                params
+                    .total_gene_counts
+                    .sum_axis(Axis(1))
+                    .iter()
+                    .map(|x| *x as u64)
+                    .collect::<arrow::array::UInt64Array>()
+            ),
+            Arc::new(
+                expected_counts
+                    .sum_axis(Axis(1))
+                    .iter().cloned()
+                    .collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                params
+                    .λ
+                    .mean_axis(Axis(1))
+                    .unwrap()
+                    .iter().cloned()
+                    .collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                dispersion.iter().cloned().collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                background_counts
+                    .iter()
+                    .zip(total_gene_counts.iter())
+                    .map(|(&bg, &total)| if total > 0 { bg as f32 / total as f32 } else { 0.0 })
+                    .collect::<arrow::array::Float32Array>()
+            ),
+        ];
+
+        // ids and types from --gene-panel, after the gene name
+        if let Some(features) = gene_features {
+            schema_fields.insert(1, Field::new("gene_id", DataType::Utf8, false));
+            schema_fields.insert(2, Field::new("feature_type", DataType::Utf8, false));
+            columns.insert(1, Arc::new(arrow::array::StringArray::from(features.gene_ids.clone())));
+            columns.insert(2, Arc::new(arrow::array::StringArray::from(features.feature_types.clone())));
+        }
+
+        // cell type dispersions
+        for i in 0..params.ncomponents() {
+            schema_fields.push(Field::new(
+                &format!("dispersion_{}", i),
+                DataType::Float32,
                 false,
             ));
             columns.push(Arc::new(
@@ -494,6 +1610,16 @@ pub fn write_gene_metadata(
             ));
         }
 
+        // z repositioning
+        schema_fields.push(Field::new("z_offset", DataType::Float32, false));
+        columns.push(Arc::new(
+            params.gene_z_offset.iter().cloned().collect::<arrow::array::Float32Array>()
+        ));
+        schema_fields.push(Field::new("z_sigma", DataType::Float32, false));
+        columns.push(Arc::new(
+            params.gene_σ_z.iter().cloned().collect::<arrow::array::Float32Array>()
+        ));
+
         // background rates
         for i in 0..params.nlayers() {
             schema_fields.push(Field::new(format!("λ_bg_{}", i), DataType::Float32, false));
@@ -520,6 +1646,7 @@ pub fn write_voxels(
     output_voxels: &Option<String>,
     output_voxels_fmt: OutputFormat,
     sampler: &VoxelSampler,
+    qc: Option<&CellQc>,
 ) {
     if let Some(output_voxels) = output_voxels {
         let nvoxels = sampler.voxels().count();
@@ -533,7 +1660,7 @@ pub fn write_voxels(
         let mut z1s = Vec::with_capacity(nvoxels);
 
         for (cell, (x0, y0, z0, x1, y1, z1)) in sampler.voxels() {
-            cells.push(cell);
+            cells.push(qc.map_or(cell, |qc| qc.renumber(cell)));
             x0s.push(x0);
             y0s.push(y0);
             z0s.push(z0);
@@ -571,18 +1698,375 @@ pub fn write_voxels(
     }
 }
 
+// Ambient ("soup") expression profile, estimated from transcripts assigned to
+// the background: each gene's share of background transcripts, and its
+// background rate per unit volume.
+pub fn write_ambient_profile(
+    output_ambient_profile: &Option<String>,
+    output_ambient_profile_fmt: OutputFormat,
+    params: &ModelParams,
+    transcript_names: &[String],
+) {
+    if let Some(output_ambient_profile) = output_ambient_profile {
+        let schema = Schema::new(vec![
+            Field::new("gene", DataType::Utf8, false),
+            Field::new("background_count", DataType::UInt64, false),
+            Field::new("ambient_fraction", DataType::Float32, false),
+            Field::new("ambient_rate", DataType::Float32, false),
+        ]);
+
+        let background_counts = params.background_counts.sum_axis(Axis(1));
+        let total_background_count = background_counts.iter().map(|&x| x as u64).sum::<u64>().max(1);
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(
+                transcript_names.iter().map(|s| Some(s.clone())).collect::<arrow::array::StringArray>()
+            ),
+            Arc::new(
+                background_counts.iter().map(|&x| x as u64).collect::<arrow::array::UInt64Array>()
+            ),
+            Arc::new(
+                background_counts
+                    .iter()
+                    .map(|&x| x as f32 / total_background_count as f32)
+                    .collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                params
+                    .λ_bg
+                    .mean_axis(Axis(1))
+                    .unwrap()
+                    .iter()
+                    .cloned()
+                    .collect::<arrow::array::Float32Array>()
+            ),
+        ];
+
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            columns
+        ).unwrap();
+
+        write_table(
+            output_ambient_profile,
+            output_ambient_profile_fmt,
+            &batch,
+        );
+    }
+}
+
+// Expected counts [ngenes, ncells] less the ambient counts expected in each
+// cell's volume at the background rate, clipped at zero.
+pub fn ambient_corrected_counts(
+    params: &ModelParams,
+    ecounts: &Array2<f32>,
+    cell_volume: &Array1<f32>,
+) -> Array2<f32> {
+    let ambient_rate = params.λ_bg.mean_axis(Axis(1)).unwrap();
+    let mut corrected = ecounts.clone();
+    Zip::from(corrected.rows_mut())
+        .and(&ambient_rate)
+        .for_each(|mut counts, &rate| {
+            Zip::from(&mut counts).and(cell_volume).for_each(|count, &volume| {
+                *count = (*count - rate * volume).max(0.0);
+            });
+        });
+    corrected
+}
+
+// Fitted background rate in each bin of the background grid, if there is one.
+// `background_rate` is the expected number of background transcripts per unit
+// volume, over all genes, averaged over layers.
+pub fn write_background_map(
+    output_background_map: &Option<String>,
+    output_background_map_fmt: OutputFormat,
+    params: &ModelParams,
+) {
+    if let (Some(output_background_map), Some(grid)) =
+        (output_background_map, &params.background_grid)
+    {
+        let nbins = grid.nbins();
+        let mean_rate = params.λ_bg.sum() / params.nlayers() as f32;
+        let bounds: Vec<(f32, f32, f32, f32)> = (0..nbins).map(|bin| grid.bin_bounds(bin)).collect();
+        let tissue = |bin: usize| grid.volume[bin] > 0.0;
+
+        let schema = Schema::new(vec![
+            Field::new("x0", DataType::Float32, false),
+            Field::new("y0", DataType::Float32, false),
+            Field::new("x1", DataType::Float32, false),
+            Field::new("y1", DataType::Float32, false),
+            Field::new("tissue", DataType::Boolean, false),
+            Field::new("scale", DataType::Float32, false),
+            Field::new("background_rate", DataType::Float32, false),
+            Field::new("background_count", DataType::UInt32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(bounds.iter().map(|b| b.0).collect::<arrow::array::Float32Array>()),
+            Arc::new(bounds.iter().map(|b| b.1).collect::<arrow::array::Float32Array>()),
+            Arc::new(bounds.iter().map(|b| b.2).collect::<arrow::array::Float32Array>()),
+            Arc::new(bounds.iter().map(|b| b.3).collect::<arrow::array::Float32Array>()),
+            Arc::new(
+                (0..nbins).map(|bin| Some(tissue(bin))).collect::<arrow::array::BooleanArray>()
+            ),
+            Arc::new(grid.scale.iter().cloned().collect::<arrow::array::Float32Array>()),
+            Arc::new(
+                grid.scale.iter().map(|s| s * mean_rate).collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(grid.counts.iter().cloned().collect::<arrow::array::UInt32Array>()),
+        ];
+
+        let batch = RecordBatch::try_new(Arc::new(schema), columns).unwrap();
+        write_table(output_background_map, output_background_map_fmt, &batch);
+    }
+}
+
+// The voxels ("hexes", as they were in earlier versions) the sampler operates
+// on: every voxel that's assigned to a cell or contains transcripts.
+pub fn write_hexes(
+    output_hexes: &Option<String>,
+    output_hexes_fmt: OutputFormat,
+    sampler: &VoxelSampler,
+    qc: Option<&CellQc>,
+) {
+    if let Some(output_hexes) = output_hexes {
+        let voxels = sampler.voxel_transcript_counts();
+        let nvoxels = voxels.len();
+
+        let mut xs = Vec::with_capacity(nvoxels);
+        let mut ys = Vec::with_capacity(nvoxels);
+        let mut zs = Vec::with_capacity(nvoxels);
+        let mut widths = Vec::with_capacity(nvoxels);
+        let mut heights = Vec::with_capacity(nvoxels);
+        let mut depths = Vec::with_capacity(nvoxels);
+        for (_, (x0, y0, z0, x1, y1, z1), _) in &voxels {
+            xs.push((x0 + x1) / 2.0);
+            ys.push((y0 + y1) / 2.0);
+            zs.push((z0 + z1) / 2.0);
+            widths.push(x1 - x0);
+            heights.push(y1 - y0);
+            depths.push(z1 - z0);
+        }
+
+        let schema = Schema::new(vec![
+            Field::new("x", DataType::Float32, false),
+            Field::new("y", DataType::Float32, false),
+            Field::new("z", DataType::Float32, false),
+            Field::new("width", DataType::Float32, false),
+            Field::new("height", DataType::Float32, false),
+            Field::new("depth", DataType::Float32, false),
+            Field::new("cell", DataType::UInt32, false),
+            Field::new("transcript_count", DataType::UInt32, false),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(arrow::array::Float32Array::from(xs)),
+            Arc::new(arrow::array::Float32Array::from(ys)),
+            Arc::new(arrow::array::Float32Array::from(zs)),
+            Arc::new(arrow::array::Float32Array::from(widths)),
+            Arc::new(arrow::array::Float32Array::from(heights)),
+            Arc::new(arrow::array::Float32Array::from(depths)),
+            Arc::new(
+                voxels
+                    .iter()
+                    .map(|(cell, _, _)| qc.map_or(*cell, |qc| qc.renumber(*cell)))
+                    .collect::<arrow::array::UInt32Array>(),
+            ),
+            Arc::new(voxels.iter().map(|(_, _, count)| *count).collect::<arrow::array::UInt32Array>()),
+        ];
+
+        let batch = RecordBatch::try_new(Arc::new(schema), columns).unwrap();
+        write_table(output_hexes, output_hexes_fmt, &batch);
+    }
+}
+
+// Insert a phase number before the extension of an output filename (e.g.
+// "hexes.csv.gz" to "hexes-phase1.csv.gz").
+pub fn phase_filename(filename: &str, phase: usize) -> String {
+    let name_start = filename.rfind('/').map_or(0, |i| i + 1);
+    let ext_start = filename[name_start..]
+        .find('.')
+        .map_or(filename.len(), |i| name_start + i);
+    format!("{}-phase{}{}", &filename[..ext_start], phase, &filename[ext_start..])
+}
+
 // TODO:
 // If we want to import things into qupath, I think we need a way to scale
 // the coordinates to pixel space. It also doesn't seem like it supports
 // MultiPolygons, so we need to write each polygon in a cell to a separate Polygon entry.
 
+// Open a GeoJSON file for writing, gzipped if the filename ends in ".gz" or
+// zstd compressed if it ends in ".zst". Some viewers (e.g. QuPath) only read
+// uncompressed GeoJSON.
+pub fn geojson_writer(filename: &str) -> Box<dyn Write> {
+    let file = File::create(filename)
+        .unwrap_or_else(|err| panic!("Unable to create '{}': {}", filename, err));
+    if filename.ends_with(".gz") {
+        Box::new(std::io::BufWriter::new(ParallelGzEncoder::new(file)))
+    } else if filename.ends_with(".zst") {
+        Box::new(std::io::BufWriter::new(
+            zstd_encoder(file).unwrap().auto_finish(),
+        ))
+    } else {
+        Box::new(std::io::BufWriter::new(file))
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+// Swap the compression suffix of a CSV or GeoJSON output filename (e.g.
+// "cell-metadata.csv.gz" to "cell-metadata.csv.zst"). Other filenames are
+// returned unchanged.
+pub fn with_compression(filename: &str, compression: OutputCompression) -> String {
+    let base = filename
+        .strip_suffix(".gz")
+        .or_else(|| filename.strip_suffix(".zst"))
+        .unwrap_or(filename);
+    if !(base.ends_with(".csv") || base.ends_with(".tsv") || base.ends_with(".geojson")) {
+        return filename.to_string();
+    }
+    match compression {
+        OutputCompression::None => base.to_string(),
+        OutputCompression::Gzip => format!("{}.gz", base),
+        OutputCompression::Zstd => format!("{}.zst", base),
+    }
+}
+
+// Open a file for reading, decompressing it if it's gzip or zstd compressed.
+// Compression is detected from the first few bytes rather than the filename, so
+// e.g. an uncompressed file named ".csv.gz" is still read correctly.
+pub fn open_decompressed(path: &str) -> std::io::Result<Box<dyn Read>> {
+    const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+    const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let magic = reader.fill_buf()?;
+    if magic.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(MultiGzDecoder::new(reader)))
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        Ok(Box::new(zstd::Decoder::with_buffer(reader)?))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+// Read a whole table written in any of the table output formats, e.g. to
+// convert it to another format. Column types of delimited text tables are
+// inferred.
+pub fn read_table(path: &str, fmt: OutputFormat) -> crate::error::Result<RecordBatch> {
+    use crate::error::Error;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let fmt = match fmt {
+        OutputFormat::Infer => infer_format_from_filename(path),
+        _ => fmt,
+    };
+    let io_error = |source| Error::Io {
+        path: path.to_string(),
+        source,
+    };
+    let arrow_error = |source| Error::Arrow {
+        path: path.to_string(),
+        source,
+    };
+
+    let (schema, batches) = match fmt {
+        OutputFormat::Csv | OutputFormat::CsvGz | OutputFormat::CsvZst => {
+            let delimiter = if is_tsv_filename(path) { b'\t' } else { b',' };
+            let format = csv::reader::Format::default()
+                .with_header(true)
+                .with_delimiter(delimiter);
+            // Inferring the schema consumes the reader, so the file is opened twice.
+            let (schema, _) = format
+                .infer_schema(open_decompressed(path).map_err(io_error)?, None)
+                .map_err(arrow_error)?;
+            let schema = Arc::new(schema);
+            let batches = csv::ReaderBuilder::new(schema.clone())
+                .with_format(format)
+                .build(open_decompressed(path).map_err(io_error)?)
+                .map_err(arrow_error)?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(arrow_error)?;
+            (schema, batches)
+        }
+        OutputFormat::Parquet => {
+            let file = File::open(path).map_err(io_error)?;
+            let parquet_error = |source| Error::Parquet {
+                path: path.to_string(),
+                source,
+            };
+            let builder =
+                ParquetRecordBatchReaderBuilder::try_new(file).map_err(parquet_error)?;
+            let schema = builder.schema().clone();
+            let batches = builder
+                .build()
+                .map_err(parquet_error)?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(arrow_error)?;
+            (schema, batches)
+        }
+        _ => {
+            return Err(Error::UnsupportedFormat {
+                path: path.to_string(),
+                format: format!("{:?}", fmt).to_lowercase(),
+            })
+        }
+    };
+
+    arrow::compute::concat_batches(&schema, &batches).map_err(arrow_error)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PolygonFormat {
+    Infer,
+    Geojson,
+    // Polygons as WKB in a parquet table, along with cell metadata.
+    Geoparquet,
+}
+
+pub fn infer_polygon_format_from_filename(filename: &str) -> PolygonFormat {
+    if filename.ends_with(".parquet") {
+        PolygonFormat::Geoparquet
+    } else {
+        PolygonFormat::Geojson
+    }
+}
+
+// Consensus cell polygons as GeoJSON or GeoParquet. GeoParquet output includes
+// the columns of `cell_metadata`, if given.
+pub fn write_cell_polygons(
+    output_cell_polygons: &Option<String>,
+    output_cell_polygons_fmt: PolygonFormat,
+    polygons: Vec<MultiPolygon<f32>>,
+    cell_metadata: Option<&RecordBatch>,
+) {
+    if let Some(filename) = output_cell_polygons {
+        let fmt = match output_cell_polygons_fmt {
+            PolygonFormat::Infer => infer_polygon_format_from_filename(filename),
+            fmt => fmt,
+        };
+        match fmt {
+            PolygonFormat::Geoparquet => {
+                geoparquet::write_geoparquet(filename, &polygons, cell_metadata)
+            }
+            _ => write_cell_multipolygons(output_cell_polygons, polygons),
+        }
+    }
+}
+
 pub fn write_cell_multipolygons(
     output_cell_polygons: &Option<String>,
     polygons: Vec<MultiPolygon<f32>>,
 ) {
     if let Some(output_cell_polygons) = output_cell_polygons {
-        let file = File::create(output_cell_polygons).unwrap();
-        let mut encoder = GzEncoder::new(file, Compression::default());
+        let mut encoder = geojson_writer(output_cell_polygons);
 
         writeln!(
             encoder,
@@ -610,19 +2094,33 @@ pub fn write_cell_multipolygons(
 
             let npolys = polys.iter().count();
             for (i, poly) in polys.into_iter().enumerate() {
-                writeln!(encoder, concat!("          [\n", "            [")).unwrap();
-
-                let ncoords = poly.exterior().coords().count();
-                for (j, coord) in poly.exterior().coords().enumerate() {
-                    write!(encoder, "              [{}, {}]", coord.x, coord.y).unwrap();
-                    if j < ncoords - 1 {
+                writeln!(encoder, "          [").unwrap();
+
+                // exterior followed by any holes
+                let nrings = 1 + poly.interiors().len();
+                for (k, ring) in std::iter::once(poly.exterior())
+                    .chain(poly.interiors())
+                    .enumerate()
+                {
+                    writeln!(encoder, "            [").unwrap();
+                    let ncoords = ring.coords().count();
+                    for (j, coord) in ring.coords().enumerate() {
+                        write!(encoder, "              [{}, {}]", coord.x, coord.y).unwrap();
+                        if j < ncoords - 1 {
+                            writeln!(encoder, ",").unwrap();
+                        } else {
+                            writeln!(encoder).unwrap();
+                        }
+                    }
+                    write!(encoder, "            ]").unwrap();
+                    if k < nrings - 1 {
                         writeln!(encoder, ",").unwrap();
                     } else {
                         writeln!(encoder).unwrap();
                     }
                 }
 
-                write!(encoder, concat!("            ]\n", "          ]")).unwrap();
+                write!(encoder, "          ]").unwrap();
 
                 if i < npolys - 1 {
                     writeln!(encoder, ",").unwrap();
@@ -643,13 +2141,55 @@ pub fn write_cell_multipolygons(
     }
 }
 
+// The region estimated to be covered by tissue, as a single GeoJSON feature.
+pub fn write_tissue_mask(output_tissue_mask: &Option<String>, polygons: &MultiPolygon<f32>) {
+    if let Some(output_tissue_mask) = output_tissue_mask {
+        let mut encoder = geojson_writer(output_tissue_mask);
+
+        writeln!(
+            encoder,
+            concat!(
+                "{{\n  \"type\": \"FeatureCollection\",\n  \"features\": [\n",
+                "    {{\n",
+                "      \"type\": \"Feature\",\n",
+                "      \"properties\": {{}},\n",
+                "      \"geometry\": {{\n",
+                "        \"type\": \"MultiPolygon\",\n",
+                "        \"coordinates\": ["
+            )
+        )
+        .unwrap();
+
+        let polygons = polygons
+            .iter()
+            .map(|poly| {
+                std::iter::once(poly.exterior())
+                    .chain(poly.interiors())
+                    .map(|ring| {
+                        ring.coords()
+                            .map(|coord| format!("[{}, {}]", coord.x, coord.y))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    })
+                    .map(|ring| format!("[{}]", ring))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .map(|poly| format!("          [{}]", poly))
+            .collect::<Vec<_>>()
+            .join(",\n");
+        writeln!(encoder, "{}", polygons).unwrap();
+
+        writeln!(encoder, "        ]\n      }}\n    }}\n  ]\n}}").unwrap();
+    }
+}
+
 pub fn write_cell_layered_multipolygons(
     output_cell_polygons: &Option<String>,
     polygons: Vec<Vec<(i32, MultiPolygon<f32>)>>,
 ) {
     if let Some(output_cell_polygons) = output_cell_polygons {
-        let file = File::create(output_cell_polygons).unwrap();
-        let mut encoder = GzEncoder::new(file, Compression::default());
+        let mut encoder = geojson_writer(output_cell_polygons);
 
         writeln!(
             encoder,
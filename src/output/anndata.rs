@@ -0,0 +1,143 @@
+// Minimal AnnData (h5ad) writer, following the on-disk format described at
+// https://anndata.readthedocs.io/en/latest/fileformat-prose.html
+
+use hdf5::types::VarLenUnicode;
+use hdf5::{Group, Location};
+use ndarray::Array2;
+
+pub(super) fn varlen(s: &str) -> VarLenUnicode {
+    s.parse::<VarLenUnicode>()
+        .unwrap_or_else(|_| panic!("Invalid string for h5ad: {}", s))
+}
+
+fn write_str_attr(loc: &Location, name: &str, value: &str) -> hdf5::Result<()> {
+    loc.new_attr::<VarLenUnicode>()
+        .shape(())
+        .create(name)?
+        .write_scalar(&varlen(value))
+}
+
+fn write_encoding(loc: &Location, encoding_type: &str, encoding_version: &str) -> hdf5::Result<()> {
+    write_str_attr(loc, "encoding-type", encoding_type)?;
+    write_str_attr(loc, "encoding-version", encoding_version)
+}
+
+fn write_string_array(group: &Group, name: &str, values: &[String]) -> hdf5::Result<()> {
+    let values = values.iter().map(|v| varlen(v)).collect::<Vec<_>>();
+    let dataset = group
+        .new_dataset_builder()
+        .with_data(values.as_slice())
+        .create(name)?;
+    write_encoding(&dataset, "string-array", "0.2.0")
+}
+
+fn write_f32_array(group: &Group, name: &str, values: &[f32]) -> hdf5::Result<()> {
+    let dataset = group.new_dataset_builder().with_data(values).create(name)?;
+    write_encoding(&dataset, "array", "0.2.0")
+}
+
+fn write_u32_array(group: &Group, name: &str, values: &[u32]) -> hdf5::Result<()> {
+    let dataset = group.new_dataset_builder().with_data(values).create(name)?;
+    write_encoding(&dataset, "array", "0.2.0")
+}
+
+// Write a dataframe with a string index and string/f32/u32 columns.
+fn write_dataframe(
+    parent: &Group,
+    name: &str,
+    index_name: &str,
+    index: &[String],
+    string_columns: &[(&str, Vec<String>)],
+    f32_columns: &[(&str, Vec<f32>)],
+    u32_columns: &[(&str, Vec<u32>)],
+) -> hdf5::Result<()> {
+    let group = parent.create_group(name)?;
+    write_encoding(&group, "dataframe", "0.2.0")?;
+    write_str_attr(&group, "_index", index_name)?;
+
+    let column_order = string_columns
+        .iter()
+        .map(|(name, _)| varlen(name))
+        .chain(f32_columns.iter().map(|(name, _)| varlen(name)))
+        .chain(u32_columns.iter().map(|(name, _)| varlen(name)))
+        .collect::<Vec<_>>();
+    if column_order.is_empty() {
+        group
+            .new_attr::<f64>()
+            .shape([0])
+            .create("column-order")?;
+    } else {
+        group
+            .new_attr_builder()
+            .with_data(column_order.as_slice())
+            .create("column-order")?;
+    }
+
+    write_string_array(&group, index_name, index)?;
+    for (name, values) in string_columns {
+        write_string_array(&group, name, values)?;
+    }
+    for (name, values) in f32_columns {
+        write_f32_array(&group, name, values)?;
+    }
+    for (name, values) in u32_columns {
+        write_u32_array(&group, name, values)?;
+    }
+
+    Ok(())
+}
+
+// `counts` is [ngenes, ncells], and is written as a cells-by-genes CSR matrix.
+pub fn write_h5ad(
+    filename: &str,
+    transcript_names: &[String],
+    var_columns: &[(&str, Vec<String>)],
+    counts: &Array2<f32>,
+    obs_f32_columns: &[(&str, Vec<f32>)],
+    obs_u32_columns: &[(&str, Vec<u32>)],
+) -> hdf5::Result<()> {
+    let (ngenes, ncells) = counts.dim();
+    let file = hdf5::File::create(filename)?;
+    write_encoding(&file, "anndata", "0.1.0")?;
+
+    let mut data: Vec<f32> = Vec::new();
+    let mut indices: Vec<i32> = Vec::new();
+    let mut indptr: Vec<i64> = Vec::with_capacity(ncells + 1);
+    indptr.push(0);
+    for cell_counts in counts.columns() {
+        for (gene, &count) in cell_counts.iter().enumerate() {
+            if count > 0.0 {
+                data.push(count);
+                indices.push(gene as i32);
+            }
+        }
+        indptr.push(data.len() as i64);
+    }
+
+    let x = file.create_group("X")?;
+    write_encoding(&x, "csr_matrix", "0.1.0")?;
+    let shape = [ncells as i64, ngenes as i64];
+    x.new_attr_builder()
+        .with_data(&shape[..])
+        .create("shape")?;
+    x.new_dataset_builder()
+        .with_data(data.as_slice())
+        .create("data")?;
+    x.new_dataset_builder()
+        .with_data(indices.as_slice())
+        .create("indices")?;
+    x.new_dataset_builder()
+        .with_data(indptr.as_slice())
+        .create("indptr")?;
+
+    let cell_names = (0..ncells).map(|i| i.to_string()).collect::<Vec<_>>();
+    write_dataframe(&file, "obs", "cell", &cell_names, &[], obs_f32_columns, obs_u32_columns)?;
+    write_dataframe(&file, "var", "gene", transcript_names, var_columns, &[], &[])?;
+
+    for name in ["layers", "obsm", "obsp", "varm", "varp", "uns"] {
+        let group = file.create_group(name)?;
+        write_encoding(&group, "dict", "0.1.0")?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,80 @@
+// Cell names that stay the same between runs, unlike cell indices, so outputs
+// can be joined across re-runs and with vendor outputs.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CellIdScheme {
+    // No names, only cell indices.
+    Index,
+    // "{sample}-{z-order code}", from the centroid quantized to a grid.
+    Centroid,
+    // The input cell id the cell has most transcripts from, or a centroid
+    // name for cells without one.
+    Original,
+}
+
+// Interleave the bits of x and y (a Morton or z-order code), so that nearby
+// grid cells tend to have nearby codes.
+fn zorder(x: u32, y: u32) -> u64 {
+    let spread = |v: u32| {
+        let mut v = v as u64;
+        v = (v | (v << 16)) & 0x0000_ffff_0000_ffff;
+        v = (v | (v << 8)) & 0x00ff_00ff_00ff_00ff;
+        v = (v | (v << 4)) & 0x0f0f_0f0f_0f0f_0f0f;
+        v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+        v = (v | (v << 1)) & 0x5555_5555_5555_5555;
+        v
+    };
+    spread(x) | (spread(y) << 1)
+}
+
+fn centroid_id(sample: Option<&str>, x: f32, y: f32, resolution: f32) -> String {
+    // offset so negative coordinates quantize to distinct codes
+    let quantize = |v: f32| ((v / resolution).floor() as i64 + (1 << 31)).clamp(0, u32::MAX as i64) as u32;
+    let code = zorder(quantize(x), quantize(y));
+    match sample {
+        Some(sample) => format!("{}-{:016x}", sample, code),
+        None => format!("{:016x}", code),
+    }
+}
+
+// Names of each cell under `scheme`, or None for `CellIdScheme::Index`. Cells
+// whose names would collide are given "-2", "-3", etc. suffixes, in order of
+// cell index.
+pub fn cell_ids(
+    scheme: CellIdScheme,
+    resolution: f32,
+    cell_centroids: &[(f32, f32, f32)],
+    cell_samples: &[Option<&str>],
+    cell_original_ids: &[Option<&str>],
+) -> Option<Vec<String>> {
+    if scheme == CellIdScheme::Index {
+        return None;
+    }
+
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    Some(
+        cell_centroids
+            .iter()
+            .zip(cell_samples)
+            .zip(cell_original_ids)
+            .map(|((&(x, y, _), &sample), &original_id)| {
+                let id = match (scheme, original_id) {
+                    (CellIdScheme::Original, Some(original_id)) => original_id.to_string(),
+                    _ => centroid_id(sample, x, y, resolution),
+                };
+                let count = seen.entry(id.clone()).or_insert(0);
+                *count += 1;
+                if *count > 1 {
+                    format!("{}-{}", id, count)
+                } else {
+                    id
+                }
+            })
+            .collect(),
+    )
+}
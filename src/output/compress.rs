@@ -0,0 +1,117 @@
+// Compressed writers for text outputs, spreading compression over the rayon
+// thread pool. Gzip output is written as a series of independently compressed
+// members (as pigz does), which gzip readers decompress as a single stream.
+// Zstd output uses zstd's own worker threads, where there are threads (not on
+// wasm32).
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rayon::prelude::*;
+use std::io::{self, Write};
+
+// Bytes of input compressed into each gzip member. Blocks this large lose
+// very little compression to being compressed independently.
+const GZIP_BLOCK_SIZE: usize = 1 << 22;
+
+pub struct ParallelGzEncoder<W: Write> {
+    // None once finished
+    output: Option<W>,
+
+    // input not yet compressed, up to one block per thread
+    buffer: Vec<u8>,
+    capacity: usize,
+
+    // whether any member has been written, since even empty output needs one
+    written: bool,
+}
+
+impl<W: Write> ParallelGzEncoder<W> {
+    pub fn new(output: W) -> Self {
+        let capacity = rayon::current_num_threads().max(1) * GZIP_BLOCK_SIZE;
+        ParallelGzEncoder {
+            output: Some(output),
+            buffer: Vec::with_capacity(capacity),
+            capacity,
+            written: false,
+        }
+    }
+
+    fn compress_buffer(&mut self) -> io::Result<()> {
+        let members = if self.buffer.is_empty() {
+            vec![compress_block(&[])]
+        } else {
+            self.buffer
+                .par_chunks(GZIP_BLOCK_SIZE)
+                .map(compress_block)
+                .collect::<Vec<_>>()
+        };
+
+        let output = self.output.as_mut().unwrap();
+        for member in members {
+            output.write_all(&member?)?;
+        }
+        self.buffer.clear();
+        self.written = true;
+        Ok(())
+    }
+
+    fn try_finish(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() || !self.written {
+            self.compress_buffer()?;
+        }
+        self.output.as_mut().unwrap().flush()
+    }
+
+    // Compress any remaining input and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.try_finish()?;
+        Ok(self.output.take().unwrap())
+    }
+}
+
+fn compress_block(block: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::with_capacity(block.len() / 2), Compression::default());
+    encoder.write_all(block)?;
+    encoder.finish()
+}
+
+impl<W: Write> Write for ParallelGzEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.capacity - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == self.capacity {
+            self.compress_buffer()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.compress_buffer()?;
+        }
+        self.output.as_mut().unwrap().flush()
+    }
+}
+
+// Like flate2's encoders, finish on drop, ignoring errors. Call `finish` to see
+// them.
+impl<W: Write> Drop for ParallelGzEncoder<W> {
+    fn drop(&mut self) {
+        if self.output.is_some() {
+            let _ = self.try_finish();
+        }
+    }
+}
+
+pub fn zstd_encoder<W: Write>(output: W) -> io::Result<zstd::Encoder<'static, W>> {
+    #[allow(unused_mut)]
+    let mut encoder = zstd::Encoder::new(output, 0)?;
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let nthreads = rayon::current_num_threads();
+        if nthreads > 1 {
+            encoder.multithread(nthreads as u32)?;
+        }
+    }
+    Ok(encoder)
+}
@@ -0,0 +1,190 @@
+// How far transcripts were observed from the cells they're assigned to. The
+// model lets transcripts be claimed by nearby cells, so genes that tend to be
+// found outside their cells (e.g. highly expressed secreted genes) can leak
+// into neighbors' counts. These outputs are for finding such genes.
+
+use arrow::array::RecordBatch;
+use arrow::datatypes::{DataType, Field, Schema};
+use geo::{EuclideanDistance, Intersects, MultiPolygon, Point};
+use rayon::prelude::*;
+use std::sync::Arc;
+
+use super::{write_table, OutputFormat};
+use crate::sampler::transcripts::{Transcript, BACKGROUND_CELL};
+
+pub struct TranscriptDiffusion {
+    // distance in x and y between the observed position and the expected
+    // position under the model's diffusion process
+    pub diffusion_distance: f32,
+
+    // distance from the observed position to the boundary of the assigned
+    // cell's polygon, negative inside the cell and positive outside, or None
+    // for unassigned transcripts and cells without a polygon
+    pub distance_to_cell_boundary: Option<f32>,
+}
+
+pub fn transcript_diffusion(
+    transcripts: &[Transcript],
+    transcript_positions: &[(f32, f32, f32)],
+    cell_assignments: &[(u32, f32)],
+    cell_polygons: &[MultiPolygon<f32>],
+) -> Vec<TranscriptDiffusion> {
+    transcripts
+        .par_iter()
+        .zip(transcript_positions)
+        .zip(cell_assignments)
+        .map(|((t, &(x, y, _)), &(cell, _))| {
+            let distance_to_cell_boundary = if cell == BACKGROUND_CELL {
+                None
+            } else {
+                cell_polygons
+                    .get(cell as usize)
+                    .and_then(|polygon| signed_boundary_distance(polygon, Point::new(t.x, t.y)))
+            };
+            TranscriptDiffusion {
+                diffusion_distance: (x - t.x).hypot(y - t.y),
+                distance_to_cell_boundary,
+            }
+        })
+        .collect()
+}
+
+fn signed_boundary_distance(polygon: &MultiPolygon<f32>, point: Point<f32>) -> Option<f32> {
+    let distance = polygon
+        .iter()
+        .flat_map(|poly| std::iter::once(poly.exterior()).chain(poly.interiors()))
+        .map(|ring| point.euclidean_distance(ring))
+        .reduce(f32::min)?;
+    if polygon.intersects(&point) {
+        Some(-distance)
+    } else {
+        Some(distance)
+    }
+}
+
+pub fn write_transcript_diffusion(
+    output_transcript_diffusion: &Option<String>,
+    output_transcript_diffusion_fmt: OutputFormat,
+    transcripts: &[Transcript],
+    transcript_names: &[String],
+    cell_assignments: &[(u32, f32)],
+    diffusion: &[TranscriptDiffusion],
+) {
+    if let Some(output_transcript_diffusion) = output_transcript_diffusion {
+        let schema = Schema::new(vec![
+            Field::new("transcript_id", DataType::UInt64, false),
+            Field::new("gene", DataType::LargeUtf8, false),
+            Field::new("assignment", DataType::UInt32, false),
+            Field::new("diffusion_distance", DataType::Float32, false),
+            Field::new("distance_to_cell_boundary", DataType::Float32, true),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(
+                transcripts.iter().map(|t| t.transcript_id).collect::<arrow::array::UInt64Array>()
+            ),
+            Arc::new(
+                transcripts
+                    .iter()
+                    .map(|t| Some(transcript_names[t.gene as usize].clone()))
+                    .collect::<arrow::array::LargeStringArray>()
+            ),
+            Arc::new(
+                cell_assignments.iter().map(|(cell, _)| *cell).collect::<arrow::array::UInt32Array>()
+            ),
+            Arc::new(
+                diffusion.iter().map(|d| d.diffusion_distance).collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                diffusion
+                    .iter()
+                    .map(|d| d.distance_to_cell_boundary)
+                    .collect::<arrow::array::Float32Array>()
+            ),
+        ];
+
+        let batch = RecordBatch::try_new(Arc::new(schema), columns).unwrap();
+        write_table(output_transcript_diffusion, output_transcript_diffusion_fmt, &batch);
+    }
+}
+
+// Per-gene summaries of transcript diffusion. Genes with a high fraction of
+// assigned transcripts observed outside their cell are the likeliest to be
+// contaminating their neighbors' counts.
+pub fn write_gene_diffusion(
+    output_gene_diffusion: &Option<String>,
+    output_gene_diffusion_fmt: OutputFormat,
+    transcripts: &[Transcript],
+    transcript_names: &[String],
+    cell_assignments: &[(u32, f32)],
+    diffusion: &[TranscriptDiffusion],
+) {
+    if let Some(output_gene_diffusion) = output_gene_diffusion {
+        let ngenes = transcript_names.len();
+        let mut total_count = vec![0_u64; ngenes];
+        let mut assigned_count = vec![0_u64; ngenes];
+        let mut diffusion_distance_sum = vec![0_f64; ngenes];
+        let mut measured_count = vec![0_u64; ngenes];
+        let mut outside_count = vec![0_u64; ngenes];
+        let mut outside_distance_sum = vec![0_f64; ngenes];
+
+        for ((t, &(cell, _)), d) in transcripts.iter().zip(cell_assignments).zip(diffusion) {
+            let gene = t.gene as usize;
+            total_count[gene] += 1;
+            diffusion_distance_sum[gene] += d.diffusion_distance as f64;
+            if cell != BACKGROUND_CELL {
+                assigned_count[gene] += 1;
+            }
+            if let Some(distance) = d.distance_to_cell_boundary {
+                measured_count[gene] += 1;
+                if distance > 0.0 {
+                    outside_count[gene] += 1;
+                    outside_distance_sum[gene] += distance as f64;
+                }
+            }
+        }
+
+        let ratio = |numer: f64, denom: u64| {
+            if denom > 0 {
+                Some((numer / denom as f64) as f32)
+            } else {
+                None
+            }
+        };
+
+        let schema = Schema::new(vec![
+            Field::new("gene", DataType::Utf8, false),
+            Field::new("total_count", DataType::UInt64, false),
+            Field::new("assigned_count", DataType::UInt64, false),
+            Field::new("mean_diffusion_distance", DataType::Float32, true),
+            Field::new("outside_cell_fraction", DataType::Float32, true),
+            Field::new("mean_distance_outside_cell", DataType::Float32, true),
+        ]);
+
+        let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+            Arc::new(
+                transcript_names.iter().map(|s| Some(s.clone())).collect::<arrow::array::StringArray>()
+            ),
+            Arc::new(arrow::array::UInt64Array::from(total_count.clone())),
+            Arc::new(arrow::array::UInt64Array::from(assigned_count)),
+            Arc::new(
+                (0..ngenes)
+                    .map(|gene| ratio(diffusion_distance_sum[gene], total_count[gene]))
+                    .collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                (0..ngenes)
+                    .map(|gene| ratio(outside_count[gene] as f64, measured_count[gene]))
+                    .collect::<arrow::array::Float32Array>()
+            ),
+            Arc::new(
+                (0..ngenes)
+                    .map(|gene| ratio(outside_distance_sum[gene], outside_count[gene]))
+                    .collect::<arrow::array::Float32Array>()
+            ),
+        ];
+
+        let batch = RecordBatch::try_new(Arc::new(schema), columns).unwrap();
+        write_table(output_gene_diffusion, output_gene_diffusion_fmt, &batch);
+    }
+}
@@ -0,0 +1,117 @@
+// Cell polygons as GeoParquet: a parquet table with one row per cell and its
+// polygons encoded as WKB (well-known binary) in a "geometry" column, which
+// geopandas and similar tools read much faster than GeoJSON. SpatialData
+// shapes are stored the same way.
+
+use arrow::array::{Array, BinaryArray, RecordBatch, UInt32Array};
+use arrow::datatypes::{DataType, Field, Schema};
+use geo::{LineString, MultiPolygon};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression::ZSTD, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use parquet::format::KeyValue;
+use std::fs::File;
+use std::sync::Arc;
+
+// WKB geometry type codes
+const WKB_POLYGON: u32 = 3;
+const WKB_MULTIPOLYGON: u32 = 6;
+
+fn write_wkb_header(wkb: &mut Vec<u8>, geometry_type: u32) {
+    // little endian
+    wkb.push(1);
+    wkb.extend_from_slice(&geometry_type.to_le_bytes());
+}
+
+fn write_wkb_ring(wkb: &mut Vec<u8>, ring: &LineString<f32>) {
+    wkb.extend_from_slice(&(ring.0.len() as u32).to_le_bytes());
+    for coord in ring.coords() {
+        wkb.extend_from_slice(&(coord.x as f64).to_le_bytes());
+        wkb.extend_from_slice(&(coord.y as f64).to_le_bytes());
+    }
+}
+
+pub fn multipolygon_wkb(polygons: &MultiPolygon<f32>) -> Vec<u8> {
+    let mut wkb = Vec::new();
+    write_wkb_header(&mut wkb, WKB_MULTIPOLYGON);
+    wkb.extend_from_slice(&(polygons.0.len() as u32).to_le_bytes());
+    for polygon in polygons {
+        write_wkb_header(&mut wkb, WKB_POLYGON);
+        wkb.extend_from_slice(&(1 + polygon.interiors().len() as u32).to_le_bytes());
+        write_wkb_ring(&mut wkb, polygon.exterior());
+        for ring in polygon.interiors() {
+            write_wkb_ring(&mut wkb, ring);
+        }
+    }
+    wkb
+}
+
+pub fn geometry_field() -> Field {
+    Field::new("geometry", DataType::Binary, false)
+}
+
+pub fn geometry_column(polygons: &[MultiPolygon<f32>]) -> BinaryArray {
+    BinaryArray::from_iter_values(polygons.iter().map(multipolygon_wkb))
+}
+
+// Writer properties for a table with a `geometry_column`, declaring it in the
+// GeoParquet metadata.
+pub fn geoparquet_properties() -> WriterProperties {
+    // Coordinates are in the same units as the transcripts (usually microns),
+    // not longitude and latitude, so the CRS is given as unknown (null) rather
+    // than left to default to OGC:CRS84.
+    let geo_metadata = concat!(
+        "{\"version\": \"1.0.0\", \"primary_column\": \"geometry\", ",
+        "\"columns\": {\"geometry\": {\"encoding\": \"WKB\", ",
+        "\"geometry_types\": [\"MultiPolygon\"], \"crs\": null}}}"
+    );
+
+    WriterProperties::builder()
+        .set_compression(ZSTD(ZstdLevel::try_new(3).unwrap()))
+        .set_key_value_metadata(Some(vec![KeyValue::new(
+            "geo".to_string(),
+            geo_metadata.to_string(),
+        )]))
+        .build()
+}
+
+// Write polygons with the columns of `cell_metadata` (which must have a row for
+// each cell), or just a "cell" column without it.
+pub fn write_geoparquet(
+    filename: &str,
+    polygons: &[MultiPolygon<f32>],
+    cell_metadata: Option<&RecordBatch>,
+) {
+    let (mut fields, mut columns): (Vec<Field>, Vec<Arc<dyn Array>>) = match cell_metadata {
+        Some(cell_metadata) => {
+            assert_eq!(cell_metadata.num_rows(), polygons.len());
+            (
+                cell_metadata
+                    .schema()
+                    .fields()
+                    .iter()
+                    .map(|field| field.as_ref().clone())
+                    .collect(),
+                cell_metadata.columns().to_vec(),
+            )
+        }
+        None => (
+            vec![Field::new("cell", DataType::UInt32, false)],
+            vec![Arc::new(
+                (0..polygons.len() as u32).collect::<UInt32Array>(),
+            )],
+        ),
+    };
+
+    fields.push(geometry_field());
+    columns.push(Arc::new(geometry_column(polygons)));
+
+    let batch = RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).unwrap();
+
+    let file = File::create(filename)
+        .unwrap_or_else(|err| panic!("Unable to create '{}': {}", filename, err));
+    let mut writer =
+        ArrowWriter::try_new(file, batch.schema(), Some(geoparquet_properties())).unwrap();
+    writer.write(&batch).unwrap();
+    writer.close().unwrap();
+}
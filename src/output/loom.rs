@@ -0,0 +1,66 @@
+// Minimal loom writer, following the file format described at
+// https://linnarssonlab.org/loompy/format/
+
+use hdf5::types::VarLenUnicode;
+use hdf5::{Group, H5Type};
+use ndarray::Array2;
+
+use super::anndata::varlen;
+
+fn write_string_array(group: &Group, name: &str, values: &[String]) -> hdf5::Result<()> {
+    let values = values.iter().map(|v| varlen(v)).collect::<Vec<_>>();
+    group
+        .new_dataset_builder()
+        .with_data(values.as_slice())
+        .create(name)?;
+    Ok(())
+}
+
+fn write_f32_array(group: &Group, name: &str, values: &[f32]) -> hdf5::Result<()> {
+    group.new_dataset_builder().with_data(values).create(name)?;
+    Ok(())
+}
+
+// `counts` is [ngenes, ncells], which is the orientation loom uses for its
+// main matrix. Cells are given by index, with their centroids as column
+// attributes.
+pub fn write_loom<T: H5Type>(
+    filename: &str,
+    transcript_names: &[String],
+    counts: &Array2<T>,
+    cell_centroids: &[(f32, f32, f32)],
+) -> hdf5::Result<()> {
+    let ncells = counts.ncols();
+    let file = hdf5::File::create(filename)?;
+
+    let attrs = file.create_group("attrs")?;
+    attrs
+        .new_dataset::<VarLenUnicode>()
+        .shape(())
+        .create("LOOM_SPEC_VERSION")?
+        .write_scalar(&varlen("3.0.0"))?;
+
+    let matrix = counts.as_standard_layout();
+    file.new_dataset_builder()
+        .with_data(matrix.view())
+        .create("matrix")?;
+
+    let row_attrs = file.create_group("row_attrs")?;
+    write_string_array(&row_attrs, "Gene", transcript_names)?;
+
+    let col_attrs = file.create_group("col_attrs")?;
+    let cell_names = (0..ncells).map(|i| i.to_string()).collect::<Vec<_>>();
+    write_string_array(&col_attrs, "CellID", &cell_names)?;
+    let x = cell_centroids.iter().map(|c| c.0).collect::<Vec<_>>();
+    let y = cell_centroids.iter().map(|c| c.1).collect::<Vec<_>>();
+    let z = cell_centroids.iter().map(|c| c.2).collect::<Vec<_>>();
+    write_f32_array(&col_attrs, "X", &x)?;
+    write_f32_array(&col_attrs, "Y", &y)?;
+    write_f32_array(&col_attrs, "Z", &z)?;
+
+    for name in ["layers", "row_graphs", "col_graphs"] {
+        file.create_group(name)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,84 @@
+// Record of how a run was made (--output-run-manifest): the version of proseg,
+// options, priors, and checksums of the inputs, so results can be traced back
+// to what produced them.
+
+use flate2::Crc;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use super::super::error::{Error, Result};
+use super::super::sampler::controls::NegativeControlSummary;
+use super::super::sampler::ModelPriors;
+
+#[derive(Serialize)]
+pub struct InputFile {
+    pub path: String,
+    pub size: u64,
+    pub crc32: String,
+}
+
+impl InputFile {
+    pub fn read(path: &str) -> Result<InputFile> {
+        let io_error = |source| Error::Io {
+            path: path.to_string(),
+            source,
+        };
+        let mut reader = BufReader::new(File::open(path).map_err(io_error)?);
+        let mut crc = Crc::new();
+        // counted here, since `Crc::amount` wraps at 4GB
+        let mut size: u64 = 0;
+        let mut buf = vec![0_u8; 1 << 20];
+        loop {
+            let n = reader.read(&mut buf).map_err(io_error)?;
+            if n == 0 {
+                break;
+            }
+            crc.update(&buf[..n]);
+            size += n as u64;
+        }
+        Ok(InputFile {
+            path: path.to_string(),
+            size,
+            crc32: format!("{:08x}", crc.sum()),
+        })
+    }
+}
+
+// Wall clock time of each part of the run, in seconds.
+#[derive(Serialize)]
+pub struct RunTiming {
+    pub started: u64,
+    pub reading: f64,
+    pub sampling: f64,
+    pub output: f64,
+    pub total: f64,
+}
+
+#[derive(Serialize)]
+pub struct RunManifest<'a, A: Serialize> {
+    pub proseg_version: &'static str,
+    pub command_line: Vec<String>,
+    // every option, after presets and defaults are applied
+    pub arguments: &'a A,
+    pub seed: Option<u64>,
+    pub schedule: &'a [usize],
+    // absent with --partition-fovs, where each FOV has its own
+    pub priors: Option<&'a ModelPriors>,
+    // with --negative-controls
+    pub negative_controls: Option<&'a NegativeControlSummary>,
+    pub inputs: Vec<InputFile>,
+    pub timing: RunTiming,
+}
+
+pub fn write_run_manifest<A: Serialize>(path: &str, manifest: &RunManifest<A>) -> Result<()> {
+    let io_error = |source| Error::Io {
+        path: path.to_string(),
+        source,
+    };
+    let mut writer = BufWriter::new(File::create(path).map_err(io_error)?);
+    serde_json::to_writer_pretty(&mut writer, manifest)
+        .map_err(|err| io_error(std::io::Error::other(err)))?;
+    writeln!(writer).map_err(io_error)?;
+    writer.flush().map_err(io_error)
+}
@@ -0,0 +1,258 @@
+// Post-sampling QC of cells. Cells left with no voxels (by merges, or never
+// used after being allocated for new cells), or with too few transcripts or
+// too small an area, are dropped from every output: their transcripts are
+// reported as unassigned, and the remaining cells are renumbered so outputs
+// stay aligned with one another.
+
+use ndarray::{Array1, Array2, Axis};
+
+use crate::sampler::transcripts::{CellIndex, BACKGROUND_CELL};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QcFlag {
+    Empty,
+    FewTranscripts,
+    SmallArea,
+}
+
+impl QcFlag {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QcFlag::Empty => "empty",
+            QcFlag::FewTranscripts => "few_transcripts",
+            QcFlag::SmallArea => "small_area",
+        }
+    }
+}
+
+pub struct CellQc {
+    // [ncells] why each cell was removed, if it was
+    flags: Vec<Option<QcFlag>>,
+
+    // [ncells] index of each cell after removing cells, or BACKGROUND_CELL
+    cell_map: Vec<CellIndex>,
+
+    // indices of the cells that were kept
+    kept: Vec<usize>,
+}
+
+impl CellQc {
+    // Flag cells given which have no voxels, and their maximum posterior counts
+    // ([ngenes, ncells]) and areas. A threshold of 0 disables that check.
+    pub fn new(
+        empty: &[bool],
+        counts: &Array2<u32>,
+        cell_areas: &[f32],
+        min_transcripts: usize,
+        min_area: f32,
+    ) -> Self {
+        let flags: Vec<Option<QcFlag>> = counts
+            .columns()
+            .into_iter()
+            .zip(cell_areas)
+            .zip(empty)
+            .map(|((cell_counts, &area), &empty)| {
+                if empty {
+                    Some(QcFlag::Empty)
+                } else if (cell_counts.sum() as usize) < min_transcripts {
+                    Some(QcFlag::FewTranscripts)
+                } else if area < min_area {
+                    Some(QcFlag::SmallArea)
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        let mut cell_map = vec![BACKGROUND_CELL; flags.len()];
+        let mut kept = Vec::new();
+        for (cell, flag) in flags.iter().enumerate() {
+            if flag.is_none() {
+                cell_map[cell] = kept.len() as CellIndex;
+                kept.push(cell);
+            }
+        }
+
+        CellQc {
+            flags,
+            cell_map,
+            kept,
+        }
+    }
+
+    // Keep every cell.
+    pub fn none(ncells: usize) -> Self {
+        CellQc {
+            flags: vec![None; ncells],
+            cell_map: (0..ncells as CellIndex).collect(),
+            kept: (0..ncells).collect(),
+        }
+    }
+
+    pub fn nremoved(&self) -> usize {
+        self.flags.len() - self.kept.len()
+    }
+
+    pub fn report(&self) {
+        if self.nremoved() == 0 {
+            return;
+        }
+        let count = |flag| self.flags.iter().filter(|&&f| f == Some(flag)).count();
+        println!(
+            "Removed {} cells in QC ({} empty, {} with too few transcripts, {} too small)",
+            self.nremoved(),
+            count(QcFlag::Empty),
+            count(QcFlag::FewTranscripts),
+            count(QcFlag::SmallArea)
+        );
+    }
+
+    // New index of a cell, or BACKGROUND_CELL if it was removed.
+    pub fn renumber(&self, cell: CellIndex) -> CellIndex {
+        if cell == BACKGROUND_CELL {
+            BACKGROUND_CELL
+        } else {
+            self.cell_map[cell as usize]
+        }
+    }
+
+    // Per-cell values for the cells that were kept.
+    pub fn select<T: Clone>(&self, values: &[T]) -> Vec<T> {
+        self.kept.iter().map(|&cell| values[cell].clone()).collect()
+    }
+
+    pub fn select_array<T: Clone>(&self, values: &Array1<T>) -> Array1<T> {
+        values.select(Axis(0), &self.kept)
+    }
+
+    // Columns of a [ngenes, ncells] matrix for the cells that were kept.
+    pub fn select_columns<T: Clone>(&self, values: &Array2<T>) -> Array2<T> {
+        values.select(Axis(1), &self.kept)
+    }
+
+    // Transcript assignments with removed cells' transcripts unassigned and
+    // the remaining cells renumbered.
+    pub fn cell_assignments(&self, cell_assignments: &[(CellIndex, f32)]) -> Vec<(CellIndex, f32)> {
+        cell_assignments
+            .iter()
+            .map(|&(cell, pr)| (self.renumber(cell), pr))
+            .collect()
+    }
+
+    // Transcript assignments with removed cells' transcripts unassigned, but
+    // keeping the original numbering, for looking up model parameters.
+    pub fn unassign_removed(&self, cell_assignments: &[(CellIndex, f32)]) -> Vec<(CellIndex, f32)> {
+        cell_assignments
+            .iter()
+            .map(|&(cell, pr)| {
+                if self.renumber(cell) == BACKGROUND_CELL {
+                    (BACKGROUND_CELL, pr)
+                } else {
+                    (cell, pr)
+                }
+            })
+            .collect()
+    }
+
+    // For each transcript, why the cell it was assigned to was removed, if it
+    // was.
+    pub fn transcript_flags(&self, cell_assignments: &[(CellIndex, f32)]) -> Vec<Option<&'static str>> {
+        cell_assignments
+            .iter()
+            .map(|&(cell, _)| {
+                if cell == BACKGROUND_CELL {
+                    None
+                } else {
+                    self.flags[cell as usize].map(|flag| flag.as_str())
+                }
+            })
+            .collect()
+    }
+
+    // (transcript, cell, probability) posteriors with removed cells merged
+    // into the background, ordered as before by transcript and decreasing
+    // probability.
+    pub fn posterior(&self, posterior: &[(usize, CellIndex, f32)]) -> Vec<(usize, CellIndex, f32)> {
+        let mut posterior: Vec<(usize, CellIndex, f32)> = posterior
+            .iter()
+            .map(|&(i, cell, pr)| (i, self.renumber(cell), pr))
+            .collect();
+        posterior.sort_by_key(|&(i, cell, _)| (i, cell));
+        posterior.dedup_by(|a, b| {
+            if a.0 == b.0 && a.1 == b.1 {
+                b.2 += a.2;
+                true
+            } else {
+                false
+            }
+        });
+        posterior.sort_by(|(i_a, j_a, pr_a), (i_b, j_b, pr_b)| {
+            i_a.cmp(i_b)
+                .then(pr_b.total_cmp(pr_a))
+                .then(j_a.cmp(j_b))
+        });
+        posterior
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn removed_cells_are_renumbered_consistently() {
+        // cells 0 and 2 pass, 1 has too few transcripts, 3 is too small, and 4 is empty
+        let counts = array![[5, 0, 3, 4, 0], [5, 1, 3, 4, 0]];
+        let areas = [10.0, 10.0, 10.0, 1.0, 10.0];
+        let empty = [false, false, false, false, true];
+        let qc = CellQc::new(&empty, &counts, &areas, 5, 2.0);
+
+        assert_eq!(qc.nremoved(), 3);
+        assert_eq!(
+            (0..5).map(|cell| qc.renumber(cell)).collect::<Vec<_>>(),
+            vec![0, BACKGROUND_CELL, 1, BACKGROUND_CELL, BACKGROUND_CELL]
+        );
+        assert_eq!(qc.renumber(BACKGROUND_CELL), BACKGROUND_CELL);
+        assert_eq!(qc.select(&["a", "b", "c", "d", "e"]), vec!["a", "c"]);
+        assert_eq!(qc.select_columns(&counts), array![[5, 3], [5, 3]]);
+
+        let assignments = [(0, 0.9), (1, 0.8), (BACKGROUND_CELL, 0.7), (2, 0.6), (3, 0.5)];
+        assert_eq!(
+            qc.cell_assignments(&assignments),
+            vec![(0, 0.9), (BACKGROUND_CELL, 0.8), (BACKGROUND_CELL, 0.7), (1, 0.6), (BACKGROUND_CELL, 0.5)]
+        );
+        assert_eq!(
+            qc.unassign_removed(&assignments),
+            vec![(0, 0.9), (BACKGROUND_CELL, 0.8), (BACKGROUND_CELL, 0.7), (2, 0.6), (BACKGROUND_CELL, 0.5)]
+        );
+        assert_eq!(
+            qc.transcript_flags(&assignments),
+            vec![None, Some("few_transcripts"), None, None, Some("small_area")]
+        );
+    }
+
+    #[test]
+    fn posterior_merges_removed_cells_into_background() {
+        let counts = array![[5, 0, 5]];
+        let qc = CellQc::new(&[false; 3], &counts, &[1.0; 3], 5, 0.0);
+        let posterior = [
+            (0, 1, 0.5),
+            (0, BACKGROUND_CELL, 0.25),
+            (0, 0, 0.25),
+            (1, 0, 0.375),
+            (1, 2, 0.625),
+        ];
+        assert_eq!(
+            qc.posterior(&posterior),
+            vec![(0, BACKGROUND_CELL, 0.75), (0, 0, 0.25), (1, 1, 0.625), (1, 0, 0.375)]
+        );
+    }
+
+    #[test]
+    fn none_keeps_every_cell() {
+        let qc = CellQc::none(3);
+        assert_eq!(qc.nremoved(), 0);
+        assert_eq!((0..3).map(|cell| qc.renumber(cell)).collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
+}
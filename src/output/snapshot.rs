@@ -0,0 +1,130 @@
+// PNG images of the current segmentation (--render-snapshots), for a quick look
+// at how a run is converging without loading polygons into another tool.
+// Voxels are drawn flattened over z, colored by cell or by mixture component,
+// with cell boundaries darkened and unassigned space left black.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use std::fs::File;
+use std::io::BufWriter;
+
+use crate::error::{Error, Result};
+use crate::sampler::transcripts::{CellIndex, BACKGROUND_CELL};
+use crate::sampler::voxelsampler::VoxelSampler;
+use crate::sampler::ModelParams;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SnapshotColor {
+    // A color for each cell, so neighboring cells can be told apart.
+    Cell,
+    // A color for each mixture component, showing preliminary cell types.
+    Component,
+}
+
+// Distinct colors for components (Tableau 20), reused when there are more.
+const PALETTE: [[u8; 3]; 20] = [
+    [31, 119, 180],
+    [174, 199, 232],
+    [255, 127, 14],
+    [255, 187, 120],
+    [44, 160, 44],
+    [152, 223, 138],
+    [214, 39, 40],
+    [255, 152, 150],
+    [148, 103, 189],
+    [197, 176, 213],
+    [140, 86, 75],
+    [196, 156, 148],
+    [227, 119, 194],
+    [247, 182, 210],
+    [127, 127, 127],
+    [199, 199, 199],
+    [188, 189, 34],
+    [219, 219, 141],
+    [23, 190, 207],
+    [158, 218, 229],
+];
+
+// A bright color for a cell, with hues spread by the golden ratio so cells
+// with nearby indices (which tend to be nearby) differ.
+fn cell_color(cell: CellIndex) -> [u8; 3] {
+    let h = (cell as f32 * 0.618_034).fract() * 6.0;
+    let (s, v) = (0.65, 0.95);
+    let f = h.fract();
+    let (p, q, t) = (v * (1.0 - s), v * (1.0 - s * f), v * (1.0 - s * (1.0 - f)));
+    let (r, g, b) = match h as u32 {
+        0 => (v, t, p),
+        1 => (q, v, p),
+        2 => (p, v, t),
+        3 => (p, q, v),
+        4 => (t, p, v),
+        _ => (v, p, q),
+    };
+    [(r * 255.0) as u8, (g * 255.0) as u8, (b * 255.0) as u8]
+}
+
+// Render the voxels within `bounds` (xmin, ymin, xmax, ymax) to a PNG image
+// `width` pixels wide, with y increasing downwards as in the coordinates.
+pub fn render_snapshot(
+    filename: &str,
+    sampler: &VoxelSampler,
+    params: &ModelParams,
+    bounds: (f32, f32, f32, f32),
+    width: usize,
+    color: SnapshotColor,
+) -> Result<()> {
+    let (xmin, ymin, xmax, ymax) = bounds;
+    let scale = width as f32 / (xmax - xmin);
+    let height = (((ymax - ymin) * scale).ceil() as usize).max(1);
+
+    // cell covering each pixel
+    let mut cells = vec![BACKGROUND_CELL; width * height];
+    let pixel = |v: f32, vmin: f32, n: usize| (((v - vmin) * scale).round().max(0.0) as usize).min(n);
+    for (cell, (x0, y0, _, x1, y1, _)) in sampler.voxels() {
+        if x1 <= xmin || x0 >= xmax || y1 <= ymin || y0 >= ymax {
+            continue;
+        }
+        let (i0, i1) = (pixel(x0, xmin, width), pixel(x1, xmin, width));
+        let (j0, j1) = (pixel(y0, ymin, height), pixel(y1, ymin, height));
+        for j in j0..j1 {
+            cells[j * width + i0..j * width + i1].fill(cell);
+        }
+    }
+
+    let mut data = vec![0_u8; 3 * width * height];
+    for j in 0..height {
+        for i in 0..width {
+            let cell = cells[j * width + i];
+            if cell == BACKGROUND_CELL {
+                continue;
+            }
+            let mut rgb = match color {
+                SnapshotColor::Cell => cell_color(cell),
+                SnapshotColor::Component => PALETTE[params.z[cell as usize] as usize % PALETTE.len()],
+            };
+            let boundary = (i + 1 < width && cells[j * width + i + 1] != cell)
+                || (j + 1 < height && cells[(j + 1) * width + i] != cell);
+            if boundary {
+                rgb = rgb.map(|c| c / 3);
+            }
+            data[3 * (j * width + i)..3 * (j * width + i + 1)].copy_from_slice(&rgb);
+        }
+    }
+
+    let io_error = |source| Error::Io {
+        path: filename.to_string(),
+        source,
+    };
+    let image_error = |err: png::EncodingError| Error::Image {
+        path: filename.to_string(),
+        message: err.to_string(),
+    };
+    let file = File::create(filename).map_err(io_error)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(image_error)?;
+    writer.write_image_data(&data).map_err(image_error)?;
+    writer.finish().map_err(image_error)
+}
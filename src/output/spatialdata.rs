@@ -0,0 +1,352 @@
+// Minimal SpatialData zarr writer, following the on-disk format described at
+// https://spatialdata.scverse.org/en/latest/design_doc.html. The store has a
+// points element of transcripts, a shapes element of cell polygons, and an
+// AnnData table of expected counts annotating the shapes.
+
+use arrow::array::RecordBatch;
+use arrow::datatypes::{DataType, Field, Schema};
+use geo::MultiPolygon;
+use json::{array, object, JsonValue};
+use ndarray::Array2;
+use parquet::arrow::ArrowWriter;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::geoparquet::{geometry_column, geometry_field, geoparquet_properties};
+use super::{write_table, OutputFormat};
+use crate::sampler::transcripts::Transcript;
+
+const SHAPES_NAME: &str = "cell_boundaries";
+const POINTS_NAME: &str = "transcripts";
+const TABLE_NAME: &str = "table";
+
+fn write_json(path: &Path, value: &JsonValue) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(value.pretty(2).as_bytes())
+}
+
+fn create_group(path: &Path, attrs: JsonValue) -> std::io::Result<()> {
+    std::fs::create_dir_all(path)?;
+    write_json(&path.join(".zgroup"), &object! { zarr_format: 2 })?;
+    if !attrs.is_empty() {
+        write_json(&path.join(".zattrs"), &attrs)?;
+    }
+    Ok(())
+}
+
+fn anndata_encoding(encoding_type: &str, encoding_version: &str) -> JsonValue {
+    object! {
+        "encoding-type": encoding_type,
+        "encoding-version": encoding_version,
+    }
+}
+
+// Write an uncompressed zarr array stored in a single chunk.
+fn write_zarray(
+    path: &Path,
+    dtype: &str,
+    shape: &[usize],
+    filters: JsonValue,
+    fill_value: JsonValue,
+    chunk: &[u8],
+    attrs: JsonValue,
+) -> std::io::Result<()> {
+    std::fs::create_dir_all(path)?;
+    let chunks: Vec<usize> = shape.iter().map(|&n| n.max(1)).collect();
+    write_json(
+        &path.join(".zarray"),
+        &object! {
+            chunks: chunks,
+            compressor: JsonValue::Null,
+            dtype: dtype,
+            fill_value: fill_value,
+            filters: filters,
+            order: "C",
+            shape: shape,
+            zarr_format: 2,
+        },
+    )?;
+    write_json(&path.join(".zattrs"), &attrs)?;
+    if shape.iter().all(|&n| n > 0) {
+        File::create(path.join("0"))?.write_all(chunk)?;
+    }
+    Ok(())
+}
+
+fn write_numeric_array<T: Copy>(
+    path: &Path,
+    dtype: &str,
+    values: &[T],
+    to_le_bytes: impl Fn(T) -> Vec<u8>,
+    attrs: JsonValue,
+) -> std::io::Result<()> {
+    let chunk: Vec<u8> = values.iter().flat_map(|&v| to_le_bytes(v)).collect();
+    write_zarray(path, dtype, &[values.len()], JsonValue::Null, 0.into(), &chunk, attrs)
+}
+
+// Strings are stored as object arrays using the numcodecs vlen-utf8 encoding.
+fn vlen_utf8(values: &[&str]) -> Vec<u8> {
+    let mut chunk = Vec::new();
+    chunk.extend((values.len() as u32).to_le_bytes());
+    for value in values {
+        chunk.extend((value.len() as u32).to_le_bytes());
+        chunk.extend(value.as_bytes());
+    }
+    chunk
+}
+
+fn write_string_array(path: &Path, values: &[&str]) -> std::io::Result<()> {
+    write_zarray(
+        path,
+        "|O",
+        &[values.len()],
+        array![object! { id: "vlen-utf8" }],
+        JsonValue::Null,
+        &vlen_utf8(values),
+        anndata_encoding("string-array", "0.2.0"),
+    )
+}
+
+fn write_string_scalar(path: &Path, value: &str) -> std::io::Result<()> {
+    write_zarray(
+        path,
+        "|O",
+        &[],
+        array![object! { id: "vlen-utf8" }],
+        JsonValue::Null,
+        &vlen_utf8(&[value]),
+        anndata_encoding("string", "0.2.0"),
+    )
+}
+
+fn identity_transform(axes: &[&str]) -> JsonValue {
+    let axes_json: Vec<JsonValue> = axes
+        .iter()
+        .map(|axis| object! { name: *axis, type: "space", unit: "unit" })
+        .collect();
+
+    let mut transform = JsonValue::new_object();
+    transform["input"] = object! { axes: axes_json.clone(), name: axes.concat() };
+    transform["output"] = object! { axes: axes_json, name: "global" };
+    transform["type"] = "identity".into();
+    array![transform]
+}
+
+fn write_shapes(path: &Path, cell_polygons: &[MultiPolygon<f32>]) -> std::io::Result<()> {
+    create_group(
+        path,
+        object! {
+            axes: array!["x", "y"],
+            coordinateTransformations: identity_transform(&["x", "y"]),
+            "encoding-type": "ngff:shapes",
+            spatialdata_attrs: object! { version: "0.2" },
+        },
+    )?;
+
+    let schema = Schema::new(vec![geometry_field()]);
+    let geometry = geometry_column(cell_polygons);
+    let batch = RecordBatch::try_new(Arc::new(schema), vec![Arc::new(geometry)]).unwrap();
+
+    let file = File::create(path.join("shapes.parquet"))?;
+    let mut writer = ArrowWriter::try_new(file, batch.schema(), Some(geoparquet_properties()))
+        .map_err(std::io::Error::other)?;
+    writer.write(&batch).map_err(std::io::Error::other)?;
+    writer.close().map_err(std::io::Error::other)?;
+
+    Ok(())
+}
+
+fn write_points(
+    path: &Path,
+    transcripts: &[Transcript],
+    transcript_names: &[String],
+    cell_assignments: &[(u32, f32)],
+) -> std::io::Result<()> {
+    create_group(
+        path,
+        object! {
+            axes: array!["x", "y", "z"],
+            coordinateTransformations: identity_transform(&["x", "y", "z"]),
+            "encoding-type": "ngff:points",
+            spatialdata_attrs: object! {
+                feature_key: "gene",
+                instance_key: "cell_id",
+                version: "0.1",
+            },
+        },
+    )?;
+
+    let schema = Schema::new(vec![
+        Field::new("x", DataType::Float32, false),
+        Field::new("y", DataType::Float32, false),
+        Field::new("z", DataType::Float32, false),
+        Field::new("gene", DataType::Utf8, false),
+        Field::new("transcript_id", DataType::UInt64, false),
+        Field::new("cell_id", DataType::UInt32, false),
+        Field::new("probability", DataType::Float32, false),
+    ]);
+
+    let columns: Vec<Arc<dyn arrow::array::Array>> = vec![
+        Arc::new(transcripts.iter().map(|t| t.x).collect::<arrow::array::Float32Array>()),
+        Arc::new(transcripts.iter().map(|t| t.y).collect::<arrow::array::Float32Array>()),
+        Arc::new(transcripts.iter().map(|t| t.z).collect::<arrow::array::Float32Array>()),
+        Arc::new(
+            transcripts
+                .iter()
+                .map(|t| Some(transcript_names[t.gene as usize].clone()))
+                .collect::<arrow::array::StringArray>()
+        ),
+        Arc::new(transcripts.iter().map(|t| t.transcript_id).collect::<arrow::array::UInt64Array>()),
+        Arc::new(cell_assignments.iter().map(|(cell, _)| *cell).collect::<arrow::array::UInt32Array>()),
+        Arc::new(cell_assignments.iter().map(|(_, pr)| *pr).collect::<arrow::array::Float32Array>()),
+    ];
+
+    let batch = RecordBatch::try_new(Arc::new(schema), columns).unwrap();
+    write_table(
+        path.join("points.parquet").to_str().unwrap(),
+        OutputFormat::Parquet,
+        &batch,
+    );
+
+    Ok(())
+}
+
+// `counts` is [ngenes, ncells], and is written as a cells-by-genes CSR matrix.
+fn write_table_element(
+    path: &Path,
+    transcript_names: &[String],
+    var_columns: &[(&str, Vec<String>)],
+    counts: &Array2<f32>,
+    obs_f32_columns: &[(&str, Vec<f32>)],
+    obs_u32_columns: &[(&str, Vec<u32>)],
+) -> std::io::Result<()> {
+    let (ngenes, ncells) = counts.dim();
+
+    let mut attrs = anndata_encoding("anndata", "0.1.0");
+    attrs["spatialdata_attrs"] = object! { version: "0.1" };
+    create_group(path, attrs)?;
+
+    let mut data: Vec<f32> = Vec::new();
+    let mut indices: Vec<i32> = Vec::new();
+    let mut indptr: Vec<i64> = Vec::with_capacity(ncells + 1);
+    indptr.push(0);
+    for cell_counts in counts.columns() {
+        for (gene, &count) in cell_counts.iter().enumerate() {
+            if count > 0.0 {
+                data.push(count);
+                indices.push(gene as i32);
+            }
+        }
+        indptr.push(data.len() as i64);
+    }
+
+    let x = path.join("X");
+    let mut x_attrs = anndata_encoding("csr_matrix", "0.1.0");
+    x_attrs["shape"] = array![ncells, ngenes];
+    create_group(&x, x_attrs)?;
+    let array_encoding = || anndata_encoding("array", "0.2.0");
+    write_numeric_array(&x.join("data"), "<f4", &data, |v| v.to_le_bytes().to_vec(), array_encoding())?;
+    write_numeric_array(&x.join("indices"), "<i4", &indices, |v| v.to_le_bytes().to_vec(), array_encoding())?;
+    write_numeric_array(&x.join("indptr"), "<i8", &indptr, |v| v.to_le_bytes().to_vec(), array_encoding())?;
+
+    // obs, including the columns linking each row to a cell polygon
+    let obs = path.join("obs");
+    let mut column_order: Vec<&str> = vec!["region", "cell_id"];
+    column_order.extend(obs_f32_columns.iter().map(|(name, _)| *name));
+    column_order.extend(obs_u32_columns.iter().map(|(name, _)| *name));
+    let mut obs_attrs = anndata_encoding("dataframe", "0.2.0");
+    obs_attrs["_index"] = "cell".into();
+    obs_attrs["column-order"] = column_order.into();
+    create_group(&obs, obs_attrs)?;
+
+    let cell_names = (0..ncells).map(|i| i.to_string()).collect::<Vec<_>>();
+    write_string_array(
+        &obs.join("cell"),
+        &cell_names.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+    )?;
+
+    let region = obs.join("region");
+    let mut region_attrs = anndata_encoding("categorical", "0.2.0");
+    region_attrs["ordered"] = false.into();
+    create_group(&region, region_attrs)?;
+    write_numeric_array(&region.join("codes"), "|i1", &vec![0_i8; ncells], |v| v.to_le_bytes().to_vec(), array_encoding())?;
+    write_string_array(&region.join("categories"), &[SHAPES_NAME])?;
+
+    let cell_ids = (0..ncells as u32).collect::<Vec<_>>();
+    write_numeric_array(&obs.join("cell_id"), "<u4", &cell_ids, |v| v.to_le_bytes().to_vec(), array_encoding())?;
+    for (name, values) in obs_f32_columns {
+        write_numeric_array(&obs.join(name), "<f4", values, |v| v.to_le_bytes().to_vec(), array_encoding())?;
+    }
+    for (name, values) in obs_u32_columns {
+        write_numeric_array(&obs.join(name), "<u4", values, |v| v.to_le_bytes().to_vec(), array_encoding())?;
+    }
+
+    // var
+    let var = path.join("var");
+    let mut var_attrs = anndata_encoding("dataframe", "0.2.0");
+    var_attrs["_index"] = "gene".into();
+    var_attrs["column-order"] = var_columns.iter().map(|(name, _)| *name).collect::<Vec<_>>().into();
+    create_group(&var, var_attrs)?;
+    write_string_array(
+        &var.join("gene"),
+        &transcript_names.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+    )?;
+    for (name, values) in var_columns {
+        write_string_array(
+            &var.join(name),
+            &values.iter().map(|s| s.as_str()).collect::<Vec<_>>(),
+        )?;
+    }
+
+    for name in ["layers", "obsm", "obsp", "varm", "varp", "uns"] {
+        create_group(&path.join(name), anndata_encoding("dict", "0.1.0"))?;
+    }
+
+    let spatialdata_attrs = path.join("uns").join("spatialdata_attrs");
+    create_group(&spatialdata_attrs, anndata_encoding("dict", "0.1.0"))?;
+    write_string_scalar(&spatialdata_attrs.join("region"), SHAPES_NAME)?;
+    write_string_scalar(&spatialdata_attrs.join("region_key"), "region")?;
+    write_string_scalar(&spatialdata_attrs.join("instance_key"), "cell_id")?;
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn write_spatialdata_zarr(
+    dirname: &str,
+    transcripts: &[Transcript],
+    transcript_names: &[String],
+    var_columns: &[(&str, Vec<String>)],
+    cell_assignments: &[(u32, f32)],
+    counts: &Array2<f32>,
+    cell_polygons: &[MultiPolygon<f32>],
+    obs_f32_columns: &[(&str, Vec<f32>)],
+    obs_u32_columns: &[(&str, Vec<u32>)],
+) -> std::io::Result<()> {
+    let path = Path::new(dirname);
+    create_group(path, object! { spatialdata_attrs: object! { version: "0.1" } })?;
+
+    for element_type in ["points", "shapes", "tables"] {
+        create_group(&path.join(element_type), JsonValue::new_object())?;
+    }
+
+    write_points(
+        &path.join("points").join(POINTS_NAME),
+        transcripts,
+        transcript_names,
+        cell_assignments,
+    )?;
+    write_shapes(&path.join("shapes").join(SHAPES_NAME), cell_polygons)?;
+    write_table_element(
+        &path.join("tables").join(TABLE_NAME),
+        transcript_names,
+        var_columns,
+        counts,
+        obs_f32_columns,
+        obs_u32_columns,
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+// Outputs of runs over several samples split into a set of files for each
+// (--split-output-by sample), so sections segmented together don't have to be
+// pulled apart afterwards. Genes are in the same order in every sample, and
+// cells are renumbered from 0 within each.
+
+use arrow::array::{RecordBatch, UInt32Array};
+use ndarray::{Array1, Array2, Axis};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::cell_fov_vote;
+use crate::sampler::transcripts::{CellIndex, BACKGROUND_CELL};
+
+pub struct SampleSplit {
+    pub name: String,
+
+    // indices of the sample's cells
+    cells: Vec<usize>,
+
+    // [ncells] index of each cell within the sample, or BACKGROUND_CELL if
+    // it's in another sample
+    cell_map: Vec<CellIndex>,
+
+    // indices of the sample's transcripts
+    transcripts: Vec<usize>,
+}
+
+// Split cells and transcripts by sample, given each transcript's (renumbered,
+// after QC) cell assignment. Transcripts belong to the sample of their fov, and
+// cells to the sample most of their transcripts came from. Cells with no
+// transcripts are put in the first sample.
+pub fn split_by_sample(
+    ncells: usize,
+    cell_assignments: &[(CellIndex, f32)],
+    fovs: &[u32],
+    fov_samples: &[u32],
+    sample_names: &[String],
+) -> Vec<SampleSplit> {
+    let cell_fovs = cell_fov_vote(ncells, fov_samples.len(), cell_assignments, fovs);
+
+    let mut splits: Vec<SampleSplit> = sample_names
+        .iter()
+        .map(|name| SampleSplit {
+            name: name.clone(),
+            cells: Vec::new(),
+            cell_map: vec![BACKGROUND_CELL; ncells],
+            transcripts: Vec::new(),
+        })
+        .collect();
+
+    for (cell, &fov) in cell_fovs.iter().enumerate() {
+        let sample = if fov == u32::MAX {
+            0
+        } else {
+            fov_samples[fov as usize] as usize
+        };
+        let split = &mut splits[sample];
+        split.cell_map[cell] = split.cells.len() as CellIndex;
+        split.cells.push(cell);
+    }
+
+    for (i, &fov) in fovs.iter().enumerate() {
+        splits[fov_samples[fov as usize] as usize].transcripts.push(i);
+    }
+
+    splits
+}
+
+impl SampleSplit {
+    pub fn ncells(&self) -> usize {
+        self.cells.len()
+    }
+
+    // The output file with the sample's name inserted as a directory before
+    // the file name, e.g. "output/sample1/expected-counts.csv.gz", creating
+    // the directory if needed.
+    pub fn output_path(&self, output: &Option<String>) -> Option<String> {
+        output.as_ref().map(|output| {
+            let output = Path::new(output);
+            let dir = output.parent().unwrap_or(Path::new("")).join(&self.name);
+            std::fs::create_dir_all(&dir).unwrap();
+            dir.join(output.file_name().unwrap())
+                .to_string_lossy()
+                .into_owned()
+        })
+    }
+
+    // Per-cell values for the sample's cells.
+    pub fn select<T: Clone>(&self, values: &[T]) -> Vec<T> {
+        self.cells.iter().map(|&cell| values[cell].clone()).collect()
+    }
+
+    // Columns of a [ngenes, ncells] matrix for the sample's cells.
+    pub fn select_columns<T: Clone>(&self, values: &Array2<T>) -> Array2<T> {
+        values.select(Axis(1), &self.cells)
+    }
+
+    // Rows of a table with a row for each cell, renumbering the "cell" column.
+    pub fn select_rows(&self, batch: &RecordBatch) -> RecordBatch {
+        let indices = self.cells.iter().map(|&cell| cell as u32).collect::<UInt32Array>();
+        let selected = arrow::compute::take_record_batch(batch, &indices).unwrap();
+
+        let mut columns = selected.columns().to_vec();
+        if let Ok(i) = selected.schema().index_of("cell") {
+            columns[i] = Arc::new((0..self.ncells() as u32).collect::<UInt32Array>());
+        }
+        RecordBatch::try_new(selected.schema(), columns).unwrap()
+    }
+
+    // Per-transcript values for the sample's transcripts.
+    pub fn select_transcripts<T: Clone>(&self, values: &[T]) -> Vec<T> {
+        self.transcripts.iter().map(|&i| values[i].clone()).collect()
+    }
+
+    pub fn select_transcripts_array<T: Clone>(&self, values: &Array1<T>) -> Array1<T> {
+        values.select(Axis(0), &self.transcripts)
+    }
+
+    // The sample's transcript assignments with cells renumbered. Transcripts
+    // assigned to a cell of another sample are reported as unassigned.
+    pub fn cell_assignments(&self, cell_assignments: &[(CellIndex, f32)]) -> Vec<(CellIndex, f32)> {
+        self.transcripts
+            .iter()
+            .map(|&i| {
+                let (cell, pr) = cell_assignments[i];
+                if cell == BACKGROUND_CELL {
+                    (BACKGROUND_CELL, pr)
+                } else {
+                    (self.cell_map[cell as usize], pr)
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,176 @@
+// Timing of each phase of a run, and of local proposals in each chunk, to help
+// choose the number of threads and chunk size (`--cells-per-chunk`) for a
+// machine. Phases are named by ';' separated paths (e.g.
+// "sampling;local_proposals"), so the report can be written as folded stacks
+// for flamegraph tools as well as JSON.
+
+use json::JsonValue;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct PhaseProfile {
+    name: String,
+    time: Duration,
+    calls: usize,
+}
+
+#[derive(Clone, Default)]
+struct ChunkProfile {
+    // proposals evaluated, i.e. not ignored
+    proposals: u64,
+    accepted: u64,
+    ignored: u64,
+    // time spent evaluating proposals, summed over iterations
+    time: Duration,
+}
+
+pub struct Profiler {
+    start: Instant,
+    phases: Mutex<Vec<PhaseProfile>>,
+    chunks: Mutex<Vec<ChunkProfile>>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            start: Instant::now(),
+            phases: Mutex::new(Vec::new()),
+            chunks: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn record(&self, phase: &str, elapsed: Duration) {
+        let mut phases = self.phases.lock().unwrap();
+        match phases.iter_mut().find(|p| p.name == phase) {
+            Some(p) => {
+                p.time += elapsed;
+                p.calls += 1;
+            }
+            None => phases.push(PhaseProfile {
+                name: phase.to_string(),
+                time: elapsed,
+                calls: 1,
+            }),
+        }
+    }
+
+    pub fn time<T>(&self, phase: &str, f: impl FnOnce() -> T) -> T {
+        let t0 = Instant::now();
+        let result = f();
+        self.record(phase, t0.elapsed());
+        result
+    }
+
+    // Record one round of local proposals, given (ignored, accepted, time) for
+    // the proposal made in each chunk.
+    pub fn record_chunks(&self, proposals: impl Iterator<Item = (bool, bool, Duration)>) {
+        let mut chunks = self.chunks.lock().unwrap();
+        for (chunk, (ignored, accepted, elapsed)) in proposals.enumerate() {
+            if chunk >= chunks.len() {
+                chunks.resize(chunk + 1, ChunkProfile::default());
+            }
+            let c = &mut chunks[chunk];
+            if ignored {
+                c.ignored += 1;
+            } else {
+                c.proposals += 1;
+                if accepted {
+                    c.accepted += 1;
+                }
+            }
+            c.time += elapsed;
+        }
+    }
+
+    fn to_json(&self, nthreads: usize) -> JsonValue {
+        let total = self.start.elapsed().as_secs_f64();
+        let phases = self.phases.lock().unwrap();
+        let chunks = self.chunks.lock().unwrap();
+
+        let mut report = JsonValue::new_object();
+        report["total_seconds"] = total.into();
+        report["nthreads"] = nthreads.into();
+
+        let mut phase_values = JsonValue::new_array();
+        for phase in phases.iter() {
+            let seconds = phase.time.as_secs_f64();
+            let mut value = JsonValue::new_object();
+            value["name"] = phase.name.as_str().into();
+            value["seconds"] = seconds.into();
+            value["calls"] = phase.calls.into();
+            value["fraction"] = (seconds / total).into();
+            phase_values.push(value).unwrap();
+        }
+        report["phases"] = phase_values;
+
+        // Chunks that take much longer than average leave threads idle, which
+        // suggests using smaller chunks.
+        let chunk_seconds: Vec<f64> = chunks.iter().map(|c| c.time.as_secs_f64()).collect();
+        if !chunk_seconds.is_empty() {
+            let mean = chunk_seconds.iter().sum::<f64>() / chunk_seconds.len() as f64;
+            let max = chunk_seconds.iter().cloned().fold(0.0, f64::max);
+            report["nchunks"] = chunks.len().into();
+            report["chunk_imbalance"] = if mean > 0.0 { max / mean } else { 1.0 }.into();
+        }
+
+        let mut chunk_values = JsonValue::new_array();
+        for (chunk, (c, &seconds)) in chunks.iter().zip(&chunk_seconds).enumerate() {
+            let mut value = JsonValue::new_object();
+            value["chunk"] = chunk.into();
+            value["proposals"] = c.proposals.into();
+            value["accepted"] = c.accepted.into();
+            value["ignored"] = c.ignored.into();
+            value["seconds"] = seconds.into();
+            value["proposals_per_second"] = if seconds > 0.0 {
+                c.proposals as f64 / seconds
+            } else {
+                0.0
+            }
+            .into();
+            chunk_values.push(value).unwrap();
+        }
+        report["chunks"] = chunk_values;
+
+        report
+    }
+
+    // Folded stacks, one "proseg;<phase> <microseconds>" line per phase, as
+    // read by flamegraph.pl and inferno. Time not spent in any recorded phase
+    // is attributed to "proseg" itself.
+    fn to_folded(&self) -> String {
+        let total = self.start.elapsed();
+        let phases = self.phases.lock().unwrap();
+        let mut folded = String::new();
+        let recorded: Duration = phases.iter().map(|p| p.time).sum();
+        folded.push_str(&format!(
+            "proseg {}\n",
+            total.saturating_sub(recorded).as_micros()
+        ));
+        for phase in phases.iter() {
+            folded.push_str(&format!("proseg;{} {}\n", phase.name, phase.time.as_micros()));
+        }
+        folded
+    }
+
+    // Write the report as folded stacks if the filename ends in ".folded", and
+    // otherwise as JSON.
+    pub fn write(&self, filename: &str, nthreads: usize) {
+        let content = if filename.ends_with(".folded") {
+            self.to_folded()
+        } else {
+            self.to_json(nthreads).pretty(2)
+        };
+        let mut file = File::create(filename)
+            .unwrap_or_else(|err| panic!("Unable to create profile '{}': {}", filename, err));
+        file.write_all(content.as_bytes())
+            .unwrap_or_else(|err| panic!("Unable to write profile '{}': {}", filename, err));
+    }
+}
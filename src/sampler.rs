@@ -1,37 +1,65 @@
+pub mod background;
+pub mod boundary;
+pub mod chunks;
 mod connectivity;
+pub mod controls;
+mod delaunay;
+pub mod dedup;
+pub mod downsample;
+pub mod expression_prior;
 pub mod voxelsampler;
+pub mod genefilter;
+pub mod genepanel;
 pub mod hull;
+pub mod mask;
+pub mod pbin;
 mod math;
 pub mod polyagamma;
 mod polygons;
+pub mod refine;
+pub mod rng;
+pub mod roi;
+pub mod rowfilter;
 mod sampleset;
+pub mod smoothing;
+pub mod tissue;
 pub mod transcripts;
+pub mod transform;
 
 use core::fmt::Debug;
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use crate::output::{geojson_writer, CellQc};
+use crate::profile::Profiler;
+use geo::geometry::{LineString, MultiPolygon, Polygon};
+use geo::Area;
 use hull::convex_hull_area;
 use itertools::{izip, Itertools};
+use kiddo::float::kdtree::KdTree;
+use kiddo::SquaredEuclidean;
 use libm::{lgammaf, log1pf};
 use linfa::traits::{Fit, Predict};
 use linfa::DatasetBase;
 use linfa_clustering::KMeans;
 use math::{
-    logistic, lognormal_logpdf, negbin_logpmf_fast, normal_pdf, normal_x2_logpdf, normal_x2_pdf,
-    rand_crt, LogFactorial, LogGammaPlus,
+    beta_mode, crt_mean, gamma_logpdf, gamma_mode, logistic, lognormal_logpdf, negbin_logpmf_fast, normal_pdf,
+    normal_x2_logpdf, normal_x2_pdf, rand_crt, LogFactorial, LogGammaPlus,
 };
-use ndarray::{Array1, Array2, Array3, Axis, Zip};
+use ndarray::{Array1, Array2, Array3, ArrayView1, Axis, Zip};
 use polyagamma::PolyaGamma;
-use rand::{thread_rng, Rng};
-use rand_distr::{Dirichlet, Distribution, Gamma, Normal, StandardNormal};
+use rand::Rng;
+use rand_distr::{Beta, Dirichlet, Distribution, Gamma, Normal, StandardNormal};
 use rayon::prelude::*;
+use rng::{FixedState, SamplerRng};
+use smoothing::remove_overlaps;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::f32;
-use std::fs::File;
 use std::io::Write;
 use std::iter::Iterator;
+use std::time::{Duration, Instant};
 use thread_local::ThreadLocal;
+use background::BackgroundGrid;
+use expression_prior::ExpressionPrior;
 use transcripts::{CellIndex, Transcript, BACKGROUND_CELL};
 
 // use std::time::Instant;
@@ -60,15 +88,25 @@ fn chunkquad(x: f32, y: f32, xmin: f32, ymin: f32, chunk_size: f32, nxchunks: us
     (chunk, quad)
 }
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum TranscriptState {
     Background,
     Foreground,
     Confusion,
 }
 
+// How neighboring cells are found for the component smoothness prior.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NeighborhoodMethod {
+    // cells within three length scales
+    Radius,
+    // cells adjacent in a Delaunay triangulation of cell centroids
+    Delaunay,
+}
+
 // Model prior parameters.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize)]
 pub struct ModelPriors {
     pub dispersion: Option<f32>,
     pub burnin_dispersion: Option<f32>,
@@ -104,6 +142,9 @@ pub struct ModelPriors {
     pub perimeter_eta: f32,
     pub perimeter_bound: f32,
 
+    // log probability penalty per unit length of cell boundary in each layer
+    pub perimeter_penalty: f32,
+
     pub nuclear_reassignment_log_prob: f32,
     pub nuclear_reassignment_1mlog_prob: f32,
 
@@ -120,15 +161,122 @@ pub struct ModelPriors {
     pub σ_z_diffusion_proposal: f32,
     pub σ_z_diffusion: f32,
 
+    // infer a per-gene offset and spread of z diffusion, with a normal prior
+    // on offsets and inverse-gamma prior on variances
+    pub use_gene_z_offsets: bool,
+    pub σ_z_offset: f32,
+    pub α_σ_z_diffusion: f32,
+    pub β_σ_z_diffusion: f32,
+
     // bounds on z coordinate
     pub zmin: f32,
     pub zmax: f32,
 
     // whether to check if voxel updates break local connectivity
     pub enforce_connectivity: bool,
+
+    // don't let cells extend further than this from their centroid
+    pub max_cell_radius: Option<f32>,
+
+    // if set, use a (truncated) Dirichlet process prior on the mixing
+    // proportions with this concentration, rather than a flat Dirichlet
+    pub dp_concentration: Option<f32>,
+
+    // if set, a Potts-style prior favoring neighboring cells being assigned
+    // the same component, with this strength, weighting neighbors by a
+    // squared-exponential kernel on distance with length scale
+    // `component_smoothness_length`
+    pub component_smoothness: Option<f32>,
+    pub component_smoothness_length: f32,
+    pub neighborhood_method: NeighborhoodMethod,
+
+    // if set, scale background rates by a factor for each bin of a grid with
+    // bins of this size, with a Gamma(α_bg_scale, α_bg_scale) prior
+    pub background_grid_size: Option<f32>,
+    pub α_bg_scale: f32,
+
+    // if set, z distances are multiplied by this to put them in the same units
+    // as x and y, and vertical and lateral neighbors are weighted by distance
+    // in the voxel sampler and the cell neighborhood graph
+    pub z_scale: Option<f32>,
+}
+
+impl ModelPriors {
+    // Default priors, matching the command line defaults, for cells with the
+    // given mean nucleus area and transcripts between zmin and zmax.
+    pub fn new(mean_nucleus_area: f32, zmin: f32, zmax: f32) -> ModelPriors {
+        let zspan = if zmax > zmin { zmax - zmin } else { 1.0 };
+        ModelPriors {
+            dispersion: None,
+            burnin_dispersion: Some(1.0),
+
+            min_cell_volume: 1e-6 * mean_nucleus_area * zspan,
+
+            μ_μ_volume: (2.0 * mean_nucleus_area * zspan).ln(),
+            σ_μ_volume: 3.0,
+            α_σ_volume: 0.1,
+            β_σ_volume: 0.1,
+
+            e_r: 1.0,
+
+            e_h: 1.0,
+            f_h: 1.0,
+
+            γ: 1.0,
+
+            α_bg: 1.0,
+            β_bg: 1.0,
+
+            α_c: 1.0,
+            β_c: 1.0,
+
+            perimeter_eta: 5.3,
+            perimeter_bound: 1.3,
+            perimeter_penalty: 0.0,
+
+            nuclear_reassignment_log_prob: 0.2_f32.ln(),
+            nuclear_reassignment_1mlog_prob: 0.8_f32.ln(),
+
+            prior_seg_reassignment_log_prob: 0.5_f32.ln(),
+            prior_seg_reassignment_1mlog_prob: 0.5_f32.ln(),
+
+            use_diffusion_model: true,
+            p_diffusion: 0.2,
+            σ_diffusion_proposal: 4.0,
+            σ_diffusion_near: 0.5,
+            σ_diffusion_far: 4.0,
+
+            σ_z_diffusion_proposal: 0.2 * zspan,
+            σ_z_diffusion: 0.2 * zspan,
+
+            use_gene_z_offsets: false,
+            σ_z_offset: 0.2 * zspan,
+            α_σ_z_diffusion: 2.0,
+            β_σ_z_diffusion: (0.2 * zspan).powi(2),
+
+            zmin,
+            zmax,
+
+            enforce_connectivity: true,
+            max_cell_radius: None,
+
+            dp_concentration: None,
+
+            // twice the diameter of a circular cell with twice the nucleus area
+            component_smoothness: None,
+            component_smoothness_length: 4.0 * (2.0 * mean_nucleus_area / std::f32::consts::PI).sqrt(),
+            neighborhood_method: NeighborhoodMethod::Radius,
+
+            background_grid_size: None,
+            α_bg_scale: 4.0,
+
+            z_scale: None,
+        }
+    }
 }
 
 // Model global parameters.
+#[derive(Serialize, Deserialize)]
 pub struct ModelParams {
     pub transcript_positions: Vec<(f32, f32, f32)>,
     proposed_transcript_positions: Vec<(f32, f32, f32)>,
@@ -138,6 +286,13 @@ pub struct ModelParams {
     init_nuclear_cell_assignment: Vec<CellIndex>,
     prior_seg_cell_assignment: Vec<CellIndex>,
 
+    // [ntranscripts] source of each prior segmentation assignment, and for each
+    // source, log probabilities of the assignment being wrong and right. Empty
+    // unless confidence differs by source, in which case the
+    // prior_seg_reassignment priors are used.
+    prior_seg_sources: Vec<u32>,
+    prior_seg_log_probs: Vec<(f32, f32)>,
+
     pub cell_assignments: Vec<CellIndex>,
     pub cell_assignment_time: Vec<u32>,
 
@@ -151,10 +306,10 @@ pub struct ModelParams {
     pub component_volume: Array1<f32>,
 
     // area of the convex hull containing all transcripts
-    full_layer_volume: f32,
+    pub full_layer_volume: f32,
 
     z0: f32,
-    layer_depth: f32,
+    pub layer_depth: f32,
 
     // [ntranscripts] current assignment of transcripts to background
     pub transcript_state: Array1<TranscriptState>,
@@ -167,31 +322,34 @@ pub struct ModelParams {
     foreground_counts: Array3<u16>,
 
     // [ngenes] background transcripts counts
-    confusion_counts: Array1<u32>,
+    pub confusion_counts: Array1<u32>,
 
     // [ngenes, nlayers] background transcripts counts
-    background_counts: Array2<u32>,
+    pub background_counts: Array2<u32>,
 
     // [ngenes, nlayers] total gene occourance counts
     pub total_gene_counts: Array2<u32>,
 
     // Not parameters, but needed for sampling global params
+    #[serde(skip)]
     logfactorial: LogFactorial,
 
     // TODO: This needs to be an matrix I guess!
+    #[serde(skip)]
     loggammaplus: Array2<LogGammaPlus>,
 
     pub z: Array1<u32>, // assignment of cells to components
 
-    component_population: Array1<u32>, // number of cells assigned to each component
+    pub component_population: Array1<u32>, // number of cells assigned to each component
 
     // thread-local space used for sampling z
+    #[serde(skip)]
     z_probs: ThreadLocal<RefCell<Vec<f64>>>,
 
-    π: Vec<f32>, // mixing proportions over components
+    pub π: Vec<f32>, // mixing proportions over components
 
-    μ_volume: Array1<f32>, // volume dist mean param by component
-    σ_volume: Array1<f32>, // volume dist std param by component
+    pub μ_volume: Array1<f32>, // volume dist mean param by component
+    pub σ_volume: Array1<f32>, // volume dist std param by component
 
     // Prior on NB dispersion parameters
     h: f32,
@@ -234,6 +392,17 @@ pub struct ModelParams {
     // [ngenes] confusion: rate at which we halucinate transcripts within cells
     pub λ_c: Array1<f32>,
 
+    // [ngenes] mean and stddev of the z displacement of transcripts from their
+    // observed positions
+    pub gene_z_offset: Array1<f32>,
+    pub gene_σ_z: Array1<f32>,
+
+    // reference cell type expression used as a prior on components
+    pub expression_prior: Option<ExpressionPrior>,
+
+    // spatially varying scale of background rates
+    pub background_grid: Option<BackgroundGrid>,
+
     // time, which is incremented after every iteration
     t: u32,
 }
@@ -298,7 +467,7 @@ impl ModelParams {
         // let init_samples =
         //     DatasetBase::from(counts.sum_axis(Axis(2)).map(|&x| (x as f32).ln_1p()).reversed_axes());
 
-        let rng = rand::thread_rng();
+        let rng = rng::rng();
         let model = KMeans::params_with_rng(ncomponents, rng)
             .tolerance(1e-1)
             .fit(&init_samples)
@@ -343,6 +512,8 @@ impl ModelParams {
             transcript_position_updates,
             init_nuclear_cell_assignment: init_cell_assignments.to_vec(),
             prior_seg_cell_assignment: prior_seg_cell_assignment.to_vec(),
+            prior_seg_sources: Vec::new(),
+            prior_seg_log_probs: Vec::new(),
             cell_assignments: init_cell_assignments.to_vec(),
             cell_assignment_time: vec![0; init_cell_assignments.len()],
             cell_population: init_cell_population.to_vec(),
@@ -379,6 +550,12 @@ impl ModelParams {
             λ: Array2::<f32>::from_elem((ngenes, ncells), 0.1),
             λ_bg: Array2::<f32>::from_elem((ngenes, nlayers), 0.0),
             λ_c: Array1::<f32>::from_elem(ngenes, 1e-4),
+            gene_z_offset: Array1::<f32>::zeros(ngenes),
+            gene_σ_z: Array1::<f32>::from_elem(ngenes, priors.σ_z_diffusion),
+            expression_prior: None,
+            background_grid: priors
+                .background_grid_size
+                .map(|size| BackgroundGrid::new(transcripts, size, full_layer_volume)),
             t: 0,
         }
     }
@@ -387,6 +564,57 @@ impl ModelParams {
         self.π.len()
     }
 
+    // Use reference expression as a prior on components, initially assigning
+    // each cell to the reference type that best explains its counts.
+    pub fn set_expression_prior(&mut self, expression_prior: Option<ExpressionPrior>) {
+        if let Some(expression_prior) = &expression_prior {
+            assert_eq!(expression_prior.ncomponents(), self.ncomponents());
+            let cell_counts = self.counts.sum_axis(Axis(2)).mapv(|c| c as u32);
+            self.z = cell_counts
+                .columns()
+                .into_iter()
+                .map(|counts| expression_prior.classify(counts))
+                .collect();
+        }
+        self.expression_prior = expression_prior;
+    }
+
+    // Weigh prior segmentation assignments by their source, with
+    // `log_probs[source]` the log probabilities of an assignment from that
+    // source being wrong and right.
+    pub fn set_prior_seg_sources(&mut self, sources: &[u32], log_probs: Vec<(f32, f32)>) {
+        assert_eq!(sources.len(), self.prior_seg_cell_assignment.len());
+        self.prior_seg_sources = sources.to_vec();
+        self.prior_seg_log_probs = log_probs;
+    }
+
+    // Log probabilities of transcript `t`'s prior segmentation assignment
+    // being wrong and right.
+    fn prior_seg_log_probs(&self, priors: &ModelPriors, t: usize) -> (f32, f32) {
+        source_log_probs(priors, &self.prior_seg_log_probs, &self.prior_seg_sources, t)
+    }
+
+    // Rebuild memoized values that are not serialized in checkpoints.
+    pub fn rebuild_caches(&mut self) {
+        self.logfactorial = LogFactorial::new();
+        self.loggammaplus = self.r.map(|&r| LogGammaPlus::new(r));
+    }
+
+    // Factor by which background rates are scaled at a position.
+    pub fn background_scale(&self, x: f32, y: f32) -> f32 {
+        self.background_grid
+            .as_ref()
+            .map_or(1.0, |grid| grid.scale_at(x, y))
+    }
+
+    // Volume over which background transcripts in each layer are spread,
+    // weighted by the background scale.
+    pub fn background_volume(&self) -> f32 {
+        self.background_grid
+            .as_ref()
+            .map_or(self.full_layer_volume, |grid| grid.scaled_volume())
+    }
+
     fn zlayer(&self, z: f32) -> usize {
         let layer = ((z - self.z0) / self.layer_depth).max(0.0) as usize;
         layer.min(self.nlayers() - 1)
@@ -431,6 +659,206 @@ impl ModelParams {
         self.cell_population.len()
     }
 
+    // Number of iterations run.
+    pub fn time(&self) -> u32 {
+        self.t
+    }
+
+    // Mean x/y area of cells that currently have any voxels.
+    pub fn mean_cell_area(&self) -> f32 {
+        let zspan = self.layer_depth * self.nlayers() as f32;
+        let (total_volume, count) = self
+            .cell_volume
+            .iter()
+            .zip(&self.cell_population)
+            .filter(|(_, &population)| population > 0)
+            .fold((0.0, 0), |(v, n), (volume, _)| (v + volume, n + 1));
+        total_volume / (count as f32 * zspan)
+    }
+
+    // Allocate `n` new empty cells, for split moves, returning the index of
+    // the first.
+    fn add_cells(&mut self, priors: &ModelPriors, n: usize) -> CellIndex {
+        let ncells = self.ncells();
+        let ngenes = self.ngenes();
+        let nlayers = self.nlayers();
+
+        self.cell_population.resize(ncells + n, 0);
+        self.cell_volume
+            .append(Axis(0), Array1::from_elem(n, priors.min_cell_volume).view())
+            .unwrap();
+        self.cell_log_volume
+            .append(
+                Axis(0),
+                Array1::from_elem(n, priors.min_cell_volume.ln()).view(),
+            )
+            .unwrap();
+        self.counts
+            .append(Axis(1), Array3::zeros((ngenes, n, nlayers)).view())
+            .unwrap();
+        self.foreground_counts
+            .append(Axis(0), Array3::zeros((n, ngenes, nlayers)).view())
+            .unwrap();
+        self.z.append(Axis(0), Array1::zeros(n).view()).unwrap();
+        self.ω
+            .append(Axis(0), Array2::zeros((n, ngenes)).view())
+            .unwrap();
+        self.λ
+            .append(Axis(1), Array2::from_elem((ngenes, n), 0.1).view())
+            .unwrap();
+
+        ncells as CellIndex
+    }
+
+    // Reassign a whole region, with the given transcripts and volume, from one
+    // cell to another, either of which may be the background.
+    #[allow(clippy::too_many_arguments)]
+    fn move_cell_region(
+        &mut self,
+        priors: &ModelPriors,
+        transcript_genes: &[u32],
+        transcripts: &[usize],
+        volume: f32,
+        from: CellIndex,
+        to: CellIndex,
+        from_emptied: bool,
+        to_created: bool,
+    ) {
+        for &i in transcripts {
+            let layer = self.zlayer(self.transcript_positions[i].2);
+            let gene = transcript_genes[i] as usize;
+            if from != BACKGROUND_CELL {
+                self.counts[[gene, from as usize, layer]] -= 1;
+            }
+            if to != BACKGROUND_CELL {
+                self.counts[[gene, to as usize, layer]] += 1;
+            }
+            self.cell_assignments[i] = to;
+            self.cell_assignment_time[i] = self.t;
+        }
+
+        if from != BACKGROUND_CELL {
+            self.cell_population[from as usize] -= transcripts.len();
+            self.cell_volume[from as usize] = if from_emptied {
+                priors.min_cell_volume
+            } else {
+                (self.cell_volume[from as usize] - volume).max(priors.min_cell_volume)
+            };
+        }
+
+        if to != BACKGROUND_CELL {
+            self.cell_population[to as usize] += transcripts.len();
+            self.cell_volume[to as usize] = if to_created {
+                volume
+            } else {
+                self.cell_volume[to as usize] + volume
+            };
+        }
+    }
+
+    // Draw rates for a new cell from their posterior given only its transcript
+    // counts (`gene_count`, [ngenes, nlayers]) and volume, ignoring noise, for
+    // reversible jump moves that create cells. The cell's component must
+    // already be set. Returns `new_cell_rates_log_ratio` for the draw.
+    fn draw_new_cell_rates(
+        &mut self,
+        rng: &mut SamplerRng,
+        cell: CellIndex,
+        gene_count: &Array2<u32>,
+        volume: f32,
+    ) -> f32 {
+        let z = self.z[cell as usize] as usize;
+        Zip::from(self.λ.column_mut(cell as usize))
+            .and(&gene_count.sum_axis(Axis(1)))
+            .and(self.φ.row(z))
+            .and(self.r.row(z))
+            .for_each(|λ, &c, &φ, &r| {
+                *λ = Gamma::new(r + c as f32, ((-φ).exp() + volume).recip())
+                    .unwrap()
+                    .sample(rng)
+                    .max(f32::MIN_POSITIVE);
+            });
+        self.new_cell_rates_log_ratio(cell, gene_count, volume)
+    }
+
+    // Log prior density of a cell's rates, less their log density under the
+    // proposal of `draw_new_cell_rates`, were the cell created from the given
+    // counts and volume.
+    fn new_cell_rates_log_ratio(&self, cell: CellIndex, gene_count: &Array2<u32>, volume: f32) -> f32 {
+        let z = self.z[cell as usize] as usize;
+        let mut log_ratio = 0.0;
+        Zip::from(self.λ.column(cell as usize))
+            .and(&gene_count.sum_axis(Axis(1)))
+            .and(self.φ.row(z))
+            .and(self.r.row(z))
+            .for_each(|&λ, &c, &φ, &r| {
+                let β = (-φ).exp();
+                log_ratio += gamma_logpdf(r, β, λ) - gamma_logpdf(r + c as f32, β + volume, λ);
+            });
+        log_ratio
+    }
+
+    // Draw a component from the mixing proportions.
+    fn random_component(&self, rng: &mut SamplerRng) -> u32 {
+        let mut u = rng.gen::<f32>();
+        self.π
+            .iter()
+            .position(|&π| {
+                u -= π;
+                u < 0.0
+            })
+            .unwrap_or(self.ncomponents() - 1) as u32
+    }
+
+    // Relabel nuclear and prior segmentation assignments of removed cells, with
+    // the cell they were merged into (or the background), so their index can be
+    // reused.
+    fn relabel_prior_cells(&mut self, relabel: &[CellIndex]) {
+        let relabel_cell = |cell: &mut CellIndex| {
+            if *cell != BACKGROUND_CELL && (*cell as usize) < relabel.len() {
+                *cell = relabel[*cell as usize];
+            }
+        };
+        self.init_nuclear_cell_assignment
+            .par_iter_mut()
+            .for_each(relabel_cell);
+        self.prior_seg_cell_assignment
+            .par_iter_mut()
+            .for_each(relabel_cell);
+    }
+
+    // Probability that each transcript is noise (background or confusion)
+    // rather than expression of the cell it's assigned to, under the current
+    // rates. Unassigned transcripts are always noise.
+    pub fn noise_probabilities(
+        &self,
+        transcripts: &[Transcript],
+        cell_assignments: &[(CellIndex, f32)],
+    ) -> Vec<f32> {
+        transcripts
+            .iter()
+            .zip(cell_assignments)
+            .zip(&self.transcript_positions)
+            .map(|((t, &(cell, _)), position)| {
+                if cell == BACKGROUND_CELL {
+                    return 1.0;
+                }
+                let gene = t.gene as usize;
+                let layer = self.zlayer(position.2);
+                let λ_cell = self.λ[[gene, cell as usize]];
+                let λ_noise = self.λ_bg[[gene, layer]] * self.background_scale(position.0, position.1)
+                    + self.λ_c[gene];
+                λ_noise / (λ_cell + λ_noise)
+            })
+            .collect()
+    }
+
+    // Area of each cell, as its volume over the full depth of the data.
+    pub fn cell_areas(&self) -> Vec<f32> {
+        let zspan = self.layer_depth * self.nlayers() as f32;
+        self.cell_volume.iter().map(|v| v / zspan).collect()
+    }
+
     pub fn ngenes(&self) -> usize {
         self.total_gene_counts.shape()[0]
     }
@@ -490,71 +918,114 @@ impl ModelParams {
             });
 
         // prior seg reassignment terms
-        ll += Zip::from(&self.cell_assignments)
-            .and(&self.prior_seg_cell_assignment)
-            .fold(0_f32, |accum, &cell, &nuc_cell| {
+        ll += self
+            .cell_assignments
+            .iter()
+            .zip(&self.prior_seg_cell_assignment)
+            .enumerate()
+            .fold(0_f32, |accum, (t, (&cell, &nuc_cell))| {
+                let (log_prob, log_1mprob) = self.prior_seg_log_probs(priors, t);
                 if cell == nuc_cell {
-                    accum + priors.prior_seg_reassignment_1mlog_prob
+                    accum + log_1mprob
                 } else {
-                    accum + priors.prior_seg_reassignment_log_prob
+                    accum + log_prob
                 }
             });
 
         // cell volume terms
         ll += Zip::from(&self.cell_volume)
             .and(&self.z)
-            .fold(0_f32, |accum, &v, &z| {
-                accum + lognormal_logpdf(self.μ_volume[z as usize], self.σ_volume[z as usize], v)
+            .and(ArrayView1::from(&self.cell_population))
+            .fold(0_f32, |accum, &v, &z, &population| {
+                if is_empty_cell(priors, population, v) {
+                    accum
+                } else {
+                    accum
+                        + lognormal_logpdf(self.μ_volume[z as usize], self.σ_volume[z as usize], v)
+                }
             });
 
         // background terms
+        let background_volume = self.background_volume();
         ll += Zip::from(&self.background_counts)
             .and(&self.λ_bg)
             .fold(0_f32, |accum, &c, &λ_bg| {
                 if c > 0 {
-                    accum + (c as f32) * λ_bg.ln() - λ_bg * self.full_layer_volume
+                    accum + (c as f32) * λ_bg.ln() - λ_bg * background_volume
                 } else {
-                    accum - λ_bg * self.full_layer_volume
+                    accum - λ_bg * background_volume
                 }
             });
+        if let Some(grid) = &self.background_grid {
+            ll += grid
+                .counts
+                .iter()
+                .zip(&grid.scale)
+                .map(|(&c, &scale)| c as f32 * scale.ln())
+                .sum::<f32>();
+        }
 
         ll
     }
 
+    // Cells are renumbered by `qc`, if given, and `counts` should be of the
+    // renumbered cells.
     pub fn write_cell_hulls(
         &self,
         transcripts: &[Transcript],
         counts: &Array2<u32>,
         filename: &str,
+        qc: Option<&CellQc>,
     ) {
         // We are not maintaining any kind of per-cell array, so I guess I have
         // no choice but to compute such a thing here.
-        let mut cell_transcripts: Vec<Vec<usize>> = vec![Vec::new(); self.ncells()];
+        let mut cell_transcripts: Vec<Vec<usize>> = vec![Vec::new(); counts.ncols()];
         for (i, &cell) in self.cell_assignments.iter().enumerate() {
+            let cell = qc.map_or(cell, |qc| qc.renumber(cell));
             if cell != BACKGROUND_CELL {
                 cell_transcripts[cell as usize].push(i);
             }
         }
 
-        let file = File::create(filename).unwrap();
-        let mut encoder = GzEncoder::new(file, Compression::default());
+        let mut vertices: Vec<(f32, f32)> = Vec::new();
+        let mut hull: Vec<(f32, f32)> = Vec::new();
+        let hulls: Vec<MultiPolygon<f32>> = cell_transcripts
+            .iter()
+            .map(|js| {
+                vertices.clear();
+                for j in js {
+                    let transcript = transcripts[*j];
+                    vertices.push((transcript.x, transcript.y));
+                }
+                let area = convex_hull_area(&mut vertices, &mut hull);
+                if hull.len() < 3 || area <= 0.0 {
+                    MultiPolygon::new(vec![])
+                } else {
+                    MultiPolygon::new(vec![Polygon::new(LineString::from(hull.clone()), vec![])])
+                }
+            })
+            .collect();
+
+        // Hulls of neighboring cells generally overlap, so clip them against
+        // each other. Clipping can cut a hull in pieces, of which the largest
+        // is kept, so that each cell is still one polygon.
+        let hulls: Vec<Option<Polygon<f32>>> = remove_overlaps(&hulls)
+            .into_iter()
+            .map(|polys| {
+                polys
+                    .into_iter()
+                    .max_by(|a, b| a.unsigned_area().total_cmp(&b.unsigned_area()))
+            })
+            .collect();
+
+        let mut encoder = geojson_writer(filename);
         writeln!(
             encoder,
             "{{\n  \"type\": \"FeatureCollection\",\n  \"features\": ["
         )
         .unwrap();
 
-        let mut vertices: Vec<(f32, f32)> = Vec::new();
-        let mut hull: Vec<(f32, f32)> = Vec::new();
-
-        for (i, js) in cell_transcripts.iter().enumerate() {
-            vertices.clear();
-            for j in js {
-                let transcript = transcripts[*j];
-                vertices.push((transcript.x, transcript.y));
-            }
-
-            let area = convex_hull_area(&mut vertices, &mut hull);
+        for (i, poly) in hulls.iter().enumerate() {
             let count = counts.column(i).sum();
 
             writeln!(
@@ -569,31 +1040,35 @@ impl ModelParams {
                     "      }},\n",
                     "      \"geometry\": {{\n",
                     "        \"type\": \"Polygon\",\n",
-                    "        \"coordinates\": [",
-                    "          ["
+                    "        \"coordinates\": ["
                 ),
-                i, area, count
+                i,
+                poly.as_ref().map_or(0.0, |poly| poly.unsigned_area()),
+                count
             )
             .unwrap();
-            for (i, (x, y)) in hull.iter().enumerate() {
-                writeln!(encoder, "            [{}, {}]", x, y).unwrap();
-                if i < hull.len() - 1 {
-                    write!(encoder, ",").unwrap();
+            let rings = poly.iter().flat_map(|poly| std::iter::once(poly.exterior()).chain(poly.interiors()));
+            for (k, ring) in rings.enumerate() {
+                if k > 0 {
+                    writeln!(encoder, ",").unwrap();
                 }
+                let coords = ring
+                    .coords()
+                    .map(|c| format!("[{}, {}]", c.x, c.y))
+                    .join(", ");
+                write!(encoder, "          [{}]", coords).unwrap();
             }
             write!(
                 encoder,
                 concat!(
-                    "          ]\n", // polygon
-                    "        ]\n",   // coordinates
-                    "      }}\n",    // geometry
-                    "    }}\n",      // feature
+                    "\n        ]\n", // coordinates
+                    "      }}\n",  // geometry
+                    "    }}",       // feature
                 )
             )
             .unwrap();
-
-            if i < cell_transcripts.len() - 1 {
-                write!(encoder, ",").unwrap();
+            if i < hulls.len() - 1 {
+                writeln!(encoder, ",").unwrap();
             }
         }
 
@@ -601,6 +1076,35 @@ impl ModelParams {
     }
 }
 
+// Summary of the sampler state at the end of one iteration.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IterationDiagnostics {
+    pub chain: usize,
+    pub iteration: usize,
+    pub phase: usize,
+    pub log_likelihood: f32,
+    pub ncells: usize,
+    pub unassigned_fraction: f32,
+    pub background_fraction: f32,
+    pub mean_cell_area: f32,
+    pub mean_compactness: f32,
+    pub acceptance: AcceptanceRates,
+}
+
+// Fraction of proposals of each kind accepted over some number of iterations,
+// which is NaN if there were none. Voxel proposals either grow a cell into the
+// background, shrink it into the background, or move a voxel between cells.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AcceptanceRates {
+    pub cell_to_cell: f32,
+    pub background_to_cell: f32,
+    pub cell_to_background: f32,
+    pub split: f32,
+    pub merge: f32,
+    pub birth: f32,
+    pub death: f32,
+}
+
 #[derive(Clone, Debug)]
 pub struct ProposalStats {
     cell_to_cell_accept: usize,
@@ -612,6 +1116,14 @@ pub struct ProposalStats {
     cell_to_background_accept: usize,
     cell_to_background_reject: usize,
     cell_to_background_ignore: usize,
+    split_accept: usize,
+    split_reject: usize,
+    merge_accept: usize,
+    merge_reject: usize,
+    birth_accept: usize,
+    birth_reject: usize,
+    death_accept: usize,
+    death_reject: usize,
 }
 
 impl ProposalStats {
@@ -626,9 +1138,52 @@ impl ProposalStats {
             cell_to_background_accept: 0,
             cell_to_background_reject: 0,
             cell_to_background_ignore: 0,
+            split_accept: 0,
+            split_reject: 0,
+            merge_accept: 0,
+            merge_reject: 0,
+            birth_accept: 0,
+            birth_reject: 0,
+            death_accept: 0,
+            death_reject: 0,
+        }
+    }
+
+    // Fraction of proposals of each kind accepted, excluding ignored proposals.
+    pub fn acceptance_rates(&self) -> AcceptanceRates {
+        let rate = |accept: usize, reject: usize| accept as f32 / (accept + reject) as f32;
+        AcceptanceRates {
+            cell_to_cell: rate(self.cell_to_cell_accept, self.cell_to_cell_reject),
+            background_to_cell: rate(self.background_to_cell_accept, self.background_to_cell_reject),
+            cell_to_background: rate(self.cell_to_background_accept, self.cell_to_background_reject),
+            split: rate(self.split_accept, self.split_reject),
+            merge: rate(self.merge_accept, self.merge_reject),
+            birth: rate(self.birth_accept, self.birth_reject),
+            death: rate(self.death_accept, self.death_reject),
         }
     }
 
+    // Add counts from another set of stats, to summarize a whole phase.
+    pub fn add(&mut self, other: &ProposalStats) {
+        self.cell_to_cell_accept += other.cell_to_cell_accept;
+        self.cell_to_cell_reject += other.cell_to_cell_reject;
+        self.cell_to_cell_ignore += other.cell_to_cell_ignore;
+        self.background_to_cell_accept += other.background_to_cell_accept;
+        self.background_to_cell_reject += other.background_to_cell_reject;
+        self.background_to_cell_ignore += other.background_to_cell_ignore;
+        self.cell_to_background_accept += other.cell_to_background_accept;
+        self.cell_to_background_reject += other.cell_to_background_reject;
+        self.cell_to_background_ignore += other.cell_to_background_ignore;
+        self.split_accept += other.split_accept;
+        self.split_reject += other.split_reject;
+        self.merge_accept += other.merge_accept;
+        self.merge_reject += other.merge_reject;
+        self.birth_accept += other.birth_accept;
+        self.birth_reject += other.birth_reject;
+        self.death_accept += other.death_accept;
+        self.death_reject += other.death_reject;
+    }
+
     pub fn reset(&mut self) {
         self.cell_to_cell_accept = 0;
         self.cell_to_cell_reject = 0;
@@ -639,22 +1194,149 @@ impl ProposalStats {
         self.cell_to_background_accept = 0;
         self.cell_to_background_reject = 0;
         self.cell_to_background_ignore = 0;
+        self.split_accept = 0;
+        self.split_reject = 0;
+        self.merge_accept = 0;
+        self.merge_reject = 0;
+        self.birth_accept = 0;
+        self.birth_reject = 0;
+        self.death_accept = 0;
+        self.death_reject = 0;
+    }
+}
+
+impl Default for ProposalStats {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct UncertaintyTracker {
-    cell_assignment_duration: HashMap<(usize, CellIndex), u32>,
+    // Fixed hasher so expected counts are summed in a reproducible order.
+    cell_assignment_duration: HashMap<(usize, CellIndex), u32, FixedState>,
+
+    // Sampler time spanned by chains merged into this one, and their number.
+    merged_time: u32,
+    merged_chains: u32,
+
+    // Summed displacement of each transcript from its observed position over
+    // recorded samples, and the number of samples, giving posterior mean positions.
+    position_displacement: Vec<(f32, f32, f32)>,
+    position_samples: u32,
+
+    // [ncomponents, ncells] number of recorded samples in which each cell was
+    // assigned to each component.
+    component_samples: Array2<u32>,
+}
+
+impl Default for UncertaintyTracker {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl UncertaintyTracker {
     pub fn new() -> UncertaintyTracker {
-        let cell_assignment_duration = HashMap::new();
+        let cell_assignment_duration = HashMap::default();
 
         UncertaintyTracker {
             cell_assignment_duration,
+            merged_time: 0,
+            merged_chains: 0,
+            position_displacement: Vec::new(),
+            position_samples: 0,
+            component_samples: Array2::zeros((0, 0)),
         }
     }
 
+    // Pool the assignment durations of another finished chain, which spanned
+    // sampler time `other_time`, into this one.
+    pub fn merge(&mut self, other: UncertaintyTracker, other_time: u32) {
+        for ((i, j), d) in other.cell_assignment_duration {
+            self.update_assignment_duration(i, j, d);
+        }
+        self.merged_time += other_time + other.merged_time;
+        self.merged_chains += 1 + other.merged_chains;
+
+        if self.position_displacement.is_empty() {
+            self.position_displacement = other.position_displacement;
+        } else if !other.position_displacement.is_empty() {
+            for (a, b) in self
+                .position_displacement
+                .iter_mut()
+                .zip(&other.position_displacement)
+            {
+                a.0 += b.0;
+                a.1 += b.1;
+                a.2 += b.2;
+            }
+        }
+        self.position_samples += other.position_samples;
+
+        // Component labels aren't comparable between chains, so only this
+        // chain's component samples are kept.
+    }
+
+    // Add current transcript positions to the running posterior mean.
+    pub fn record_positions(&mut self, params: &ModelParams, transcripts: &[Transcript]) {
+        if self.position_displacement.is_empty() {
+            self.position_displacement = vec![(0.0, 0.0, 0.0); transcripts.len()];
+        }
+        for ((d, position), t) in self
+            .position_displacement
+            .iter_mut()
+            .zip(&params.transcript_positions)
+            .zip(transcripts)
+        {
+            d.0 += position.0 - t.x;
+            d.1 += position.1 - t.y;
+            d.2 += position.2 - t.z;
+        }
+        self.position_samples += 1;
+    }
+
+    // Count the current component of every cell.
+    pub fn record_components(&mut self, params: &ModelParams) {
+        if self.component_samples.is_empty() {
+            self.component_samples = Array2::zeros((params.ncomponents(), params.ncells()));
+        }
+        for (cell, &z) in params.z.iter().enumerate() {
+            self.component_samples[[z as usize, cell]] += 1;
+        }
+    }
+
+    // [ncomponents, ncells] posterior probability of each cell belonging to each
+    // component, as the fraction of recorded samples in which it did. Without
+    // any recorded samples, this is the current assignment.
+    pub fn component_probabilities(&self, params: &ModelParams) -> Array2<f32> {
+        let mut probs = Array2::zeros((params.ncomponents(), params.ncells()));
+        if self.component_samples.is_empty() {
+            for (cell, &z) in params.z.iter().enumerate() {
+                probs[[z as usize, cell]] = 1.0;
+            }
+            return probs;
+        }
+        for (mut prob, samples) in probs.columns_mut().into_iter().zip(self.component_samples.columns()) {
+            let total = samples.sum() as f32;
+            prob.zip_mut_with(&samples, |p, &n| *p = n as f32 / total);
+        }
+        probs
+    }
+
+    // Posterior mean position of each transcript over the recorded samples.
+    pub fn mean_transcript_positions(&self, transcripts: &[Transcript]) -> Vec<(f32, f32, f32)> {
+        if self.position_samples == 0 {
+            return transcripts.iter().map(|t| (t.x, t.y, t.z)).collect();
+        }
+        let n = self.position_samples as f32;
+        transcripts
+            .iter()
+            .zip(&self.position_displacement)
+            .map(|(t, d)| (t.x + d.0 / n, t.y + d.1 / n, t.z + d.2 / n))
+            .collect()
+    }
+
     // record the duration of the current cell assignment. Called when the state
     // is about to change.
     fn update(&mut self, params: &ModelParams, i: usize) {
@@ -677,6 +1359,22 @@ impl UncertaintyTracker {
             .or_insert(duration);
     }
 
+    // Make `cell` the only recorded assignment of each of `transcripts`, for
+    // transcripts reassigned after sampling has finished.
+    fn reassign(&mut self, transcripts: &[usize], cell: CellIndex) {
+        let mut durations: HashMap<usize, u32> = transcripts.iter().map(|&i| (i, 0)).collect();
+        self.cell_assignment_duration.retain(|(i, _), d| match durations.get_mut(i) {
+            Some(total) => {
+                *total += *d;
+                false
+            }
+            None => true,
+        });
+        for (i, d) in durations {
+            self.update_assignment_duration(i, cell, d);
+        }
+    }
+
     pub fn finish(&mut self, params: &ModelParams) {
         for ((i, &j), &t) in params
             .cell_assignments
@@ -692,7 +1390,7 @@ impl UncertaintyTracker {
         }
     }
 
-    fn max_posterior_cell_assignments(&self, params: &ModelParams) -> Vec<(u32, f32)> {
+    pub fn max_posterior_cell_assignments(&self, params: &ModelParams) -> Vec<(u32, f32)> {
         // sort ascending on (transcript, cell)
         let sorted_durations: Vec<(usize, u32, u32)> = self
             .cell_assignment_duration
@@ -729,7 +1427,8 @@ impl UncertaintyTracker {
                 assert!(d <= d_prev);
                 continue;
             } else if i_prev == usize::MAX || (i > 0 && i - 1 == i_prev) {
-                maxpost_cell_assignments.push((j, d as f32 / params.t as f32));
+                maxpost_cell_assignments
+                    .push((j, d as f32 / (params.t + self.merged_time) as f32));
                 i_prev = i;
                 j_prev = j;
                 d_prev = d;
@@ -773,18 +1472,68 @@ impl UncertaintyTracker {
         (counts, maxpost_assignments)
     }
 
+    // Posterior probability of every (transcript, cell) assignment visited
+    // while recording samples, normalized per transcript. Sorted by transcript,
+    // then descending probability.
+    pub fn posterior_cell_assignments(&self) -> Vec<(usize, CellIndex, f32)> {
+        let mut total_durations: HashMap<usize, u32> = HashMap::new();
+        for (&(i, _j), &d) in self.cell_assignment_duration.iter() {
+            *total_durations.entry(i).or_insert(0) += d;
+        }
+
+        let mut posterior: Vec<(usize, CellIndex, f32)> = self
+            .cell_assignment_duration
+            .iter()
+            .filter(|(_, &d)| d > 0)
+            .map(|(&(i, j), &d)| (i, j, d as f32 / total_durations[&i] as f32))
+            .collect();
+
+        posterior.sort_by(|(i_a, j_a, pr_a), (i_b, j_b, pr_b)| {
+            i_a.cmp(i_b)
+                .then(pr_b.partial_cmp(pr_a).unwrap())
+                .then(j_a.cmp(j_b))
+        });
+
+        posterior
+    }
+
     pub fn expected_counts(&self, params: &ModelParams, transcripts: &[Transcript]) -> Array2<f32> {
+        self.expected_counts_where(params, transcripts, |_| true)
+    }
+
+    // Expected counts of only the nuclear (or only the non-nuclear) transcripts,
+    // which together sum to `expected_counts`.
+    pub fn compartment_expected_counts(
+        &self,
+        params: &ModelParams,
+        transcripts: &[Transcript],
+        nuclear: &[bool],
+        compartment_nuclear: bool,
+    ) -> Array2<f32> {
+        self.expected_counts_where(params, transcripts, |i| nuclear[i] == compartment_nuclear)
+    }
+
+    fn expected_counts_where<F>(
+        &self,
+        params: &ModelParams,
+        transcripts: &[Transcript],
+        include: F,
+    ) -> Array2<f32>
+    where
+        F: Fn(usize) -> bool,
+    {
         let mut ecounts = Array2::<f32>::zeros((params.ngenes(), params.ncells()));
 
         for (&(i, j), &d) in self.cell_assignment_duration.iter() {
-            if j == BACKGROUND_CELL {
+            if j == BACKGROUND_CELL || !include(i) {
                 continue;
             }
 
             let gene = transcripts[i].gene;
             // let layer = params.zlayer(params.transcript_positions[i].2);
 
-            let w_d = d as f32 / (params.t - 1) as f32;
+            let w_d = d as f32
+                / (params.t - 1 + self.merged_time - self.merged_chains) as f32;
 
             // TODO: not accounting for λ_c here!!!
 
@@ -801,6 +1550,251 @@ impl UncertaintyTracker {
     }
 }
 
+// Log probability of a transcript keeping or leaving its prior cell, given the
+// confidence in its source, or the global prior without --prior-confidence.
+fn source_log_probs(
+    priors: &ModelPriors,
+    log_probs: &[(f32, f32)],
+    sources: &[u32],
+    t: usize,
+) -> (f32, f32) {
+    if log_probs.is_empty() {
+        (
+            priors.prior_seg_reassignment_log_prob,
+            priors.prior_seg_reassignment_1mlog_prob,
+        )
+    } else {
+        log_probs[sources[t] as usize]
+    }
+}
+
+// Tally penalties from mis-assigning nuclear or prior segmentation transcripts,
+// when moving `transcripts` from `old_cell` to `new_cell`.
+fn reassignment_log_ratio(
+    priors: &ModelPriors,
+    params: &ModelParams,
+    transcripts: &[usize],
+    old_cell: CellIndex,
+    new_cell: CellIndex,
+) -> f32 {
+    let mut δ = 0.0;
+
+    for &t in transcripts {
+        let cell = params.init_nuclear_cell_assignment[t];
+        if cell != BACKGROUND_CELL {
+            if cell == old_cell {
+                δ -= priors.nuclear_reassignment_1mlog_prob;
+            } else {
+                δ -= priors.nuclear_reassignment_log_prob;
+            }
+
+            if cell == new_cell {
+                δ += priors.nuclear_reassignment_1mlog_prob;
+            } else {
+                δ += priors.nuclear_reassignment_log_prob;
+            }
+        }
+    }
+
+    for &t in transcripts {
+        let cell = params.prior_seg_cell_assignment[t];
+        let (log_prob, log_1mprob) = params.prior_seg_log_probs(priors, t);
+        if cell == old_cell {
+            δ -= log_1mprob;
+        } else {
+            δ -= log_prob;
+        }
+
+        if cell == new_cell {
+            δ += log_1mprob;
+        } else {
+            δ += log_prob;
+        }
+    }
+
+    δ
+}
+
+// Log acceptance ratio of moving a whole region, containing `transcripts`
+// (with counts `gene_count`, [ngenes, nlayers]) and `volume`, from one cell to
+// another, either of which may be the background. Used by moves that change
+// the number of cells, where `from_emptied` means the region is all of `from`,
+// and `to_created` that `to` is a new cell, so their volume prior terms are
+// removed or added rather than changed.
+#[allow(clippy::too_many_arguments)]
+pub fn region_move_log_ratio(
+    priors: &ModelPriors,
+    params: &ModelParams,
+    transcripts: &[usize],
+    gene_count: &Array2<u32>,
+    volume: f32,
+    from: CellIndex,
+    to: CellIndex,
+    from_emptied: bool,
+    to_created: bool,
+) -> f32 {
+    let mut δ = reassignment_log_ratio(priors, params, transcripts, from, to);
+
+    // regions may span background grid bins, so use their mean scale
+    let bg_scale = if params.background_grid.is_some() && !transcripts.is_empty() {
+        transcripts
+            .iter()
+            .map(|&t| {
+                let (x, y, _) = params.transcript_positions[t];
+                params.background_scale(x, y)
+            })
+            .sum::<f32>()
+            / transcripts.len() as f32
+    } else {
+        1.0
+    };
+
+    // likelihood of the region's transcripts (and normalization term) when
+    // assigned to `cell`
+    let region_log_likelihood = |cell: CellIndex| {
+        let mut ll = 0.0;
+        if cell == BACKGROUND_CELL {
+            Zip::from(gene_count.rows())
+                .and(params.λ_bg.rows())
+                .for_each(|gene_counts, λ_bg| {
+                    Zip::from(gene_counts).and(λ_bg).for_each(|&count, &λ_bg| {
+                        if count > 0 {
+                            ll += count as f32 * (bg_scale * λ_bg).ln();
+                        }
+                    });
+                });
+        } else {
+            ll -= params.λ.column(cell as usize).sum() * volume;
+            Zip::from(gene_count.rows())
+                .and(params.λ_bg.rows())
+                .and(&params.λ_c)
+                .and(params.λ.column(cell as usize))
+                .for_each(|gene_counts, λ_bg, &λ_c, &λ| {
+                    Zip::from(gene_counts).and(λ_bg).for_each(|&count, &λ_bg| {
+                        if count > 0 {
+                            ll += count as f32 * (bg_scale * λ_bg + λ_c + λ).ln();
+                        }
+                    })
+                });
+        }
+        ll
+    };
+    δ += region_log_likelihood(to) - region_log_likelihood(from);
+
+    let volume_logpdf = |cell: CellIndex, v: f32| {
+        let z = params.z[cell as usize] as usize;
+        lognormal_logpdf(params.μ_volume[z], params.σ_volume[z], v)
+    };
+
+    if from != BACKGROUND_CELL {
+        let from_volume = params.cell_volume[from as usize];
+        δ -= volume_logpdf(from, from_volume);
+        if !from_emptied {
+            δ += volume_logpdf(from, from_volume - volume);
+        }
+    }
+
+    if to != BACKGROUND_CELL {
+        let to_volume = params.cell_volume[to as usize];
+        if to_created {
+            δ += volume_logpdf(to, volume);
+        } else {
+            δ -= volume_logpdf(to, to_volume);
+            δ += volume_logpdf(to, to_volume + volume);
+        }
+    }
+
+    δ
+}
+
+// Cells emptied by merge or death moves keep their index, so that cell ids
+// stay stable, but have no transcripts and only the minimum volume. They're
+// left out of volume and component sampling until a new cell reuses them.
+fn is_empty_cell(priors: &ModelPriors, population: usize, volume: f32) -> bool {
+    population == 0 && volume <= priors.min_cell_volume
+}
+
+// Neighbors of each cell for the component smoothness prior, with their
+// squared-exponential weights, based on the centroids of the cells' assigned
+// transcripts. With the radius method, neighbors further than three length
+// scales away are left out, since their weight is negligible. With the
+// Delaunay method, neighbors are those adjacent in a triangulation of the
+// (x, y) centroids, however far apart.
+fn component_neighbors(
+    priors: &ModelPriors,
+    params: &ModelParams,
+    transcripts: &[Transcript],
+) -> Vec<Vec<(u32, f32)>> {
+    let ncells = params.ncells();
+    // without a z scale, neighbors are found in x and y only
+    let z_scale = priors.z_scale.unwrap_or(0.0);
+    let mut centroids = vec![(0_f32, 0_f32, 0_f32); ncells];
+    let mut counts = vec![0_u32; ncells];
+    for (t, &cell) in transcripts.iter().zip(&params.cell_assignments) {
+        if cell != BACKGROUND_CELL {
+            centroids[cell as usize].0 += t.x;
+            centroids[cell as usize].1 += t.y;
+            centroids[cell as usize].2 += z_scale * t.z;
+            counts[cell as usize] += 1;
+        }
+    }
+
+    for (centroid, &count) in centroids.iter_mut().zip(&counts) {
+        if count > 0 {
+            centroid.0 /= count as f32;
+            centroid.1 /= count as f32;
+            centroid.2 /= count as f32;
+        }
+    }
+
+    let length = priors.component_smoothness_length;
+    let weight = |distance_squared: f32| (-distance_squared / (2.0 * length * length)).exp();
+
+    if priors.neighborhood_method == NeighborhoodMethod::Delaunay {
+        let points: Vec<(u32, f32, f32)> = centroids
+            .iter()
+            .zip(&counts)
+            .enumerate()
+            .filter(|(_, (_, &count))| count > 0)
+            .map(|(i, (&(x, y, _), _))| (i as u32, x, y))
+            .collect();
+        let mut neighbors = vec![Vec::new(); ncells];
+        for (i, j) in delaunay::delaunay_edges(&points) {
+            let (xi, yi, zi) = centroids[i as usize];
+            let (xj, yj, zj) = centroids[j as usize];
+            let w = weight((xi - xj).powi(2) + (yi - yj).powi(2) + (zi - zj).powi(2));
+            neighbors[i as usize].push((j, w));
+            neighbors[j as usize].push((i, w));
+        }
+        return neighbors;
+    }
+
+    let mut kdtree: KdTree<f32, u32, 3, 32, u32> = KdTree::with_capacity(ncells);
+    for (i, (centroid, &count)) in centroids.iter().zip(&counts).enumerate() {
+        if count > 0 {
+            kdtree.add(&[centroid.0, centroid.1, centroid.2], i as u32);
+        }
+    }
+
+    let radius_squared = (3.0 * length).powi(2);
+    centroids
+        .par_iter()
+        .zip(&counts)
+        .enumerate()
+        .map(|(i, (&(x, y, z), &count))| {
+            if count == 0 {
+                return Vec::new();
+            }
+            kdtree
+                .within_unsorted::<SquaredEuclidean>(&[x, y, z], radius_squared)
+                .iter()
+                .filter(|neighbor| neighbor.item != i as u32)
+                .map(|neighbor| (neighbor.item, weight(neighbor.distance)))
+                .collect()
+        })
+        .collect()
+}
+
 pub trait Proposal {
     fn accept(&mut self);
     fn reject(&mut self);
@@ -819,6 +1813,14 @@ pub trait Proposal {
 
     fn log_weight(&self) -> f32;
 
+    // Change in log prior density from terms of the model not computed here
+    // (e.g. the boundary stain prior), which is tempered along with the rest
+    // of the ratio.
+    fn log_prior_delta(&self) -> f32;
+
+    // Factor by which background rates are scaled where the proposal is.
+    fn background_scale(&self) -> f32;
+
     fn transcripts<'b, 'c>(&'b self) -> &'c [usize]
     where
         'b: 'c;
@@ -829,7 +1831,13 @@ pub trait Proposal {
     where
         'b: 'c;
 
-    fn evaluate(&mut self, priors: &ModelPriors, params: &ModelParams, hillclimb: bool) {
+    fn evaluate(
+        &mut self,
+        priors: &ModelPriors,
+        params: &ModelParams,
+        temperature: f32,
+        rng: &mut SamplerRng,
+    ) {
         if self.ignored() {
             self.reject();
             return;
@@ -841,47 +1849,15 @@ pub trait Proposal {
         let to_background = new_cell == BACKGROUND_CELL;
 
         // Log Metropolis-Hastings acceptance ratio
-        let mut δ = 0.0;
-
-        // Tally penalties from mis-assigning nuclear transcripts
-        for &t in self.transcripts() {
-            let cell = params.init_nuclear_cell_assignment[t];
-            if cell != BACKGROUND_CELL {
-                if cell == old_cell {
-                    δ -= priors.nuclear_reassignment_1mlog_prob;
-                } else {
-                    δ -= priors.nuclear_reassignment_log_prob;
-                }
-
-                if cell == new_cell {
-                    δ += priors.nuclear_reassignment_1mlog_prob;
-                } else {
-                    δ += priors.nuclear_reassignment_log_prob;
-                }
-            }
-        }
-
-        for &t in self.transcripts() {
-            let cell = params.prior_seg_cell_assignment[t];
-            if cell == old_cell {
-                δ -= priors.prior_seg_reassignment_1mlog_prob;
-            } else {
-                δ -= priors.prior_seg_reassignment_log_prob;
-            }
-
-            if cell == new_cell {
-                δ += priors.prior_seg_reassignment_1mlog_prob;
-            } else {
-                δ += priors.prior_seg_reassignment_log_prob;
-            }
-        }
+        let mut δ = reassignment_log_ratio(priors, params, self.transcripts(), old_cell, new_cell);
+        let bg_scale = self.background_scale();
 
         if from_background {
             Zip::from(self.gene_count().rows())
                 .and(params.λ_bg.rows())
                 .for_each(|gene_counts, λ_bg| {
                     Zip::from(gene_counts).and(λ_bg).for_each(|&count, &λ_bg| {
-                        δ -= count as f32 * λ_bg.ln();
+                        δ -= count as f32 * (bg_scale * λ_bg).ln();
                     });
                 });
         } else {
@@ -901,7 +1877,7 @@ pub trait Proposal {
                 .for_each(|gene_counts, λ_bg, &λ_c, λ| {
                     Zip::from(gene_counts).and(λ_bg).for_each(|&count, &λ_bg| {
                         if count > 0 {
-                            δ -= count as f32 * (λ_bg + λ_c + λ).ln();
+                            δ -= count as f32 * (bg_scale * λ_bg + λ_c + λ).ln();
                         }
                     })
                 });
@@ -924,7 +1900,7 @@ pub trait Proposal {
                 .and(params.λ_bg.rows())
                 .for_each(|gene_counts, λ_bg| {
                     Zip::from(gene_counts).and(λ_bg).for_each(|&count, &λ_bg| {
-                        δ += count as f32 * λ_bg.ln();
+                        δ += count as f32 * (bg_scale * λ_bg).ln();
                     });
                 });
         } else {
@@ -945,7 +1921,7 @@ pub trait Proposal {
                 .for_each(|gene_counts, λ_bg, &λ_c, λ| {
                     Zip::from(gene_counts).and(λ_bg).for_each(|&count, &λ_bg| {
                         if count > 0 {
-                            δ += count as f32 * (λ_bg + λ_c + λ).ln();
+                            δ += count as f32 * (bg_scale * λ_bg + λ_c + λ).ln();
                         }
                     })
                 });
@@ -963,10 +1939,18 @@ pub trait Proposal {
             );
         }
 
-        let mut rng = thread_rng();
+        δ += self.log_prior_delta();
+
         let logu = rng.gen::<f32>().ln();
 
-        if (hillclimb && δ > 0.0) || (!hillclimb && logu < δ + self.log_weight()) {
+        // At temperature 0, greedily accept only improvements.
+        let accept = if temperature > 0.0 {
+            logu < δ / temperature + self.log_weight()
+        } else {
+            δ > 0.0
+        };
+
+        if accept {
             self.accept();
             // TODO: debugging
             // if from_background && !to_background {
@@ -991,7 +1975,7 @@ where
     fn initialize(&mut self, priors: &ModelPriors, params: &mut ModelParams) {
         // get to a reasonably high probability assignment
         for _ in 0..40 {
-            self.sample_component_nb_params(priors, params, true);
+            self.sample_component_nb_params(priors, params, true, false);
         }
     }
 
@@ -1014,23 +1998,56 @@ where
 
     fn cell_at_position(&self, pos: (f32, f32, f32)) -> u32;
 
+    // Whether a transcript at `pos` can be moved, which is anywhere unless only
+    // a region of a previous run is being refined.
+    fn editable_position(&self, _pos: (f32, f32, f32)) -> bool {
+        true
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn sample_cell_regions(
         &mut self,
         priors: &ModelPriors,
         params: &mut ModelParams,
         stats: &mut ProposalStats,
         transcripts: &[Transcript],
-        hillclimb: bool,
+        temperature: f32,
         uncertainty: &mut Option<&mut UncertaintyTracker>,
+        profiler: Option<&Profiler>,
     ) {
         // don't count time unless we are tracking uncertainty
         if uncertainty.is_some() {
             params.t += 1;
         }
         self.repopulate_proposals(priors, params);
-        self.proposals_mut()
-            .par_iter_mut()
-            .for_each(|p| p.evaluate(priors, params, hillclimb));
+        let stream = rng::next_stream();
+        match profiler {
+            Some(profiler) => {
+                // there's one proposal per chunk, so time each separately
+                let elapsed: Vec<Duration> = self
+                    .proposals_mut()
+                    .par_iter_mut()
+                    .enumerate()
+                    .map(|(k, p)| {
+                        let t0 = Instant::now();
+                        p.evaluate(priors, params, temperature, &mut rng::stream_rng(stream, k));
+                        t0.elapsed()
+                    })
+                    .collect();
+                profiler.record_chunks(
+                    self.proposals()
+                        .iter()
+                        .zip(elapsed)
+                        .map(|(p, elapsed)| (p.ignored(), p.accepted(), elapsed)),
+                );
+            }
+            None => {
+                self.proposals_mut()
+                    .par_iter_mut()
+                    .enumerate()
+                    .for_each(|(k, p)| p.evaluate(priors, params, temperature, &mut rng::stream_rng(stream, k)));
+            }
+        }
         self.apply_accepted_proposals(stats, transcripts, priors, params, uncertainty);
     }
 
@@ -1133,6 +2150,9 @@ where
         self.update_sampler_state(params);
     }
 
+    // With `greedy`, every parameter is set to its most probable value given
+    // the others (iterated conditional modes), rather than sampled, with
+    // auxiliary variables set to their expectations.
     fn sample_global_params(
         &mut self,
         priors: &ModelPriors,
@@ -1140,16 +2160,17 @@ where
         transcripts: &Vec<Transcript>,
         uncertainty: &mut Option<&mut UncertaintyTracker>,
         burnin: bool,
+        greedy: bool,
     ) {
-        let mut rng = thread_rng();
+        let mut rng = rng::rng();
 
         // let t0 = Instant::now();
-        self.sample_volume_params(priors, params);
+        self.sample_volume_params(priors, params, greedy);
         // println!("  Sample volume params: {:?}", t0.elapsed());
 
         // // Sample background/foreground counts
         // let t0 = Instant::now();
-        self.sample_transcript_state(priors, params, transcripts, uncertainty);
+        self.sample_transcript_state(priors, params, transcripts, uncertainty, greedy);
         // println!("  Sample transcript states: {:?}", t0.elapsed());
 
         // let t0 = Instant::now();
@@ -1157,12 +2178,12 @@ where
         // println!("  Compute counts: {:?}", t0.elapsed());
 
         // let t0 = Instant::now();
-        self.sample_component_nb_params(priors, params, burnin);
+        self.sample_component_nb_params(priors, params, burnin, greedy);
         // println!("  Sample nb params: {:?}", t0.elapsed());
 
         // Sample λ
         // let t0 = Instant::now();
-        self.sample_rates(priors, params);
+        self.sample_rates(priors, params, greedy);
         // println!("  Sample λ: {:?}", t0.elapsed());
 
         // TODO:
@@ -1174,7 +2195,10 @@ where
 
         // Sample z
         // let t0 = Instant::now();
-        self.sample_component_assignments(priors, params);
+        let neighbors = priors
+            .component_smoothness
+            .map(|_| component_neighbors(priors, params, transcripts));
+        self.sample_component_assignments(priors, params, neighbors.as_deref(), greedy);
         // println!("  Sample z: {:?}", t0.elapsed());
 
         // sample π
@@ -1183,9 +2207,46 @@ where
             α[*z_i as usize] += 1.0;
         }
 
-        if α.len() == 1 {
+        if let Some(concentration) = priors.dp_concentration {
+            // Stick-breaking construction, truncated at the number of
+            // components. Only non-empty cells are counted, so that components
+            // left without cells die out.
+            let mut counts = vec![0_f32; params.ncomponents()];
+            for (i, z_i) in params.z.iter().enumerate() {
+                if !is_empty_cell(priors, params.cell_population[i], params.cell_volume[i]) {
+                    counts[*z_i as usize] += 1.0;
+                }
+            }
+
+            let mut remaining = counts.iter().sum::<f32>();
+            let mut stick = 1_f32;
+            params.π.clear();
+            for (k, count) in counts.iter().enumerate() {
+                remaining -= count;
+                let v = if k + 1 == counts.len() {
+                    1.0
+                } else if greedy {
+                    beta_mode(1.0 + count, concentration + remaining)
+                } else {
+                    Beta::new(1.0 + count, concentration + remaining)
+                        .unwrap()
+                        .sample(&mut rng)
+                };
+                params.π.push(stick * v);
+                stick *= 1.0 - v;
+            }
+        } else if α.len() == 1 {
             params.π.clear();
             params.π.push(1.0);
+        } else if greedy {
+            // Dirichlet mode
+            let total = α.iter().sum::<f32>() - α.len() as f32;
+            params.π.clear();
+            if total > 0.0 {
+                params.π.extend(α.iter().map(|α_k| (α_k - 1.0) / total));
+            } else {
+                params.π.resize(α.len(), 1.0 / α.len() as f32);
+            }
         } else {
             params.π.clear();
             params
@@ -1194,17 +2255,20 @@ where
         }
 
         // let t0 = Instant::now();
-        self.sample_background_rates(priors, params);
+        self.sample_background_rates(priors, params, greedy);
         // println!("  Sample background rates: {:?}", t0.elapsed());
 
         // let t0 = Instant::now();
-        self.sample_confusion_rates(priors, params);
+        self.sample_confusion_rates(priors, params, greedy);
         // TODO: disabling confusion to see if it actually does anything
         // println!("  Sample confusion rates: {:?}", t0.elapsed());
 
         // let t0 = Instant::now();
         if !burnin && priors.use_diffusion_model {
-            self.sample_transcript_positions(priors, params, transcripts, uncertainty);
+            self.sample_transcript_positions(priors, params, transcripts, uncertainty, greedy);
+            if priors.use_gene_z_offsets {
+                self.sample_gene_z_offsets(priors, params, transcripts, greedy);
+            }
         }
         // println!("  Sample transcript positions: {:?}", t0.elapsed());
     }
@@ -1215,18 +2279,21 @@ where
         params: &mut ModelParams,
         transcripts: &Vec<Transcript>,
         uncertainty: &mut Option<&mut UncertaintyTracker>,
+        greedy: bool,
     ) {
         params
             .prev_transcript_state
             .clone_from(&params.transcript_state);
         let nlayers = params.nlayers();
-        Zip::from(&mut params.transcript_state)
+        let background_grid = params.background_grid.as_ref();
+        let stream = rng::next_stream();
+        Zip::indexed(&mut params.transcript_state)
             .and(&params.cell_assignments)
             .and(&params.transcript_positions)
             .and(transcripts)
             .into_par_iter()
             .with_min_len(100)
-            .for_each(|(state, &cell, position, t)| {
+            .for_each(|(i, state, &cell, position, t)| {
                 if cell == BACKGROUND_CELL {
                     *state = TranscriptState::Background;
                 } else {
@@ -1235,17 +2302,29 @@ where
                     let layer = layer.min(nlayers - 1);
 
                     let λ_cell = params.λ[[gene, cell as usize]];
-                    let λ_bg = params.λ_bg[[gene, layer]];
+                    let λ_bg = params.λ_bg[[gene, layer]]
+                        * background_grid.map_or(1.0, |grid| grid.scale_at(position.0, position.1));
                     let λ_c = params.λ_c[gene];
                     let λ = λ_cell + λ_bg + λ_c;
 
-                    let u = thread_rng().gen::<f32>();
-                    *state = if u < λ_cell / λ {
-                        TranscriptState::Foreground
-                    } else if u < (λ_cell + λ_bg) / λ {
-                        TranscriptState::Background
+                    *state = if greedy {
+                        // whichever source has the largest rate
+                        if λ_cell >= λ_bg && λ_cell >= λ_c {
+                            TranscriptState::Foreground
+                        } else if λ_bg >= λ_c {
+                            TranscriptState::Background
+                        } else {
+                            TranscriptState::Confusion
+                        }
                     } else {
-                        TranscriptState::Confusion
+                        let u = rng::stream_rng(stream, i).gen::<f32>();
+                        if u < λ_cell / λ {
+                            TranscriptState::Foreground
+                        } else if u < (λ_cell + λ_bg) / λ {
+                            TranscriptState::Background
+                        } else {
+                            TranscriptState::Confusion
+                        }
                     };
                 }
             });
@@ -1320,6 +2399,7 @@ where
         priors: &ModelPriors,
         params: &mut ModelParams,
         burnin: bool,
+        greedy: bool,
     ) {
         // total component area
         // let mut component_cell_area = vec![0_f32; params.ncomponents()];
@@ -1347,18 +2427,20 @@ where
         // dbg!(vmin, vmax);
 
         // let t0 = Instant::now();
-        Zip::from(params.ω.rows_mut()) // for every cell
+        let stream = rng::next_stream();
+        Zip::indexed(params.ω.rows_mut()) // for every cell
             .and(params.foreground_counts.axis_iter(Axis(0)))
             .and(&params.cell_log_volume)
             .and(&params.z)
-            .par_for_each(|ωs, cs, &logv, &z| {
-                let mut rng = thread_rng();
+            .par_for_each(|i, ωs, cs, &logv, &z| {
+                let mut rng = rng::stream_rng(stream, i);
                 Zip::from(cs.axis_iter(Axis(0))) // for every gene
                     .and(ωs)
                     .and(params.φ.row(z as usize))
                     .and(params.r.row(z as usize))
                     .for_each(|c, ω, φ, &r| {
-                        *ω = PolyaGamma::new(c.sum() as f32 + r, logv + φ).sample(&mut rng);
+                        let dist = PolyaGamma::new(c.sum() as f32 + r, logv + φ);
+                        *ω = if greedy { dist.mean() } else { dist.sample(&mut rng) };
                     });
             });
         // println!("  Sample ω: {:?}", t0.elapsed());
@@ -1383,21 +2465,48 @@ where
                     });
             });
 
-        Zip::from(&mut params.σ_φ).for_each(|σ| *σ = (priors.γ + *σ).recip());
+        if let Some(expression_prior) = &params.expression_prior {
+            let log_density = expression_prior.component_log_density(
+                &params.foreground_counts,
+                &params.cell_volume,
+                &params.z,
+            );
+            Zip::indexed(&mut params.μ_φ)
+                .and(&mut params.σ_φ)
+                .and(&params.r)
+                .for_each(|(k, gene), μ, σ, &r| {
+                    match expression_prior.prior_mean(k, gene, log_density[k], r) {
+                        Some(μ_prior) => {
+                            *σ = (expression_prior.precision + *σ).recip();
+                            *μ = (*μ + expression_prior.precision * μ_prior) * *σ;
+                        }
+                        None => {
+                            *σ = (priors.γ + *σ).recip();
+                            *μ *= *σ;
+                        }
+                    }
+                });
+        } else {
+            Zip::from(&mut params.σ_φ).for_each(|σ| *σ = (priors.γ + *σ).recip());
 
-        Zip::from(&mut params.μ_φ)
-            .and(&params.σ_φ)
-            .for_each(|μ, σ| *μ *= σ);
+            Zip::from(&mut params.μ_φ)
+                .and(&params.σ_φ)
+                .for_each(|μ, σ| *μ *= σ);
+        }
         // println!("  Compute φ parameters: {:?}", t0.elapsed());
 
         // Sample φ
         // let t0 = Instant::now();
+        let mut rng = rng::rng();
         Zip::from(&mut params.φ)
             .and(&params.μ_φ)
             .and(&params.σ_φ)
             .for_each(|φ, &μ, &σ| {
-                let mut rng = thread_rng();
-                *φ = Normal::new(μ, σ.sqrt()).unwrap().sample(&mut rng);
+                *φ = if greedy {
+                    μ
+                } else {
+                    Normal::new(μ, σ.sqrt()).unwrap().sample(&mut rng)
+                };
             });
         // println!("  Sample φ: {:?}", t0.elapsed());
 
@@ -1456,14 +2565,15 @@ where
         } else {
             // for each gene
             params.uv.fill((0_u32, 0_f32));
-            Zip::from(params.r.columns_mut())
+            let stream = rng::next_stream();
+            Zip::indexed(params.r.columns_mut())
                 .and(params.lgamma_r.columns_mut())
                 .and(params.loggammaplus.columns_mut())
-                .and(params.φ.columns())
                 .and(params.foreground_counts.axis_iter(Axis(1)))
                 .and(params.uv.columns_mut())
-                .par_for_each(|rs, lgamma_rs, loggammaplus, φs, cs, mut uv| {
-                    let mut rng = thread_rng();
+                .par_for_each(|gene, rs, lgamma_rs, loggammaplus, cs, mut uv| {
+                    let mut rng = rng::stream_rng(stream, gene);
+                    let φs = params.φ.column(gene);
 
                     // iterate over cells computing u and v
                     Zip::from(&params.z)
@@ -1483,7 +2593,12 @@ where
                                 dbg!(uv[z], ψ, φ, vol);
                             }
 
-                            uv[z] = (uv_z.0 + rand_crt(&mut rng, c as u32, r), uv_z.1 + δv);
+                            let u = if greedy {
+                                crt_mean(c as u32, r).round() as u32
+                            } else {
+                                rand_crt(&mut rng, c as u32, r)
+                            };
+                            uv[z] = (uv_z.0 + u, uv_z.1 + δv);
 
                             assert!(uv[z].1.is_finite());
                         });
@@ -1500,7 +2615,11 @@ where
                                 dbg!(uv.0, uv.1, params.h);
                             }
                             let dist = dist.unwrap();
-                            *r = dist.sample(&mut rng);
+                            *r = if greedy {
+                                gamma_mode(priors.e_r + uv.0 as f32, params.h - uv.1)
+                            } else {
+                                dist.sample(&mut rng)
+                            };
 
                             // if *r < 0.001 {
                             //     dbg!(uv.0, uv.1, params.h, *r);
@@ -1553,23 +2672,25 @@ where
         }
 
         // params.h = 0.1;
-        params.h = Gamma::new(
-            priors.e_h * (1_f32 + params.r.len() as f32),
-            (priors.f_h + params.r.sum()).recip(),
-        )
-        .unwrap()
-        .sample(&mut thread_rng());
+        let α_h = priors.e_h * (1_f32 + params.r.len() as f32);
+        let β_h = priors.f_h + params.r.sum();
+        params.h = if greedy {
+            gamma_mode(α_h, β_h)
+        } else {
+            Gamma::new(α_h, β_h.recip()).unwrap().sample(&mut rng::rng())
+        };
         // dbg!(params.h);
     }
 
-    fn sample_rates(&mut self, _priors: &ModelPriors, params: &mut ModelParams) {
+    fn sample_rates(&mut self, _priors: &ModelPriors, params: &mut ModelParams, greedy: bool) {
         // loop over genes
-        Zip::from(params.λ.rows_mut())
+        let stream = rng::next_stream();
+        Zip::indexed(params.λ.rows_mut())
             .and(params.foreground_counts.axis_iter(Axis(1)))
             .and(params.φ.columns())
             .and(params.r.columns())
-            .par_for_each(|mut λs, cs, φs, rs| {
-                let mut rng = thread_rng();
+            .par_for_each(|gene, mut λs, cs, φs, rs| {
+                let mut rng = rng::stream_rng(stream, gene);
                 // loop over cells
                 for (λ, &z, cs, cell_volume) in
                     izip!(&mut λs, &params.z, cs.outer_iter(), &params.cell_volume)
@@ -1587,7 +2708,11 @@ where
                     let β0 = (-φ).exp();
                     let β = β0 + cell_volume;
 
-                    *λ = Gamma::new(α, β.recip()).unwrap().sample(&mut rng);
+                    *λ = if greedy {
+                        gamma_mode(α, β)
+                    } else {
+                        Gamma::new(α, β.recip()).unwrap().sample(&mut rng)
+                    };
                     // .max(1e-14);
 
                     // if c > 10 {
@@ -1603,19 +2728,46 @@ where
             });
     }
 
-    fn sample_background_rates(&mut self, priors: &ModelPriors, params: &mut ModelParams) {
-        let mut rng = thread_rng();
+    fn sample_background_rates(&mut self, priors: &ModelPriors, params: &mut ModelParams, greedy: bool) {
+        let mut rng = rng::rng();
 
+        let background_volume = params.background_volume();
         Zip::from(params.λ_bg.rows_mut())
             .and(params.background_counts.rows())
             .for_each(|λs, cs| {
                 Zip::from(λs).and(cs).for_each(|λ, c| {
                     let α = priors.α_bg + *c as f32;
-                    let β = priors.β_bg + params.full_layer_volume;
-                    *λ = Gamma::new(α, β.recip()).unwrap().sample(&mut rng) as f32;
+                    let β = priors.β_bg + background_volume;
+                    *λ = if greedy {
+                        gamma_mode(α, β)
+                    } else {
+                        Gamma::new(α, β.recip()).unwrap().sample(&mut rng)
+                    };
                 });
             });
 
+        if let Some(grid) = &mut params.background_grid {
+            grid.counts.fill(0);
+            for (&state, &(x, y, _)) in params.transcript_state.iter().zip(&params.transcript_positions) {
+                if state == TranscriptState::Background {
+                    let bin = grid.bin(x, y);
+                    grid.counts[bin] += 1;
+                }
+            }
+
+            // every bin has the same volume in each layer
+            let λ_bg_total = params.λ_bg.sum();
+            for ((scale, &c), &volume) in grid.scale.iter_mut().zip(&grid.counts).zip(&grid.volume) {
+                let α = priors.α_bg_scale + c as f32;
+                let β = priors.α_bg_scale + λ_bg_total * volume;
+                *scale = if greedy {
+                    gamma_mode(α, β)
+                } else {
+                    Gamma::new(α, β.recip()).unwrap().sample(&mut rng)
+                };
+            }
+        }
+
         // dbg!(&params.total_transcript_density);
         // dbg!(params.full_layer_volume);
         // dbg!(&params.λ_bg);
@@ -1631,26 +2783,46 @@ where
         //     });
     }
 
-    fn sample_confusion_rates(&mut self, priors: &ModelPriors, params: &mut ModelParams) {
+    fn sample_confusion_rates(&mut self, priors: &ModelPriors, params: &mut ModelParams, greedy: bool) {
         let total_cell_volume = params.cell_volume.sum();
-        let mut rng = thread_rng();
+        let mut rng = rng::rng();
         Zip::from(&mut params.λ_c)
             .and(&params.confusion_counts)
             .for_each(|λ, c| {
                 let α = priors.α_c + *c as f32;
                 let β = priors.β_c + total_cell_volume;
-                *λ = Gamma::new(α, β.recip()).unwrap().sample(&mut rng) as f32;
+                *λ = if greedy {
+                    gamma_mode(α, β)
+                } else {
+                    Gamma::new(α, β.recip()).unwrap().sample(&mut rng)
+                };
             });
     }
 
-    fn sample_component_assignments(&mut self, _priors: &ModelPriors, params: &mut ModelParams) {
+    fn sample_component_assignments(
+        &mut self,
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        neighbors: Option<&[Vec<(u32, f32)>]>,
+        greedy: bool,
+    ) {
         let ncomponents = params.ncomponents();
 
+        // All cells are updated at once, so the smoothness prior conditions
+        // on neighbors' assignments from the previous iteration.
+        let prev_z = neighbors.map(|_| params.z.clone());
+        let smoothness = priors.component_smoothness.unwrap_or(0.0);
+
         // loop over cells
-        Zip::from(params.foreground_counts.axis_iter(Axis(0)))
+        let stream = rng::next_stream();
+        Zip::indexed(params.foreground_counts.axis_iter(Axis(0)))
             .and(&mut params.z)
             .and(&params.cell_log_volume)
-            .par_for_each(|cs, z_i, cell_log_volume| {
+            .par_for_each(|i, cs, z_i, cell_log_volume| {
+                if is_empty_cell(priors, params.cell_population[i], params.cell_volume[i]) {
+                    return;
+                }
+
                 let mut z_probs = params
                     .z_probs
                     .get_or(|| RefCell::new(vec![0_f64; ncomponents]))
@@ -1692,12 +2864,37 @@ where
                     *zp *= normal_pdf(μ_volume, σ_volume, *cell_log_volume).exp() as f64;
                 }
 
+                if let (Some(neighbors), Some(prev_z)) = (neighbors, &prev_z) {
+                    let mut agreement = vec![0_f64; ncomponents];
+                    for &(j, w) in &neighbors[i] {
+                        let j = j as usize;
+                        if !is_empty_cell(priors, params.cell_population[j], params.cell_volume[j]) {
+                            agreement[prev_z[j] as usize] += (smoothness * w) as f64;
+                        }
+                    }
+                    // relative to the largest, to avoid overflow
+                    let max_agreement = agreement.iter().cloned().fold(0.0, f64::max);
+                    for (zp, a) in z_probs.iter_mut().zip(agreement) {
+                        *zp *= (a - max_agreement).exp();
+                    }
+                }
+
                 // z_probs.iter_mut().enumerate().for_each(|(j, zp)| {
                 //     *zp = (self.params.π[j] as f64) *
                 //         negbin_logpmf(r, lgamma_r, p, k)
                 //         // (self.params.cell_logprob_fast(j as usize, *cell_area, &cs, &clfs) as f64).exp();
                 // });
 
+                if greedy {
+                    *z_i = z_probs
+                        .iter()
+                        .enumerate()
+                        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+                        .unwrap()
+                        .0 as u32;
+                    return;
+                }
+
                 let z_prob_sum = z_probs.iter().sum::<f64>();
 
                 assert!(z_prob_sum.is_finite());
@@ -1709,13 +2906,14 @@ where
                     acc
                 });
 
-                let rng = &mut thread_rng();
-                let u = rng.gen::<f64>();
+                let u = rng::stream_rng(stream, i).gen::<f64>();
                 *z_i = z_probs.partition_point(|x| *x < u) as u32;
             });
     }
 
-    fn sample_volume_params(&mut self, priors: &ModelPriors, params: &mut ModelParams) {
+    fn sample_volume_params(&mut self, priors: &ModelPriors, params: &mut ModelParams, greedy: bool) {
+        let mut rng = rng::rng();
+
         Zip::from(&mut params.cell_log_volume)
             .and(&params.cell_volume)
             .into_par_iter()
@@ -1727,9 +2925,12 @@ where
         // compute sample means
         params.component_population.fill(0_u32);
         params.μ_volume.fill(0_f32);
-        Zip::from(&params.z)
+        Zip::indexed(&params.z)
             .and(&params.cell_log_volume)
-            .for_each(|&z, &log_volume| {
+            .for_each(|i, &z, &log_volume| {
+                if is_empty_cell(priors, params.cell_population[i], params.cell_volume[i]) {
+                    return;
+                }
                 params.μ_volume[z as usize] += log_volume;
                 params.component_population[z as usize] += 1;
             });
@@ -1744,22 +2945,23 @@ where
             .and(&params.σ_volume)
             .and(&params.component_population)
             .for_each(|μ, &σ, &pop| {
-                let mut rng = thread_rng();
-
                 let v = (1_f32 / priors.σ_μ_volume.powi(2) + pop as f32 / σ.powi(2)).recip();
-                *μ = Normal::new(
-                    v * (priors.μ_μ_volume / priors.σ_μ_volume.powi(2) + *μ / σ.powi(2)),
-                    v.sqrt(),
-                )
-                .unwrap()
-                .sample(&mut rng);
+                let mean = v * (priors.μ_μ_volume / priors.σ_μ_volume.powi(2) + *μ / σ.powi(2));
+                *μ = if greedy {
+                    mean
+                } else {
+                    Normal::new(mean, v.sqrt()).unwrap().sample(&mut rng)
+                };
             });
 
         // compute sample variances
         params.σ_volume.fill(0_f32);
-        Zip::from(&params.z)
+        Zip::indexed(&params.z)
             .and(&params.cell_log_volume)
-            .for_each(|&z, &log_volume| {
+            .for_each(|i, &z, &log_volume| {
+                if is_empty_cell(priors, params.cell_population[i], params.cell_volume[i]) {
+                    return;
+                }
                 params.σ_volume[z as usize] += (params.μ_volume[z as usize] - log_volume).powi(2);
             });
 
@@ -1767,15 +2969,72 @@ where
         Zip::from(&mut params.σ_volume)
             .and(&params.component_population)
             .for_each(|σ, &pop| {
-                let mut rng = thread_rng();
-                *σ = Gamma::new(
-                    priors.α_σ_volume + (pop as f32) / 2.0,
-                    (priors.β_σ_volume + *σ / 2.0).recip(),
-                )
-                .unwrap()
-                .sample(&mut rng)
-                .recip()
-                .sqrt();
+                let α = priors.α_σ_volume + (pop as f32) / 2.0;
+                let β = priors.β_σ_volume + *σ / 2.0;
+                *σ = if greedy {
+                    // inverse-gamma mode of the variance
+                    (β / (α + 1.0)).sqrt()
+                } else {
+                    Gamma::new(α, β.recip()).unwrap().sample(&mut rng).recip().sqrt()
+                };
+            });
+    }
+
+    // Sample each gene's z offset and spread given current transcript positions.
+    // Genes concentrated at some depth in cells (e.g. nuclear or membrane
+    // associated) are then repositioned relative to that depth.
+    fn sample_gene_z_offsets(
+        &mut self,
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        transcripts: &[Transcript],
+        greedy: bool,
+    ) {
+        let mut rng = rng::rng();
+        let ngenes = params.gene_z_offset.len();
+
+        let mut population = Array1::<u32>::zeros(ngenes);
+        let mut displacement = Array1::<f32>::zeros(ngenes);
+        for (t, position) in transcripts.iter().zip(&params.transcript_positions) {
+            population[t.gene as usize] += 1;
+            displacement[t.gene as usize] += position.2 - t.z;
+        }
+
+        // sample offsets
+        Zip::from(&mut params.gene_z_offset)
+            .and(&params.gene_σ_z)
+            .and(&population)
+            .and(&displacement)
+            .for_each(|μ, &σ, &pop, &d| {
+                let v = (1_f32 / priors.σ_z_offset.powi(2) + pop as f32 / σ.powi(2)).recip();
+                *μ = if greedy {
+                    v * d / σ.powi(2)
+                } else {
+                    Normal::new(v * d / σ.powi(2), v.sqrt())
+                        .unwrap()
+                        .sample(&mut rng)
+                };
+            });
+
+        // compute sample variances
+        let mut sq_displacement = Array1::<f32>::zeros(ngenes);
+        for (t, position) in transcripts.iter().zip(&params.transcript_positions) {
+            let gene = t.gene as usize;
+            sq_displacement[gene] += (position.2 - t.z - params.gene_z_offset[gene]).powi(2);
+        }
+
+        // sample σ parameters
+        Zip::from(&mut params.gene_σ_z)
+            .and(&population)
+            .and(&sq_displacement)
+            .for_each(|σ, &pop, &ss| {
+                let α = priors.α_σ_z_diffusion + (pop as f32) / 2.0;
+                let β = priors.β_σ_z_diffusion + ss / 2.0;
+                *σ = if greedy {
+                    (β / (α + 1.0)).sqrt()
+                } else {
+                    Gamma::new(α, β.recip()).unwrap().sample(&mut rng).recip().sqrt()
+                };
             });
     }
 
@@ -1784,23 +3043,28 @@ where
         priors: &ModelPriors,
         params: &mut ModelParams,
         transcripts: &Vec<Transcript>,
+        greedy: bool,
     ) {
         // make proposals
         // let t0 = Instant::now();
+        let stream = rng::next_stream();
         params
             .proposed_transcript_positions
             .par_iter_mut()
             // .zip(&params.transcript_positions)
             .zip(transcripts)
-            .for_each(|(proposed_position, t)| {
-                let mut rng = thread_rng();
+            .enumerate()
+            .for_each(|(i, (proposed_position, t))| {
+                let mut rng = rng::stream_rng(stream, i);
+                let z_offset = params.gene_z_offset[t.gene as usize];
                 *proposed_position = (
                     t.x + priors.σ_diffusion_proposal
                         * rng.sample::<f32, StandardNormal>(StandardNormal),
                     t.y + priors.σ_diffusion_proposal
                         * rng.sample::<f32, StandardNormal>(StandardNormal),
-                    (t.z + priors.σ_z_diffusion_proposal
-                        * rng.sample::<f32, StandardNormal>(StandardNormal))
+                    (t.z + z_offset
+                        + priors.σ_z_diffusion_proposal
+                            * rng.sample::<f32, StandardNormal>(StandardNormal))
                     .min(priors.zmax)
                     .max(priors.zmin),
                 );
@@ -1816,6 +3080,10 @@ where
 
         // accept/reject proposals
         // let t0 = Instant::now();
+        let stream = rng::next_stream();
+        let background_grid = params.background_grid.as_ref();
+        let prior_seg_log_probs = &params.prior_seg_log_probs;
+        let prior_seg_sources = &params.prior_seg_sources;
         params
             .accept_proposed_transcript_positions
             .par_iter_mut()
@@ -1838,12 +3106,23 @@ where
                         return;
                     }
 
+                    // Cells outside the region being refined keep their
+                    // transcripts.
+                    if !self.editable_position(*position)
+                        || !self.editable_position(*proposed_position)
+                    {
+                        *accept = false;
+                        return;
+                    }
+
                     let sq_dist_new = (proposed_position.0 - transcript.x).powi(2)
                         + (proposed_position.1 - transcript.y).powi(2);
                     let sq_dist_prev =
                         (position.0 - transcript.x).powi(2) + (position.1 - transcript.y).powi(2);
-                    let z_sq_dist_new = (proposed_position.2 - transcript.z).powi(2);
-                    let z_sq_dist_prev = (position.2 - transcript.z).powi(2);
+                    let gene = transcript.gene as usize;
+                    let z_mean = transcript.z + params.gene_z_offset[gene];
+                    let z_sq_dist_new = (proposed_position.2 - z_mean).powi(2);
+                    let z_sq_dist_prev = (position.2 - z_mean).powi(2);
 
                     let mut δ = 0.0;
 
@@ -1860,18 +3139,17 @@ where
                     .ln();
 
                     // weight by xy proposal distribution
-                    δ += normal_x2_logpdf(priors.σ_diffusion_proposal, sq_dist_prev);
-                    δ -= normal_x2_logpdf(priors.σ_diffusion_proposal, sq_dist_new);
+                    let mut log_weight = normal_x2_logpdf(priors.σ_diffusion_proposal, sq_dist_prev)
+                        - normal_x2_logpdf(priors.σ_diffusion_proposal, sq_dist_new);
 
                     // prior on z diffusion distance
-                    δ -= -0.5 * (z_sq_dist_prev / priors.σ_z_diffusion.powi(2));
-                    δ += -0.5 * (z_sq_dist_new / priors.σ_z_diffusion.powi(2));
+                    let σ_z = params.gene_σ_z[gene];
+                    δ -= -0.5 * (z_sq_dist_prev / σ_z.powi(2));
+                    δ += -0.5 * (z_sq_dist_new / σ_z.powi(2));
 
                     // weight by z proposal distribution
-                    δ += normal_x2_logpdf(priors.σ_z_diffusion_proposal, z_sq_dist_prev);
-                    δ -= normal_x2_logpdf(priors.σ_z_diffusion_proposal, z_sq_dist_new);
-
-                    let gene = transcript.gene as usize;
+                    log_weight += normal_x2_logpdf(priors.σ_z_diffusion_proposal, z_sq_dist_prev)
+                        - normal_x2_logpdf(priors.σ_z_diffusion_proposal, z_sq_dist_new);
 
                     let layer_prev =
                         ((position.2 - params.z0) / params.layer_depth).max(0.0) as usize;
@@ -1881,7 +3159,8 @@ where
                         0.0
                     } else {
                         params.λ[[gene, cell_prev as usize]] + params.λ_c[gene]
-                    } + params.λ_bg[[gene, layer_prev]];
+                    } + params.λ_bg[[gene, layer_prev]]
+                        * background_grid.map_or(1.0, |grid| grid.scale_at(position.0, position.1));
 
                     let layer_new =
                         ((proposed_position.2 - params.z0) / params.layer_depth).max(0.0) as usize;
@@ -1891,7 +3170,10 @@ where
                         0.0
                     } else {
                         params.λ[[gene, cell_new as usize]] + params.λ_c[gene]
-                    } + params.λ_bg[[gene, layer_new]];
+                    } + params.λ_bg[[gene, layer_new]]
+                        * background_grid.map_or(1.0, |grid| {
+                            grid.scale_at(proposed_position.0, proposed_position.1)
+                        });
 
                     let ln_λ_diff = λ_new.ln() - λ_prev.ln();
                     δ += ln_λ_diff;
@@ -1912,21 +3194,27 @@ where
                     // }
 
                     let cell_prior = params.prior_seg_cell_assignment[i];
+                    let (log_prob, log_1mprob) =
+                        source_log_probs(priors, prior_seg_log_probs, prior_seg_sources, i);
                     if cell_prior == cell_prev {
-                        δ -= priors.prior_seg_reassignment_1mlog_prob;
+                        δ -= log_1mprob;
                     } else {
-                        δ -= priors.prior_seg_reassignment_log_prob;
+                        δ -= log_prob;
                     }
 
                     if cell_prior == cell_new {
-                        δ += priors.prior_seg_reassignment_1mlog_prob;
+                        δ += log_1mprob;
                     } else {
-                        δ += priors.prior_seg_reassignment_log_prob;
+                        δ += log_prob;
                     }
 
-                    let mut rng = thread_rng();
-                    let logu = rng.gen::<f32>().ln();
-                    *accept = logu < δ;
+                    // Greedily, accept only improvements, as with cell boundaries.
+                    *accept = if greedy {
+                        δ > 0.0
+                    } else {
+                        let logu = rng::stream_rng(stream, i).gen::<f32>().ln();
+                        logu < δ + log_weight
+                    };
                 },
             );
         // println!("  Eval transcript position proposals: {:?}", t0.elapsed());
@@ -1942,8 +3230,9 @@ where
         params: &mut ModelParams,
         transcripts: &Vec<Transcript>,
         uncertainty: &mut Option<&mut UncertaintyTracker>,
+        greedy: bool,
     ) {
-        self.propose_eval_transcript_positions(priors, params, transcripts);
+        self.propose_eval_transcript_positions(priors, params, transcripts, greedy);
 
         // Update position and compute cell and layer changes for updates
         params
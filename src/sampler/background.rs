@@ -0,0 +1,103 @@
+// Spatial variation in the background rate, e.g. from autofluorescent regions
+// of a slide. The tissue is divided into a coarse grid, and the background
+// rate of every gene in every layer is scaled by a factor for the bin a
+// transcript falls in. So the gene composition of background stays shared
+// across the slide, while its intensity varies. Scales have a gamma prior with
+// mean 1.
+
+use serde::{Deserialize, Serialize};
+
+use super::transcripts::Transcript;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BackgroundGrid {
+    x0: f32,
+    y0: f32,
+    bin_size: f32,
+    ncols: usize,
+    nrows: usize,
+
+    // [nbins] background rate scale factor
+    pub scale: Vec<f32>,
+
+    // [nbins] volume of tissue in each bin, in each layer
+    pub volume: Vec<f32>,
+
+    // [nbins] number of transcripts currently in the background state
+    pub counts: Vec<u32>,
+}
+
+impl BackgroundGrid {
+    // Bins containing any transcripts are taken to be tissue, and the estimated
+    // tissue volume per layer is divided between them in proportion to their
+    // area. (Bins on the edge of the data are only partly covered.)
+    pub fn new(transcripts: &[Transcript], bin_size: f32, full_layer_volume: f32) -> Self {
+        let (mut xmin, mut xmax, mut ymin, mut ymax) =
+            (f32::INFINITY, f32::NEG_INFINITY, f32::INFINITY, f32::NEG_INFINITY);
+        for t in transcripts {
+            xmin = xmin.min(t.x);
+            xmax = xmax.max(t.x);
+            ymin = ymin.min(t.y);
+            ymax = ymax.max(t.y);
+        }
+
+        let ncols = (((xmax - xmin) / bin_size).floor() as usize + 1).max(1);
+        let nrows = (((ymax - ymin) / bin_size).floor() as usize + 1).max(1);
+        let mut grid = BackgroundGrid {
+            x0: xmin,
+            y0: ymin,
+            bin_size,
+            ncols,
+            nrows,
+            scale: vec![1.0; ncols * nrows],
+            volume: vec![0.0; ncols * nrows],
+            counts: vec![0; ncols * nrows],
+        };
+
+        let mut occupied = vec![false; grid.nbins()];
+        for t in transcripts {
+            occupied[grid.bin(t.x, t.y)] = true;
+        }
+        let noccupied = occupied.iter().filter(|&&occupied| occupied).count().max(1);
+        for (volume, occupied) in grid.volume.iter_mut().zip(occupied) {
+            if occupied {
+                *volume = full_layer_volume / noccupied as f32;
+            }
+        }
+
+        grid
+    }
+
+    pub fn nbins(&self) -> usize {
+        self.ncols * self.nrows
+    }
+
+    // Bin containing a position. Positions outside the grid (e.g. transcripts
+    // that diffused past the edge) are put in the nearest bin.
+    pub fn bin(&self, x: f32, y: f32) -> usize {
+        let col = (((x - self.x0) / self.bin_size).max(0.0) as usize).min(self.ncols - 1);
+        let row = (((y - self.y0) / self.bin_size).max(0.0) as usize).min(self.nrows - 1);
+        row * self.ncols + col
+    }
+
+    pub fn scale_at(&self, x: f32, y: f32) -> f32 {
+        self.scale[self.bin(x, y)]
+    }
+
+    // Background volume per layer, weighted by each bin's scale, which takes
+    // the place of the full layer volume in the background rate's likelihood.
+    pub fn scaled_volume(&self) -> f32 {
+        self.scale
+            .iter()
+            .zip(&self.volume)
+            .map(|(scale, volume)| scale * volume)
+            .sum()
+    }
+
+    // (x0, y0, x1, y1) bounds of a bin.
+    pub fn bin_bounds(&self, bin: usize) -> (f32, f32, f32, f32) {
+        let x0 = self.x0 + (bin % self.ncols) as f32 * self.bin_size;
+        let y0 = self.y0 + (bin / self.ncols) as f32 * self.bin_size;
+        (x0, y0, x0 + self.bin_size, y0 + self.bin_size)
+    }
+}
@@ -0,0 +1,186 @@
+// Cell boundary prior from a boundary stain image (e.g. Xenium's boundary
+// stain segmentation channel), where each pixel gives the probability of there
+// being a cell membrane there.
+//
+// Cells are penalized for covering boundary: every pair of neighboring voxels
+// in the same cell costs the highest boundary probability on the line between
+// their centers, times a weight. Cells are then discouraged from growing across
+// membranes, but not from having their edges on them.
+
+use std::fs::File;
+use std::io::BufReader;
+
+use super::super::error::{Error, Result};
+use super::mask::image_error;
+
+pub struct BoundaryPrior {
+    width: usize,
+    height: usize,
+    values: Vec<f32>,
+
+    // affine transform [a, b, c, d, e, f] from transcript coordinates to
+    // pixels: (a*x + b*y + c, d*x + e*y + f)
+    transform: [f32; 6],
+
+    // log probability penalty for each unit of boundary covered
+    pub weight: f32,
+}
+
+impl BoundaryPrior {
+    // A boundary image of `width` by `height` pixels, row by row, for tests.
+    #[cfg(test)]
+    pub(crate) fn new(
+        width: usize,
+        height: usize,
+        values: Vec<f32>,
+        transform: [f32; 6],
+        weight: f32,
+    ) -> Self {
+        assert_eq!(values.len(), width * height);
+        BoundaryPrior {
+            width,
+            height,
+            values,
+            transform,
+            weight,
+        }
+    }
+
+    // Boundary probability at the given pixel, or 0 if out of bounds.
+    fn get(&self, i: isize, j: isize) -> f32 {
+        if i < 0 || j < 0 || i as usize >= self.width || j as usize >= self.height {
+            0.0
+        } else {
+            self.values[(j as usize) * self.width + (i as usize)]
+        }
+    }
+
+    fn to_pixel(&self, (x, y): (f32, f32)) -> (f32, f32) {
+        let [a, b, c, d, e, f] = self.transform;
+        (a * x + b * y + c, d * x + e * y + f)
+    }
+
+    // Highest boundary probability on the line between two points in
+    // transcript coordinates, sampled about once per pixel.
+    pub fn line_max(&self, p0: (f32, f32), p1: (f32, f32)) -> f32 {
+        let (u0, v0) = self.to_pixel(p0);
+        let (u1, v1) = self.to_pixel(p1);
+        let n = (u1 - u0).abs().max((v1 - v0).abs()).ceil().max(1.0) as usize;
+
+        (0..=n)
+            .map(|k| {
+                let t = k as f32 / n as f32;
+                self.get(
+                    (u0 + t * (u1 - u0)).floor() as isize,
+                    (v0 + t * (v1 - v0)).floor() as isize,
+                )
+            })
+            .fold(0.0, f32::max)
+    }
+}
+
+// Read a single channel boundary probability image from a TIFF or PNG file.
+// Integer pixel values are scaled to [0, 1] by the maximum of their type, and
+// floating point values are taken as is.
+pub fn read_boundary_prior(path: &str, transform: [f32; 6], weight: f32) -> Result<BoundaryPrior> {
+    let file = File::open(path).map_err(|source| Error::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    let reader = BufReader::new(file);
+
+    let lower_path = path.to_lowercase();
+    let (width, height, values) = if lower_path.ends_with(".tif") || lower_path.ends_with(".tiff")
+    {
+        read_boundary_tiff(path, reader)?
+    } else if lower_path.ends_with(".png") {
+        read_boundary_png(path, reader)?
+    } else {
+        return Err(image_error(
+            path,
+            "boundary images must be .tif, .tiff, or .png files",
+        ));
+    };
+
+    Ok(BoundaryPrior {
+        width,
+        height,
+        values: values.into_iter().map(|v| v.clamp(0.0, 1.0)).collect(),
+        transform,
+        weight,
+    })
+}
+
+fn read_boundary_tiff(path: &str, reader: BufReader<File>) -> Result<(usize, usize, Vec<f32>)> {
+    use tiff::decoder::{Decoder, DecodingResult, Limits};
+
+    let mut decoder = Decoder::new(reader)
+        .map_err(|err| image_error(path, err))?
+        .with_limits(Limits::unlimited());
+
+    match decoder.colortype().map_err(|err| image_error(path, err))? {
+        tiff::ColorType::Gray(_) => {}
+        colortype => {
+            return Err(image_error(
+                path,
+                format!("expected a single channel boundary image, found {:?}", colortype),
+            ))
+        }
+    }
+
+    let (width, height) = decoder.dimensions().map_err(|err| image_error(path, err))?;
+    let values: Vec<f32> = match decoder.read_image().map_err(|err| image_error(path, err))? {
+        DecodingResult::U8(data) => data.iter().map(|&v| v as f32 / u8::MAX as f32).collect(),
+        DecodingResult::U16(data) => data.iter().map(|&v| v as f32 / u16::MAX as f32).collect(),
+        DecodingResult::U32(data) => data.iter().map(|&v| v as f32 / u32::MAX as f32).collect(),
+        DecodingResult::U64(data) => data.iter().map(|&v| v as f32 / u64::MAX as f32).collect(),
+        DecodingResult::I8(data) => data.iter().map(|&v| v as f32 / i8::MAX as f32).collect(),
+        DecodingResult::I16(data) => data.iter().map(|&v| v as f32 / i16::MAX as f32).collect(),
+        DecodingResult::I32(data) => data.iter().map(|&v| v as f32 / i32::MAX as f32).collect(),
+        DecodingResult::I64(data) => data.iter().map(|&v| v as f32 / i64::MAX as f32).collect(),
+        DecodingResult::F32(data) => data,
+        DecodingResult::F64(data) => data.iter().map(|&v| v as f32).collect(),
+    };
+
+    Ok((width as usize, height as usize, values))
+}
+
+fn read_boundary_png(path: &str, reader: BufReader<File>) -> Result<(usize, usize, Vec<f32>)> {
+    let mut decoder = png::Decoder::new(reader);
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info().map_err(|err| image_error(path, err))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buf)
+        .map_err(|err| image_error(path, err))?;
+
+    if info.color_type != png::ColorType::Grayscale {
+        return Err(image_error(
+            path,
+            format!("expected a grayscale boundary image, found {:?}", info.color_type),
+        ));
+    }
+
+    let (width, height) = (info.width as usize, info.height as usize);
+    let mut values = Vec::with_capacity(width * height);
+    for row in buf.chunks(info.line_size).take(height) {
+        match info.bit_depth {
+            png::BitDepth::Eight => {
+                values.extend(row[..width].iter().map(|&v| v as f32 / u8::MAX as f32))
+            }
+            png::BitDepth::Sixteen => values.extend(
+                row[..2 * width]
+                    .chunks(2)
+                    .map(|v| u16::from_be_bytes([v[0], v[1]]) as f32 / u16::MAX as f32),
+            ),
+            bit_depth => {
+                return Err(image_error(
+                    path,
+                    format!("unsupported bit depth {:?} for a boundary image", bit_depth),
+                ))
+            }
+        }
+    }
+
+    Ok((width, height, values))
+}
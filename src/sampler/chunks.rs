@@ -0,0 +1,221 @@
+// Division of the x-y plane into chunks, which are sampled in parallel.
+//
+// Each chunk is split into four quadrants at its midpoint, and one quadrant
+// index is sampled at a time. Quadrants with the same index in different chunks
+// are always separated by half a chunk, so updates to them never interact.
+//
+// Voxels near the seams between quadrants are proposed less evenly than those
+// in their interiors, so the sampler shifts the whole layout by a random offset
+// (of up to `max_offset`) every `--chunk-shift-interval` iterations (10 by
+// default), to keep seams from staying in place.
+
+use serde::{Deserialize, Serialize};
+
+use super::chunkquad;
+use super::transcripts::Transcript;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum ChunkLayout {
+    // Square chunks of equal size on a regular grid.
+    Grid {
+        xmin: f32,
+        ymin: f32,
+        chunk_size: f32,
+        nxchunks: usize,
+        nchunks: usize,
+    },
+    // Rectangles found by recursively splitting transcripts at the median, so
+    // that each chunk contains roughly the same number of transcripts.
+    Balanced {
+        nodes: Vec<ChunkNode>,
+        chunk_bounds: Vec<(f32, f32, f32, f32)>, // [xmin, ymin, xmax, ymax]
+        min_chunk_size: f32,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub enum ChunkNode {
+    Split {
+        axis: usize,
+        value: f32,
+        lower: usize,
+        upper: usize,
+    },
+    Chunk(u32),
+}
+
+impl ChunkLayout {
+    pub fn grid(xmin: f32, xmax: f32, ymin: f32, ymax: f32, chunk_size: f32) -> ChunkLayout {
+        // an extra row and column of chunks, to cover the data when shifted
+        let nxchunks = ((xmax - xmin) / chunk_size).ceil() as usize + 1;
+        let nychunks = ((ymax - ymin) / chunk_size).ceil() as usize + 1;
+        ChunkLayout::Grid {
+            xmin,
+            ymin,
+            chunk_size,
+            nxchunks,
+            nchunks: nxchunks * nychunks,
+        }
+    }
+
+    // Split into (at most) `nchunks` chunks with equal numbers of transcripts.
+    // Chunks are never narrower than `min_chunk_size`, so that quadrants stay
+    // wide enough to separate parallel updates.
+    pub fn balanced(transcripts: &[Transcript], nchunks: usize, min_chunk_size: f32) -> ChunkLayout {
+        let points = transcripts.iter().map(|t| (t.x, t.y)).collect();
+        ChunkLayout::balanced_points(points, nchunks, min_chunk_size)
+    }
+
+    // As `balanced`, but with equal numbers of arbitrary points, e.g. of
+    // voxels on cell boundaries, where proposals are made.
+    pub fn balanced_points(mut points: Vec<(f32, f32)>, nchunks: usize, min_chunk_size: f32) -> ChunkLayout {
+        let (xmin, xmax, ymin, ymax) = points.iter().fold(
+            (f32::MAX, f32::MIN, f32::MAX, f32::MIN),
+            |(xmin, xmax, ymin, ymax), &(x, y)| (xmin.min(x), xmax.max(x), ymin.min(y), ymax.max(y)),
+        );
+
+        let mut nodes = Vec::new();
+        let mut chunk_bounds = Vec::new();
+        build_balanced(
+            &mut points,
+            (xmin, ymin, xmax, ymax),
+            nchunks.max(1),
+            min_chunk_size,
+            &mut nodes,
+            &mut chunk_bounds,
+        );
+
+        ChunkLayout::Balanced {
+            nodes,
+            chunk_bounds,
+            min_chunk_size,
+        }
+    }
+
+    pub fn nchunks(&self) -> usize {
+        match self {
+            ChunkLayout::Grid { nchunks, .. } => *nchunks,
+            ChunkLayout::Balanced { chunk_bounds, .. } => chunk_bounds.len(),
+        }
+    }
+
+    // Largest shift in x or y that `get` should be given points with. Chunks
+    // still cover all the data when shifted by up to this amount.
+    pub fn max_offset(&self) -> f32 {
+        match self {
+            ChunkLayout::Grid { chunk_size, .. } => *chunk_size,
+            ChunkLayout::Balanced { min_chunk_size, .. } => *min_chunk_size,
+        }
+    }
+
+    // Compute chunk and quadrant for a single (x,y) point.
+    pub fn get(&self, x: f32, y: f32) -> (u32, u32) {
+        match self {
+            ChunkLayout::Grid {
+                xmin,
+                ymin,
+                chunk_size,
+                nxchunks,
+                ..
+            } => chunkquad(x, y, *xmin, *ymin, *chunk_size, *nxchunks),
+            ChunkLayout::Balanced {
+                nodes,
+                chunk_bounds,
+                ..
+            } => {
+                let mut i = 0;
+                let chunk = loop {
+                    match nodes[i] {
+                        ChunkNode::Split {
+                            axis,
+                            value,
+                            lower,
+                            upper,
+                        } => {
+                            let u = if axis == 0 { x } else { y };
+                            i = if u < value { lower } else { upper };
+                        }
+                        ChunkNode::Chunk(chunk) => break chunk,
+                    }
+                };
+
+                let (x0, y0, x1, y1) = chunk_bounds[chunk as usize];
+                let quad = ((x >= (x0 + x1) / 2.0) as u32) + ((y >= (y0 + y1) / 2.0) as u32) * 2;
+
+                (chunk, quad)
+            }
+        }
+    }
+}
+
+// Build the subtree for the given points and bounds, returning its index.
+fn build_balanced(
+    points: &mut [(f32, f32)],
+    bounds: (f32, f32, f32, f32),
+    nchunks: usize,
+    min_chunk_size: f32,
+    nodes: &mut Vec<ChunkNode>,
+    chunk_bounds: &mut Vec<(f32, f32, f32, f32)>,
+) -> usize {
+    let (x0, y0, x1, y1) = bounds;
+    let axis = if x1 - x0 >= y1 - y0 { 0 } else { 1 };
+    let (lo, hi) = if axis == 0 { (x0, x1) } else { (y0, y1) };
+
+    let node = nodes.len();
+    if nchunks == 1 || points.is_empty() || hi - lo < 2.0 * min_chunk_size {
+        nodes.push(ChunkNode::Chunk(chunk_bounds.len() as u32));
+        chunk_bounds.push(bounds);
+        return node;
+    }
+
+    let coord = move |p: &(f32, f32)| if axis == 0 { p.0 } else { p.1 };
+
+    // Split so that the two sides get transcripts in proportion to the number
+    // of chunks they will be divided into.
+    let nlower = nchunks / 2;
+    let k = (points.len() * nlower / nchunks).min(points.len() - 1);
+    points.select_nth_unstable_by(k, |a, b| coord(a).partial_cmp(&coord(b)).unwrap());
+    let value = coord(&points[k]).clamp(lo + min_chunk_size, hi - min_chunk_size);
+
+    let mut mid = 0;
+    for j in 0..points.len() {
+        if coord(&points[j]) < value {
+            points.swap(mid, j);
+            mid += 1;
+        }
+    }
+
+    // placeholder, filled in once the children are built
+    nodes.push(ChunkNode::Chunk(0));
+
+    let (lower_points, upper_points) = points.split_at_mut(mid);
+    let (lower_bounds, upper_bounds) = if axis == 0 {
+        ((x0, y0, value, y1), (value, y0, x1, y1))
+    } else {
+        ((x0, y0, x1, value), (x0, value, x1, y1))
+    };
+    let lower = build_balanced(
+        lower_points,
+        lower_bounds,
+        nlower,
+        min_chunk_size,
+        nodes,
+        chunk_bounds,
+    );
+    let upper = build_balanced(
+        upper_points,
+        upper_bounds,
+        nchunks - nlower,
+        min_chunk_size,
+        nodes,
+        chunk_bounds,
+    );
+
+    nodes[node] = ChunkNode::Split {
+        axis,
+        value,
+        lower,
+        upper,
+    };
+    node
+}
@@ -0,0 +1,102 @@
+// Estimates of the rate of false transcripts from negative control probes
+// (e.g. Xenium's NegControlProbe_* and BLANK_* codewords), which detect nothing
+// real, so the number of them landing in cells indicates how many of each
+// gene's transcripts in cells are likely to be spurious.
+
+use regex::Regex;
+use serde::Serialize;
+
+use super::genefilter::{filter_genes, GeneFilter};
+use super::transcripts::{CellIndex, TranscriptDataset, BACKGROUND_CELL};
+
+pub struct NegativeControls {
+    // number of distinct control probes
+    pub nprobes: usize,
+    pub positions: Vec<(f32, f32, f32)>,
+}
+
+// Summary over all cells, as recorded in the run manifest.
+#[derive(Serialize)]
+pub struct NegativeControlSummary {
+    pub nprobes: usize,
+    pub ngenes: usize,
+    pub control_count: usize,
+    pub assigned_control_count: usize,
+    pub false_discovery_rate: f32,
+}
+
+// Set aside transcripts of genes matching `pattern`, so they aren't modeled as
+// expression, keeping their positions.
+pub fn extract_negative_controls(dataset: &mut TranscriptDataset, pattern: &Regex) -> NegativeControls {
+    let positions = dataset
+        .transcripts
+        .iter()
+        .filter(|t| pattern.is_match(&dataset.transcript_names[t.gene as usize]))
+        .map(|t| (t.x, t.y, t.z))
+        .collect();
+    let excluded = filter_genes(dataset, &GeneFilter::new(Some(pattern.clone()), None));
+    NegativeControls {
+        nprobes: excluded.len(),
+        positions,
+    }
+}
+
+impl NegativeControls {
+    // Given the cell each control transcript falls in, and the total
+    // (expected) count of each cell, the number of control transcripts in each
+    // cell, and the estimated fraction of each cell's transcripts that are
+    // false: the mean count per control probe, times the number of genes,
+    // relative to the cell's count.
+    pub fn cell_false_discovery_rates(
+        &self,
+        control_cells: &[CellIndex],
+        cell_counts: &[f32],
+        ngenes: usize,
+    ) -> (Vec<u32>, Vec<f32>) {
+        let mut control_counts = vec![0_u32; cell_counts.len()];
+        for &cell in control_cells {
+            if cell != BACKGROUND_CELL {
+                control_counts[cell as usize] += 1;
+            }
+        }
+
+        let rates = control_counts
+            .iter()
+            .zip(cell_counts)
+            .map(|(&control_count, &count)| {
+                if count > 0.0 {
+                    (self.false_count(control_count as usize, ngenes) / count).min(1.0)
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+
+        (control_counts, rates)
+    }
+
+    pub fn summary(&self, control_cells: &[CellIndex], cell_counts: &[f32], ngenes: usize) -> NegativeControlSummary {
+        let assigned_control_count = control_cells
+            .iter()
+            .filter(|&&cell| cell != BACKGROUND_CELL)
+            .count();
+        let total_count: f32 = cell_counts.iter().sum();
+        NegativeControlSummary {
+            nprobes: self.nprobes,
+            ngenes,
+            control_count: self.positions.len(),
+            assigned_control_count,
+            false_discovery_rate: if total_count > 0.0 {
+                (self.false_count(assigned_control_count, ngenes) / total_count).min(1.0)
+            } else {
+                0.0
+            },
+        }
+    }
+
+    // Expected number of false transcripts over all genes, given a count of
+    // control transcripts.
+    fn false_count(&self, control_count: usize, ngenes: usize) -> f32 {
+        control_count as f32 / self.nprobes.max(1) as f32 * ngenes as f32
+    }
+}
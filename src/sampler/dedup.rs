@@ -0,0 +1,99 @@
+// Removal of transcripts duplicated across FOV seams. Where neighboring fields
+// of view overlap (e.g. in CosMx data), the same molecule can be decoded in
+// both, giving pairs of transcripts of the same gene a short distance apart
+// in different FOVs.
+
+use serde::Serialize;
+use std::collections::HashMap;
+
+use super::transcripts::{postprocess_cell_assignments, TranscriptDataset};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DedupAction {
+    // keep one transcript at the mean position of the copies
+    Merge,
+    // remove every copy, when which is right can't be told
+    Drop,
+}
+
+pub struct DedupStats {
+    // number of transcripts found to have copies in other FOVs
+    pub nduplicated: usize,
+    pub nremoved: usize,
+}
+
+// Find transcripts of the same gene in different FOVs within `radius` of each
+// other in x and y, and merge or drop them. Each transcript is matched with at
+// most one copy from each other FOV, taking earlier transcripts first.
+pub fn dedup_transcripts(dataset: &mut TranscriptDataset, radius: f32, action: DedupAction) -> DedupStats {
+    let bin = |x: f32| (x / radius).floor() as i32;
+    let mut bins: HashMap<(u32, i32, i32), Vec<usize>> = HashMap::new();
+    for (i, t) in dataset.transcripts.iter().enumerate() {
+        bins.entry((t.gene, bin(t.x), bin(t.y))).or_default().push(i);
+    }
+
+    // index of the transcript each is a copy of, if it is one
+    let mut copy_of: Vec<Option<usize>> = vec![None; dataset.transcripts.len()];
+    let mut copies: Vec<(usize, Vec<usize>)> = Vec::new();
+    let r2 = radius * radius;
+    for (i, t) in dataset.transcripts.iter().enumerate() {
+        if copy_of[i].is_some() {
+            continue;
+        }
+        let mut group: Vec<usize> = Vec::new();
+        let (bi, bj) = (bin(t.x), bin(t.y));
+        for (di, dj) in (-1..=1).flat_map(|di| (-1..=1).map(move |dj| (di, dj))) {
+            for &j in bins.get(&(t.gene, bi + di, bj + dj)).into_iter().flatten() {
+                let u = &dataset.transcripts[j];
+                if j <= i
+                    || copy_of[j].is_some()
+                    || u.fov == t.fov
+                    || group.iter().any(|&k| dataset.transcripts[k].fov == u.fov)
+                    || (u.x - t.x).powi(2) + (u.y - t.y).powi(2) > r2
+                {
+                    continue;
+                }
+                group.push(j);
+            }
+        }
+        if !group.is_empty() {
+            for &j in &group {
+                copy_of[j] = Some(i);
+            }
+            copies.push((i, group));
+        }
+    }
+
+    let mut keep: Vec<bool> = copy_of.iter().map(|i| i.is_none()).collect();
+    for (i, group) in &copies {
+        match action {
+            DedupAction::Merge => {
+                let n = (group.len() + 1) as f32;
+                let (mut x, mut y, mut z) = (0.0, 0.0, 0.0);
+                for &j in group.iter().chain(std::iter::once(i)) {
+                    let u = &dataset.transcripts[j];
+                    x += u.x;
+                    y += u.y;
+                    z += u.z;
+                }
+                let t = &mut dataset.transcripts[*i];
+                (t.x, t.y, t.z) = (x / n, y / n, z / n);
+            }
+            DedupAction::Drop => keep[*i] = false,
+        }
+    }
+
+    let nremoved = keep.iter().filter(|&&kept| !kept).count();
+    dataset.retain(&keep);
+
+    dataset.nucleus_population = postprocess_cell_assignments(
+        &mut dataset.nucleus_assignments,
+        &mut dataset.cell_assignments,
+    );
+
+    DedupStats {
+        nduplicated: copies.len(),
+        nremoved,
+    }
+}
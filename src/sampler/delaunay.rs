@@ -0,0 +1,34 @@
+// Neighboring cells by Delaunay triangulation of their centroids. Unlike
+// neighbors within a fixed radius, every cell gets a handful of neighbors
+// whether cells are densely packed or sparse, so the neighborhood doesn't
+// depend on choosing a length scale to suit the data.
+
+// Undirected edges between points, given as (index, x, y). Points at the same
+// position as an earlier point are left without neighbors.
+#[cfg(feature = "delaunay")]
+pub fn delaunay_edges(points: &[(u32, f32, f32)]) -> Vec<(u32, u32)> {
+    use spade::{DelaunayTriangulation, Point2, Triangulation};
+
+    let mut triangulation: DelaunayTriangulation<Point2<f64>> = DelaunayTriangulation::new();
+    let mut vertex_items = Vec::with_capacity(points.len());
+    for &(item, x, y) in points {
+        if let Ok(vertex) = triangulation.insert(Point2::new(x as f64, y as f64)) {
+            if vertex.index() == vertex_items.len() {
+                vertex_items.push(item);
+            }
+        }
+    }
+
+    triangulation
+        .undirected_edges()
+        .map(|edge| {
+            let [a, b] = edge.vertices();
+            (vertex_items[a.fix().index()], vertex_items[b.fix().index()])
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "delaunay"))]
+pub fn delaunay_edges(_points: &[(u32, f32, f32)]) -> Vec<(u32, u32)> {
+    panic!("proseg was built without Delaunay triangulation support (rebuild with `--features delaunay`)");
+}
@@ -0,0 +1,40 @@
+// Random downsampling of transcripts, for quick trial runs when choosing
+// parameters before segmenting the full data.
+
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+use super::rng::SamplerRng;
+use super::transcripts::{postprocess_cell_assignments, TranscriptDataset};
+
+// Keep a random `fraction` of each gene's transcripts (rounding to the nearest
+// whole transcript), so that rare genes are kept in proportion, drawn with
+// their own seed so the subset doesn't change with --seed. Transcripts keep
+// their order, and cells left without nuclear transcripts are dropped. Returns
+// the number of transcripts removed.
+pub fn downsample_transcripts(dataset: &mut TranscriptDataset, fraction: f32, seed: u64) -> usize {
+    let mut gene_transcripts: Vec<Vec<usize>> = vec![Vec::new(); dataset.transcript_names.len()];
+    for (i, t) in dataset.transcripts.iter().enumerate() {
+        gene_transcripts[t.gene as usize].push(i);
+    }
+
+    let mut rng = SamplerRng::seed_from_u64(seed);
+    let mut keep = vec![false; dataset.transcripts.len()];
+    for transcripts in gene_transcripts.iter_mut() {
+        let n = (fraction * transcripts.len() as f32).round() as usize;
+        let (kept, _) = transcripts.partial_shuffle(&mut rng, n);
+        for &i in kept.iter() {
+            keep[i] = true;
+        }
+    }
+
+    let nremoved = keep.iter().filter(|&&kept| !kept).count();
+    dataset.retain(&keep);
+
+    dataset.nucleus_population = postprocess_cell_assignments(
+        &mut dataset.nucleus_assignments,
+        &mut dataset.cell_assignments,
+    );
+
+    nremoved
+}
@@ -0,0 +1,201 @@
+// Informative prior on mixture components from reference cell type expression
+// profiles, e.g. mean expression from a scRNA-seq atlas.
+//
+// Each component corresponds to one reference cell type. The prior on a
+// component's expression rate for a gene is centered on the type's share of
+// expression for that gene, times the component's overall transcript density,
+// so only the relative expression of genes is taken from the reference (the
+// scale of scRNA-seq counts doesn't carry over to in situ data).
+
+use ndarray::{Array1, Array2, Array3, ArrayView1, Axis};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use super::super::error::{Error, Result};
+use super::super::output::open_decompressed;
+use super::transcripts::parse_field;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ExpressionPrior {
+    pub cell_types: Vec<String>,
+
+    // [ntypes, ngenes] log of each type's share of expression from each gene,
+    // NaN for genes missing from the reference
+    log_proportions: Array2<f32>,
+
+    // [ngenes] whether the gene is in the reference
+    matched: Array1<bool>,
+
+    // precision of the normal prior on component log expression rates
+    pub precision: f32,
+}
+
+impl ExpressionPrior {
+    pub fn ncomponents(&self) -> usize {
+        self.cell_types.len()
+    }
+
+    // Prior mean of a component's log expression rate for a gene, relative to
+    // the NB dispersion `r` (i.e. of φ), or None for genes not in the reference.
+    pub fn prior_mean(&self, component: usize, gene: usize, log_density: f32, r: f32) -> Option<f32> {
+        if self.matched[gene] {
+            Some(self.log_proportions[[component, gene]] + log_density - r.ln())
+        } else {
+            None
+        }
+    }
+
+    // Log transcripts per unit volume, counting only genes in the reference,
+    // for the cells in each component. Components with no cells use the density
+    // over all cells.
+    pub fn component_log_density(
+        &self,
+        foreground_counts: &Array3<u16>,
+        cell_volume: &Array1<f32>,
+        z: &Array1<u32>,
+    ) -> Vec<f32> {
+        let ncomponents = self.ncomponents();
+        let mut counts = vec![0_f64; ncomponents];
+        let mut volumes = vec![0_f64; ncomponents];
+        for ((cell_counts, &volume), &z_i) in foreground_counts
+            .axis_iter(Axis(0))
+            .zip(cell_volume)
+            .zip(z)
+        {
+            counts[z_i as usize] += cell_counts
+                .axis_iter(Axis(0))
+                .zip(&self.matched)
+                .filter(|(_, &matched)| matched)
+                .map(|(c, _)| c.iter().map(|&c| c as f64).sum::<f64>())
+                .sum::<f64>();
+            volumes[z_i as usize] += volume as f64;
+        }
+
+        let total_count: f64 = counts.iter().sum();
+        let total_volume: f64 = volumes.iter().sum();
+        let overall = ((total_count + 1.0) / total_volume.max(1e-6)).ln() as f32;
+        counts
+            .iter()
+            .zip(&volumes)
+            .map(|(&count, &volume)| {
+                if count > 0.0 && volume > 0.0 {
+                    (count / volume).ln() as f32
+                } else {
+                    overall
+                }
+            })
+            .collect()
+    }
+
+    // The reference type that best explains a cell's gene counts.
+    pub fn classify(&self, counts: ArrayView1<u32>) -> u32 {
+        self.log_proportions
+            .rows()
+            .into_iter()
+            .map(|log_proportions| {
+                log_proportions
+                    .iter()
+                    .zip(&counts)
+                    .zip(&self.matched)
+                    .filter(|(_, &matched)| matched)
+                    .map(|((&logp, &c), _)| c as f32 * logp)
+                    .sum::<f32>()
+            })
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(0, |(k, _)| k as u32)
+    }
+}
+
+// Read reference expression from a table with genes in rows and cell types in
+// columns, with gene names in the first column. Genes not in `transcript_names`
+// are ignored, and each type's expression is normalized to proportions over
+// the remaining genes.
+pub fn read_expression_prior(
+    path: &str,
+    transcript_names: &[String],
+    precision: f32,
+) -> Result<ExpressionPrior> {
+    let input = open_decompressed(path).map_err(|source| Error::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    let delimiter = if path.contains(".tsv") { b'\t' } else { b',' };
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .from_reader(input);
+    let csv_error = |source| Error::Csv {
+        path: path.to_string(),
+        source,
+    };
+    let headers = rdr.headers().map_err(csv_error)?.clone();
+    if headers.len() < 2 {
+        return Err(Error::ExpressionPrior {
+            path: path.to_string(),
+            message: String::from("expected a gene column followed by one column per cell type"),
+        });
+    }
+    let cell_types: Vec<String> = headers.iter().skip(1).map(String::from).collect();
+    let ntypes = cell_types.len();
+    let ngenes = transcript_names.len();
+    let gene_index: HashMap<&str, usize> = transcript_names
+        .iter()
+        .enumerate()
+        .map(|(gene, name)| (name.as_str(), gene))
+        .collect();
+
+    let mut expression = Array2::<f32>::zeros((ntypes, ngenes));
+    let mut matched = Array1::<bool>::from_elem(ngenes, false);
+    let mut row = csv::StringRecord::new();
+    while rdr.read_record(&mut row).map_err(csv_error)? {
+        let gene = match gene_index.get(&row[0]) {
+            Some(&gene) => gene,
+            None => continue,
+        };
+        matched[gene] = true;
+        for k in 0..ntypes {
+            let value = parse_field::<f32>(path, &headers, &row, k + 1, "a non-negative number")?;
+            expression[[k, gene]] = value.max(0.0);
+        }
+    }
+
+    let nmatched = matched.iter().filter(|&&matched| matched).count();
+    if nmatched == 0 {
+        return Err(Error::ExpressionPrior {
+            path: path.to_string(),
+            message: String::from("none of the genes in the transcript table were found"),
+        });
+    }
+    if 2 * nmatched < ngenes {
+        println!(
+            "WARNING: only {} of {} genes were found in the expression prior",
+            nmatched, ngenes
+        );
+    }
+
+    // A pseudocount of 1% of the mean expression keeps genes a type doesn't
+    // express in the reference from being ruled out entirely.
+    let mut log_proportions = Array2::<f32>::from_elem((ntypes, ngenes), f32::NAN);
+    for k in 0..ntypes {
+        let total: f32 = expression.row(k).sum();
+        let pseudocount = 0.01 * (total / nmatched as f32).max(1e-6);
+        let denom = total + pseudocount * nmatched as f32;
+        for gene in 0..ngenes {
+            if matched[gene] {
+                log_proportions[[k, gene]] = ((expression[[k, gene]] + pseudocount) / denom).ln();
+            }
+        }
+    }
+
+    println!(
+        "Read expression prior for {} cell types and {} genes",
+        ntypes, nmatched
+    );
+
+    Ok(ExpressionPrior {
+        cell_types,
+        log_proportions,
+        matched,
+        precision,
+    })
+}
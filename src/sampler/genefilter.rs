@@ -0,0 +1,100 @@
+// Gene exclusion and inclusion lists, used to drop e.g. negative control probes
+// before any modeling.
+
+use regex::Regex;
+use std::collections::HashSet;
+
+use super::super::error::{Error, Result};
+use super::transcripts::{postprocess_cell_assignments, TranscriptDataset, BACKGROUND_CELL};
+
+pub struct GeneFilter {
+    exclude: Option<Regex>,
+    include: Option<HashSet<String>>,
+}
+
+// Transcripts of a gene that were dropped, kept for QC since negative controls
+// are a direct measure of the background rate.
+pub struct ExcludedGene {
+    pub name: String,
+    pub count: usize,
+    pub assigned_count: usize,
+}
+
+impl GeneFilter {
+    pub fn new(exclude: Option<Regex>, include: Option<HashSet<String>>) -> GeneFilter {
+        GeneFilter { exclude, include }
+    }
+
+    // Read a list of gene names, one per line. Blank lines are ignored.
+    pub fn read_gene_list(path: &str) -> Result<HashSet<String>> {
+        let text = std::fs::read_to_string(path).map_err(|source| Error::Io {
+            path: path.to_string(),
+            source,
+        })?;
+        Ok(text
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_string())
+            .collect())
+    }
+
+    pub fn keep(&self, gene: &str) -> bool {
+        if self
+            .include
+            .as_ref()
+            .is_some_and(|include| !include.contains(gene))
+        {
+            return false;
+        }
+        !self
+            .exclude
+            .as_ref()
+            .is_some_and(|exclude| exclude.is_match(gene))
+    }
+}
+
+// Remove transcripts of genes that don't pass the filter, renumbering genes and
+// cells. Returns per-gene counts of what was removed.
+pub fn filter_genes(dataset: &mut TranscriptDataset, filter: &GeneFilter) -> Vec<ExcludedGene> {
+    let mut gene_map = Vec::with_capacity(dataset.transcript_names.len());
+    let mut transcript_names = Vec::new();
+    let mut excluded = Vec::new();
+    for name in dataset.transcript_names.drain(..) {
+        if filter.keep(&name) {
+            gene_map.push(Ok(transcript_names.len() as u32));
+            transcript_names.push(name);
+        } else {
+            gene_map.push(Err(excluded.len()));
+            excluded.push(ExcludedGene {
+                name,
+                count: 0,
+                assigned_count: 0,
+            });
+        }
+    }
+    dataset.transcript_names = transcript_names;
+
+    let mut keep = vec![true; dataset.transcripts.len()];
+    for (i, t) in dataset.transcripts.iter_mut().enumerate() {
+        match gene_map[t.gene as usize] {
+            Ok(gene) => t.gene = gene,
+            Err(k) => {
+                keep[i] = false;
+                excluded[k].count += 1;
+                if dataset.cell_assignments[i] != BACKGROUND_CELL {
+                    excluded[k].assigned_count += 1;
+                }
+            }
+        }
+    }
+    dataset.retain(&keep);
+
+    // Cells made up entirely of excluded transcripts are dropped.
+    dataset.nucleus_population = postprocess_cell_assignments(
+        &mut dataset.nucleus_assignments,
+        &mut dataset.cell_assignments,
+    );
+
+    excluded
+}
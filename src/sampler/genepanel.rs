@@ -0,0 +1,134 @@
+// Gene panel manifests (Xenium's gene_panel.json), giving the gene symbol,
+// Ensembl id, and kind (gene or control probe) of each target, so outputs
+// carry proper feature metadata.
+
+use flate2::read::MultiGzDecoder;
+use std::collections::HashMap;
+use std::io::Read;
+
+use super::super::error::{Error, Result};
+use super::transcripts::TranscriptDataset;
+
+struct PanelTarget {
+    name: String,
+    gene_id: String,
+    feature_type: String,
+}
+
+pub struct GenePanel {
+    targets: Vec<PanelTarget>,
+    // targets by both id and name
+    index: HashMap<String, usize>,
+}
+
+// Metadata of each gene, in the order of `transcript_names`.
+pub struct GeneFeatures {
+    pub gene_ids: Vec<String>,
+    pub feature_types: Vec<String>,
+}
+
+impl GenePanel {
+    pub fn read(path: &str) -> Result<GenePanel> {
+        let panel_error = |message: String| Error::GenePanel {
+            path: path.to_string(),
+            message,
+        };
+
+        let file = std::fs::File::open(path).map_err(|source| Error::Io {
+            path: path.to_string(),
+            source,
+        })?;
+        let mut reader: Box<dyn Read> = if path.ends_with(".gz") {
+            Box::new(MultiGzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        let mut text = String::new();
+        reader.read_to_string(&mut text).map_err(|source| Error::Io {
+            path: path.to_string(),
+            source,
+        })?;
+
+        let manifest = json::parse(&text).map_err(|err| panel_error(err.to_string()))?;
+        let entries = &manifest["payload"]["targets"];
+        if !entries.is_array() {
+            return Err(panel_error(String::from("expected a 'payload.targets' array")));
+        }
+
+        let mut targets = Vec::new();
+        let mut index = HashMap::new();
+        for entry in entries.members() {
+            let data = &entry["type"]["data"];
+            let (gene_id, name) = match (data["id"].as_str(), data["name"].as_str()) {
+                (Some(gene_id), Some(name)) => (gene_id, name),
+                _ => return Err(panel_error(String::from("target without a 'type.data' id and name"))),
+            };
+            let feature_type = entry["type"]["descriptor"].as_str().unwrap_or("gene");
+
+            index.entry(gene_id.to_string()).or_insert(targets.len());
+            index.entry(name.to_string()).or_insert(targets.len());
+            targets.push(PanelTarget {
+                name: name.to_string(),
+                gene_id: gene_id.to_string(),
+                feature_type: feature_type.to_string(),
+            });
+        }
+
+        Ok(GenePanel { targets, index })
+    }
+
+    fn target(&self, gene: &str) -> Option<&PanelTarget> {
+        self.index.get(gene).map(|&i| &self.targets[i])
+    }
+
+    // Control probes and codewords, or anything else that isn't a gene.
+    pub fn is_control(&self, gene: &str) -> bool {
+        self.target(gene)
+            .is_some_and(|target| target.feature_type != "gene")
+    }
+
+    // Genes not in the panel are given their own name as id, and no type.
+    pub fn features(&self, transcript_names: &[String]) -> GeneFeatures {
+        let (gene_ids, feature_types) = transcript_names
+            .iter()
+            .map(|name| match self.target(name) {
+                Some(target) => (target.gene_id.clone(), target.feature_type.clone()),
+                None => (name.clone(), String::new()),
+            })
+            .unzip();
+        GeneFeatures {
+            gene_ids,
+            feature_types,
+        }
+    }
+}
+
+// Rename genes given by probe or Ensembl id to their symbol in the panel,
+// merging any that end up with the same name. Returns the number renamed.
+pub fn rename_genes(dataset: &mut TranscriptDataset, panel: &GenePanel) -> usize {
+    let mut nrenamed = 0;
+    let mut gene_map = Vec::with_capacity(dataset.transcript_names.len());
+    let mut transcript_names: Vec<String> = Vec::new();
+    let mut name_index: HashMap<String, u32> = HashMap::new();
+    for name in dataset.transcript_names.drain(..) {
+        let renamed = match panel.target(&name) {
+            Some(target) if target.name != name => {
+                nrenamed += 1;
+                target.name.clone()
+            }
+            _ => name,
+        };
+        let gene = *name_index.entry(renamed.clone()).or_insert_with(|| {
+            transcript_names.push(renamed);
+            (transcript_names.len() - 1) as u32
+        });
+        gene_map.push(gene);
+    }
+    dataset.transcript_names = transcript_names;
+
+    for transcript in dataset.transcripts.iter_mut() {
+        transcript.gene = gene_map[transcript.gene as usize];
+    }
+
+    nrenamed
+}
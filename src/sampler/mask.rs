@@ -0,0 +1,170 @@
+// Initialization from labeled segmentation masks (e.g. Cellpose or Stardist
+// output), where each pixel holds the id of the cell covering it, or 0.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+
+use super::super::error::{Error, Result};
+use super::transcripts::{postprocess_cell_assignments, CellIndex, TranscriptDataset, BACKGROUND_CELL};
+
+pub struct LabelMask {
+    pub width: usize,
+    pub height: usize,
+    pub labels: Vec<u32>,
+}
+
+impl LabelMask {
+    // Label at the given pixel, or 0 if out of bounds.
+    fn get(&self, i: isize, j: isize) -> u32 {
+        if i < 0 || j < 0 || i as usize >= self.width || j as usize >= self.height {
+            0
+        } else {
+            self.labels[(j as usize) * self.width + (i as usize)]
+        }
+    }
+}
+
+pub(crate) fn image_error(path: &str, message: impl ToString) -> Error {
+    Error::Image {
+        path: path.to_string(),
+        message: message.to_string(),
+    }
+}
+
+// Read a single channel label image from a TIFF or PNG file.
+pub fn read_label_mask(path: &str) -> Result<LabelMask> {
+    let file = File::open(path).map_err(|source| Error::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    let reader = BufReader::new(file);
+
+    let lower_path = path.to_lowercase();
+    if lower_path.ends_with(".tif") || lower_path.ends_with(".tiff") {
+        read_label_mask_tiff(path, reader)
+    } else if lower_path.ends_with(".png") {
+        read_label_mask_png(path, reader)
+    } else {
+        Err(image_error(path, "mask images must be .tif, .tiff, or .png files"))
+    }
+}
+
+fn read_label_mask_tiff(path: &str, reader: BufReader<File>) -> Result<LabelMask> {
+    use tiff::decoder::{Decoder, DecodingResult, Limits};
+
+    let mut decoder = Decoder::new(reader)
+        .map_err(|err| image_error(path, err))?
+        .with_limits(Limits::unlimited());
+
+    match decoder.colortype().map_err(|err| image_error(path, err))? {
+        tiff::ColorType::Gray(_) => {}
+        colortype => {
+            return Err(image_error(
+                path,
+                format!("expected a single channel label image, found {:?}", colortype),
+            ))
+        }
+    }
+
+    let (width, height) = decoder.dimensions().map_err(|err| image_error(path, err))?;
+    let labels: Vec<u32> = match decoder.read_image().map_err(|err| image_error(path, err))? {
+        DecodingResult::U8(data) => data.iter().map(|&v| v as u32).collect(),
+        DecodingResult::U16(data) => data.iter().map(|&v| v as u32).collect(),
+        DecodingResult::U32(data) => data,
+        DecodingResult::U64(data) => data.iter().map(|&v| v as u32).collect(),
+        DecodingResult::I8(data) => data.iter().map(|&v| v.max(0) as u32).collect(),
+        DecodingResult::I16(data) => data.iter().map(|&v| v.max(0) as u32).collect(),
+        DecodingResult::I32(data) => data.iter().map(|&v| v.max(0) as u32).collect(),
+        DecodingResult::I64(data) => data.iter().map(|&v| v.max(0) as u32).collect(),
+        DecodingResult::F32(data) => data.iter().map(|&v| v.max(0.0) as u32).collect(),
+        DecodingResult::F64(data) => data.iter().map(|&v| v.max(0.0) as u32).collect(),
+    };
+
+    Ok(LabelMask {
+        width: width as usize,
+        height: height as usize,
+        labels,
+    })
+}
+
+fn read_label_mask_png(path: &str, reader: BufReader<File>) -> Result<LabelMask> {
+    let mut decoder = png::Decoder::new(reader);
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info().map_err(|err| image_error(path, err))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut buf)
+        .map_err(|err| image_error(path, err))?;
+
+    if info.color_type != png::ColorType::Grayscale {
+        return Err(image_error(
+            path,
+            format!("expected a grayscale label image, found {:?}", info.color_type),
+        ));
+    }
+
+    let (width, height) = (info.width as usize, info.height as usize);
+    let mut labels = Vec::with_capacity(width * height);
+    for row in buf.chunks(info.line_size).take(height) {
+        match info.bit_depth {
+            png::BitDepth::Eight => labels.extend(row[..width].iter().map(|&v| v as u32)),
+            png::BitDepth::Sixteen => labels.extend(
+                row[..2 * width]
+                    .chunks(2)
+                    .map(|v| u16::from_be_bytes([v[0], v[1]]) as u32),
+            ),
+            bit_depth => {
+                return Err(image_error(
+                    path,
+                    format!("unsupported bit depth {:?} for a label image", bit_depth),
+                ))
+            }
+        }
+    }
+
+    Ok(LabelMask {
+        width,
+        height,
+        labels,
+    })
+}
+
+// Replace any initial cell assignments with the label under each transcript.
+// `transform` is an affine transform [a, b, c, d, e, f] from transcript
+// coordinates to pixels: (a*x + b*y + c, d*x + e*y + f).
+pub fn assign_transcripts_from_mask(
+    dataset: &mut TranscriptDataset,
+    mask: &LabelMask,
+    transform: &[f32; 6],
+) {
+    let [a, b, c, d, e, f] = *transform;
+
+    let mut label_cells: HashMap<u32, CellIndex> = HashMap::new();
+    for (i, t) in dataset.transcripts.iter().enumerate() {
+        let px = (a * t.x + b * t.y + c).floor() as isize;
+        let py = (d * t.x + e * t.y + f).floor() as isize;
+        let label = mask.get(px, py);
+
+        let cell = if label == 0 {
+            BACKGROUND_CELL
+        } else {
+            let next_cell = label_cells.len() as CellIndex;
+            *label_cells.entry(label).or_insert(next_cell)
+        };
+        dataset.nucleus_assignments[i] = cell;
+        dataset.cell_assignments[i] = cell;
+    }
+    dataset.original_cell_ids = vec![String::new(); label_cells.len()];
+    for (label, cell) in label_cells {
+        dataset.original_cell_ids[cell as usize] = label.to_string();
+    }
+    dataset
+        .original_cell_assignments
+        .clone_from(&dataset.cell_assignments);
+
+    dataset.nucleus_population = postprocess_cell_assignments(
+        &mut dataset.nucleus_assignments,
+        &mut dataset.cell_assignments,
+    );
+}
@@ -1,6 +1,5 @@
 // use libm::{lgammaf, erff};
 use libm::lgammaf;
-use rand::rngs::ThreadRng;
 use rand::Rng;
 
 // pub fn logit(p: f32) -> f32 {
@@ -57,6 +56,11 @@ pub fn lognormal_logpdf(μ: f32, σ: f32, x: f32) -> f32 {
     -LN_SQRT_TWO_PI - σ.ln() - xln - ((xln - μ) / σ).powi(2) / 2.0
 }
 
+// Gamma log density, with shape `α` and rate `β`.
+pub fn gamma_logpdf(α: f32, β: f32, x: f32) -> f32 {
+    α * β.ln() - lgammaf(α) + (α - 1.0) * x.ln() - β * x
+}
+
 // Negative binomial log probability function with capacity for precomputing some values.
 pub fn negbin_logpmf_fast(
     r: f32,
@@ -82,12 +86,31 @@ pub fn negbin_logpmf_fast(
     }
 }
 
-pub fn rand_crt(rng: &mut ThreadRng, n: u32, r: f32) -> u32 {
+// Gamma mode, with shape `α` and rate `β`, kept positive when `α` <= 1.
+pub fn gamma_mode(α: f32, β: f32) -> f32 {
+    ((α - 1.0) / β).max(f32::MIN_POSITIVE)
+}
+
+// Beta mode, or the mean where there's no interior mode.
+pub fn beta_mode(α: f32, β: f32) -> f32 {
+    if α > 1.0 && β > 1.0 {
+        (α - 1.0) / (α + β - 2.0)
+    } else {
+        α / (α + β)
+    }
+}
+
+pub fn rand_crt<R: Rng>(rng: &mut R, n: u32, r: f32) -> u32 {
     (0..n)
         .map(|t| rng.gen_bool(r as f64 / (r as f64 + t as f64)) as u32)
         .sum()
 }
 
+// Expectation of the Chinese restaurant table distribution `rand_crt` draws from.
+pub fn crt_mean(n: u32, r: f32) -> f32 {
+    (0..n).map(|t| r / (r + t as f32)).sum()
+}
+
 // log-factorial with precomputed values for small numbers
 pub struct LogFactorial {
     values: Vec<f32>,
@@ -111,6 +134,12 @@ impl LogFactorial {
     }
 }
 
+impl Default for LogFactorial {
+    fn default() -> Self {
+        LogFactorial::new()
+    }
+}
+
 // Partially memoized lgamma(r + k), memoized over k.
 #[derive(Clone)]
 pub struct LogGammaPlus {
@@ -118,6 +147,12 @@ pub struct LogGammaPlus {
     values: Vec<f32>,
 }
 
+impl Default for LogGammaPlus {
+    fn default() -> Self {
+        LogGammaPlus::new(0.0)
+    }
+}
+
 impl LogGammaPlus {
     fn new_with_n(r: f32, n: usize) -> Self {
         LogGammaPlus {
@@ -130,9 +165,6 @@ impl LogGammaPlus {
         LogGammaPlus::new_with_n(r, 100)
     }
 
-    pub fn default() -> Self {
-        LogGammaPlus::new(0.0)
-    }
 
     pub fn reset(&mut self, r: f32) {
         self.values.iter_mut().enumerate().for_each(|(k, v)| {
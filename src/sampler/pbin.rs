@@ -0,0 +1,479 @@
+// Compact binary format for transcripts (.pbin), written by `proseg convert`,
+// so repeated runs on the same data don't have to parse large CSV files.
+//
+// A file holds a TranscriptDataset, as read with the options given to `proseg
+// convert`, as uncompressed columns of fixed width little-endian values, so it
+// can be memory mapped and each column read straight out of the mapping. It
+// starts with a 16 byte header:
+//
+//   magic "PROSEGT\0", format version (u32), number of columns (u32)
+//
+// followed by a directory giving the byte offset and number of elements (both
+// u64) of each column in `COLUMNS`, in that order. Columns start at offsets
+// aligned to 8 bytes, with zero padding between them. Lists of strings are
+// stored as two columns: the u64 offsets of each string's start and the end of
+// the last into a column of their concatenated UTF-8 bytes.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use super::super::error::{Error, Result};
+use super::transcripts::{Transcript, TranscriptDataset};
+
+const MAGIC: &[u8; 8] = b"PROSEGT\0";
+const VERSION: u32 = 2;
+const HEADER_LEN: u64 = 16;
+const ALIGNMENT: u64 = 8;
+
+// Name and element width in bytes of each column.
+const COLUMNS: [(&str, u64); 25] = [
+    ("transcript_id", 8),
+    ("x", 4),
+    ("y", 4),
+    ("z", 4),
+    ("gene", 4),
+    ("fov", 4),
+    ("nucleus_assignments", 4),
+    ("cell_assignments", 4),
+    ("fovs", 4),
+    ("qvs", 4),
+    ("nuclear", 1),
+    ("original_cell_assignments", 4),
+    ("prior_sources", 4),
+    ("nucleus_population", 8),
+    ("fov_samples", 4),
+    ("transcript_names.offsets", 8),
+    ("transcript_names.bytes", 1),
+    ("fov_names.offsets", 8),
+    ("fov_names.bytes", 1),
+    ("sample_names.offsets", 8),
+    ("sample_names.bytes", 1),
+    ("original_cell_ids.offsets", 8),
+    ("original_cell_ids.bytes", 1),
+    ("prior_source_names.offsets", 8),
+    ("prior_source_names.bytes", 1),
+];
+
+pub fn is_pbin_filename(filename: &str) -> bool {
+    filename.ends_with(".pbin")
+}
+
+// Values stored in columns.
+trait Value: Copy {
+    const WIDTH: usize;
+    fn write_le(self, writer: &mut impl Write) -> std::io::Result<()>;
+    fn read_le(bytes: &[u8]) -> Self;
+}
+
+macro_rules! impl_value {
+    ($t:ty) => {
+        impl Value for $t {
+            const WIDTH: usize = std::mem::size_of::<$t>();
+
+            fn write_le(self, writer: &mut impl Write) -> std::io::Result<()> {
+                writer.write_all(&self.to_le_bytes())
+            }
+
+            fn read_le(bytes: &[u8]) -> Self {
+                <$t>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    };
+}
+
+impl_value!(u8);
+impl_value!(u32);
+impl_value!(u64);
+impl_value!(f32);
+
+fn string_offsets(strings: &[String]) -> Vec<u64> {
+    let mut offsets = vec![0];
+    for s in strings {
+        offsets.push(offsets.last().unwrap() + s.len() as u64);
+    }
+    offsets
+}
+
+fn string_bytes(strings: &[String]) -> impl Iterator<Item = u8> + '_ {
+    strings.iter().flat_map(|s| s.bytes())
+}
+
+fn align(offset: u64) -> u64 {
+    offset.div_ceil(ALIGNMENT) * ALIGNMENT
+}
+
+// Writes columns one after another, padding each to its aligned offset.
+struct ColumnWriter<W: Write> {
+    writer: W,
+    position: u64,
+}
+
+impl<W: Write> ColumnWriter<W> {
+    fn column<T: Value>(&mut self, values: impl IntoIterator<Item = T>) -> std::io::Result<()> {
+        let start = align(self.position);
+        self.writer
+            .write_all(&vec![0_u8; (start - self.position) as usize])?;
+        self.position = start;
+        for value in values {
+            value.write_le(&mut self.writer)?;
+            self.position += T::WIDTH as u64;
+        }
+        Ok(())
+    }
+}
+
+pub fn write_transcripts_pbin(filename: &str, dataset: &TranscriptDataset) -> Result<()> {
+    let io_error = |source| Error::Io {
+        path: filename.to_string(),
+        source,
+    };
+
+    let ntranscripts = dataset.transcripts.len() as u64;
+    let lengths: [u64; 25] = [
+        ntranscripts,
+        ntranscripts,
+        ntranscripts,
+        ntranscripts,
+        ntranscripts,
+        ntranscripts,
+        dataset.nucleus_assignments.len() as u64,
+        dataset.cell_assignments.len() as u64,
+        dataset.fovs.len() as u64,
+        dataset.qvs.len() as u64,
+        dataset.nuclear.len() as u64,
+        dataset.original_cell_assignments.len() as u64,
+        dataset.prior_sources.len() as u64,
+        dataset.nucleus_population.len() as u64,
+        dataset.fov_samples.len() as u64,
+        dataset.transcript_names.len() as u64 + 1,
+        string_bytes(&dataset.transcript_names).count() as u64,
+        dataset.fov_names.len() as u64 + 1,
+        string_bytes(&dataset.fov_names).count() as u64,
+        dataset.sample_names.len() as u64 + 1,
+        string_bytes(&dataset.sample_names).count() as u64,
+        dataset.original_cell_ids.len() as u64 + 1,
+        string_bytes(&dataset.original_cell_ids).count() as u64,
+        dataset.prior_source_names.len() as u64 + 1,
+        string_bytes(&dataset.prior_source_names).count() as u64,
+    ];
+
+    let mut writer = BufWriter::new(File::create(filename).map_err(io_error)?);
+    writer.write_all(MAGIC).map_err(io_error)?;
+    writer.write_all(&VERSION.to_le_bytes()).map_err(io_error)?;
+    writer
+        .write_all(&(COLUMNS.len() as u32).to_le_bytes())
+        .map_err(io_error)?;
+
+    let mut offset = HEADER_LEN + 16 * COLUMNS.len() as u64;
+    for (&length, (_, width)) in lengths.iter().zip(COLUMNS) {
+        offset = align(offset);
+        writer.write_all(&offset.to_le_bytes()).map_err(io_error)?;
+        writer.write_all(&length.to_le_bytes()).map_err(io_error)?;
+        offset += length * width;
+    }
+
+    let mut columns = ColumnWriter {
+        writer,
+        position: HEADER_LEN + 16 * COLUMNS.len() as u64,
+    };
+    write_columns(&mut columns, dataset)
+        .and_then(|_| columns.writer.flush())
+        .map_err(io_error)
+}
+
+// Write the columns of `dataset`, in the order of `COLUMNS`.
+fn write_columns<W: Write>(
+    columns: &mut ColumnWriter<W>,
+    dataset: &TranscriptDataset,
+) -> std::io::Result<()> {
+    let transcripts = &dataset.transcripts;
+    columns.column(transcripts.iter().map(|t| t.transcript_id))?;
+    columns.column(transcripts.iter().map(|t| t.x))?;
+    columns.column(transcripts.iter().map(|t| t.y))?;
+    columns.column(transcripts.iter().map(|t| t.z))?;
+    columns.column(transcripts.iter().map(|t| t.gene))?;
+    columns.column(transcripts.iter().map(|t| t.fov))?;
+    columns.column(dataset.nucleus_assignments.iter().cloned())?;
+    columns.column(dataset.cell_assignments.iter().cloned())?;
+    columns.column(dataset.fovs.iter().cloned())?;
+    columns.column(dataset.qvs.iter().cloned())?;
+    columns.column(dataset.nuclear.iter().map(|&nuclear| nuclear as u8))?;
+    columns.column(dataset.original_cell_assignments.iter().cloned())?;
+    columns.column(dataset.prior_sources.iter().cloned())?;
+    columns.column(dataset.nucleus_population.iter().map(|&n| n as u64))?;
+    columns.column(dataset.fov_samples.iter().cloned())?;
+    for strings in [
+        &dataset.transcript_names,
+        &dataset.fov_names,
+        &dataset.sample_names,
+        &dataset.original_cell_ids,
+        &dataset.prior_source_names,
+    ] {
+        columns.column(string_offsets(strings))?;
+        columns.column(string_bytes(strings))?;
+    }
+    Ok(())
+}
+
+// The contents of a file, memory mapped where that's possible.
+#[cfg(not(target_arch = "wasm32"))]
+fn map_file(file: &File) -> std::io::Result<memmap2::Mmap> {
+    // SAFETY: the mapping is only read while the dataset is copied out of it,
+    // and is undefined only if the file is truncated or modified meanwhile,
+    // which proseg never does.
+    unsafe { memmap2::Mmap::map(file) }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn map_file(mut file: &File) -> std::io::Result<Vec<u8>> {
+    use std::io::Read;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+pub fn read_transcripts_pbin(filename: &str) -> Result<TranscriptDataset> {
+    let pbin_error = |message: String| Error::Pbin {
+        path: filename.to_string(),
+        message,
+    };
+    let io_error = |source| Error::Io {
+        path: filename.to_string(),
+        source,
+    };
+    let file = File::open(filename).map_err(io_error)?;
+    let bytes = map_file(&file).map_err(io_error)?;
+    let bytes: &[u8] = &bytes;
+
+    if bytes.len() < HEADER_LEN as usize || &bytes[..8] != MAGIC {
+        return Err(pbin_error(String::from("not a proseg transcript file")));
+    }
+    let version = u32::read_le(&bytes[8..12]);
+    if version != VERSION {
+        return Err(pbin_error(format!(
+            "written by an incompatible version of proseg (format {}, expected {}), convert it again",
+            version, VERSION
+        )));
+    }
+    let ncolumns = u32::read_le(&bytes[12..16]) as usize;
+    let directory_end = HEADER_LEN as usize + 16 * COLUMNS.len();
+    if ncolumns != COLUMNS.len() || bytes.len() < directory_end {
+        return Err(pbin_error(String::from("file is corrupt")));
+    }
+
+    // byte range of each column
+    let mut ranges = Vec::with_capacity(COLUMNS.len());
+    for (i, (name, width)) in COLUMNS.iter().enumerate() {
+        let entry = HEADER_LEN as usize + 16 * i;
+        let offset = u64::read_le(&bytes[entry..entry + 8]);
+        let length = u64::read_le(&bytes[entry + 8..entry + 16]);
+        let end = length
+            .checked_mul(*width)
+            .and_then(|size| size.checked_add(offset))
+            .filter(|&end| end <= bytes.len() as u64);
+        match end {
+            Some(end) => ranges.push(offset as usize..end as usize),
+            None => {
+                return Err(pbin_error(format!(
+                    "file is truncated or corrupt (column '{}')",
+                    name
+                )))
+            }
+        }
+    }
+
+    // the six transcript fields are stored as separate columns of equal length
+    let ntranscripts = ranges[0].len() / 8;
+    if (1..6).any(|i| ranges[i].len() / COLUMNS[i].1 as usize != ntranscripts) {
+        return Err(pbin_error(String::from(
+            "file is corrupt (transcript columns differ in length)",
+        )));
+    }
+
+    let mut columns = ranges.into_iter().map(|range| &bytes[range]);
+    let mut column = || columns.next().unwrap();
+    let strings = |offsets: &[u8], data: &[u8]| -> Result<Vec<String>> {
+        values::<u64>(offsets)
+            .windows(2)
+            .map(|w| {
+                data.get(w[0] as usize..w[1] as usize)
+                    .and_then(|s| std::str::from_utf8(s).ok())
+                    .map(String::from)
+                    .ok_or_else(|| pbin_error(String::from("file is corrupt (invalid string)")))
+            })
+            .collect()
+    };
+
+    let transcript_ids = values::<u64>(column());
+    let xs = values::<f32>(column());
+    let ys = values::<f32>(column());
+    let zs = values::<f32>(column());
+    let genes = values::<u32>(column());
+    let transcript_fovs = values::<u32>(column());
+    let transcripts = (0..transcript_ids.len())
+        .map(|i| Transcript {
+            transcript_id: transcript_ids[i],
+            x: xs[i],
+            y: ys[i],
+            z: zs[i],
+            gene: genes[i],
+            fov: transcript_fovs[i],
+        })
+        .collect();
+
+    let nucleus_assignments = values(column());
+    let cell_assignments = values(column());
+    let fovs = values(column());
+    let qvs = values(column());
+    let nuclear = column().iter().map(|&nuclear| nuclear != 0).collect();
+    let original_cell_assignments = values(column());
+    let prior_sources = values(column());
+    let nucleus_population = values::<u64>(column())
+        .into_iter()
+        .map(|n| n as usize)
+        .collect();
+    let fov_samples = values(column());
+    let transcript_names = strings(column(), column())?;
+    let fov_names = strings(column(), column())?;
+    let sample_names = strings(column(), column())?;
+    let original_cell_ids = strings(column(), column())?;
+    let prior_source_names = strings(column(), column())?;
+
+    Ok(TranscriptDataset {
+        transcript_names,
+        transcripts,
+        nucleus_assignments,
+        cell_assignments,
+        nucleus_population,
+        fovs,
+        qvs,
+        fov_names,
+        sample_names,
+        fov_samples,
+        nuclear,
+        original_cell_ids,
+        original_cell_assignments,
+        prior_source_names,
+        prior_sources,
+    })
+}
+
+fn values<T: Value>(bytes: &[u8]) -> Vec<T> {
+    bytes.chunks_exact(T::WIDTH).map(T::read_le).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::transcripts::{Transcript, BACKGROUND_CELL};
+
+    fn test_path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("proseg-test-{}-{}", std::process::id(), name));
+        path.to_str().unwrap().to_string()
+    }
+
+    fn test_dataset() -> TranscriptDataset {
+        let transcripts = (0..5)
+            .map(|i| Transcript {
+                transcript_id: 1000 + i as u64,
+                x: i as f32 * 1.5,
+                y: -(i as f32),
+                z: 0.25,
+                gene: i % 2,
+                fov: i / 3,
+            })
+            .collect();
+        TranscriptDataset {
+            transcript_names: vec![String::from("ACTB"), String::from("GAPDH")],
+            transcripts,
+            nucleus_assignments: vec![0, 0, BACKGROUND_CELL, 1, 1],
+            cell_assignments: vec![0, 0, BACKGROUND_CELL, 1, BACKGROUND_CELL],
+            nucleus_population: vec![2, 2],
+            fovs: vec![0, 0, 0, 1, 1],
+            qvs: vec![20.0, 30.5, 40.0, 12.0, 39.0],
+            fov_names: vec![String::from("fov_a"), String::from("fov_b")],
+            sample_names: vec![String::from("sample.csv")],
+            fov_samples: vec![0, 0],
+            nuclear: vec![true, true, false, true, false],
+            original_cell_ids: vec![String::from("c1"), String::from("c2")],
+            original_cell_assignments: vec![0, 0, BACKGROUND_CELL, 1, 1],
+            prior_source_names: vec![String::new()],
+            prior_sources: vec![0; 5],
+        }
+    }
+
+    #[test]
+    fn pbin_round_trip() {
+        let path = test_path("round-trip.pbin");
+        let dataset = test_dataset();
+        write_transcripts_pbin(&path, &dataset).unwrap();
+        let read = read_transcripts_pbin(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.transcript_names, dataset.transcript_names);
+        assert_eq!(read.transcripts, dataset.transcripts);
+        assert_eq!(read.nucleus_assignments, dataset.nucleus_assignments);
+        assert_eq!(read.cell_assignments, dataset.cell_assignments);
+        assert_eq!(read.nucleus_population, dataset.nucleus_population);
+        assert_eq!(read.fovs, dataset.fovs);
+        assert_eq!(read.qvs, dataset.qvs);
+        assert_eq!(read.fov_names, dataset.fov_names);
+        assert_eq!(read.sample_names, dataset.sample_names);
+        assert_eq!(read.fov_samples, dataset.fov_samples);
+        assert_eq!(read.nuclear, dataset.nuclear);
+        assert_eq!(read.original_cell_ids, dataset.original_cell_ids);
+        assert_eq!(
+            read.original_cell_assignments,
+            dataset.original_cell_assignments
+        );
+        assert_eq!(read.prior_source_names, dataset.prior_source_names);
+        assert_eq!(read.prior_sources, dataset.prior_sources);
+    }
+
+    #[test]
+    fn pbin_columns_are_aligned() {
+        let path = test_path("aligned.pbin");
+        let dataset = test_dataset();
+        write_transcripts_pbin(&path, &dataset).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        for (i, (_, width)) in COLUMNS.iter().enumerate() {
+            let entry = HEADER_LEN as usize + 16 * i;
+            let offset = u64::read_le(&bytes[entry..entry + 8]);
+            let length = u64::read_le(&bytes[entry + 8..entry + 16]);
+            assert_eq!(offset % ALIGNMENT, 0);
+            assert!(offset + length * width <= bytes.len() as u64);
+        }
+
+        // x coordinates can be read in place
+        let entry = HEADER_LEN as usize + 16;
+        let offset = u64::read_le(&bytes[entry..entry + 8]) as usize;
+        let xs: Vec<f32> = values(&bytes[offset..offset + 4 * dataset.transcripts.len()]);
+        assert_eq!(
+            xs,
+            dataset.transcripts.iter().map(|t| t.x).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let path = test_path("truncated.pbin");
+        write_transcripts_pbin(&path, &test_dataset()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 8]).unwrap();
+        let result = read_transcripts_pbin(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = test_path("not-a.pbin");
+        std::fs::write(&path, "transcript_id,x,y\n1,2,3\n").unwrap();
+        let result = read_transcripts_pbin(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
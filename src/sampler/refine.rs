@@ -0,0 +1,64 @@
+// Initialization from a previous proseg run, for `proseg refine`, which
+// re-samples cells within a region while leaving the rest of the previous
+// segmentation as it was.
+
+use std::collections::HashMap;
+
+use super::super::compare::Segmentation;
+use super::transcripts::{postprocess_cell_assignments, CellIndex, TranscriptDataset, BACKGROUND_CELL};
+
+// Assign transcripts to the cells they had in `previous`, matched by transcript
+// id, returning the number of transcripts found there. Each nucleus is relabeled
+// to the previous cell most of its transcripts were in, so nuclei still inform
+// the cells being refined.
+pub fn assign_transcripts_from_previous(
+    dataset: &mut TranscriptDataset,
+    previous: &Segmentation,
+) -> usize {
+    let previous_cells: HashMap<u64, CellIndex> = previous
+        .transcript_ids
+        .iter()
+        .cloned()
+        .zip(previous.cells.iter().cloned())
+        .collect();
+
+    let mut nmatched = 0;
+    let mut votes: HashMap<(CellIndex, CellIndex), usize> = HashMap::new();
+    for (i, t) in dataset.transcripts.iter().enumerate() {
+        let cell = match previous_cells.get(&t.transcript_id) {
+            Some(&cell) => {
+                nmatched += 1;
+                cell
+            }
+            None => BACKGROUND_CELL,
+        };
+        let nucleus = dataset.nucleus_assignments[i];
+        if nucleus != BACKGROUND_CELL && cell != BACKGROUND_CELL {
+            *votes.entry((nucleus, cell)).or_insert(0) += 1;
+        }
+        dataset.cell_assignments[i] = cell;
+    }
+
+    let mut nucleus_cells: HashMap<CellIndex, (CellIndex, usize)> = HashMap::new();
+    for ((nucleus, cell), count) in votes {
+        let winner = nucleus_cells.entry(nucleus).or_insert((cell, count));
+        if (count, cell) > (winner.1, winner.0) {
+            *winner = (cell, count);
+        }
+    }
+    for nucleus in dataset.nucleus_assignments.iter_mut() {
+        if *nucleus != BACKGROUND_CELL {
+            *nucleus = nucleus_cells
+                .get(nucleus)
+                .map(|&(cell, _)| cell)
+                .unwrap_or(BACKGROUND_CELL);
+        }
+    }
+
+    dataset.nucleus_population = postprocess_cell_assignments(
+        &mut dataset.nucleus_assignments,
+        &mut dataset.cell_assignments,
+    );
+
+    nmatched
+}
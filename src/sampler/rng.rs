@@ -0,0 +1,123 @@
+// Random number streams for the sampler.
+//
+// Every random draw is taken from a stream derived from a global seed, so that
+// runs with the same seed are reproducible. Work is spread over threads by
+// rayon in no fixed order, so rather than one stream per thread, parallel loops
+// draw a single stream key up front (from serial code, where the order is
+// fixed) and derive a stream for each item from it.
+//
+// Generators are PCG, rather than rand's SmallRng, whose algorithm differs
+// between 32 and 64-bit platforms and may change between rand versions, so
+// that results are the same from one machine to the next.
+//
+// The seed and stream keys are global, except in thread pools made with
+// `thread_pool`, which each get their own, so that several runs can sample at
+// once (e.g. FOVs segmented in parallel) without drawing from each other's
+// streams.
+
+use rand::SeedableRng;
+use rand_pcg::Pcg64Mcg;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::cell::OnceCell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::BuildHasherDefault;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+pub type SamplerRng = Pcg64Mcg;
+
+// Hasher with fixed keys, for hash maps whose iteration order affects sampling.
+pub type FixedState = BuildHasherDefault<DefaultHasher>;
+
+struct Streams {
+    seed: AtomicU64,
+    next_stream: AtomicU64,
+}
+
+impl Streams {
+    const fn new() -> Self {
+        Streams {
+            seed: AtomicU64::new(0),
+            next_stream: AtomicU64::new(0),
+        }
+    }
+}
+
+static STREAMS: Streams = Streams::new();
+
+thread_local! {
+    static POOL_STREAMS: OnceCell<Arc<Streams>> = const { OnceCell::new() };
+}
+
+fn with_streams<T>(f: impl FnOnce(&Streams) -> T) -> T {
+    POOL_STREAMS.with(|pool_streams| match pool_streams.get() {
+        Some(streams) => f(streams),
+        None => f(&STREAMS),
+    })
+}
+
+// Thread pool with streams of its own. Everything run with `install` on it,
+// including its parallel loops, draws from these rather than the global streams.
+pub fn thread_pool(nthreads: usize) -> ThreadPool {
+    let streams = Arc::new(Streams::new());
+    ThreadPoolBuilder::new()
+        .num_threads(nthreads)
+        .start_handler(move |_| {
+            POOL_STREAMS.with(|pool_streams| {
+                let _ = pool_streams.set(Arc::clone(&streams));
+            })
+        })
+        .build()
+        .unwrap()
+}
+
+// Reset all streams to start from the given seed.
+pub fn set_seed(seed: u64) {
+    with_streams(|streams| {
+        streams.seed.store(seed, Ordering::SeqCst);
+        streams.next_stream.store(0, Ordering::SeqCst);
+    })
+}
+
+// The seed and next stream key, which is all there is to the state of the
+// streams, for checkpointing.
+pub fn state() -> (u64, u64) {
+    with_streams(|streams| {
+        (
+            streams.seed.load(Ordering::SeqCst),
+            streams.next_stream.load(Ordering::SeqCst),
+        )
+    })
+}
+
+// Continue the streams from a state returned by `state`.
+pub fn restore_state((seed, next_stream): (u64, u64)) {
+    with_streams(|streams| {
+        streams.seed.store(seed, Ordering::SeqCst);
+        streams.next_stream.store(next_stream, Ordering::SeqCst);
+    })
+}
+
+// Claim a new stream key. Must only be called from serial code.
+pub fn next_stream() -> u64 {
+    with_streams(|streams| streams.next_stream.fetch_add(1, Ordering::SeqCst))
+}
+
+// Generator for one item of a parallel loop.
+pub fn stream_rng(stream: u64, item: usize) -> SamplerRng {
+    let seed = with_streams(|streams| streams.seed.load(Ordering::Relaxed));
+    let seed = splitmix64(splitmix64(seed ^ splitmix64(stream)) ^ item as u64);
+    SamplerRng::seed_from_u64(seed)
+}
+
+// Generator for serial code.
+pub fn rng() -> SamplerRng {
+    stream_rng(next_stream(), 0)
+}
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
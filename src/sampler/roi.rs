@@ -0,0 +1,249 @@
+// Regions of interest, used to crop transcripts while they are read, and
+// indexed sets of many small polygons (e.g. nuclei) used to label transcripts.
+
+use geo::{BoundingRect, Contains, Coord, LineString, MultiPolygon, Point, Polygon, Rect};
+use json::JsonValue;
+use std::collections::HashMap;
+use std::io::Read;
+
+use super::super::error::{Error, Result};
+use super::super::output::open_decompressed;
+
+pub enum Roi {
+    Rect(Rect<f32>),
+    Polygons {
+        polygons: MultiPolygon<f32>,
+        bounds: Rect<f32>,
+    },
+}
+
+impl Roi {
+    pub fn rect(xmin: f32, ymin: f32, xmax: f32, ymax: f32) -> Roi {
+        Roi::Rect(Rect::new(
+            Coord { x: xmin, y: ymin },
+            Coord { x: xmax, y: ymax },
+        ))
+    }
+
+    // Read every Polygon and MultiPolygon in a GeoJSON file, which may be a
+    // FeatureCollection, Feature, or bare geometry.
+    pub fn from_geojson(path: &str) -> Result<Roi> {
+        let geojson = read_geojson(path)?;
+
+        let mut polygons = Vec::new();
+        collect_polygons(path, &geojson, &mut polygons)?;
+        let polygons = MultiPolygon::new(polygons);
+
+        let bounds = polygons
+            .bounding_rect()
+            .ok_or_else(|| geojson_error(path, "no polygons found"))?;
+
+        Ok(Roi::Polygons { polygons, bounds })
+    }
+
+    pub fn bounds(&self) -> Rect<f32> {
+        match self {
+            Roi::Rect(rect) => *rect,
+            Roi::Polygons { bounds, .. } => *bounds,
+        }
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        match self {
+            Roi::Rect(rect) => {
+                x >= rect.min().x && x <= rect.max().x && y >= rect.min().y && y <= rect.max().y
+            }
+            Roi::Polygons { polygons, bounds } => {
+                x >= bounds.min().x
+                    && x <= bounds.max().x
+                    && y >= bounds.min().y
+                    && y <= bounds.max().y
+                    && polygons.contains(&Point::new(x, y))
+            }
+        }
+    }
+}
+
+// Rectangles bucketed on a grid, so that finding those near a point or another
+// rectangle only considers the few in nearby grid cells.
+pub struct RectIndex {
+    cell_size: f32,
+    grid: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl RectIndex {
+    // Index rectangles by their position in `bounds`, skipping any that are
+    // missing.
+    pub fn new(bounds: &[Option<Rect<f32>>]) -> RectIndex {
+        // size grid cells to a typical rectangle, so each is in only a few
+        let mut sizes: Vec<f32> = bounds
+            .iter()
+            .flatten()
+            .map(|rect| rect.width().max(rect.height()))
+            .collect();
+        let cell_size = if sizes.is_empty() {
+            1.0
+        } else {
+            let mid = sizes.len() / 2;
+            let (_, median, _) = sizes.select_nth_unstable_by(mid, |a, b| a.total_cmp(b));
+            median.max(1e-6)
+        };
+
+        let mut index = RectIndex {
+            cell_size,
+            grid: HashMap::new(),
+        };
+        for (i, rect) in bounds.iter().enumerate() {
+            if let Some(rect) = rect {
+                for key in index.keys(rect) {
+                    index.grid.entry(key).or_default().push(i);
+                }
+            }
+        }
+        index
+    }
+
+    fn key(&self, x: f32, y: f32) -> (i32, i32) {
+        ((x / self.cell_size).floor() as i32, (y / self.cell_size).floor() as i32)
+    }
+
+    fn keys(&self, rect: &Rect<f32>) -> impl Iterator<Item = (i32, i32)> {
+        let (imin, jmin) = self.key(rect.min().x, rect.min().y);
+        let (imax, jmax) = self.key(rect.max().x, rect.max().y);
+        (imin..=imax).flat_map(move |i| (jmin..=jmax).map(move |j| (i, j)))
+    }
+
+    // Indices of every rectangle sharing a grid cell with this one.
+    pub fn query(&self, rect: &Rect<f32>) -> Vec<usize> {
+        let mut found: Vec<usize> = self
+            .keys(rect)
+            .filter_map(|key| self.grid.get(&key))
+            .flatten()
+            .cloned()
+            .collect();
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+
+    // Indices of the rectangles in the grid cell containing a point, which
+    // include every rectangle containing it.
+    pub fn query_point(&self, x: f32, y: f32) -> &[usize] {
+        self.grid.get(&self.key(x, y)).map_or(&[], |found| found.as_slice())
+    }
+}
+
+// Polygons indexed by their bounding boxes, so that testing a point only
+// considers the few polygons nearby.
+pub struct PolygonIndex {
+    polygons: Vec<(Polygon<f32>, Rect<f32>)>,
+    index: RectIndex,
+}
+
+impl PolygonIndex {
+    pub fn from_geojson(path: &str) -> Result<PolygonIndex> {
+        let geojson = read_geojson(path)?;
+
+        let mut polygons = Vec::new();
+        collect_polygons(path, &geojson, &mut polygons)?;
+        let polygons: Vec<(Polygon<f32>, Rect<f32>)> = polygons
+            .into_iter()
+            .filter_map(|polygon| polygon.bounding_rect().map(|bounds| (polygon, bounds)))
+            .collect();
+        if polygons.is_empty() {
+            return Err(geojson_error(path, "no polygons found"));
+        }
+
+        let bounds: Vec<Option<Rect<f32>>> = polygons.iter().map(|(_, bounds)| Some(*bounds)).collect();
+        let index = RectIndex::new(&bounds);
+
+        Ok(PolygonIndex { polygons, index })
+    }
+
+    pub fn len(&self) -> usize {
+        self.polygons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.polygons.is_empty()
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        self.index.query_point(x, y).iter().any(|&k| {
+            let (polygon, bounds) = &self.polygons[k];
+            x >= bounds.min().x
+                && x <= bounds.max().x
+                && y >= bounds.min().y
+                && y <= bounds.max().y
+                && polygon.contains(&Point::new(x, y))
+        })
+    }
+}
+
+// Parse a GeoJSON file, decompressing it if it's gzip or zstd compressed.
+pub(crate) fn read_geojson(path: &str) -> Result<JsonValue> {
+    let io_error = |source| Error::Io {
+        path: path.to_string(),
+        source,
+    };
+    let mut text = String::new();
+    open_decompressed(path)
+        .map_err(io_error)?
+        .read_to_string(&mut text)
+        .map_err(io_error)?;
+    json::parse(&text).map_err(|err| geojson_error(path, err))
+}
+
+pub(crate) fn geojson_error(path: &str, message: impl ToString) -> Error {
+    Error::GeoJson {
+        path: path.to_string(),
+        message: message.to_string(),
+    }
+}
+
+fn collect_polygons(path: &str, value: &JsonValue, polygons: &mut Vec<Polygon<f32>>) -> Result<()> {
+    match value["type"].as_str() {
+        Some("FeatureCollection") => {
+            for feature in value["features"].members() {
+                collect_polygons(path, feature, polygons)?;
+            }
+        }
+        Some("Feature") => collect_polygons(path, &value["geometry"], polygons)?,
+        Some("GeometryCollection") => {
+            for geometry in value["geometries"].members() {
+                collect_polygons(path, geometry, polygons)?;
+            }
+        }
+        Some("Polygon") => polygons.push(parse_polygon(path, &value["coordinates"])?),
+        Some("MultiPolygon") => {
+            for coordinates in value["coordinates"].members() {
+                polygons.push(parse_polygon(path, coordinates)?);
+            }
+        }
+        // Points and lines don't enclose anything.
+        _ => {}
+    }
+    Ok(())
+}
+
+pub(crate) fn parse_polygon(path: &str, rings: &JsonValue) -> Result<Polygon<f32>> {
+    let mut rings = rings
+        .members()
+        .map(|ring| parse_ring(path, ring))
+        .collect::<Result<Vec<_>>>()?;
+    if rings.is_empty() {
+        return Err(geojson_error(path, "polygon with no coordinates"));
+    }
+    let exterior = rings.remove(0);
+    Ok(Polygon::new(exterior, rings))
+}
+
+fn parse_ring(path: &str, ring: &JsonValue) -> Result<LineString<f32>> {
+    ring.members()
+        .map(|position| match (position[0].as_f32(), position[1].as_f32()) {
+            (Some(x), Some(y)) => Ok(Coord { x, y }),
+            _ => Err(geojson_error(path, format!("invalid position {}", position))),
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(LineString::new)
+}
@@ -0,0 +1,388 @@
+// Row filter expressions, like `qv >= 20 && fov != 12`, evaluated on each row
+// of the transcript table as it's read.
+//
+// Expressions compare columns and literals with ==, !=, <, <=, >, >=, combined
+// with &&, ||, !, and parentheses. Values are compared as numbers when both
+// sides parse as numbers, and as strings otherwise.
+
+use std::cmp::Ordering;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug)]
+enum Operand {
+    // Index into RowFilter::columns
+    Column(usize),
+    Literal(String, Option<f64>),
+}
+
+#[derive(Debug)]
+enum Expr {
+    Cmp(Operand, CmpOp, Operand),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(String),
+    Cmp(CmpOp),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+pub struct RowFilter {
+    expr: Expr,
+    columns: Vec<String>,
+}
+
+impl RowFilter {
+    pub fn parse(source: &str) -> Result<RowFilter, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            columns: Vec::new(),
+        };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {:?}", token));
+        }
+        Ok(RowFilter {
+            expr,
+            columns: parser.columns,
+        })
+    }
+
+    // Names of the columns the expression refers to. Values are passed to
+    // `eval` by position in this list.
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    pub fn eval<'a, F>(&self, value: F) -> bool
+    where
+        F: Fn(usize) -> &'a str,
+    {
+        eval_expr(&self.expr, &value)
+    }
+}
+
+fn eval_expr<'a, F>(expr: &Expr, value: &F) -> bool
+where
+    F: Fn(usize) -> &'a str,
+{
+    match expr {
+        Expr::Cmp(a, op, b) => {
+            let (a, a_num) = eval_operand(a, value);
+            let (b, b_num) = eval_operand(b, value);
+            let ord = match (a_num, b_num) {
+                (Some(a_num), Some(b_num)) => a_num.partial_cmp(&b_num),
+                _ => Some(a.cmp(b)),
+            };
+            match ord {
+                Some(ord) => match op {
+                    CmpOp::Eq => ord == Ordering::Equal,
+                    CmpOp::Ne => ord != Ordering::Equal,
+                    CmpOp::Lt => ord == Ordering::Less,
+                    CmpOp::Le => ord != Ordering::Greater,
+                    CmpOp::Gt => ord == Ordering::Greater,
+                    CmpOp::Ge => ord != Ordering::Less,
+                },
+                // NaN compares unequal to everything
+                None => *op == CmpOp::Ne,
+            }
+        }
+        Expr::And(a, b) => eval_expr(a, value) && eval_expr(b, value),
+        Expr::Or(a, b) => eval_expr(a, value) || eval_expr(b, value),
+        Expr::Not(a) => !eval_expr(a, value),
+    }
+}
+
+fn eval_operand<'a, 'b, F>(operand: &'b Operand, value: &F) -> (&'b str, Option<f64>)
+where
+    F: Fn(usize) -> &'a str,
+    'a: 'b,
+{
+    match operand {
+        Operand::Column(i) => {
+            let v = value(*i);
+            (v, v.trim().parse::<f64>().ok())
+        }
+        Operand::Literal(s, num) => (s.as_str(), *num),
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        match c {
+            _ if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '&' if next == Some('&') => {
+                tokens.push(Token::And);
+                i += 2;
+            }
+            '|' if next == Some('|') => {
+                tokens.push(Token::Or);
+                i += 2;
+            }
+            '=' if next == Some('=') => {
+                tokens.push(Token::Cmp(CmpOp::Eq));
+                i += 2;
+            }
+            '!' if next == Some('=') => {
+                tokens.push(Token::Cmp(CmpOp::Ne));
+                i += 2;
+            }
+            '!' => {
+                tokens.push(Token::Not);
+                i += 1;
+            }
+            '<' | '>' => {
+                let op = match (c, next == Some('=')) {
+                    ('<', false) => CmpOp::Lt,
+                    ('<', true) => CmpOp::Le,
+                    ('>', false) => CmpOp::Gt,
+                    _ => CmpOp::Ge,
+                };
+                tokens.push(Token::Cmp(op));
+                i += if next == Some('=') { 2 } else { 1 };
+            }
+            '"' | '\'' => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&d| d == c)
+                    .ok_or_else(|| String::from("unterminated string"))?;
+                tokens.push(Token::Str(chars[i + 1..i + 1 + end].iter().collect()));
+                i += end + 2;
+            }
+            _ if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let start = i;
+                i += 1;
+                while i < chars.len()
+                    && (chars[i].is_ascii_alphanumeric()
+                        || chars[i] == '.'
+                        || (chars[i] == '-' && matches!(chars[i - 1], 'e' | 'E')))
+                {
+                    i += 1;
+                }
+                tokens.push(Token::Num(chars[start..i].iter().collect()));
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+                {
+                    i += 1;
+                }
+                tokens.push(Token::Ident(chars[start..i].iter().collect()));
+            }
+            // Backticks allow column names that aren't plain identifiers.
+            '`' => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|&d| d == '`')
+                    .ok_or_else(|| String::from("unterminated column name"))?;
+                tokens.push(Token::Ident(chars[i + 1..i + 1 + end].iter().collect()));
+                i += end + 2;
+            }
+            _ => return Err(format!("unexpected character '{}'", c)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    columns: Vec<String>,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.parse_unary()?)))
+            }
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Some(Token::RParen) => Ok(expr),
+                    _ => Err(String::from("expected ')'")),
+                }
+            }
+            _ => {
+                let a = self.parse_operand()?;
+                let op = match self.next() {
+                    Some(Token::Cmp(op)) => *op,
+                    _ => return Err(String::from("expected a comparison")),
+                };
+                let b = self.parse_operand()?;
+                Ok(Expr::Cmp(a, op, b))
+            }
+        }
+    }
+
+    fn parse_operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some(Token::Ident(name)) => {
+                let name = name.clone();
+                let i = match self.columns.iter().position(|column| *column == name) {
+                    Some(i) => i,
+                    None => {
+                        self.columns.push(name);
+                        self.columns.len() - 1
+                    }
+                };
+                Ok(Operand::Column(i))
+            }
+            Some(Token::Str(s)) => Ok(Operand::Literal(s.clone(), s.trim().parse::<f64>().ok())),
+            Some(Token::Num(s)) => {
+                let num = s
+                    .parse::<f64>()
+                    .map_err(|_| format!("invalid number '{}'", s))?;
+                Ok(Operand::Literal(s.clone(), Some(num)))
+            }
+            Some(token) => Err(format!("unexpected {:?}", token)),
+            None => Err(String::from("unexpected end of expression")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Evaluate `source` on a row given as (column, value) pairs.
+    fn eval(source: &str, row: &[(&str, &str)]) -> bool {
+        let filter = RowFilter::parse(source).unwrap();
+        let values: Vec<&str> = filter
+            .columns()
+            .iter()
+            .map(|column| row.iter().find(|(name, _)| name == column).unwrap().1)
+            .collect();
+        filter.eval(|i| values[i])
+    }
+
+    fn parse_error(source: &str) -> String {
+        RowFilter::parse(source).err().unwrap()
+    }
+
+    #[test]
+    fn precedence() {
+        let row = [("a", "1"), ("b", "2"), ("c", "3")];
+        // && binds tighter than ||
+        assert!(eval("a == 1 || b == 0 && c == 0", &row));
+        assert!(!eval("(a == 1 || b == 0) && c == 0", &row));
+        assert!(eval("a == 0 && b == 0 || c == 3", &row));
+        assert!(!eval("a == 0 && (b == 0 || c == 3)", &row));
+        // ! applies to the comparison or group that follows
+        assert!(!eval("!a == 1 || b == 0", &row));
+        assert!(eval("!(a == 1 && b == 0)", &row));
+        assert!(eval("!!(a == 1)", &row));
+    }
+
+    #[test]
+    fn quoting() {
+        let row = [("gene", "Neg Control"), ("cell id", "x"), ("fov", "12")];
+        assert!(eval("gene == \"Neg Control\"", &row));
+        assert!(eval("gene == 'Neg Control'", &row));
+        assert!(eval("'a && b' != gene", &row));
+        assert!(eval("`cell id` == 'x'", &row));
+        // quoted literals that are numbers still compare as numbers
+        assert!(eval("fov == \"12.0\"", &row));
+    }
+
+    #[test]
+    fn numeric_and_string_comparison() {
+        let row = [("qv", "9"), ("fov", "FOV_10"), ("x", "1e3"), ("y", "NaN")];
+        // as numbers, where as strings "9" > "10"
+        assert!(eval("qv < 10", &row));
+        assert!(eval("qv >= -1.5", &row));
+        assert!(eval("x == 1000", &row));
+        // as strings, so "FOV_10" < "FOV_9"
+        assert!(eval("fov < 'FOV_9'", &row));
+        assert!(eval("fov > FOV_1", &[("fov", "FOV_10"), ("FOV_1", "FOV_1")]));
+        // a number against a string that isn't one compares as strings
+        assert!(!eval("qv > 'abc'", &row));
+        // NaN is unequal to everything, itself included
+        assert!(!eval("y == y", &row));
+        assert!(eval("y != y", &row));
+        assert!(!eval("y < 0 || y >= 0", &row));
+    }
+
+    #[test]
+    fn columns() {
+        let filter = RowFilter::parse("qv >= 20 && fov != 12 || 0 > qv").unwrap();
+        assert_eq!(filter.columns(), &["qv", "fov"]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse_error("qv >= 20 &&"), "unexpected end of expression");
+        assert_eq!(parse_error("qv"), "expected a comparison");
+        assert_eq!(parse_error("qv >= 20 fov"), "unexpected Ident(\"fov\")");
+        assert_eq!(parse_error("(qv > 1"), "expected ')'");
+        assert_eq!(parse_error("qv > 1)"), "unexpected RParen");
+        assert_eq!(parse_error("gene == 'x"), "unterminated string");
+        assert_eq!(parse_error("`gene == 1"), "unterminated column name");
+        assert_eq!(parse_error("qv & 1"), "unexpected character '&'");
+        assert_eq!(parse_error("qv > 1.2.3"), "invalid number '1.2.3'");
+    }
+}
@@ -1,4 +1,3 @@
-use rand::rngs::ThreadRng;
 use rand::Rng;
 use std::collections::hash_map::Entry::{Occupied, Vacant};
 use std::collections::HashMap;
@@ -62,7 +61,12 @@ where
         }
     }
 
-    pub fn choose(&self, rng: &mut ThreadRng) -> Option<&T> {
+    // Elements, in the order they're chosen by index.
+    pub fn as_slice(&self) -> &[T] {
+        &self.vec
+    }
+
+    pub fn choose<R: Rng>(&self, rng: &mut R) -> Option<&T> {
         if self.is_empty() {
             return None;
         }
@@ -78,3 +82,16 @@ where
     //     return self.vec.iter();
     // }
 }
+
+impl<T> FromIterator<T> for SampleSet<T>
+where
+    T: Eq + Hash + Copy,
+{
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut set = SampleSet::new();
+        for value in iter {
+            set.insert(value);
+        }
+        set
+    }
+}
@@ -0,0 +1,286 @@
+// Smoothing and simplification of consensus cell polygons, which are traced
+// along voxel edges and so are otherwise jagged and have many vertices.
+
+use clap::ValueEnum;
+use serde::Serialize;
+use geo::geometry::{Coord, Line, LineString, MultiPolygon, Polygon, Rect};
+use geo::line_intersection::{line_intersection, LineIntersection};
+use geo::{
+    Area, BooleanOps, BoundingRect, ChaikinSmoothing, Densify, Intersects, MapCoords,
+    RemoveRepeatedPoints, Simplify,
+};
+use rayon::prelude::*;
+use std::panic::AssertUnwindSafe;
+
+use super::roi::RectIndex;
+use super::voxelsampler::CellPolygon;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PolygonSmoothing {
+    None,
+    Chaikin,
+    Gaussian,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PolygonSmoothingParams {
+    pub method: PolygonSmoothing,
+
+    // Rounds of corner cutting, for Chaikin smoothing
+    pub iterations: usize,
+
+    // Kernel standard deviation, in output units, for Gaussian smoothing
+    pub sigma: f32,
+
+    // Douglas-Peucker tolerance, in output units. 0 disables simplification.
+    pub simplify_tolerance: f32,
+}
+
+impl PolygonSmoothingParams {
+    pub fn is_noop(&self) -> bool {
+        self.method == PolygonSmoothing::None && self.simplify_tolerance <= 0.0
+    }
+}
+
+// Smooth and simplify every cell's polygons, then remove any overlaps.
+//
+// Each ring is checked after smoothing and again after simplification, falling
+// back to the last version that doesn't self-intersect.
+pub fn smooth_cell_polygons(
+    polygons: &[CellPolygon],
+    params: &PolygonSmoothingParams,
+) -> Vec<CellPolygon> {
+    if params.is_noop() {
+        return remove_overlaps(polygons);
+    }
+
+    let smoothed: Vec<CellPolygon> = polygons
+        .par_iter()
+        .map(|multipoly| {
+            MultiPolygon::new(
+                multipoly
+                    .iter()
+                    .map(|poly| Polygon::new(smooth_ring(poly.exterior(), params), vec![]))
+                    .collect(),
+            )
+        })
+        .collect();
+
+    remove_overlaps(&smoothed)
+}
+
+// Clip cell polygons so that no two overlap. Consensus polygons overlap where
+// one cell encloses another (holes are dropped when they're traced), and
+// smoothing or simplification moves shared boundaries. Overlaps are removed by
+// subtracting neighbors from each cell in turn: earlier cells in their final
+// form, later cells as given. Every pair of cells is separated by one of these
+// subtractions, so the output polygons never overlap.
+pub fn remove_overlaps(polygons: &[CellPolygon]) -> Vec<CellPolygon> {
+    let bounds: Vec<Option<Rect<f32>>> = polygons.iter().map(|p| p.bounding_rect()).collect();
+    let index = RectIndex::new(&bounds);
+
+    let mut resolved: Vec<CellPolygon> = Vec::with_capacity(polygons.len());
+    for (i, multipoly) in polygons.iter().enumerate() {
+        let bounds_i = match bounds[i] {
+            Some(bounds_i) => bounds_i,
+            None => {
+                resolved.push(multipoly.clone());
+                continue;
+            }
+        };
+
+        // Boolean ops are done in f64, since nearly coincident shared edges are
+        // prone to failing in f32. Neighbors are subtracted one at a time, as
+        // those given may overlap one another, which isn't a valid
+        // MultiPolygon.
+        let mut multipoly = to_f64(multipoly);
+        for j in index.query(&bounds_i) {
+            if j == i || !bounds[j].is_some_and(|bounds_j| bounds_j.intersects(&bounds_i)) {
+                continue;
+            }
+            let neighbor = if j < i { &resolved[j] } else { &polygons[j] };
+            if multipoly.0.is_empty() || neighbor.0.is_empty() {
+                continue;
+            }
+            let neighbor = to_f64(neighbor);
+            if multipoly.intersects(&neighbor) {
+                multipoly = match try_boolean_op(|| multipoly.difference(&neighbor)) {
+                    Some(difference) => difference,
+                    // If the difference can't be computed, drop whichever
+                    // parts of the cell touch the neighbor.
+                    None => MultiPolygon::new(
+                        multipoly
+                            .into_iter()
+                            .filter(|poly| !poly.intersects(&neighbor))
+                            .collect(),
+                    ),
+                };
+            }
+        }
+        resolved.push(to_f32(&multipoly));
+    }
+
+    resolved
+}
+
+// geo's boolean ops can panic on nearly degenerate inputs. Run one, returning
+// None rather than unwinding. (The panic hook is process wide, so it's left to
+// report the panic as usual.)
+pub(crate) fn try_boolean_op<T>(op: impl FnOnce() -> T) -> Option<T> {
+    std::panic::catch_unwind(AssertUnwindSafe(op)).ok()
+}
+
+// Round back to f32. Vertices closer together than f32 can resolve would
+// round into spikes or repeated points, so those are simplified away first,
+// and any rings that collapse are dropped.
+fn to_f32(multipoly: &MultiPolygon<f64>) -> MultiPolygon<f32> {
+    let magnitude = multipoly.bounding_rect().map_or(0.0, |rect| {
+        [rect.min().x, rect.min().y, rect.max().x, rect.max().y]
+            .iter()
+            .fold(1.0_f64, |a, b| a.max(b.abs()))
+    });
+    let epsilon = 16.0 * f32::EPSILON as f64 * magnitude;
+    let multipoly: MultiPolygon<f32> = multipoly
+        .simplify(&epsilon)
+        .map_coords(|c| Coord {
+            x: c.x as f32,
+            y: c.y as f32,
+        })
+        .remove_repeated_points();
+    MultiPolygon::new(
+        multipoly
+            .into_iter()
+            .filter(|poly| poly.exterior().0.len() >= 4)
+            .map(|poly| {
+                let (exterior, interiors) = poly.into_inner();
+                let interiors = interiors
+                    .into_iter()
+                    .filter(|ring| ring.0.len() >= 4)
+                    .collect();
+                Polygon::new(exterior, interiors)
+            })
+            .collect(),
+    )
+}
+
+pub(crate) fn to_f64(multipoly: &MultiPolygon<f32>) -> MultiPolygon<f64> {
+    multipoly.map_coords(|c| Coord {
+        x: c.x as f64,
+        y: c.y as f64,
+    })
+}
+
+fn smooth_ring(ring: &LineString<f32>, params: &PolygonSmoothingParams) -> LineString<f32> {
+    let mut ring = ring.clone();
+
+    let smoothed = match params.method {
+        PolygonSmoothing::None => ring.clone(),
+        PolygonSmoothing::Chaikin => ring.chaikin_smoothing(params.iterations),
+        PolygonSmoothing::Gaussian => gaussian_smooth_ring(&ring, params.sigma),
+    };
+    if is_valid_ring(&smoothed) {
+        ring = smoothed;
+    }
+
+    if params.simplify_tolerance > 0.0 {
+        let simplified = ring.simplify(&params.simplify_tolerance);
+        if is_valid_ring(&simplified) {
+            ring = simplified;
+        }
+    }
+
+    ring
+}
+
+// Replace each vertex with a Gaussian weighted average of the vertices within
+// 3σ of it along the ring. The ring is densified first so that the kernel
+// sees evenly spread points rather than just the voxel corners. Shared
+// boundaries are densified identically from either side, so neighboring cells
+// smooth them to the same curve.
+fn gaussian_smooth_ring(ring: &LineString<f32>, sigma: f32) -> LineString<f32> {
+    if sigma <= 0.0 || ring.0.len() < 4 {
+        return ring.clone();
+    }
+
+    let dense = ring.densify(sigma / 2.0);
+    // drop the closing coordinate
+    let coords = &dense.0[..dense.0.len() - 1];
+    let n = coords.len();
+
+    let dist = |a: Coord<f32>, b: Coord<f32>| (a.x - b.x).hypot(a.y - b.y);
+    let perimeter: f32 = (0..n).map(|i| dist(coords[i], coords[(i + 1) % n])).sum();
+    let radius = (3.0 * sigma).min(perimeter / 2.0);
+
+    let mut smoothed = Vec::with_capacity(n + 1);
+    for i in 0..n {
+        let mut wsum = 1.0;
+        let mut x = coords[i].x;
+        let mut y = coords[i].y;
+
+        for step in [1, n - 1] {
+            let mut d = 0.0;
+            let mut prev = i;
+            let mut k = (i + step) % n;
+            while k != i {
+                d += dist(coords[prev], coords[k]);
+                if d > radius {
+                    break;
+                }
+                let w = (-0.5 * (d / sigma).powi(2)).exp();
+                wsum += w;
+                x += w * coords[k].x;
+                y += w * coords[k].y;
+                prev = k;
+                k = (k + step) % n;
+            }
+        }
+
+        smoothed.push(Coord {
+            x: x / wsum,
+            y: y / wsum,
+        });
+    }
+    smoothed.push(smoothed[0]);
+
+    LineString::new(smoothed)
+}
+
+fn is_valid_ring(ring: &LineString<f32>) -> bool {
+    ring.0.len() >= 4
+        && ring.is_closed()
+        && Polygon::new(ring.clone(), vec![]).unsigned_area() > 0.0
+        && !ring_self_intersects(ring)
+}
+
+// Check whether any two segments of a closed ring cross or touch, other than
+// consecutive segments at their shared vertex. Segments are swept in order of
+// their minimum x coordinate, so only those with overlapping x extents are
+// compared.
+pub fn ring_self_intersects(ring: &LineString<f32>) -> bool {
+    // repeated vertices are harmless, so zero length segments are skipped
+    let lines: Vec<Line<f32>> = ring.lines().filter(|line| line.start != line.end).collect();
+    let n = lines.len();
+    let xmin = |line: &Line<f32>| line.start.x.min(line.end.x);
+    let xmax = |line: &Line<f32>| line.start.x.max(line.end.x);
+
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_unstable_by(|&a, &b| xmin(&lines[a]).total_cmp(&xmin(&lines[b])));
+
+    for (pos, &a) in order.iter().enumerate() {
+        let a_xmax = xmax(&lines[a]);
+        for &b in order[pos + 1..].iter() {
+            if xmin(&lines[b]) > a_xmax {
+                break;
+            }
+            let adjacent = (a + 1) % n == b || (b + 1) % n == a;
+            match line_intersection(lines[a], lines[b]) {
+                Some(LineIntersection::SinglePoint { .. }) if adjacent => {}
+                Some(_) => return true,
+                None => {}
+            }
+        }
+    }
+
+    false
+}
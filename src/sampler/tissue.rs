@@ -0,0 +1,162 @@
+// Estimate of the region of the slide covered by tissue, as the bins of a
+// coarse grid that contain any transcripts. Its area sets the volume over
+// which the background rates are spread.
+
+use geo::{Coord, LineString, MultiPolygon, Polygon};
+use geo::{Area, Contains};
+use ndarray::Array2;
+use std::collections::HashMap;
+
+use super::transcripts::{coordinate_span, Transcript};
+
+pub struct TissueMask {
+    xmin: f32,
+    ymin: f32,
+    binsize: f32,
+    // [xbins, ybins]
+    occupied: Array2<bool>,
+}
+
+impl TissueMask {
+    // Bins are about twice the width of a typical nucleus, so the mask follows
+    // the tissue without gaps between cells.
+    pub fn estimate(transcripts: &[Transcript], mean_nucleus_area: f32) -> TissueMask {
+        let (xmin, xmax, ymin, ymax, _, _) = coordinate_span(transcripts);
+
+        const SCALE: f32 = 2.0;
+        let binsize = SCALE * mean_nucleus_area.sqrt();
+
+        let xbins = (((xmax - xmin) / binsize).floor() as usize) + 1;
+        let ybins = (((ymax - ymin) / binsize).floor() as usize) + 1;
+
+        let mut occupied = Array2::from_elem((xbins, ybins), false);
+        for transcript in transcripts {
+            let xbin = ((transcript.x - xmin) / binsize).floor() as usize;
+            let ybin = ((transcript.y - ymin) / binsize).floor() as usize;
+            occupied[[xbin.min(xbins - 1), ybin.min(ybins - 1)]] = true;
+        }
+
+        TissueMask {
+            xmin,
+            ymin,
+            binsize,
+            occupied,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        self.occupied.iter().filter(|&&x| x).count() as f32 * self.binsize * self.binsize
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        let i = ((x - self.xmin) / self.binsize).floor();
+        let j = ((y - self.ymin) / self.binsize).floor();
+        if i < 0.0 || j < 0.0 {
+            return false;
+        }
+        self.occupied
+            .get([i as usize, j as usize])
+            .cloned()
+            .unwrap_or(false)
+    }
+
+    fn is_occupied(&self, i: i32, j: i32) -> bool {
+        i >= 0
+            && j >= 0
+            && self
+                .occupied
+                .get([i as usize, j as usize])
+                .cloned()
+                .unwrap_or(false)
+    }
+
+    // Outline of the occupied bins, traced along bin edges.
+    pub fn polygons(&self) -> MultiPolygon<f32> {
+        let (xbins, ybins) = self.occupied.dim();
+
+        // Directed edges between occupied and unoccupied bins, oriented to
+        // keep the occupied bin on the left, so exteriors run counter-clockwise
+        // and holes clockwise.
+        let mut edges: HashMap<(i32, i32), Vec<(i32, i32)>> = HashMap::new();
+        for i in 0..xbins as i32 {
+            for j in 0..ybins as i32 {
+                if !self.is_occupied(i, j) {
+                    continue;
+                }
+                if !self.is_occupied(i, j - 1) {
+                    edges.entry((i, j)).or_default().push((i + 1, j));
+                }
+                if !self.is_occupied(i + 1, j) {
+                    edges.entry((i + 1, j)).or_default().push((i + 1, j + 1));
+                }
+                if !self.is_occupied(i, j + 1) {
+                    edges.entry((i + 1, j + 1)).or_default().push((i, j + 1));
+                }
+                if !self.is_occupied(i - 1, j) {
+                    edges.entry((i, j + 1)).or_default().push((i, j));
+                }
+            }
+        }
+
+        let mut starts: Vec<(i32, i32)> = edges.keys().cloned().collect();
+        starts.sort();
+
+        let mut exteriors = Vec::new();
+        let mut holes = Vec::new();
+        for start in starts {
+            while let Some(mut v) = edges.get_mut(&start).and_then(|next| next.pop()) {
+                let mut ring = vec![start];
+                while v != start {
+                    ring.push(v);
+                    v = edges.get_mut(&v).and_then(|next| next.pop()).unwrap();
+                }
+                ring.push(start);
+
+                let ring = LineString::from(
+                    remove_collinear(ring)
+                        .into_iter()
+                        .map(|(i, j)| Coord {
+                            x: self.xmin + i as f32 * self.binsize,
+                            y: self.ymin + j as f32 * self.binsize,
+                        })
+                        .collect::<Vec<_>>(),
+                );
+                let polygon = Polygon::new(ring, vec![]);
+                if polygon.signed_area() > 0.0 {
+                    exteriors.push(polygon);
+                } else {
+                    holes.push(polygon);
+                }
+            }
+        }
+
+        for hole in holes {
+            let hole = hole.exterior();
+            // the innermost exterior around it, for holes in islands in holes
+            if let Some(exterior) = exteriors
+                .iter_mut()
+                .filter(|exterior| exterior.contains(&hole.0[0]))
+                .min_by(|a, b| a.unsigned_area().total_cmp(&b.unsigned_area()))
+            {
+                exterior.interiors_push(hole.clone());
+            }
+        }
+
+        MultiPolygon::new(exteriors)
+    }
+}
+
+// Drop vertices along straight runs of a closed ring of grid points.
+fn remove_collinear(ring: Vec<(i32, i32)>) -> Vec<(i32, i32)> {
+    let n = ring.len() - 1;
+    let mut simplified = Vec::with_capacity(ring.len());
+    for k in 0..n {
+        let (a, b, c) = (ring[(k + n - 1) % n], ring[k], ring[k + 1]);
+        let cross = (b.0 - a.0) * (c.1 - b.1) - (b.1 - a.1) * (c.0 - b.0);
+        if cross != 0 {
+            simplified.push(b);
+        }
+    }
+    simplified.push(simplified[0]);
+    simplified
+}
@@ -1,13 +1,13 @@
 use csv;
-use flate2::read::GzDecoder;
 use kiddo::SquaredEuclidean;
 use kiddo::float::kdtree::KdTree;
-use ndarray::Array2;
 use std::collections::HashMap;
 use std::fs::File;
+use std::io::{BufRead, BufReader};
 use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ProjectionMask;
 use arrow;
-use itertools::izip;
+use serde::{Deserialize, Serialize};
 use std::str;
 
 pub type CellIndex = u32;
@@ -15,9 +15,13 @@ pub const BACKGROUND_CELL: CellIndex = std::u32::MAX;
 
 
 // Should probably rearrange this...
-use super::super::output::{infer_format_from_filename, OutputFormat};
+use super::super::error::{Error, Result};
+use super::super::output::{infer_format_from_filename, open_decompressed, OutputFormat};
+use super::roi::Roi;
+use super::rowfilter::RowFilter;
+use super::tissue::TissueMask;
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Transcript {
     pub transcript_id: u64,
     pub x: f32,
@@ -27,6 +31,7 @@ pub struct Transcript {
     pub fov: u32,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct TranscriptDataset {
     pub transcript_names: Vec<String>,
     pub transcripts: Vec<Transcript>,
@@ -36,11 +41,55 @@ pub struct TranscriptDataset {
     pub fovs: Vec<u32>,
     pub qvs: Vec<f32>,
     pub fov_names: Vec<String>,
+    // names of the input files transcripts were read from, and [nfovs] the
+    // index into these of the file each fov came from
+    pub sample_names: Vec<String>,
+    pub fov_samples: Vec<u32>,
+    // [ntranscripts] whether each transcript was observed in a nucleus, if known
+    pub nuclear: Vec<bool>,
+    // cell ids as given in the input (or by --nuclei-csv or --init-mask), and
+    // [ntranscripts] the index into these of each transcript's input cell. These
+    // aren't renumbered as cells are dropped, so proseg's cells can be related
+    // back to the input.
+    pub original_cell_ids: Vec<String>,
+    pub original_cell_assignments: Vec<CellIndex>,
+    // source of each transcript's prior cell assignment (e.g. nucleus or
+    // boundary stain segmentation), from --prior-assignment-column, as
+    // [ntranscripts] indexes into the source names. Without the column every
+    // transcript has the one source "".
+    pub prior_source_names: Vec<String>,
+    pub prior_sources: Vec<u32>,
+}
+
+impl TranscriptDataset {
+    // Keep only the transcripts for which `keep` is true, in the same order.
+    // Cells aren't renumbered, so any that may be left empty should be dropped
+    // with `postprocess_cell_assignments` afterwards.
+    pub fn retain(&mut self, keep: &[bool]) {
+        assert_eq!(keep.len(), self.transcripts.len());
+        fn retain<T>(values: &mut Vec<T>, keep: &[bool]) {
+            let mut i = 0;
+            values.retain(|_| {
+                i += 1;
+                keep[i - 1]
+            });
+        }
+        retain(&mut self.transcripts, keep);
+        retain(&mut self.nucleus_assignments, keep);
+        retain(&mut self.cell_assignments, keep);
+        retain(&mut self.fovs, keep);
+        retain(&mut self.qvs, keep);
+        retain(&mut self.nuclear, keep);
+        retain(&mut self.original_cell_assignments, keep);
+        retain(&mut self.prior_sources, keep);
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
 pub fn read_transcripts_csv(
     path: &str,
+    fmt: OutputFormat,
+    delimiter: Option<u8>,
     transcript_column: &str,
     id_column: Option<String>,
     compartment_column: Option<String>,
@@ -48,44 +97,40 @@ pub fn read_transcripts_csv(
     fov_column: Option<String>,
     cell_assignment_column: Option<String>,
     cell_assignment_unassigned: Option<String>,
-    cell_id_column: &str,
-    cell_id_unassigned: &str,
+    cell_id_column: Option<String>,
+    cell_id_unassigned: Option<String>,
     qv_column: Option<String>,
+    prior_assignment_column: Option<String>,
     x_column: &str,
     y_column: &str,
     z_column: &str,
     min_qv: f32,
     ignore_z_column: bool,
     coordinate_scale: f32,
-) -> TranscriptDataset {
-    let fmt = infer_format_from_filename(path);
+    roi: Option<&Roi>,
+    filter: Option<&RowFilter>,
+) -> Result<TranscriptDataset> {
+    let fmt = match fmt {
+        OutputFormat::Infer => infer_format_from_filename(path),
+        _ => fmt,
+    };
 
     match fmt {
-        OutputFormat::Csv => {
-            let mut rdr = csv::Reader::from_path(path).unwrap();
-            read_transcripts_csv_xyz(
-                &mut rdr,
-                transcript_column,
-                id_column,
-                compartment_column,
-                compartment_nuclear,
-                fov_column,
-                cell_assignment_column,
-                cell_assignment_unassigned,
-                cell_id_column,
-                cell_id_unassigned,
-                qv_column,
-                x_column,
-                y_column,
-                z_column,
-                min_qv,
-                ignore_z_column,
-                coordinate_scale,
-            )
-        }
-        OutputFormat::CsvGz => {
-            let mut rdr = csv::Reader::from_reader(GzDecoder::new(File::open(path).unwrap()));
+        OutputFormat::Csv | OutputFormat::CsvGz | OutputFormat::CsvZst => {
+            let io_error = |source| Error::Io {
+                path: path.to_string(),
+                source,
+            };
+            let mut file = BufReader::new(open_decompressed(path).map_err(io_error)?);
+            let delimiter = match delimiter {
+                Some(delimiter) => delimiter,
+                None => sniff_delimiter(&mut file).map_err(io_error)?,
+            };
+            let mut rdr = csv::ReaderBuilder::new()
+                .delimiter(delimiter)
+                .from_reader(file);
             read_transcripts_csv_xyz(
+                path,
                 &mut rdr,
                 transcript_column,
                 id_column,
@@ -97,40 +142,78 @@ pub fn read_transcripts_csv(
                 cell_id_column,
                 cell_id_unassigned,
                 qv_column,
+                prior_assignment_column,
                 x_column,
                 y_column,
                 z_column,
                 min_qv,
                 ignore_z_column,
                 coordinate_scale,
+                roi,
+                filter,
             )
         }
-        OutputFormat::Parquet => read_xenium_transcripts_parquet(
+        OutputFormat::Parquet => read_transcripts_parquet(
             path,
             transcript_column,
-            &id_column.unwrap(),
-            &compartment_column.unwrap(),
-            compartment_nuclear.unwrap().parse::<u8>().unwrap(),
-            &fov_column.unwrap(),
+            id_column,
+            compartment_column,
+            compartment_nuclear,
+            fov_column,
+            cell_assignment_column,
+            cell_assignment_unassigned,
             cell_id_column,
             cell_id_unassigned,
-            &qv_column.unwrap(),
+            qv_column,
+            prior_assignment_column,
             x_column,
             y_column,
             z_column,
             min_qv,
             ignore_z_column,
-            coordinate_scale),
-        OutputFormat::Infer => panic!("Could not infer format of file '{}'", path),
+            coordinate_scale,
+            roi,
+            filter,
+        ),
+        OutputFormat::Infer => Err(Error::UnknownFormat {
+            path: path.to_string(),
+        }),
+        OutputFormat::Mtx => Err(Error::UnsupportedFormat {
+            path: path.to_string(),
+            format: String::from("mtx"),
+        }),
+        OutputFormat::Loom => Err(Error::UnsupportedFormat {
+            path: path.to_string(),
+            format: String::from("loom"),
+        }),
     }
 }
 
-fn find_column(headers: &csv::StringRecord, column: &str) -> usize {
-    let col = headers.iter().position(|x| x == column);
-    match col {
-        Some(col) => col,
-        None => panic!("Column '{}' not found in CSV file", column),
-    }
+pub(crate) fn find_column(path: &str, headers: &csv::StringRecord, column: &str) -> Result<usize> {
+    headers
+        .iter()
+        .position(|x| x == column)
+        .ok_or_else(|| Error::MissingColumn {
+            path: path.to_string(),
+            column: column.to_string(),
+        })
+}
+
+// Parse a field from a CSV row, reporting the line, column, and value on failure.
+pub(crate) fn parse_field<F: str::FromStr>(
+    path: &str,
+    headers: &csv::StringRecord,
+    row: &csv::StringRecord,
+    col: usize,
+    expected: &'static str,
+) -> Result<F> {
+    row[col].parse::<F>().map_err(|_| Error::InvalidValue {
+        path: path.to_string(),
+        row: row.position().map_or(0, |pos| pos.line()),
+        column: headers[col].to_string(),
+        value: row[col].to_string(),
+        expected,
+    })
 }
 
 fn find_optional_column(headers: &csv::StringRecord, column: &Option<String>) -> Option<usize> {
@@ -141,7 +224,7 @@ fn find_optional_column(headers: &csv::StringRecord, column: &Option<String>) ->
     }
 }
 
-fn postprocess_cell_assignments(
+pub(crate) fn postprocess_cell_assignments(
     nucleus_assignments: &mut [CellIndex],
     cell_assignments: &mut [CellIndex],
 ) -> Vec<usize> {
@@ -184,8 +267,23 @@ fn postprocess_cell_assignments(
     nucleus_population
 }
 
+// Guess the delimiter of a delimited text file from its header: whichever of
+// comma, tab, or semicolon occurs most, defaulting to comma.
+fn sniff_delimiter<R: BufRead>(reader: &mut R) -> std::io::Result<u8> {
+    let buf = reader.fill_buf()?;
+    let header = buf.split(|&c| c == b'\n').next().unwrap_or(buf);
+    let count = |delimiter: u8| header.iter().filter(|&&c| c == delimiter).count();
+    Ok([b',', b'\t', b';']
+        .into_iter()
+        .rev()
+        .max_by_key(|&delimiter| count(delimiter))
+        .filter(|&delimiter| count(delimiter) > 0)
+        .unwrap_or(b','))
+}
+
 #[allow(clippy::too_many_arguments)]
 fn read_transcripts_csv_xyz<T>(
+    path: &str,
     rdr: &mut csv::Reader<T>,
 
     transcript_column: &str,
@@ -195,42 +293,89 @@ fn read_transcripts_csv_xyz<T>(
     fov_column: Option<String>,
     cell_assignment_column: Option<String>,
     cell_assignment_unassigned: Option<String>,
-    cell_id_column: &str,
-    cell_id_unassigned: &str,
+    cell_id_column: Option<String>,
+    cell_id_unassigned: Option<String>,
     qv_column: Option<String>,
+    prior_assignment_column: Option<String>,
     x_column: &str,
     y_column: &str,
     z_column: &str,
     min_qv: f32,
     ignore_z_column: bool,
     coordinate_scale: f32,
-) -> TranscriptDataset
+    roi: Option<&Roi>,
+    filter: Option<&RowFilter>,
+) -> Result<TranscriptDataset>
 where
     T: std::io::Read,
 {
+    let csv_error = |source| Error::Csv {
+        path: path.to_string(),
+        source,
+    };
+
     // Find the column we need
-    let headers = rdr.headers().unwrap();
-    let transcript_col = find_column(headers, transcript_column);
-    let x_col = find_column(headers, x_column);
-    let y_col = find_column(headers, y_column);
-    let z_col = find_column(headers, z_column);
-    let id_col = id_column.map(|id_column| find_column(headers, &id_column));
-
-    let cell_id_col = find_column(headers, cell_id_column);
-    let compartment_col =
-        compartment_column.map(|compartment_column| find_column(headers, &compartment_column));
+    let headers = rdr.headers().map_err(csv_error)?.clone();
+    let headers = &headers;
+    let transcript_col = find_column(path, headers, transcript_column)?;
+    let x_col = find_column(path, headers, x_column)?;
+    let y_col = find_column(path, headers, y_column)?;
+    let z_col = find_column(path, headers, z_column)?;
+    let id_col = id_column
+        .map(|id_column| find_column(path, headers, &id_column))
+        .transpose()?;
+
+    // Cell ids and compartments are used only for initialization, so if they
+    // are missing we warn and fall back rather than fail.
+    let cell_id_col = find_optional_column(headers, &cell_id_column);
+    if let (Some(cell_id_column), None) = (&cell_id_column, cell_id_col) {
+        println!(
+            "WARNING: Cell ID column '{}' not found. Transcripts will be initially unassigned.",
+            cell_id_column
+        );
+    }
+    let cell_id_unassigned = cell_id_unassigned.unwrap_or_default();
+
+    let compartment_col = find_optional_column(headers, &compartment_column);
+    if let (Some(compartment_column), None) = (&compartment_column, compartment_col) {
+        println!(
+            "WARNING: Compartment column '{}' not found. Using all cell assigned transcripts for initialization.",
+            compartment_column
+        );
+    }
     let has_compartment = compartment_col.is_some();
 
-    let compartment_nuclear = if has_compartment {
-        compartment_nuclear.unwrap()
-    } else {
-        String::new()
+    let compartment_nuclear = match (has_compartment, compartment_nuclear) {
+        (false, _) => String::new(),
+        (true, Some(compartment_nuclear)) => compartment_nuclear,
+        (true, None) => {
+            return Err(Error::MissingNuclearValue {
+                path: path.to_string(),
+                column: compartment_column.unwrap_or_default(),
+            })
+        }
     };
 
     let qv_col = find_optional_column(headers, &qv_column);
+    let prior_source_col = find_optional_column(headers, &prior_assignment_column);
+    if let (Some(prior_assignment_column), None) = (&prior_assignment_column, prior_source_col) {
+        println!(
+            "WARNING: Prior assignment column '{}' not found. Using --prior-seg-reassignment-prob for every transcript.",
+            prior_assignment_column
+        );
+    }
     let fov_col = find_optional_column(headers, &fov_column);
     let cell_assignment_col = find_optional_column(headers, &cell_assignment_column);
     let cell_assignment_unassigned = cell_assignment_unassigned.unwrap_or(String::from(""));
+    let filter_cols = filter
+        .map(|filter| {
+            filter
+                .columns()
+                .iter()
+                .map(|column| find_column(path, headers, column))
+                .collect::<Result<Vec<_>>>()
+        })
+        .transpose()?;
 
     let mut transcripts = Vec::new();
     let mut transcript_name_map: HashMap<String, usize> = HashMap::new();
@@ -239,15 +384,24 @@ where
     let mut cell_assignments = Vec::new();
     let mut qvs = Vec::new();
     let mut fovs = Vec::new();
+    let mut nuclear = Vec::new();
+    let mut prior_sources = Vec::new();
 
     let mut fov_map: HashMap<String, u32> = HashMap::new();
+    let mut prior_source_map: HashMap<String, u32> = HashMap::new();
     let mut cell_id_map: HashMap<(u32, String), CellIndex> = HashMap::new();
 
-    for result in rdr.records() {
-        let row = result.unwrap();
+    // Reuse one record rather than allocating for every row.
+    let mut row = csv::StringRecord::new();
+    while rdr.read_record(&mut row).map_err(csv_error)? {
+        if let (Some(filter), Some(filter_cols)) = (filter, &filter_cols) {
+            if !filter.eval(|k| &row[filter_cols[k]]) {
+                continue;
+            }
+        }
 
         let qv = if let Some(qv_col) = qv_col {
-            row[qv_col].parse::<f32>().unwrap()
+            parse_field::<f32>(path, headers, &row, qv_col, "a number")?
         } else {
             f32::INFINITY
         };
@@ -279,13 +433,14 @@ where
             transcript_names.len() - 1
         };
 
-        let x = coordinate_scale * row[x_col].parse::<f32>().unwrap();
-        let y = coordinate_scale * row[y_col].parse::<f32>().unwrap();
-        let z = row[z_col].parse::<f32>().unwrap();
+        let x = coordinate_scale * parse_field::<f32>(path, headers, &row, x_col, "a number")?;
+        let y = coordinate_scale * parse_field::<f32>(path, headers, &row, y_col, "a number")?;
+        if roi.is_some_and(|roi| !roi.contains(x, y)) {
+            continue;
+        }
+        let z = parse_field::<f32>(path, headers, &row, z_col, "a number")?;
         let transcript_id = if let Some(id_col) = id_col {
-            row[id_col]
-                .parse::<u64>()
-                .unwrap_or_else(|_| panic!("Transcript ID must be an integer: {}", &row[id_col]))
+            parse_field::<u64>(path, headers, &row, id_col, "an integer transcript ID")?
         } else {
             transcripts.len() as u64
         };
@@ -301,6 +456,12 @@ where
 
         qvs.push(qv);
         fovs.push(fov);
+        nuclear.push(
+            compartment_col
+                .is_some_and(|compartment_col| row[compartment_col] == compartment_nuclear),
+        );
+        let prior_source = prior_source_col.map_or("", |prior_source_col| &row[prior_source_col]);
+        prior_sources.push(prior_source_index(&mut prior_source_map, prior_source));
 
         if let Some(cell_assignment_col) = cell_assignment_col {
             if row[cell_assignment_col] == cell_assignment_unassigned {
@@ -310,7 +471,11 @@ where
             }
         };
 
-        let cell_id_str = &row[cell_id_col];
+        let cell_id_str = if let Some(cell_id_col) = cell_id_col {
+            &row[cell_id_col]
+        } else {
+            cell_id_unassigned.as_str()
+        };
         // let overlaps_nucleus = row[overlaps_nucleus_col].parse::<i32>().unwrap();
 
         // Earlier version of Xenium used numeric cell ids and -1 for unassigned.
@@ -360,10 +525,16 @@ where
         }
     }
 
+    let mut original_cell_ids = vec![String::new(); cell_id_map.len()];
+    for ((_, cell_id), cell) in cell_id_map {
+        original_cell_ids[cell as usize] = cell_id;
+    }
+    let original_cell_assignments = cell_assignments.clone();
+
     let nucleus_population =
         postprocess_cell_assignments(&mut nucleus_assignments, &mut cell_assignments);
 
-    TranscriptDataset {
+    Ok(TranscriptDataset {
         transcript_names,
         transcripts,
         nucleus_assignments,
@@ -371,141 +542,304 @@ where
         nucleus_population,
         qvs,
         fovs,
+        sample_names: vec![path.to_string()],
+        fov_samples: vec![0; fov_names.len()],
         fov_names,
+        nuclear,
+        original_cell_ids,
+        original_cell_assignments,
+        prior_source_names: prior_source_names(prior_source_map),
+        prior_sources,
+    })
+}
+
+
+// Index of a prior assignment source, numbered in order of appearance.
+fn prior_source_index(prior_source_map: &mut HashMap<String, u32>, prior_source: &str) -> u32 {
+    match prior_source_map.get(prior_source) {
+        Some(&index) => index,
+        None => {
+            let index = prior_source_map.len() as u32;
+            prior_source_map.insert(prior_source.to_string(), index);
+            index
+        }
+    }
+}
+
+fn prior_source_names(prior_source_map: HashMap<String, u32>) -> Vec<String> {
+    let mut names = vec![String::new(); prior_source_map.len()];
+    for (name, index) in prior_source_map {
+        names[index as usize] = name;
     }
+    names
 }
 
+// Read a parquet column, casting to the given arrow type. This lets us accept
+// e.g. float64 coordinates or integer cell ids without special casing every
+// platform's choice of types.
+pub(crate) fn parquet_column<T>(
+    path: &str,
+    rec_batch: &arrow::record_batch::RecordBatch,
+    idx: usize,
+    data_type: &arrow::datatypes::DataType,
+) -> Result<T>
+where
+    T: arrow::array::Array + Clone + 'static,
+{
+    let col = arrow::compute::cast(rec_batch.column(idx), data_type).map_err(|_| {
+        Error::ColumnType {
+            path: path.to_string(),
+            column: rec_batch.schema().field(idx).name().clone(),
+            data_type: data_type.to_string(),
+        }
+    })?;
+    Ok(col.as_any().downcast_ref::<T>().unwrap().clone())
+}
+
+pub(crate) fn find_parquet_column(path: &str, schema: &arrow::datatypes::Schema, column: &str) -> Result<usize> {
+    schema.index_of(column).map_err(|_| Error::MissingColumn {
+        path: path.to_string(),
+        column: column.to_string(),
+    })
+}
+
+fn find_optional_parquet_column(
+    schema: &arrow::datatypes::Schema,
+    column: &Option<String>,
+) -> Option<usize> {
+    if let Some(column) = column {
+        schema.index_of(column).ok()
+    } else {
+        None
+    }
+}
 
 #[allow(clippy::too_many_arguments)]
-fn read_xenium_transcripts_parquet(
+fn read_transcripts_parquet(
     filename: &str,
-    transcript_col_name: &str,
-    id_col_name: &str,
-    compartment_col_name: &str,
-    compartment_nuclear: u8,
-    fov_col_name: &str,
-    cell_id_col_name: &str,
-    cell_id_unassigned: &str,
-    qv_col_name: &str,
-    x_col_name: &str,
-    y_col_name: &str,
-    z_col_name: &str,
+    transcript_column: &str,
+    id_column: Option<String>,
+    compartment_column: Option<String>,
+    compartment_nuclear: Option<String>,
+    fov_column: Option<String>,
+    cell_assignment_column: Option<String>,
+    cell_assignment_unassigned: Option<String>,
+    cell_id_column: Option<String>,
+    cell_id_unassigned: Option<String>,
+    qv_column: Option<String>,
+    prior_assignment_column: Option<String>,
+    x_column: &str,
+    y_column: &str,
+    z_column: &str,
     min_qv: f32,
     ignore_z_column: bool,
     coordinate_scale: f32,
-) -> TranscriptDataset
+    roi: Option<&Roi>,
+    filter: Option<&RowFilter>,
+) -> Result<TranscriptDataset>
 {
-    let input_file = File::open(&filename).expect(&format!("Unable to open '{}'.", &filename));
-    let builder = ParquetRecordBatchReaderBuilder::try_new(input_file)
-        .unwrap();
+    let parquet_error = |source| Error::Parquet {
+        path: filename.to_string(),
+        source,
+    };
+
+    let input_file = File::open(filename).map_err(|source| Error::Io {
+        path: filename.to_string(),
+        source,
+    })?;
+    let builder = ParquetRecordBatchReaderBuilder::try_new(input_file).map_err(parquet_error)?;
     let schema = builder.schema().as_ref().clone();
-    let rdr = builder.build()
-        .expect(&format!("Unable to read parquet data from frobm {}", filename));
-
-    let transcript_col_idx = schema.index_of(transcript_col_name).unwrap();
-    let id_col_idx = schema.index_of(id_col_name).unwrap();
-    let compartment_col_idx = schema.index_of(compartment_col_name).unwrap();
-    let cell_id_col_idx = schema.index_of(cell_id_col_name).unwrap();
-    let fov_col_idx = schema.index_of(fov_col_name).unwrap();
-    let x_col_idx = schema.index_of(x_col_name).unwrap();
-    let y_col_idx = schema.index_of(y_col_name).unwrap();
-    let z_col_idx = schema.index_of(z_col_name).unwrap();
-    let qv_col_idx = schema.index_of(qv_col_name).unwrap();
+    let nrows = builder.metadata().file_metadata().num_rows().max(0) as usize;
+
+    let transcript_col_idx = find_parquet_column(filename, &schema, transcript_column)?;
+    let x_col_idx = find_parquet_column(filename, &schema, x_column)?;
+    let y_col_idx = find_parquet_column(filename, &schema, y_column)?;
+    let z_col_idx = find_parquet_column(filename, &schema, z_column)?;
+    let id_col_idx = id_column
+        .map(|id_column| find_parquet_column(filename, &schema, &id_column))
+        .transpose()?;
+    let cell_id_col_idx = find_optional_parquet_column(&schema, &cell_id_column);
+    if let (Some(cell_id_column), None) = (&cell_id_column, cell_id_col_idx) {
+        println!(
+            "WARNING: Cell ID column '{}' not found. Transcripts will be initially unassigned.",
+            cell_id_column
+        );
+    }
+    let cell_id_unassigned = cell_id_unassigned.unwrap_or_default();
+
+    let compartment_col_idx = find_optional_parquet_column(&schema, &compartment_column);
+    if let (Some(compartment_column), None) = (&compartment_column, compartment_col_idx) {
+        println!(
+            "WARNING: Compartment column '{}' not found. Using all cell assigned transcripts for initialization.",
+            compartment_column
+        );
+    }
+    let compartment_nuclear = match (compartment_col_idx, compartment_nuclear) {
+        (None, _) => String::new(),
+        (Some(_), Some(compartment_nuclear)) => compartment_nuclear,
+        (Some(_), None) => {
+            return Err(Error::MissingNuclearValue {
+                path: filename.to_string(),
+                column: compartment_column.unwrap_or_default(),
+            })
+        }
+    };
 
-    let mut transcripts = Vec::new();
+    let qv_col_idx = find_optional_parquet_column(&schema, &qv_column);
+    let prior_source_col_idx = find_optional_parquet_column(&schema, &prior_assignment_column);
+    if let (Some(prior_assignment_column), None) = (&prior_assignment_column, prior_source_col_idx) {
+        println!(
+            "WARNING: Prior assignment column '{}' not found. Using --prior-seg-reassignment-prob for every transcript.",
+            prior_assignment_column
+        );
+    }
+    let fov_col_idx = find_optional_parquet_column(&schema, &fov_column);
+    let cell_assignment_col_idx = find_optional_parquet_column(&schema, &cell_assignment_column);
+    let cell_assignment_unassigned = cell_assignment_unassigned.unwrap_or(String::from(""));
+    let filter_col_idxs = filter.map_or(Ok(Vec::new()), |filter| {
+        filter
+            .columns()
+            .iter()
+            .map(|column| find_parquet_column(filename, &schema, column))
+            .collect::<Result<Vec<_>>>()
+    })?;
+
+    // Only decode the columns we use. Transcript tables often carry many more
+    // (codewords, nucleus distances, etc), which would otherwise all be read
+    // into memory.
+    let mut used_cols: Vec<usize> = [
+        Some(transcript_col_idx),
+        Some(x_col_idx),
+        Some(y_col_idx),
+        Some(z_col_idx),
+        id_col_idx,
+        cell_id_col_idx,
+        compartment_col_idx,
+        qv_col_idx,
+        prior_source_col_idx,
+        fov_col_idx,
+        cell_assignment_col_idx,
+    ]
+    .into_iter()
+    .flatten()
+    .chain(filter_col_idxs.iter().cloned())
+    .collect();
+    used_cols.sort_unstable();
+    used_cols.dedup();
+    let projection = ProjectionMask::roots(builder.parquet_schema(), used_cols.iter().cloned());
+    let rdr = builder.with_projection(projection).build().map_err(parquet_error)?;
+
+    // Index of a column of the file in the projected record batches.
+    let projected = |idx: usize| used_cols.binary_search(&idx).unwrap();
+    let transcript_col_idx = projected(transcript_col_idx);
+    let x_col_idx = projected(x_col_idx);
+    let y_col_idx = projected(y_col_idx);
+    let z_col_idx = projected(z_col_idx);
+    let id_col_idx = id_col_idx.map(projected);
+    let cell_id_col_idx = cell_id_col_idx.map(projected);
+    let compartment_col_idx = compartment_col_idx.map(projected);
+    let qv_col_idx = qv_col_idx.map(projected);
+    let prior_source_col_idx = prior_source_col_idx.map(projected);
+    let fov_col_idx = fov_col_idx.map(projected);
+    let cell_assignment_col_idx = cell_assignment_col_idx.map(projected);
+    let filter_col_idxs: Vec<usize> = filter_col_idxs.into_iter().map(projected).collect();
+
+    // The row count is known up front, so allocate once rather than growing.
+    let mut transcripts = Vec::with_capacity(nrows);
     let mut transcript_name_map: HashMap<String, usize> = HashMap::new();
     let mut transcript_names = Vec::new();
-    let mut nucleus_assignments = Vec::new();
-    let mut cell_assignments = Vec::new();
-    let mut qvs = Vec::new();
-    let mut fovs = Vec::new();
+    let mut nucleus_assignments = Vec::with_capacity(nrows);
+    let mut cell_assignments = Vec::with_capacity(nrows);
+    let mut qvs = Vec::with_capacity(nrows);
+    let mut fovs = Vec::with_capacity(nrows);
+    let mut nuclear = Vec::with_capacity(nrows);
+    let mut prior_sources = Vec::with_capacity(nrows);
 
     let mut fov_map: HashMap<String, u32> = HashMap::new();
+    let mut prior_source_map: HashMap<String, u32> = HashMap::new();
     let mut cell_id_map: HashMap<(u32, String), CellIndex> = HashMap::new();
 
+    use arrow::array::{Array, Float32Array, StringArray, UInt64Array};
+    use arrow::datatypes::DataType;
+
     for rec_batch in rdr {
-        let rec_batch = rec_batch.expect("Unable to read record batch.");
-
-        let transcript_col = rec_batch
-            .column(transcript_col_idx)
-            .as_any()
-            .downcast_ref::<arrow::array::StringArray>()
-            .unwrap();
-
-        let id_col = rec_batch
-            .column(id_col_idx)
-            .as_any()
-            .downcast_ref::<arrow::array::UInt64Array>()
-            .unwrap();
-
-        let compartment_col = rec_batch
-            .column(compartment_col_idx)
-            .as_any()
-            .downcast_ref::<arrow::array::UInt8Array>()
-            .unwrap();
-
-        let cell_id_col = rec_batch
-            .column(cell_id_col_idx)
-            .as_any()
-            .downcast_ref::<arrow::array::StringArray>()
-            .unwrap();
-
-        let fov_col = rec_batch
-            .column(fov_col_idx)
-            .as_any()
-            .downcast_ref::<arrow::array::StringArray>()
-            .unwrap();
-
-        let x_col = rec_batch
-            .column(x_col_idx)
-            .as_any()
-            .downcast_ref::<arrow::array::Float32Array>()
-            .unwrap();
-
-        let y_col = rec_batch
-            .column(y_col_idx)
-            .as_any()
-            .downcast_ref::<arrow::array::Float32Array>()
-            .unwrap();
-
-        let z_col = rec_batch
-            .column(z_col_idx)
-            .as_any()
-            .downcast_ref::<arrow::array::Float32Array>()
-            .unwrap();
-
-        let qv_col = rec_batch
-            .column(qv_col_idx)
-            .as_any()
-            .downcast_ref::<arrow::array::Float32Array>()
-            .unwrap();
-
-        for (transcript, id, compartment, cell_id, fov, x, y, z, qv) in
-            izip!(transcript_col, id_col, compartment_col, cell_id_col, fov_col, x_col, y_col, z_col, qv_col)
-        {
-            let transcript = transcript.unwrap();
-            let transcript_id = id.unwrap();
-            let compartment = compartment.unwrap();
-            let cell_id = cell_id.unwrap();
-            let fov = fov.unwrap();
-            let x = x.unwrap();
-            let y = y.unwrap();
-            let z = z.unwrap();
-            let qv = qv.unwrap();
+        let rec_batch = rec_batch.map_err(|source| Error::Arrow {
+            path: filename.to_string(),
+            source,
+        })?;
+
+        let transcript_col: StringArray =
+            parquet_column(filename, &rec_batch, transcript_col_idx, &DataType::Utf8)?;
+        let x_col: Float32Array = parquet_column(filename, &rec_batch, x_col_idx, &DataType::Float32)?;
+        let y_col: Float32Array = parquet_column(filename, &rec_batch, y_col_idx, &DataType::Float32)?;
+        let z_col: Float32Array = parquet_column(filename, &rec_batch, z_col_idx, &DataType::Float32)?;
+        let cell_id_col: Option<StringArray> = cell_id_col_idx
+            .map(|idx| parquet_column(filename, &rec_batch, idx, &DataType::Utf8))
+            .transpose()?;
+        let id_col: Option<UInt64Array> = id_col_idx
+            .map(|idx| parquet_column(filename, &rec_batch, idx, &DataType::UInt64))
+            .transpose()?;
+        let compartment_col: Option<StringArray> = compartment_col_idx
+            .map(|idx| parquet_column(filename, &rec_batch, idx, &DataType::Utf8))
+            .transpose()?;
+        let qv_col: Option<Float32Array> = qv_col_idx
+            .map(|idx| parquet_column(filename, &rec_batch, idx, &DataType::Float32))
+            .transpose()?;
+        let fov_col: Option<StringArray> = fov_col_idx
+            .map(|idx| parquet_column(filename, &rec_batch, idx, &DataType::Utf8))
+            .transpose()?;
+        let prior_source_col: Option<StringArray> = prior_source_col_idx
+            .map(|idx| parquet_column(filename, &rec_batch, idx, &DataType::Utf8))
+            .transpose()?;
+        let cell_assignment_col: Option<StringArray> = cell_assignment_col_idx
+            .map(|idx| parquet_column(filename, &rec_batch, idx, &DataType::Utf8))
+            .transpose()?;
+        // Filter columns are compared as strings, so any type that casts to one will do.
+        let filter_cols = filter_col_idxs
+            .iter()
+            .map(|&idx| parquet_column::<StringArray>(filename, &rec_batch, idx, &DataType::Utf8))
+            .collect::<Result<Vec<_>>>()?;
+
+        for i in 0..rec_batch.num_rows() {
+            if let Some(filter) = filter {
+                let value = |k: usize| {
+                    let col: &StringArray = &filter_cols[k];
+                    if col.is_null(i) {
+                        ""
+                    } else {
+                        col.value(i)
+                    }
+                };
+                if !filter.eval(value) {
+                    continue;
+                }
+            }
+
+            let qv = if let Some(qv_col) = &qv_col {
+                qv_col.value(i)
+            } else {
+                f32::INFINITY
+            };
 
             if qv < min_qv {
                 continue;
             }
 
-            let fov = match fov_map.get(fov) {
-                Some(fov) => *fov,
-                None => {
-                    let next_fov = fov_map.len();
-                    fov_map.insert(fov.to_string(), next_fov as u32);
-                    next_fov as u32
+            let fov = if let Some(fov_col) = &fov_col {
+                let fov = fov_col.value(i);
+                match fov_map.get(fov) {
+                    Some(fov) => *fov,
+                    None => {
+                        let next_fov = fov_map.len();
+                        fov_map.insert(fov.to_string(), next_fov as u32);
+                        next_fov as u32
+                    }
                 }
+            } else {
+                0
             };
 
+            let transcript = transcript_col.value(i);
             let gene = if let Some(gene) = transcript_name_map.get(transcript) {
                 *gene
             } else {
@@ -514,8 +848,17 @@ fn read_xenium_transcripts_parquet(
                 transcript_names.len() - 1
             };
 
-            let x = coordinate_scale * x;
-            let y = coordinate_scale * y;
+            let x = coordinate_scale * x_col.value(i);
+            let y = coordinate_scale * y_col.value(i);
+            if roi.is_some_and(|roi| !roi.contains(x, y)) {
+                continue;
+            }
+            let z = z_col.value(i);
+            let transcript_id = if let Some(id_col) = &id_col {
+                id_col.value(i)
+            } else {
+                transcripts.len() as u64
+            };
 
             transcripts.push(Transcript {
                 transcript_id,
@@ -528,7 +871,29 @@ fn read_xenium_transcripts_parquet(
 
             qvs.push(qv);
             fovs.push(fov);
+            nuclear.push(
+                compartment_col
+                    .as_ref()
+                    .is_some_and(|compartment_col| compartment_col.value(i) == compartment_nuclear),
+            );
+            let prior_source = match &prior_source_col {
+                Some(prior_source_col) if !prior_source_col.is_null(i) => prior_source_col.value(i),
+                _ => "",
+            };
+            prior_sources.push(prior_source_index(&mut prior_source_map, prior_source));
+
+            if let Some(cell_assignment_col) = &cell_assignment_col {
+                if cell_assignment_col.value(i) == cell_assignment_unassigned {
+                    nucleus_assignments.push(BACKGROUND_CELL);
+                    cell_assignments.push(BACKGROUND_CELL);
+                    continue;
+                }
+            }
 
+            let cell_id = match &cell_id_col {
+                Some(cell_id_col) if !cell_id_col.is_null(i) => cell_id_col.value(i),
+                _ => cell_id_unassigned.as_str(),
+            };
             if cell_id == cell_id_unassigned {
                 nucleus_assignments.push(BACKGROUND_CELL);
                 cell_assignments.push(BACKGROUND_CELL);
@@ -538,7 +903,11 @@ fn read_xenium_transcripts_parquet(
                     .entry((fov, cell_id.to_string()))
                     .or_insert_with(|| next_cell_id);
 
-                let is_nuclear = compartment == compartment_nuclear;
+                let is_nuclear = if let Some(compartment_col) = &compartment_col {
+                    compartment_col.value(i) == compartment_nuclear
+                } else {
+                    true
+                };
 
                 if is_nuclear {
                     nucleus_assignments.push(cell_id);
@@ -559,10 +928,16 @@ fn read_xenium_transcripts_parquet(
         }
     }
 
+    let mut original_cell_ids = vec![String::new(); cell_id_map.len()];
+    for ((_, cell_id), cell) in cell_id_map {
+        original_cell_ids[cell as usize] = cell_id;
+    }
+    let original_cell_assignments = cell_assignments.clone();
+
     let nucleus_population =
         postprocess_cell_assignments(&mut nucleus_assignments, &mut cell_assignments);
 
-    TranscriptDataset {
+    Ok(TranscriptDataset {
         transcript_names,
         transcripts,
         nucleus_assignments,
@@ -570,8 +945,104 @@ fn read_xenium_transcripts_parquet(
         nucleus_population,
         qvs,
         fovs,
+        sample_names: vec![filename.to_string()],
+        fov_samples: vec![0; fov_names.len()],
         fov_names,
+        nuclear,
+        original_cell_ids,
+        original_cell_assignments,
+        prior_source_names: prior_source_names(prior_source_map),
+        prior_sources,
+    })
+}
+
+// Read nucleus centroids (e.g. from a DAPI based segmentation) from a csv file,
+// which may be gzip or zstd compressed.
+pub fn read_nuclei_csv(
+    path: &str,
+    x_column: &str,
+    y_column: &str,
+    coordinate_scale: f32,
+) -> Result<Vec<(f32, f32)>> {
+    let file = open_decompressed(path).map_err(|source| Error::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    let mut rdr = csv::Reader::from_reader(file);
+    read_nuclei_csv_xy(path, &mut rdr, x_column, y_column, coordinate_scale)
+}
+
+fn read_nuclei_csv_xy<T>(
+    path: &str,
+    rdr: &mut csv::Reader<T>,
+    x_column: &str,
+    y_column: &str,
+    coordinate_scale: f32,
+) -> Result<Vec<(f32, f32)>>
+where
+    T: std::io::Read,
+{
+    let csv_error = |source| Error::Csv {
+        path: path.to_string(),
+        source,
+    };
+
+    let headers = rdr.headers().map_err(csv_error)?.clone();
+    let headers = &headers;
+    let x_col = find_column(path, headers, x_column)?;
+    let y_col = find_column(path, headers, y_column)?;
+
+    let mut centroids = Vec::new();
+    for result in rdr.records() {
+        let row = result.map_err(csv_error)?;
+        let x = coordinate_scale * parse_field::<f32>(path, headers, &row, x_col, "a number")?;
+        let y = coordinate_scale * parse_field::<f32>(path, headers, &row, y_col, "a number")?;
+        centroids.push((x, y));
     }
+
+    Ok(centroids)
+}
+
+// Replace any initial cell assignments by assigning every transcript within
+// `radius` of a nucleus centroid to that nucleus. Nuclei with no transcripts
+// nearby are dropped.
+pub fn assign_transcripts_to_nuclei(
+    dataset: &mut TranscriptDataset,
+    centroids: &[(f32, f32)],
+    radius: f32,
+) {
+    let radius_squared = radius * radius;
+
+    let mut kdtree: KdTree<f32, u32, 2, 32, u32> = KdTree::with_capacity(centroids.len());
+    for (i, (x, y)) in centroids.iter().enumerate() {
+        kdtree.add(&[*x, *y], i as u32);
+    }
+
+    for (i, t) in dataset.transcripts.iter().enumerate() {
+        if centroids.is_empty() {
+            dataset.nucleus_assignments[i] = BACKGROUND_CELL;
+            dataset.cell_assignments[i] = BACKGROUND_CELL;
+            continue;
+        }
+
+        let nearest = kdtree.nearest_one::<SquaredEuclidean>(&[t.x, t.y]);
+        let cell = if nearest.distance <= radius_squared {
+            nearest.item
+        } else {
+            BACKGROUND_CELL
+        };
+        dataset.nucleus_assignments[i] = cell;
+        dataset.cell_assignments[i] = cell;
+    }
+    dataset.original_cell_ids = (0..centroids.len()).map(|i| i.to_string()).collect();
+    dataset
+        .original_cell_assignments
+        .clone_from(&dataset.cell_assignments);
+
+    dataset.nucleus_population = postprocess_cell_assignments(
+        &mut dataset.nucleus_assignments,
+        &mut dataset.cell_assignments,
+    );
 }
 
 // pub fn normalize_z_coord(transcripts: &mut Vec<Transcript>, fovs: Vec<u32>) {
@@ -605,7 +1076,7 @@ fn read_xenium_transcripts_parquet(
 //     }
 // }
 
-pub fn coordinate_span(transcripts: &Vec<Transcript>) -> (f32, f32, f32, f32, f32, f32) {
+pub fn coordinate_span(transcripts: &[Transcript]) -> (f32, f32, f32, f32, f32, f32) {
     let mut min_x = std::f32::MAX;
     let mut max_x = std::f32::MIN;
     let mut min_y = std::f32::MAX;
@@ -626,25 +1097,8 @@ pub fn coordinate_span(transcripts: &Vec<Transcript>) -> (f32, f32, f32, f32, f3
 }
 
 // Estimate what region of the slide to model by counting the number of occupied bins.
-pub fn estimate_full_area(transcripts: &Vec<Transcript>, mean_nucleus_area: f32) -> f32 {
-    let (xmin, xmax, ymin, ymax, _, _) = coordinate_span(transcripts);
-
-    const SCALE: f32 = 2.0;
-    let binsize = SCALE * mean_nucleus_area.sqrt();
-
-    let xbins = ((xmax - xmin) / binsize).ceil() as usize;
-    let ybins = ((ymax - ymin) / binsize).ceil() as usize;
-
-    let mut occupied = Array2::from_elem((xbins, ybins), false);
-
-    for transcript in transcripts {
-        let xbin = ((transcript.x - xmin) / binsize).floor() as usize;
-        let ybin = ((transcript.y - ymin) / binsize).floor() as usize;
-
-        occupied[[xbin, ybin]] = true;
-    }
-
-    occupied.iter().filter(|&&x| x).count() as f32 * binsize * binsize
+pub fn estimate_full_area(transcripts: &[Transcript], mean_nucleus_area: f32) -> f32 {
+    TissueMask::estimate(transcripts, mean_nucleus_area).area()
 }
 
 // pub fn estimate_cell_fovs(
@@ -691,6 +1145,8 @@ pub fn estimate_cell_centroids(
     centroids
 }
 
+// Drop transcripts further than `max_distance` from every cell's estimated
+// centroid, returning the indices of the transcripts kept.
 pub fn filter_cellfree_transcripts(
     // transcripts: &[Transcript],
     // nucleus_assignments: &[CellIndex],
@@ -698,7 +1154,7 @@ pub fn filter_cellfree_transcripts(
     dataset: &mut TranscriptDataset,
     ncells: usize,
     max_distance: f32,
-) {
+) -> Vec<usize> {
     let max_distance_squared = max_distance * max_distance;
 
     let centroids = estimate_cell_centroids(
@@ -721,48 +1177,164 @@ pub fn filter_cellfree_transcripts(
         }
     }
 
-    dataset.transcripts.clone_from(
-        &dataset.transcripts
-            .iter()
-            .zip(mask.iter())
-            .filter(|(_, &m)| m)
-            .map(|(t, _)| t)
-            .cloned()
-            .collect::<Vec<_>>());
-
-    dataset.nucleus_assignments.clone_from(
-        &dataset.nucleus_assignments
-            .iter()
-            .zip(mask.iter())
-            .filter(|(_, &m)| m)
-            .map(|(t, _)| t)
-            .cloned()
-            .collect::<Vec<_>>());
-
-    dataset.cell_assignments.clone_from(
-        &dataset.cell_assignments
-            .iter()
-            .zip(mask.iter())
-            .filter(|(_, &m)| m)
-            .map(|(t, _)| t)
-            .cloned()
-            .collect::<Vec<_>>());
-
-    dataset.fovs.clone_from(
-        &dataset.fovs
+    dataset.retain(&mask);
+    (0..mask.len()).filter(|&i| mask[i]).collect()
+}
+
+// Concatenate datasets read from separate files (e.g. serial sections, or a
+// Xenium run split across files) into one. Genes are matched by name, and
+// cells and fovs are kept distinct between files, even where their ids are the
+// same. Coordinates are used as given.
+pub fn concatenate_datasets(datasets: Vec<TranscriptDataset>) -> TranscriptDataset {
+    let mut datasets = datasets.into_iter();
+    let mut combined = datasets.next().expect("no transcript datasets to concatenate");
+
+    let mut gene_index: HashMap<String, u32> = combined
+        .transcript_names
+        .iter()
+        .enumerate()
+        .map(|(gene, name)| (name.clone(), gene as u32))
+        .collect();
+    let mut source_index: HashMap<String, u32> = combined
+        .prior_source_names
+        .iter()
+        .enumerate()
+        .map(|(source, name)| (name.clone(), source as u32))
+        .collect();
+
+    for dataset in datasets {
+        let gene_map: Vec<u32> = dataset
+            .transcript_names
             .iter()
-            .zip(mask.iter())
-            .filter(|(_, &m)| m)
-            .map(|(t, _)| t)
-            .cloned()
-            .collect::<Vec<_>>());
-
-    dataset.qvs.clone_from(
-        &dataset.qvs
+            .map(|name| {
+                *gene_index.entry(name.clone()).or_insert_with(|| {
+                    combined.transcript_names.push(name.clone());
+                    (combined.transcript_names.len() - 1) as u32
+                })
+            })
+            .collect();
+        let prior_source_map: Vec<u32> = dataset
+            .prior_source_names
             .iter()
-            .zip(mask.iter())
-            .filter(|(_, &m)| m)
-            .map(|(t, _)| t)
-            .cloned()
-            .collect::<Vec<_>>());
+            .map(|name| {
+                *source_index.entry(name.clone()).or_insert_with(|| {
+                    combined.prior_source_names.push(name.clone());
+                    (combined.prior_source_names.len() - 1) as u32
+                })
+            })
+            .collect();
+
+        let ncells = combined.nucleus_population.len() as CellIndex;
+        let noriginal_cells = combined.original_cell_ids.len() as CellIndex;
+        let nfovs = combined.fov_names.len() as u32;
+        let nsamples = combined.sample_names.len() as u32;
+        let offset = |cell: CellIndex, n: CellIndex| {
+            if cell == BACKGROUND_CELL {
+                BACKGROUND_CELL
+            } else {
+                cell + n
+            }
+        };
+
+        combined
+            .transcripts
+            .extend(dataset.transcripts.iter().map(|t| Transcript {
+                gene: gene_map[t.gene as usize],
+                fov: t.fov + nfovs,
+                ..*t
+            }));
+        combined.nucleus_assignments.extend(
+            dataset
+                .nucleus_assignments
+                .iter()
+                .map(|&cell| offset(cell, ncells)),
+        );
+        combined.cell_assignments.extend(
+            dataset
+                .cell_assignments
+                .iter()
+                .map(|&cell| offset(cell, ncells)),
+        );
+        combined
+            .nucleus_population
+            .extend(dataset.nucleus_population);
+        combined
+            .fovs
+            .extend(dataset.fovs.iter().map(|fov| fov + nfovs));
+        combined.qvs.extend(dataset.qvs);
+        combined.fov_names.extend(dataset.fov_names);
+        combined
+            .fov_samples
+            .extend(dataset.fov_samples.iter().map(|sample| sample + nsamples));
+        combined.sample_names.extend(dataset.sample_names);
+        combined.nuclear.extend(dataset.nuclear);
+        combined.original_cell_ids.extend(dataset.original_cell_ids);
+        combined.original_cell_assignments.extend(
+            dataset
+                .original_cell_assignments
+                .iter()
+                .map(|&cell| offset(cell, noriginal_cells)),
+        );
+        combined.prior_sources.extend(
+            dataset
+                .prior_sources
+                .iter()
+                .map(|&source| prior_source_map[source as usize]),
+        );
+    }
+
+    combined
+}
+
+// Split a dataset into one dataset for each FOV that has any transcripts, with
+// cells renumbered within each, along with the index in `dataset` of each of
+// their transcripts. FOVs with no transcripts initially assigned to cells are
+// left with no cells.
+pub fn partition_by_fov(dataset: TranscriptDataset) -> Vec<(Vec<usize>, TranscriptDataset)> {
+    let mut rows = vec![Vec::new(); dataset.fov_names.len()];
+    let mut parts: Vec<TranscriptDataset> = (0..dataset.fov_names.len())
+        .map(|_| TranscriptDataset {
+            transcript_names: dataset.transcript_names.clone(),
+            transcripts: Vec::new(),
+            nucleus_assignments: Vec::new(),
+            cell_assignments: Vec::new(),
+            nucleus_population: Vec::new(),
+            fovs: Vec::new(),
+            qvs: Vec::new(),
+            fov_names: dataset.fov_names.clone(),
+            sample_names: dataset.sample_names.clone(),
+            fov_samples: dataset.fov_samples.clone(),
+            nuclear: Vec::new(),
+            original_cell_ids: dataset.original_cell_ids.clone(),
+            original_cell_assignments: Vec::new(),
+            prior_source_names: dataset.prior_source_names.clone(),
+            prior_sources: Vec::new(),
+        })
+        .collect();
+
+    for (i, t) in dataset.transcripts.iter().enumerate() {
+        rows[dataset.fovs[i] as usize].push(i);
+        let part = &mut parts[dataset.fovs[i] as usize];
+        part.transcripts.push(*t);
+        part.nucleus_assignments.push(dataset.nucleus_assignments[i]);
+        part.cell_assignments.push(dataset.cell_assignments[i]);
+        part.fovs.push(dataset.fovs[i]);
+        part.qvs.push(dataset.qvs[i]);
+        part.nuclear.push(dataset.nuclear[i]);
+        part.original_cell_assignments
+            .push(dataset.original_cell_assignments[i]);
+        part.prior_sources.push(dataset.prior_sources[i]);
+    }
+
+    rows.into_iter()
+        .zip(parts)
+        .filter(|(_, part)| !part.transcripts.is_empty())
+        .map(|(rows, mut part)| {
+            part.nucleus_population = postprocess_cell_assignments(
+                &mut part.nucleus_assignments,
+                &mut part.cell_assignments,
+            );
+            (rows, part)
+        })
+        .collect()
 }
@@ -0,0 +1,316 @@
+// Affine transforms applied to transcript coordinates as they are read, e.g.
+// to register serial sections to one another or to convert pixel coordinates
+// to microns. Cell polygons are mapped back to the input coordinates with the
+// inverse transform when they are written.
+
+use geo::{Coord, LineString, MultiPolygon, Polygon};
+
+use super::super::error::{Error, Result};
+use super::transcripts::{CellIndex, Transcript, BACKGROUND_CELL};
+
+#[derive(Clone, Copy, Debug)]
+pub struct AffineTransform {
+    // First three rows of a 4x4 matrix acting on homogeneous (x, y, z, 1)
+    // coordinates. (The last row of an affine transform is always 0, 0, 0, 1.)
+    m: [[f64; 4]; 3],
+}
+
+impl AffineTransform {
+    pub fn identity() -> Self {
+        AffineTransform {
+            m: [
+                [1.0, 0.0, 0.0, 0.0],
+                [0.0, 1.0, 0.0, 0.0],
+                [0.0, 0.0, 1.0, 0.0],
+            ],
+        }
+    }
+
+    // Read a 3x3 (x and y only, leaving z unchanged) or 4x4 affine matrix, one
+    // row per line, with values separated by commas or whitespace. Blank lines
+    // and lines starting with '#' are ignored.
+    pub fn read(path: &str) -> Result<Self> {
+        let transform_error = |message: String| Error::Transform {
+            path: path.to_string(),
+            message,
+        };
+        let content = std::fs::read_to_string(path).map_err(|source| Error::Io {
+            path: path.to_string(),
+            source,
+        })?;
+
+        let mut rows: Vec<Vec<f64>> = Vec::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let row = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|value| !value.is_empty())
+                .map(|value| {
+                    value
+                        .parse::<f64>()
+                        .map_err(|_| transform_error(format!("expected a number, found '{}'", value)))
+                })
+                .collect::<Result<Vec<f64>>>()?;
+            rows.push(row);
+        }
+
+        let n = rows.len();
+        if (n != 3 && n != 4) || rows.iter().any(|row| row.len() != n) {
+            return Err(transform_error(String::from(
+                "expected a 3x3 or 4x4 matrix",
+            )));
+        }
+        let last_row = &rows[n - 1];
+        if last_row[..n - 1].iter().any(|&v| v != 0.0) || last_row[n - 1] != 1.0 {
+            return Err(transform_error(format!(
+                "the last row of an affine transform must be {}",
+                if n == 3 { "0, 0, 1" } else { "0, 0, 0, 1" }
+            )));
+        }
+
+        let transform = if n == 3 {
+            AffineTransform {
+                m: [
+                    [rows[0][0], rows[0][1], 0.0, rows[0][2]],
+                    [rows[1][0], rows[1][1], 0.0, rows[1][2]],
+                    [0.0, 0.0, 1.0, 0.0],
+                ],
+            }
+        } else {
+            AffineTransform {
+                m: [
+                    [rows[0][0], rows[0][1], rows[0][2], rows[0][3]],
+                    [rows[1][0], rows[1][1], rows[1][2], rows[1][3]],
+                    [rows[2][0], rows[2][1], rows[2][2], rows[2][3]],
+                ],
+            }
+        };
+
+        if transform.inverse().is_none() {
+            return Err(transform_error(String::from("the matrix is not invertible")));
+        }
+
+        Ok(transform)
+    }
+
+    pub fn apply(&self, x: f32, y: f32, z: f32) -> (f32, f32, f32) {
+        let (x, y, z) = (x as f64, y as f64, z as f64);
+        let m = &self.m;
+        (
+            (m[0][0] * x + m[0][1] * y + m[0][2] * z + m[0][3]) as f32,
+            (m[1][0] * x + m[1][1] * y + m[1][2] * z + m[1][3]) as f32,
+            (m[2][0] * x + m[2][1] * y + m[2][2] * z + m[2][3]) as f32,
+        )
+    }
+
+    pub fn apply_to_transcripts(&self, transcripts: &mut [Transcript]) {
+        for t in transcripts {
+            (t.x, t.y, t.z) = self.apply(t.x, t.y, t.z);
+        }
+    }
+
+    // Inverse by cofactors of the linear part, or None if it's singular.
+    pub fn inverse(&self) -> Option<Self> {
+        let m = &self.m;
+        let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+
+        let mut inv = [[0.0; 4]; 3];
+        for (i, row) in inv.iter_mut().enumerate() {
+            // cofactors of m[j][i], which give the transposed adjugate
+            let (c0, c1) = ((i + 1) % 3, (i + 2) % 3);
+            for (j, v) in row.iter_mut().take(3).enumerate() {
+                let (r0, r1) = ((j + 1) % 3, (j + 2) % 3);
+                *v = (m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]) / det;
+            }
+            row[3] = -(0..3).map(|j| row[j] * m[j][3]).sum::<f64>();
+        }
+
+        Some(AffineTransform { m: inv })
+    }
+
+    // Apply the transform to the x and y coordinates of a polygon lying at
+    // depth z.
+    pub fn apply_to_multipolygon(&self, polygons: &MultiPolygon<f32>, z: f32) -> MultiPolygon<f32> {
+        let transform_ring = |ring: &LineString<f32>| {
+            LineString::new(
+                ring.coords()
+                    .map(|c| {
+                        let (x, y, _) = self.apply(c.x, c.y, z);
+                        Coord { x, y }
+                    })
+                    .collect(),
+            )
+        };
+        MultiPolygon::new(
+            polygons
+                .iter()
+                .map(|polygon| {
+                    Polygon::new(
+                        transform_ring(polygon.exterior()),
+                        polygon.interiors().iter().map(transform_ring).collect(),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+// Maps cell polygons back to input coordinates, using the inverse transform of
+// the sample each cell is from.
+pub struct PolygonInverseTransform {
+    // [nsamples] inverse of each sample's transform
+    inverses: Vec<AffineTransform>,
+
+    // [ncells] sample of each cell, and the mean z of its transcripts, which
+    // polygons are taken to lie at
+    cell_samples: Vec<u32>,
+    cell_z: Vec<f32>,
+}
+
+impl PolygonInverseTransform {
+    // Cells are matched to samples, and given a z, by the transcripts assigned
+    // to them in `cells`.
+    pub fn new(
+        transforms: &[AffineTransform],
+        ncells: usize,
+        transcripts: &[Transcript],
+        cells: impl Iterator<Item = CellIndex>,
+        fov_samples: &[u32],
+    ) -> Self {
+        let mut cell_samples = vec![0; ncells];
+        let mut cell_z = vec![0.0; ncells];
+        let mut cell_count = vec![0_u32; ncells];
+        for (t, cell) in transcripts.iter().zip(cells) {
+            if cell != BACKGROUND_CELL {
+                cell_samples[cell as usize] = fov_samples[t.fov as usize];
+                cell_z[cell as usize] += t.z;
+                cell_count[cell as usize] += 1;
+            }
+        }
+        for (z, &count) in cell_z.iter_mut().zip(&cell_count) {
+            *z /= count.max(1) as f32;
+        }
+
+        PolygonInverseTransform {
+            inverses: transforms
+                .iter()
+                .map(|transform| transform.inverse().unwrap())
+                .collect(),
+            cell_samples,
+            cell_z,
+        }
+    }
+
+    fn apply_cell(&self, cell: usize, polygons: &MultiPolygon<f32>) -> MultiPolygon<f32> {
+        let inverse = &self.inverses[self.cell_samples[cell] as usize];
+        inverse.apply_to_multipolygon(polygons, self.cell_z[cell])
+    }
+
+    pub fn apply(&self, polygons: Vec<MultiPolygon<f32>>) -> Vec<MultiPolygon<f32>> {
+        polygons
+            .iter()
+            .enumerate()
+            .map(|(cell, polygons)| self.apply_cell(cell, polygons))
+            .collect()
+    }
+
+    pub fn apply_layered(
+        &self,
+        polygons: Vec<Vec<(i32, MultiPolygon<f32>)>>,
+    ) -> Vec<Vec<(i32, MultiPolygon<f32>)>> {
+        polygons
+            .iter()
+            .enumerate()
+            .map(|(cell, layers)| {
+                layers
+                    .iter()
+                    .map(|(layer, polygons)| (*layer, self.apply_cell(cell, polygons)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Transcripts in input coordinates, given the sample of each transcript's
+    // fov.
+    pub fn inverse_transcripts(&self, transcripts: &[Transcript], fov_samples: &[u32]) -> Vec<Transcript> {
+        transcripts
+            .iter()
+            .map(|t| {
+                let inverse = &self.inverses[fov_samples[t.fov as usize] as usize];
+                let (x, y, z) = inverse.apply(t.x, t.y, t.z);
+                Transcript { x, y, z, ..*t }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Write a matrix file to read back, returning its path.
+    fn write_matrix(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!("proseg-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    fn read_matrix(name: &str, content: &str) -> Result<AffineTransform> {
+        let path = write_matrix(name, content);
+        let transform = AffineTransform::read(&path);
+        std::fs::remove_file(&path).unwrap();
+        transform
+    }
+
+    fn assert_close(a: (f32, f32, f32), b: (f32, f32, f32)) {
+        assert!(
+            (a.0 - b.0).abs() < 1e-4 && (a.1 - b.1).abs() < 1e-4 && (a.2 - b.2).abs() < 1e-4,
+            "{:?} != {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn inverse_round_trip() {
+        let transform = read_matrix(
+            "affine4.txt",
+            "# rotate, scale, and shift\n0.0, -2.0, 0.0, 10.0\n2.0 0.0 0.0 -5.0\n\n0 0 0.5 1\n0 0 0 1\n",
+        )
+        .unwrap();
+        assert_close(transform.apply(1.0, 2.0, 4.0), (6.0, -3.0, 3.0));
+
+        let inverse = transform.inverse().unwrap();
+        for (x, y, z) in [(0.0, 0.0, 0.0), (1.0, 2.0, 4.0), (-3.5, 100.0, 7.25)] {
+            let (tx, ty, tz) = transform.apply(x, y, z);
+            assert_close(inverse.apply(tx, ty, tz), (x, y, z));
+        }
+    }
+
+    #[test]
+    fn xy_matrix_leaves_z() {
+        let transform = read_matrix("affine3.txt", "2,0,1\n0,2,-1\n0,0,1\n").unwrap();
+        assert_close(transform.apply(1.0, 1.0, 5.0), (3.0, 1.0, 5.0));
+    }
+
+    #[test]
+    fn invalid_matrices() {
+        for (name, content) in [
+            ("singular.txt", "1 2 0\n2 4 0\n0 0 1\n"),
+            ("last-row.txt", "1 0 0\n0 1 0\n1 0 1\n"),
+            ("shape.txt", "1 0\n0 1\n"),
+            ("ragged.txt", "1 0 0\n0 1\n0 0 1\n"),
+            ("number.txt", "1 0 x\n0 1 0\n0 0 1\n"),
+        ] {
+            assert!(read_matrix(name, content).is_err(), "{}", name);
+        }
+    }
+}
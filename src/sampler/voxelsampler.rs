@@ -1,17 +1,26 @@
+use super::boundary::BoundaryPrior;
+use super::chunks::ChunkLayout;
 use super::connectivity::ConnectivityChecker;
 use super::math::relerr;
 use super::polygons::{PolygonBuilder, union_all_into_multipolygon};
+use super::rng::{self, FixedState, SamplerRng};
+use super::roi::Roi;
 use super::sampleset::SampleSet;
 use super::transcripts::{coordinate_span, CellIndex, Transcript, BACKGROUND_CELL};
-use super::{chunkquad, perimeter_bound, ModelParams, ModelPriors, Proposal, Sampler};
+use super::{
+    perimeter_bound, region_move_log_ratio, ModelParams, ModelPriors, Proposal, ProposalStats, Sampler,
+    UncertaintyTracker,
+};
 
 // use hexx::{Hex, HexLayout, HexOrientation, Vec2};
 // use arrow;
-use geo::geometry::{MultiPolygon, Polygon};
+use geo::geometry::{MultiPoint, MultiPolygon, Point, Polygon};
+use geo::{Area, ConvexHull};
 use itertools::Itertools;
-use ndarray::Array2;
-use rand::{thread_rng, Rng};
+use ndarray::{Array2, Axis};
+use rand::Rng;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
 use std::cmp::{Ord, Ordering, PartialEq, PartialOrd};
 use std::collections::{HashMap, HashSet};
@@ -22,8 +31,97 @@ use thread_local::ThreadLocal;
 pub type CellPolygon = MultiPolygon<f32>;
 pub type CellPolygonLayers = Vec<(i32, CellPolygon)>;
 
+// Descriptors of a cell's footprint: its voxels projected onto the xy-plane.
+#[derive(Clone, Debug)]
+pub struct CellShape {
+    // ratio of the major to minor axis of the footprint's second moments
+    pub elongation: f32,
+    // angle of the major axis from the x-axis, in radians in (-π/2, π/2]
+    pub orientation: f32,
+    // area over the area of the convex hull
+    pub solidity: f32,
+    // number of voxel layers with any of the cell's voxels
+    pub zlayers: u32,
+}
+
 // use std::time::Instant;
 
+// Boundary covered by `voxels`: the boundary on the line between each voxel and
+// each of its xy neighbors for which `member` is true, summed.
+fn boundary_cost(
+    boundary: &BoundaryPrior,
+    layout: &VoxelLayout,
+    voxels: &[Voxel],
+    member: impl Fn(Voxel) -> bool,
+) -> f32 {
+    let mut cost = 0.0;
+    for &voxel in voxels {
+        let (x0, y0, _) = layout.voxel_to_world_pos(voxel);
+        for neighbor in voxel.von_neumann_neighborhood_xy() {
+            if member(neighbor) {
+                let (x1, y1, _) = layout.voxel_to_world_pos(neighbor);
+                cost += boundary.line_max((x0, y0), (x1, y1));
+            }
+        }
+    }
+    cost
+}
+
+// Log probability penalty for the boundary covered by `voxels` with neighbors
+// for which `member` is true, or 0 without a boundary prior.
+fn boundary_penalty(
+    boundary: Option<&BoundaryPrior>,
+    layout: &VoxelLayout,
+    voxels: &[Voxel],
+    member: impl Fn(Voxel) -> bool,
+) -> f32 {
+    match boundary {
+        Some(boundary) => boundary.weight * boundary_cost(boundary, layout, voxels, member),
+        None => 0.0,
+    }
+}
+
+// Change in log prior density from the boundary and perimeter penalties were
+// `voxel` moved from `cell_from` to `cell_to`, changing their perimeters by
+// `perimeter_deltas`, with `perimeter_penalty` the penalty per mismatching
+// neighbor. The perimeter of either cell is penalized, so boundary between two
+// cells costs twice what boundary with the background does. Moves of whole
+// regions use `boundary_penalty` and `VoxelSampler::perimeter_penalty` in the
+// same way.
+#[allow(clippy::too_many_arguments)]
+fn voxel_move_log_prior_delta(
+    boundary: Option<&BoundaryPrior>,
+    layout: &VoxelLayout,
+    voxel_cells: &VoxelCellMap,
+    perimeter_penalty: f32,
+    voxel: Voxel,
+    cell_from: CellIndex,
+    cell_to: CellIndex,
+    perimeter_deltas: (f32, f32),
+) -> f32 {
+    let covered = |cell: CellIndex| {
+        if cell == BACKGROUND_CELL {
+            0.0
+        } else {
+            boundary_penalty(boundary, layout, &[voxel], |neighbor| {
+                voxel_cells.get(neighbor) == cell
+            })
+        }
+    };
+    let perimeter_delta = |cell: CellIndex, delta: f32| {
+        if cell == BACKGROUND_CELL {
+            0.0
+        } else {
+            delta
+        }
+    };
+    covered(cell_from)
+        - covered(cell_to)
+        - perimeter_penalty
+            * (perimeter_delta(cell_from, perimeter_deltas.0)
+                + perimeter_delta(cell_to, perimeter_deltas.1))
+}
+
 fn clip_z_position(position: (f32, f32, f32), zmin: f32, zmax: f32) -> (f32, f32, f32) {
     let eps = (zmax - zmin) * 1e-6;
     (
@@ -33,7 +131,11 @@ fn clip_z_position(position: (f32, f32, f32), zmin: f32, zmax: f32) -> (f32, f32
     )
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+// A voxel's cell, world coordinates (x0, y0, z0, x1, y1, z1), and number of
+// transcripts.
+pub type VoxelTranscriptCount = (CellIndex, (f32, f32, f32, f32, f32, f32), u32);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Voxel {
     pub i: i32,
     pub j: i32,
@@ -233,6 +335,23 @@ impl VoxelLayout {
         )
     }
 
+    // Relative weights of lateral and vertical edges between neighboring
+    // voxels, given the factor putting z in the same units as x and y. Edges
+    // are weighted by the inverse square distance between voxel centers
+    // (equivalently, shared face area over distance), so a flat voxel is more
+    // strongly tied to the voxels above and below it than to its lateral
+    // neighbors. Without a z scale every edge has weight 1.
+    fn edge_weights(&self, z_scale: Option<f32>) -> (f64, f64) {
+        if let Some(z_scale) = z_scale {
+            let lateral = self.size.0 as f64;
+            let vertical = (z_scale * self.size.2) as f64;
+            let ratio = (lateral / vertical).powi(2);
+            ((1.0 / ratio).min(1.0), ratio.min(1.0))
+        } else {
+            (1.0, 1.0)
+        }
+    }
+
     fn voxel_to_world_coords(&self, voxel: Voxel) -> (f32, f32, f32, f32, f32, f32) {
         let x0 = self.origin.0 + voxel.i as f32 * self.size.0;
         let y0 = self.origin.1 + voxel.j as f32 * self.size.1;
@@ -259,34 +378,29 @@ struct VoxelBin {
 
 struct ChunkQuadMap {
     layout: VoxelLayout,
-    xmin: f32,
-    ymin: f32,
-    chunk_size: f32,
-    nxchunks: usize,
+    chunks: ChunkLayout,
+    // current shift of the chunks, in [0, chunks.max_offset())
+    offset: (f32, f32),
 }
 
 impl ChunkQuadMap {
     fn get(&self, voxel: Voxel) -> (u32, u32) {
         let voxel_xyz = self.layout.voxel_to_world_pos(voxel);
-        chunkquad(
-            voxel_xyz.0,
-            voxel_xyz.1,
-            self.xmin,
-            self.ymin,
-            self.chunk_size,
-            self.nxchunks,
-        )
+        self.chunks
+            .get(voxel_xyz.0 + self.offset.0, voxel_xyz.1 + self.offset.1)
     }
 }
 
+// Iteration order determines the order of mismatch edges, and so which edges
+// are proposed, so this uses a fixed hasher to keep seeded runs reproducible.
 struct VoxelCellMap {
-    index: HashMap<Voxel, CellIndex>,
+    index: HashMap<Voxel, CellIndex, FixedState>,
 }
 
 impl VoxelCellMap {
     fn new() -> Self {
         Self {
-            index: HashMap::new(),
+            index: HashMap::default(),
         }
     }
 
@@ -305,6 +419,30 @@ impl VoxelCellMap {
         self.index.insert(voxel, cell);
     }
 
+    // Change in the perimeters of `cell_from` and `cell_to`, in mismatching
+    // neighbors, were `voxel` moved from one to the other.
+    fn perimeter_deltas(
+        &self,
+        voxel: Voxel,
+        cell_from: CellIndex,
+        cell_to: CellIndex,
+    ) -> (f32, f32) {
+        let mut from_delta = 0.0;
+        let mut to_delta = 0.0;
+        for neighbor in voxel.radius2_xy_neighborhood() {
+            let neighbor_cell = self.get(neighbor);
+
+            // the voxel's new mismatches, less neighbors for which it's no
+            // longer a mismatch
+            to_delta += if neighbor_cell == cell_to { -1.0 } else { 1.0 };
+
+            // neighbors for which the voxel is now a mismatch, less its
+            // previous mismatches
+            from_delta += if neighbor_cell == cell_from { 1.0 } else { -1.0 };
+        }
+        (from_delta, to_delta)
+    }
+
     // fn len(&self) -> usize {
     //     return self.index.len();
     // }
@@ -399,6 +537,122 @@ fn voxel_assignments(voxel_bins: &Vec<VoxelBin>, cell_assignments: &[CellIndex])
     voxel_cells
 }
 
+// Mean (i, j) voxel coordinates.
+fn voxel_centroid(voxels: &[Voxel]) -> (f32, f32) {
+    let (ci, cj) = voxels.iter().fold((0.0, 0.0), |(ci, cj), voxel| {
+        (ci + voxel.i as f32, cj + voxel.j as f32)
+    });
+    (ci / voxels.len() as f32, cj / voxels.len() as f32)
+}
+
+// Probability that a line through `centroid`, at an angle θ uniform on [0, π),
+// puts exactly those of `voxels` for which `positive` is true on its positive
+// side (that of the normal (cos θ, sin θ)), as split proposals draw it.
+fn split_line_probability(
+    voxels: &[Voxel],
+    centroid: (f32, f32),
+    positive: impl Fn(Voxel) -> bool,
+) -> f32 {
+    let π = f32::consts::PI;
+
+    // Each voxel restricts θ to a half circle, and the intersection of the
+    // half circles with an arc shorter than a half circle is another arc.
+    let (mut lo, mut hi) = (0.0_f32, π);
+    for &voxel in voxels {
+        let (di, dj) = (voxel.i as f32 - centroid.0, voxel.j as f32 - centroid.1);
+        let positive = positive(voxel);
+        if di == 0.0 && dj == 0.0 {
+            if positive {
+                return 0.0;
+            }
+            continue;
+        }
+
+        // a voxel is on the positive side when θ is within π/2 of its direction
+        let α = dj.atan2(di);
+        let start = if positive { α - 0.5 * π } else { α + 0.5 * π }.rem_euclid(2.0 * π);
+        let (new_lo, new_hi) = [start - 2.0 * π, start, start + 2.0 * π]
+            .iter()
+            .map(|&start| (lo.max(start), hi.min(start + π)))
+            .max_by(|a, b| (a.1 - a.0).total_cmp(&(b.1 - b.0)))
+            .unwrap();
+        if new_hi <= new_lo {
+            return 0.0;
+        }
+        (lo, hi) = (new_lo, new_hi);
+    }
+    (hi - lo) / π
+}
+
+// Accept a move with log acceptance ratio `δ`, by Metropolis-Hastings.
+fn metropolis_hastings(rng: &mut SamplerRng, δ: f32) -> bool {
+    rng.gen::<f32>().ln() < δ
+}
+
+// Voxels of every cell, for moves that change the number of cells, along with
+// which cells are empty.
+struct CellRegions {
+    voxels: Vec<Vec<Voxel>>,
+
+    // cells left empty by earlier moves, which new cells reuse
+    free: Vec<CellIndex>,
+
+    // Cells removed by these moves, mapped to the cell they were merged into
+    // (or the background). They aren't reused until their nuclear and prior
+    // segmentation assignments are relabeled, once all moves are made.
+    removed_into: Vec<CellIndex>,
+    removed: bool,
+
+    // number of non-empty cells
+    ncells: usize,
+}
+
+impl CellRegions {
+    // A uniformly random non-empty cell, if there are any.
+    fn random_cell(&self, rng: &mut SamplerRng) -> Option<CellIndex> {
+        if self.ncells == 0 {
+            return None;
+        }
+        loop {
+            let cell = rng.gen_range(0..self.voxels.len());
+            if !self.voxels[cell].is_empty() {
+                return Some(cell as CellIndex);
+            }
+        }
+    }
+
+    fn remove(&mut self, cell: CellIndex, into: CellIndex) {
+        self.removed_into[cell as usize] = into;
+        self.removed = true;
+        self.ncells -= 1;
+    }
+
+    fn finish(mut self, params: &mut ModelParams) {
+        if !self.removed {
+            return;
+        }
+
+        // follow chains of merges to the cell that ended up with the voxels
+        for cell in 0..self.removed_into.len() {
+            let mut into = self.removed_into[cell];
+            while into != BACKGROUND_CELL && self.removed_into[into as usize] != into {
+                into = self.removed_into[into as usize];
+            }
+            self.removed_into[cell] = into;
+        }
+        params.relabel_prior_cells(&self.removed_into);
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ProposalState {
+    chunks: ChunkLayout,
+    chunk_offset: (f32, f32),
+    quad: usize,
+    // [quad][chunk] mismatch edges, in the order they are sampled from
+    mismatch_edges: Vec<Vec<Vec<(Voxel, Voxel)>>>,
+}
+
 pub struct VoxelSampler {
     chunkquad: ChunkQuadMap,
     transcript_genes: Vec<u32>,
@@ -420,6 +674,17 @@ pub struct VoxelSampler {
     cell_population: Array2<f32>, // [voxellayers, ncells]
     cell_perimeter: Array2<f32>,  // [voxellayers, ncells]
 
+    // xy centroid of each cell as of the start of the iteration, which cells
+    // can't grow more than `max_cell_radius` from. (Only kept up to date when
+    // there is a max radius.)
+    cell_anchors: Vec<(f32, f32)>,
+
+    // optional boundary stain prior penalizing cells covering boundary
+    boundary: Option<Arc<BoundaryPrior>>,
+
+    // if given, voxels outside this region keep their cell assignments
+    editable_region: Option<Arc<Roi>>,
+
     proposals: Vec<VoxelProposal>,
     connectivity_checker: ThreadLocal<RefCell<ConnectivityChecker>>,
 
@@ -442,12 +707,10 @@ impl VoxelSampler {
         z0: f32,
         layer_depth: f32,
         scale: f32,
-        chunk_size: f32,
+        chunks: ChunkLayout,
     ) -> Self {
-        let (xmin, xmax, ymin, ymax, zmin, zmax) = coordinate_span(transcripts);
-        let nxchunks = ((xmax - xmin) / chunk_size).ceil() as usize;
-        let nychunks = ((ymax - ymin) / chunk_size).ceil() as usize;
-        let nchunks = nxchunks * nychunks;
+        let (_xmin, _xmax, _ymin, _ymax, zmin, zmax) = coordinate_span(transcripts);
+        let nchunks = chunks.nchunks();
 
         let (layout, voxel_bins) = bin_transcripts(transcripts, scale, voxellayers);
 
@@ -523,10 +786,8 @@ impl VoxelSampler {
         let mut sampler = VoxelSampler {
             chunkquad: ChunkQuadMap {
                 layout,
-                xmin,
-                ymin,
-                chunk_size,
-                nxchunks,
+                chunks,
+                offset: (0.0, 0.0),
             },
             transcript_genes,
             transcript_voxels,
@@ -539,6 +800,9 @@ impl VoxelSampler {
             voxel_layers: voxellayers,
             cell_population,
             cell_perimeter,
+            cell_anchors: Vec::new(),
+            boundary: None,
+            editable_region: None,
             proposals,
             connectivity_checker,
             zmin,
@@ -563,6 +827,61 @@ impl VoxelSampler {
         self.cell_population.shape()[1]
     }
 
+    pub fn set_boundary(&mut self, boundary: Option<Arc<BoundaryPrior>>) {
+        self.boundary = boundary;
+    }
+
+    pub fn set_editable_region(&mut self, region: Option<Arc<Roi>>) {
+        self.editable_region = region;
+        self.repopulate_mismatches();
+    }
+
+    // Whether `voxel` can change cells, i.e. is within the region being
+    // refined, if there is one.
+    fn editable(&self, voxel: Voxel) -> bool {
+        self.editable_region.as_ref().is_none_or(|region| {
+            let (x, y, _) = self.chunkquad.layout.voxel_to_world_pos(voxel);
+            region.contains(x, y)
+        })
+    }
+
+    // Log probability penalty for the boundary covered by `voxels` with
+    // neighbors for which `member` is true, or 0 without a boundary prior.
+    fn boundary_penalty(&self, voxels: &[Voxel], member: impl Fn(Voxel) -> bool) -> f32 {
+        boundary_penalty(self.boundary.as_deref(), &self.chunkquad.layout, voxels, member)
+    }
+
+    // Log probability penalty for a change of `delta` mismatching neighbors in
+    // the perimeters of cells, or 0 without a perimeter penalty, in which case
+    // `delta` isn't computed.
+    fn perimeter_penalty(&self, priors: &ModelPriors, delta: impl FnOnce() -> f32) -> f32 {
+        if priors.perimeter_penalty == 0.0 {
+            0.0
+        } else {
+            priors.perimeter_penalty * self.voxel_size() / priors.perimeter_eta * delta()
+        }
+    }
+
+    // Perimeter of `cell`, summed over layers.
+    fn total_perimeter(&self, cell: CellIndex) -> f32 {
+        self.cell_perimeter.column(cell as usize).sum()
+    }
+
+    // Perimeter, summed over layers, of a cell made up of `voxels` (for which
+    // `member` is true).
+    fn region_perimeter(&self, voxels: &[Voxel], member: impl Fn(Voxel) -> bool) -> f32 {
+        voxels
+            .iter()
+            .map(|voxel| {
+                voxel
+                    .radius2_xy_neighborhood()
+                    .into_iter()
+                    .filter(|&neighbor| !member(neighbor))
+                    .count() as f32
+            })
+            .sum()
+    }
+
     // Allocate a new RectBinSampler with the same state as this one, but
     // grid resolution doubled (i.e. rect size halved).
     pub fn double_resolution(&self, params: &ModelParams, double_z_layers: bool) -> VoxelSampler {
@@ -589,7 +908,7 @@ impl VoxelSampler {
         // let t0 = Instant::now();
         // Build a set of every cube that is either populated with transcripts
         // or assigned to a cell.
-        let mut voxel_set = HashSet::<Voxel>::new();
+        let mut voxel_set = HashSet::<Voxel, FixedState>::default();
         for (&voxel, &cell) in self.voxel_cells.iter() {
             if cell != BACKGROUND_CELL {
                 voxel_set.insert(voxel);
@@ -644,10 +963,8 @@ impl VoxelSampler {
         let mut sampler = VoxelSampler {
             chunkquad: ChunkQuadMap {
                 layout,
-                xmin: self.chunkquad.xmin,
-                ymin: self.chunkquad.ymin,
-                chunk_size: self.chunkquad.chunk_size,
-                nxchunks: self.chunkquad.nxchunks,
+                chunks: self.chunkquad.chunks.clone(),
+                offset: self.chunkquad.offset,
             },
             transcript_genes: self.transcript_genes.clone(),
             transcript_voxels: self.transcript_voxels.clone(),
@@ -660,6 +977,9 @@ impl VoxelSampler {
             voxel_layers: voxellayers,
             cell_population,
             cell_perimeter,
+            cell_anchors: Vec::new(),
+            boundary: self.boundary.clone(),
+            editable_region: self.editable_region.clone(),
             proposals,
             connectivity_checker,
             zmin: self.zmin,
@@ -673,109 +993,1403 @@ impl VoxelSampler {
         sampler.populate_mismatches();
         // println!("populate_mismatches: {:?}", t0.elapsed());
 
-        // 141ms
-        // let t0 = Instant::now();
-        sampler.recompute_cell_population();
-        // println!("recompute_cell_population: {:?}", t0.elapsed());
+        // 141ms
+        // let t0 = Instant::now();
+        sampler.recompute_cell_population();
+        // println!("recompute_cell_population: {:?}", t0.elapsed());
+
+        // 85.4s
+        // let t0 = Instant::now();
+        sampler.recompute_cell_perimeter();
+        // println!("recompute_cell_perimeter: {:?}", t0.elapsed());
+
+        sampler.update_transcript_positions(
+            &vec![true; params.transcript_positions.len()],
+            &params.transcript_positions,
+        );
+
+        sampler
+    }
+
+    // Every voxel currently assigned to a cell. Together with the schedule
+    // position, this is enough to reconstruct the sampler state.
+    pub fn voxel_cell_assignments(&self) -> Vec<(Voxel, CellIndex)> {
+        self.voxel_cells
+            .iter()
+            .filter(|(_, &cell)| cell != BACKGROUND_CELL)
+            .map(|(&voxel, &cell)| (voxel, cell))
+            .collect()
+    }
+
+    // Replace voxel assignments (e.g. from a checkpoint) and recompute the
+    // sampler state derived from them. The voxel layout must match the one the
+    // assignments were made on. Cell volumes are left as they are in `params`,
+    // which were updated incrementally alongside the assignments, and
+    // recomputing them would round differently.
+    pub fn restore_voxel_cells(&mut self, params: &ModelParams, voxel_cells: &[(Voxel, CellIndex)]) {
+        self.voxel_cells = VoxelCellMap::new();
+        for &(voxel, cell) in voxel_cells {
+            self.voxel_cells.insert(voxel, cell);
+        }
+
+        self.repopulate_mismatches();
+        self.recompute_cell_population();
+        self.recompute_cell_perimeter();
+        self.update_transcript_positions(
+            &vec![true; params.transcript_positions.len()],
+            &params.transcript_positions,
+        );
+    }
+
+    fn recompute_cell_volume(&mut self, priors: &ModelPriors, params: &mut ModelParams) {
+        // recompute cell areas as the sum of rect areas
+        params.cell_volume.fill(0.0_f32);
+        for (_, &cell) in self.voxel_cells.iter() {
+            if cell == BACKGROUND_CELL {
+                continue;
+            }
+            params.cell_volume[cell as usize] += self.voxel_volume;
+        }
+
+        // (cells emptied by merge moves have no voxels, and get the minimum)
+        for cell_volume in params.cell_volume.iter_mut() {
+            *cell_volume = cell_volume.max(priors.min_cell_volume);
+        }
+    }
+
+    fn recompute_cell_population(&mut self) {
+        self.cell_population.fill(0.0_f32);
+        for (&voxel, &cell) in self.voxel_cells.iter() {
+            if cell == BACKGROUND_CELL {
+                continue;
+            }
+            self.cell_population[[voxel.k as usize, cell as usize]] += 1.0_f32;
+        }
+    }
+
+    fn recompute_cell_perimeter(&mut self) {
+        // TODO: This function is super expensive. It's hard to update in parallel
+        // though, each cell consists of many voxels. So we'd either need mutexes
+        // or more likelily, organize things by cell first.
+
+        self.cell_perimeter.fill(0.0_f32);
+        for (&voxel, &cell) in self.voxel_cells.iter() {
+            if cell == BACKGROUND_CELL {
+                continue;
+            }
+
+            for neighbor in voxel.radius2_xy_neighborhood() {
+                let neighbor_cell = self.voxel_cells.get(neighbor);
+                if neighbor_cell != cell {
+                    self.cell_perimeter[[voxel.k as usize, cell as usize]] += 1.0_f32;
+                }
+            }
+        }
+    }
+
+    // Move chunks by a new random offset, so that voxels on the seams between
+    // chunk quadrants aren't always the same ones.
+    pub fn shift_chunks(&mut self) {
+        let max_offset = self.chunkquad.chunks.max_offset();
+        let mut rng = rng::rng();
+        self.chunkquad.offset = (
+            rng.gen::<f32>() * max_offset,
+            rng.gen::<f32>() * max_offset,
+        );
+        self.repopulate_mismatches();
+    }
+
+    // Replace the chunk layout, e.g. with one rebalanced for the current cells.
+    pub fn set_chunks(&mut self, chunks: ChunkLayout) {
+        let nchunks = chunks.nchunks();
+        let ngenes = self.proposals[0].genepop.shape()[0];
+        self.chunkquad.chunks = chunks;
+        self.chunkquad.offset = (0.0, 0.0);
+        self.proposals = vec![VoxelProposal::new(ngenes, self.nlayers); nchunks];
+        for chunks in self.mismatch_edges.iter_mut() {
+            *chunks = (0..nchunks)
+                .map(|_| Arc::new(Mutex::new(VoxelEdgeSampleSet::new())))
+                .collect();
+        }
+        self.populate_mismatches();
+    }
+
+    // x-y positions of voxels on the boundaries of cells, which is where
+    // proposals are made, so the work in a region is roughly proportional to
+    // the number of them.
+    pub fn boundary_positions(&self) -> Vec<(f32, f32)> {
+        self.voxel_cells
+            .iter()
+            .filter(|(&voxel, &cell)| {
+                cell != BACKGROUND_CELL
+                    && voxel
+                        .von_neumann_neighborhood()
+                        .iter()
+                        .any(|&neighbor| {
+                            neighbor.inbounds(self.voxel_layers) && self.voxel_cells.get(neighbor) != cell
+                        })
+            })
+            .map(|(&voxel, _)| {
+                let (x, y, _) = self.chunkquad.layout.voxel_to_world_pos(voxel);
+                (x, y)
+            })
+            .collect()
+    }
+
+    pub fn voxel_size(&self) -> f32 {
+        self.chunkquad.layout.size.0
+    }
+
+    // Mean, over each layer of each cell, of the ratio of the perimeter of a
+    // circle covering the same number of voxels to the perimeter, which is
+    // near 1 for round cells and smaller the more irregular they are. NaN if
+    // there are no cells.
+    pub fn mean_compactness(&self, priors: &ModelPriors) -> f32 {
+        let (mut total, mut n) = (0.0_f32, 0);
+        for (&population, &perimeter) in self.cell_population.iter().zip(&self.cell_perimeter) {
+            if population > 0.0 && perimeter > 0.0 {
+                total += perimeter_bound(priors.perimeter_eta, 1.0, population) / perimeter;
+                n += 1;
+            }
+        }
+        total / n as f32
+    }
+
+    // Clear mismatch edges and populate them from scratch.
+    fn repopulate_mismatches(&mut self) {
+        for chunks in self.mismatch_edges.iter_mut() {
+            for chunk in chunks.iter_mut() {
+                *chunk = Arc::new(Mutex::new(VoxelEdgeSampleSet::new()));
+            }
+        }
+        self.populate_mismatches();
+    }
+
+    // Edges are only kept from voxels that can change cells, so that when
+    // refining a region no proposals are made outside of it.
+    fn populate_mismatches(&mut self) {
+        let mut edges: [Vec<Vec<(Voxel, Voxel)>>; 4] =
+            std::array::from_fn(|quad| vec![Vec::new(); self.mismatch_edges[quad].len()]);
+        for (&voxel, &cell) in self.voxel_cells.iter() {
+            let (chunk, quad) = self.chunkquad.get(voxel);
+            for neighbor in voxel.von_neumann_neighborhood() {
+                // don't consider neighbors that are out of bounds on the z-axis
+                if neighbor.k < 0 || neighbor.k >= self.voxel_layers as i32 {
+                    continue;
+                }
+
+                let neighbor_cell = self.voxel_cells.get(neighbor);
+                if cell != neighbor_cell {
+                    let (neighbor_chunk, neighbor_quad) = self.chunkquad.get(neighbor);
+
+                    if self.editable(voxel) {
+                        if let Some(edges) = edges[quad as usize].get_mut(chunk as usize) {
+                            edges.push((voxel, neighbor));
+                        }
+                    }
+                    if self.editable(neighbor) {
+                        if let Some(edges) =
+                            edges[neighbor_quad as usize].get_mut(neighbor_chunk as usize)
+                        {
+                            edges.push((neighbor, voxel));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Edges are added in sorted order, rather than the hash map's, which
+        // depends on its history, so that a run resumed from a checkpoint
+        // proposes the same edges as one that wasn't interrupted.
+        for (mismatch_edges, edges) in self.mismatch_edges.iter().zip(edges) {
+            mismatch_edges
+                .par_iter()
+                .zip(edges)
+                .for_each(|(mismatch_edges, mut edges)| {
+                    edges.sort_unstable();
+                    let mut mismatch_edges = mismatch_edges.lock().unwrap();
+                    for edge in edges {
+                        mismatch_edges.insert(edge);
+                    }
+                });
+        }
+    }
+
+    // Everything besides voxel assignments that determines which proposals
+    // are made, for checkpoints.
+    pub fn proposal_state(&self) -> ProposalState {
+        ProposalState {
+            chunks: self.chunkquad.chunks.clone(),
+            chunk_offset: self.chunkquad.offset,
+            quad: self.quad,
+            mismatch_edges: self
+                .mismatch_edges
+                .iter()
+                .map(|chunks| {
+                    chunks
+                        .iter()
+                        .map(|edges| edges.lock().unwrap().as_slice().to_vec())
+                        .collect()
+                })
+                .collect(),
+        }
+    }
+
+    // Restore state from `proposal_state`, after `restore_voxel_cells`.
+    pub fn restore_proposal_state(&mut self, state: ProposalState) {
+        let ngenes = self.proposals[0].genepop.shape()[0];
+        self.proposals = vec![VoxelProposal::new(ngenes, self.nlayers); state.chunks.nchunks()];
+        self.chunkquad.chunks = state.chunks;
+        self.chunkquad.offset = state.chunk_offset;
+        self.quad = state.quad;
+        for (mismatch_edges, chunks) in self.mismatch_edges.iter_mut().zip(state.mismatch_edges) {
+            *mismatch_edges = chunks
+                .into_iter()
+                .map(|edges| Arc::new(Mutex::new(edges.into_iter().collect())))
+                .collect();
+        }
+    }
+
+    // Update mismatch edges around `voxel`, which was just assigned to `cell`.
+    fn update_mismatch_edges(&self, voxel: Voxel, cell: CellIndex) {
+        let (chunk, quad) = self.chunkquad.get(voxel);
+        let editable = self.editable(voxel);
+
+        for neighbor in voxel.von_neumann_neighborhood() {
+            if neighbor.k < 0 || neighbor.k >= self.voxel_layers as i32 {
+                continue;
+            }
+
+            let (neighbor_chunk, neighbor_quad) = self.chunkquad.get(neighbor);
+            let neighbor_cell = self.voxel_cells.get(neighbor);
+            if cell == neighbor_cell {
+                let mismatch_edges = &self.mismatch_edges[quad as usize];
+                if (chunk as usize) < mismatch_edges.len() {
+                    mismatch_edges[chunk as usize]
+                        .lock()
+                        .unwrap()
+                        .remove((voxel, neighbor));
+                }
+
+                let mismatch_edges = &self.mismatch_edges[neighbor_quad as usize];
+                if (neighbor_chunk as usize) < mismatch_edges.len() {
+                    mismatch_edges[neighbor_chunk as usize]
+                        .lock()
+                        .unwrap()
+                        .remove((neighbor, voxel));
+                }
+            } else {
+                let mismatch_edges = &self.mismatch_edges[quad as usize];
+                if editable && (chunk as usize) < mismatch_edges.len() {
+                    mismatch_edges[chunk as usize]
+                        .lock()
+                        .unwrap()
+                        .insert((voxel, neighbor));
+                }
+
+                let mismatch_edges = &self.mismatch_edges[neighbor_quad as usize];
+                if self.editable(neighbor) && (neighbor_chunk as usize) < mismatch_edges.len() {
+                    mismatch_edges[neighbor_chunk as usize]
+                        .lock()
+                        .unwrap()
+                        .insert((neighbor, voxel));
+                }
+            }
+        }
+    }
+
+    // Reassign voxels that aren't connected to the largest connected part of
+    // their cell to the neighboring cell they share the most faces with (or the
+    // background, if there's none), returning the number of cells repaired.
+    // Connectivity checks on proposals only keep cells from becoming more
+    // fragmented, so this fixes cells that start out that way.
+    pub fn repair_connectivity(&mut self, priors: &ModelPriors, params: &mut ModelParams) -> usize {
+        let mut regions = self.cell_regions();
+        let mut nrepaired = 0;
+        for cell in 0..regions.voxels.len() {
+            let cell = cell as CellIndex;
+            let mut components = self.connected_components(&regions.voxels[cell as usize], cell);
+            if components.len() < 2 {
+                continue;
+            }
+            nrepaired += 1;
+
+            let largest = (0..components.len())
+                .max_by_key(|&i| (components[i].len(), std::cmp::Reverse(i)))
+                .unwrap();
+            regions.voxels[cell as usize] = components.swap_remove(largest);
+
+            let mut touched = Vec::new();
+            for fragment in components {
+                let mut neighbor_cells = fragment
+                    .iter()
+                    .flat_map(|voxel| voxel.von_neumann_neighborhood())
+                    .filter(|neighbor| neighbor.inbounds(self.voxel_layers))
+                    .map(|neighbor| self.voxel_cells.get(neighbor))
+                    .filter(|&neighbor_cell| {
+                        neighbor_cell != BACKGROUND_CELL && neighbor_cell != cell
+                    })
+                    .collect::<Vec<_>>();
+                neighbor_cells.sort_unstable();
+                let to = neighbor_cells
+                    .iter()
+                    .dedup_with_count()
+                    .max_by_key(|&(count, &neighbor_cell)| {
+                        (count, std::cmp::Reverse(neighbor_cell))
+                    })
+                    .map_or(BACKGROUND_CELL, |(_, &neighbor_cell)| neighbor_cell);
+
+                let (transcripts, _) = self.region_transcripts(params, &fragment, cell);
+                let volume = fragment.len() as f32 * self.voxel_volume;
+                self.move_region(
+                    priors,
+                    params,
+                    &fragment,
+                    &transcripts,
+                    volume,
+                    cell,
+                    to,
+                    false,
+                    false,
+                );
+                if to != BACKGROUND_CELL {
+                    regions.voxels[to as usize].extend(fragment);
+                    touched.push(to);
+                }
+            }
+
+            self.recompute_region_perimeter(cell, &regions.voxels[cell as usize]);
+            for to in touched {
+                self.recompute_region_perimeter(to, &regions.voxels[to as usize]);
+            }
+        }
+        nrepaired
+    }
+
+    // Assign unassigned voxels that are enclosed within a layer by a single cell
+    // (holes), and their transcripts, to that cell, returning the number of
+    // voxels filled. Meant for the final segmentation, so the filled
+    // transcripts are also made the cell's in `uncertainty`.
+    pub fn fill_holes(
+        &mut self,
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        uncertainty: &mut UncertaintyTracker,
+    ) -> usize {
+        let regions = self.cell_regions();
+        let mut nfilled = 0;
+        for (cell, voxels) in regions.voxels.iter().enumerate() {
+            let cell = cell as CellIndex;
+
+            // bounds of the cell in each layer, which no hole can reach past
+            let mut bounds: HashMap<i32, (i32, i32, i32, i32)> = HashMap::new();
+            for voxel in voxels {
+                let b = bounds
+                    .entry(voxel.k)
+                    .or_insert((voxel.i, voxel.i, voxel.j, voxel.j));
+                *b = (b.0.min(voxel.i), b.1.max(voxel.i), b.2.min(voxel.j), b.3.max(voxel.j));
+            }
+
+            let mut visited = HashSet::<Voxel, FixedState>::default();
+            let mut holes = Vec::new();
+            for voxel in voxels {
+                let (imin, imax, jmin, jmax) = bounds[&voxel.k];
+                for start in voxel.von_neumann_neighborhood_xy() {
+                    if self.voxel_cells.get(start) != BACKGROUND_CELL || !visited.insert(start) {
+                        continue;
+                    }
+
+                    let mut region = vec![start];
+                    let mut enclosed = true;
+                    let mut next = 0;
+                    while next < region.len() {
+                        let v = region[next];
+                        next += 1;
+                        if v.i <= imin || v.i >= imax || v.j <= jmin || v.j >= jmax {
+                            enclosed = false;
+                            continue;
+                        }
+                        for neighbor in v.von_neumann_neighborhood_xy() {
+                            let neighbor_cell = self.voxel_cells.get(neighbor);
+                            if neighbor_cell == BACKGROUND_CELL {
+                                if visited.insert(neighbor) {
+                                    region.push(neighbor);
+                                }
+                            } else if neighbor_cell != cell {
+                                enclosed = false;
+                            }
+                        }
+                    }
+
+                    // cells outside the region being refined stay as they were
+                    enclosed &= region.iter().all(|&v| self.editable(v));
+
+                    if enclosed {
+                        holes.extend(region);
+                    }
+                }
+            }
+
+            if holes.is_empty() {
+                continue;
+            }
+            nfilled += holes.len();
+
+            let (transcripts, _) = self.region_transcripts(params, &holes, BACKGROUND_CELL);
+            let volume = holes.len() as f32 * self.voxel_volume;
+            self.move_region(
+                priors,
+                params,
+                &holes,
+                &transcripts,
+                volume,
+                BACKGROUND_CELL,
+                cell,
+                false,
+                false,
+            );
+            uncertainty.reassign(&transcripts, cell);
+
+            let mut filled = voxels.clone();
+            filled.extend(holes);
+            self.recompute_region_perimeter(cell, &filled);
+        }
+        nfilled
+    }
+
+    // Partition the voxels of `cell` into connected components, with the same
+    // (Moore neighborhood) connectivity the connectivity checker uses.
+    fn connected_components(&self, voxels: &[Voxel], cell: CellIndex) -> Vec<Vec<Voxel>> {
+        let mut visited = HashSet::<Voxel, FixedState>::default();
+        let mut components = Vec::new();
+        for &voxel in voxels {
+            if !visited.insert(voxel) {
+                continue;
+            }
+            let mut component = vec![voxel];
+            let mut next = 0;
+            while next < component.len() {
+                for neighbor in component[next].moore_neighborhood() {
+                    if self.voxel_cells.get(neighbor) == cell && visited.insert(neighbor) {
+                        component.push(neighbor);
+                    }
+                }
+                next += 1;
+            }
+            components.push(component);
+        }
+        components
+    }
+
+    // Propose `nmoves` moves that either split a cell in two, or merge a cell
+    // into one of its neighbors, so that over- and under-segmented cells can be
+    // corrected, and the number of cells can change.
+    //
+    // These are reversible jump moves, each the reverse of the other. A split
+    // picks a non-empty cell uniformly, and cuts it along a line through its
+    // centroid at a uniformly random angle. The side the line's normal points to
+    // becomes a new cell, with a component drawn from the mixing proportions,
+    // and rates drawn from their posterior given that side's transcripts and
+    // volume. A merge picks a non-empty cell uniformly and moves all of it into
+    // a random neighbor. Acceptance ratios include the probability of making
+    // each choice, forward and in reverse, and the density of the drawn rates.
+    // Rates are drawn directly, rather than as a transformation of other random
+    // variables, so the Jacobian of the dimension matching is 1.
+    //
+    // Both moves are only made if the parts are connected (when connectivity is
+    // enforced), the parts are no further past the perimeter bound than the
+    // whole nor the whole than the parts, and all are within the maximum cell
+    // radius, so that a move is possible just when its reverse is.
+    //
+    // Moves that would take the number of non-empty cells outside of
+    // `ncells_bounds` (inclusive) aren't proposed.
+    pub fn sample_split_merge(
+        &mut self,
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        stats: &mut ProposalStats,
+        nmoves: usize,
+        ncells_bounds: (usize, usize),
+    ) {
+        let mut regions = self.cell_regions();
+        let mut rng = rng::rng();
+        for _ in 0..nmoves {
+            let cell = match regions.random_cell(&mut rng) {
+                Some(cell) => cell,
+                None => break,
+            };
+
+            if rng.gen::<bool>() {
+                if regions.ncells >= ncells_bounds.1 {
+                    continue;
+                }
+                let new_cell = self.free_cell(priors, params, &mut regions);
+                if self.propose_split(
+                    priors,
+                    params,
+                    &mut rng,
+                    &mut regions.voxels,
+                    regions.ncells,
+                    cell,
+                    new_cell,
+                    metropolis_hastings,
+                ) {
+                    regions.free.pop();
+                    regions.ncells += 1;
+                    stats.split_accept += 1;
+                } else {
+                    stats.split_reject += 1;
+                }
+            } else if regions.ncells <= ncells_bounds.0 {
+                continue;
+            } else if let Some(to) = self.propose_merge(
+                priors,
+                params,
+                &mut rng,
+                &mut regions.voxels,
+                regions.ncells,
+                cell,
+                metropolis_hastings,
+            ) {
+                regions.remove(cell, to);
+                stats.merge_accept += 1;
+            } else {
+                stats.merge_reject += 1;
+            }
+        }
+        regions.finish(params);
+    }
+
+    // Propose `nmoves` moves that either create a cell in a region of
+    // unassigned transcripts, or return all of a cell to the background, so
+    // that cells whose nuclei were missed can be found.
+    //
+    // These are reversible jump moves, each the reverse of the other. A birth
+    // starts from a uniformly random transcript, which must be unassigned, and
+    // takes the unassigned voxels connected to it within a typical cell radius
+    // (fixed for each call, from the mean cell area), with a component drawn
+    // from the mixing proportions and rates drawn from their posterior given
+    // the region's transcripts and volume. A death picks a non-empty cell
+    // uniformly. Acceptance ratios include the probability of proposing each
+    // move and its reverse, which for a birth counts every transcript that
+    // would have grown the same region, so a cell can only die if a birth could
+    // have created it.
+    //
+    // Spurious cells in sparse background are avoided by the prior on the new
+    // cell's rates: the gamma prior given by its component's dispersion (as in
+    // the negative binomial expression model), whose density is part of the
+    // acceptance ratio, so that a cell is only created where its transcripts
+    // are explained enough better than by background to pay for it.
+    //
+    // As with `sample_split_merge`, these moves are bounded by `ncells_bounds`.
+    pub fn sample_birth_death(
+        &mut self,
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        stats: &mut ProposalStats,
+        nmoves: usize,
+        ncells_bounds: (usize, usize),
+    ) {
+        let voxel_size = self.chunkquad.layout.size.0;
+        let radius = (params.mean_cell_area() / f32::consts::PI).sqrt() / voxel_size;
+        if !radius.is_finite() {
+            return;
+        }
+
+        let mut regions = self.cell_regions();
+        let mut rng = rng::rng();
+        for _ in 0..nmoves {
+            if rng.gen::<bool>() {
+                if regions.ncells >= ncells_bounds.1 {
+                    continue;
+                }
+                let new_cell = self.free_cell(priors, params, &mut regions);
+                if self.propose_birth(
+                    priors,
+                    params,
+                    &mut rng,
+                    &mut regions.voxels,
+                    regions.ncells,
+                    new_cell,
+                    radius,
+                    metropolis_hastings,
+                ) {
+                    regions.free.pop();
+                    regions.ncells += 1;
+                    stats.birth_accept += 1;
+                } else {
+                    stats.birth_reject += 1;
+                }
+            } else if regions.ncells > ncells_bounds.0 {
+                let cell = match regions.random_cell(&mut rng) {
+                    Some(cell) => cell,
+                    None => continue,
+                };
+                if self.propose_death(
+                    priors,
+                    params,
+                    &mut rng,
+                    &mut regions.voxels,
+                    regions.ncells,
+                    cell,
+                    radius,
+                    metropolis_hastings,
+                ) {
+                    regions.remove(cell, BACKGROUND_CELL);
+                    stats.death_accept += 1;
+                } else {
+                    stats.death_reject += 1;
+                }
+            }
+        }
+        regions.finish(params);
+    }
+
+    fn cell_regions(&self) -> CellRegions {
+        let mut voxels = vec![Vec::new(); self.ncells()];
+        for (&voxel, &cell) in self.voxel_cells.iter() {
+            if cell != BACKGROUND_CELL {
+                voxels[cell as usize].push(voxel);
+            }
+        }
+
+        let free = (0..voxels.len())
+            .rev()
+            .filter(|&cell| voxels[cell].is_empty())
+            .map(|cell| cell as CellIndex)
+            .collect();
+
+        let ncells = voxels.iter().filter(|voxels| !voxels.is_empty()).count();
+
+        CellRegions {
+            voxels,
+            free,
+            removed_into: (0..self.ncells() as CellIndex).collect(),
+            removed: false,
+            ncells,
+        }
+    }
+
+    // An empty cell to use for a new cell, allocating more if there are none.
+    // The cell is only taken off the free list once the new cell is accepted.
+    fn free_cell(
+        &mut self,
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        regions: &mut CellRegions,
+    ) -> CellIndex {
+        if regions.free.is_empty() {
+            let n = (self.ncells() / 100).max(1);
+            let first = self.add_cells(priors, params, n);
+            regions.voxels.resize(self.ncells(), Vec::new());
+            regions.removed_into.extend(first..first + n as CellIndex);
+            regions.free.extend((first..first + n as CellIndex).rev());
+        }
+        *regions.free.last().unwrap()
+    }
+
+    // Allocate `n` new empty cells, returning the index of the first.
+    fn add_cells(&mut self, priors: &ModelPriors, params: &mut ModelParams, n: usize) -> CellIndex {
+        let first = params.add_cells(priors, n);
+        self.cell_population
+            .append(Axis(1), Array2::zeros((self.voxel_layers, n)).view())
+            .unwrap();
+        self.cell_perimeter
+            .append(Axis(1), Array2::zeros((self.voxel_layers, n)).view())
+            .unwrap();
+        first
+    }
+
+    // Try splitting `cell`, one of `ncells` non-empty cells, in two, with one
+    // side becoming `new_cell`. Returns true if the split was made, which
+    // happens if `accept` accepts its log acceptance ratio.
+    #[allow(clippy::too_many_arguments)]
+    fn propose_split(
+        &mut self,
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        rng: &mut SamplerRng,
+        cell_voxels: &mut [Vec<Voxel>],
+        ncells: usize,
+        cell: CellIndex,
+        new_cell: CellIndex,
+        accept: impl FnOnce(&mut SamplerRng, f32) -> bool,
+    ) -> bool {
+        let voxels = &cell_voxels[cell as usize];
+        let centroid = voxel_centroid(voxels);
+        let (sinθ, cosθ) = (rng.gen::<f32>() * f32::consts::PI).sin_cos();
+        let (split, rest): (Vec<Voxel>, Vec<Voxel>) = voxels.iter().partition(|voxel| {
+            (voxel.i as f32 - centroid.0) * cosθ + (voxel.j as f32 - centroid.1) * sinθ > 0.0
+        });
+
+        let split_set = split.iter().cloned().collect::<HashSet<_, FixedState>>();
+        let in_split = |voxel: Voxel| split_set.contains(&voxel);
+        let in_cell = |voxel: Voxel| self.voxel_cells.get(voxel) == cell;
+        let in_rest = |voxel: Voxel| !in_split(voxel) && in_cell(voxel);
+        if !self.splittable(priors, voxels, &split, &rest, in_cell, in_split, in_rest) {
+            return false;
+        }
+
+        // the reverse merge has to be able to pick `cell` to merge into
+        let neighbors = self.neighbor_cells(&split, new_cell, |voxel| {
+            if in_split(voxel) {
+                new_cell
+            } else {
+                self.voxel_cells.get(voxel)
+            }
+        });
+        if !neighbors.contains(&cell) {
+            return false;
+        }
+
+        let line_probability = split_line_probability(voxels, centroid, in_split);
+        if line_probability == 0.0 {
+            return false;
+        }
+
+        let (transcripts, gene_count) = self.region_transcripts(params, &split, cell);
+        let volume = split.len() as f32 * self.voxel_volume;
+
+        // The component is drawn from its prior, so the two cancel.
+        params.z[new_cell as usize] = params.random_component(rng);
+        let rates_log_ratio = params.draw_new_cell_rates(rng, new_cell, &gene_count, volume);
+
+        let δ = region_move_log_ratio(
+            priors,
+            params,
+            &transcripts,
+            &gene_count,
+            volume,
+            cell,
+            new_cell,
+            false,
+            true,
+        ) + rates_log_ratio
+            // picking `cell` and the line, reversed by picking `new_cell` and `cell`
+            + (ncells as f32).ln()
+            - line_probability.ln()
+            - ((ncells + 1) as f32).ln()
+            - (neighbors.len() as f32).ln()
+            + self.boundary_penalty(&split, in_rest)
+            - self.perimeter_penalty(priors, || {
+                self.region_perimeter(&split, in_split) + self.region_perimeter(&rest, in_rest)
+                    - self.total_perimeter(cell)
+            });
+        if !accept(rng, δ) {
+            return false;
+        }
+
+        self.move_region(
+            priors,
+            params,
+            &split,
+            &transcripts,
+            volume,
+            cell,
+            new_cell,
+            false,
+            true,
+        );
+        cell_voxels[cell as usize] = rest;
+        cell_voxels[new_cell as usize] = split;
+        self.recompute_region_perimeter(cell, &cell_voxels[cell as usize]);
+        self.recompute_region_perimeter(new_cell, &cell_voxels[new_cell as usize]);
+
+        true
+    }
+
+    // Try merging `cell`, one of `ncells` non-empty cells, into a random
+    // neighboring cell. Returns the cell it was merged into, if `accept`
+    // accepted the move's log acceptance ratio.
+    fn propose_merge(
+        &mut self,
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        rng: &mut SamplerRng,
+        cell_voxels: &mut [Vec<Voxel>],
+        ncells: usize,
+        cell: CellIndex,
+        accept: impl FnOnce(&mut SamplerRng, f32) -> bool,
+    ) -> Option<CellIndex> {
+        let voxels = &cell_voxels[cell as usize];
+        let neighbors = self.neighbor_cells(voxels, cell, |voxel| self.voxel_cells.get(voxel));
+        if neighbors.is_empty() {
+            return None;
+        }
+        let to = neighbors[rng.gen_range(0..neighbors.len())];
+
+        let to_voxels = &cell_voxels[to as usize];
+        let merged_voxels = voxels.iter().chain(to_voxels).cloned().collect::<Vec<_>>();
+        let in_cell = |voxel: Voxel| self.voxel_cells.get(voxel) == cell;
+        let in_to = |voxel: Voxel| self.voxel_cells.get(voxel) == to;
+        let in_merged = |voxel: Voxel| in_cell(voxel) || in_to(voxel);
+        if !self.splittable(priors, &merged_voxels, voxels, to_voxels, in_merged, in_cell, in_to) {
+            return None;
+        }
+
+        // probability of the reverse split cutting `cell` back off
+        let line_probability =
+            split_line_probability(&merged_voxels, voxel_centroid(&merged_voxels), in_cell);
+        if line_probability == 0.0 {
+            return None;
+        }
+
+        let (transcripts, gene_count) = self.region_transcripts(params, voxels, cell);
+        let volume = voxels.len() as f32 * self.voxel_volume;
+
+        let δ = region_move_log_ratio(
+            priors,
+            params,
+            &transcripts,
+            &gene_count,
+            volume,
+            cell,
+            to,
+            true,
+            false,
+        ) - params.new_cell_rates_log_ratio(cell, &gene_count, volume)
+            // picking `cell` and `to`, reversed by picking `to` and the line
+            + (ncells as f32).ln()
+            + (neighbors.len() as f32).ln()
+            - ((ncells - 1) as f32).ln()
+            + line_probability.ln()
+            - self.boundary_penalty(voxels, in_to)
+            - self.perimeter_penalty(priors, || {
+                self.region_perimeter(&merged_voxels, in_merged)
+                    - self.total_perimeter(cell)
+                    - self.total_perimeter(to)
+            });
+        if !accept(rng, δ) {
+            return None;
+        }
+
+        let voxels = std::mem::take(&mut cell_voxels[cell as usize]);
+        self.move_region(
+            priors,
+            params,
+            &voxels,
+            &transcripts,
+            volume,
+            cell,
+            to,
+            true,
+            false,
+        );
+        cell_voxels[to as usize].extend(voxels);
+        self.recompute_region_perimeter(cell, &[]);
+        self.recompute_region_perimeter(to, &cell_voxels[to as usize]);
+
+        Some(to)
+    }
+
+    // Non-background cells other than `cell` adjacent to any of `voxels`, with
+    // `cell_of` giving the cell of each voxel.
+    fn neighbor_cells(
+        &self,
+        voxels: &[Voxel],
+        cell: CellIndex,
+        cell_of: impl Fn(Voxel) -> CellIndex,
+    ) -> Vec<CellIndex> {
+        let mut neighbors = voxels
+            .iter()
+            .flat_map(|voxel| voxel.von_neumann_neighborhood())
+            .filter(|neighbor| neighbor.inbounds(self.voxel_layers))
+            .map(cell_of)
+            .filter(|&neighbor_cell| neighbor_cell != BACKGROUND_CELL && neighbor_cell != cell)
+            .collect::<Vec<_>>();
+        neighbors.sort_unstable();
+        neighbors.dedup();
+        neighbors
+    }
+
+    // Whether a cell made up of `whole` can be split into `a` and `b`, or
+    // merged from them, with `in_whole`, `in_a`, and `in_b` testing membership
+    // of each. The conditions are the same either way, so that a split is
+    // possible just when merging it back is.
+    #[allow(clippy::too_many_arguments)]
+    fn splittable(
+        &self,
+        priors: &ModelPriors,
+        whole: &[Voxel],
+        a: &[Voxel],
+        b: &[Voxel],
+        in_whole: impl Fn(Voxel) -> bool,
+        in_a: impl Fn(Voxel) -> bool,
+        in_b: impl Fn(Voxel) -> bool,
+    ) -> bool {
+        if a.is_empty() || b.is_empty() {
+            return false;
+        }
+
+        if priors.enforce_connectivity && !(self.is_connected(a, &in_a) && self.is_connected(b, &in_b)) {
+            return false;
+        }
+
+        let whole_ratio = self.perimeter_bound_ratio(priors, whole, in_whole);
+        let parts_ratio = self
+            .perimeter_bound_ratio(priors, a, in_a)
+            .max(self.perimeter_bound_ratio(priors, b, in_b));
+        parts_ratio <= whole_ratio.max(1.0)
+            && whole_ratio <= parts_ratio.max(1.0)
+            && self.within_max_cell_radius(priors, whole)
+            && self.within_max_cell_radius(priors, a)
+            && self.within_max_cell_radius(priors, b)
+    }
+
+    // Try creating `new_cell`, with `ncells` non-empty cells, from the
+    // unassigned voxels around a random unassigned transcript. Returns true if
+    // the birth was accepted.
+    #[allow(clippy::too_many_arguments)]
+    fn propose_birth(
+        &mut self,
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        rng: &mut SamplerRng,
+        cell_voxels: &mut [Vec<Voxel>],
+        ncells: usize,
+        new_cell: CellIndex,
+        radius: f32,
+        accept: impl FnOnce(&mut SamplerRng, f32) -> bool,
+    ) -> bool {
+        let t = rng.gen_range(0..self.transcript_voxels.len());
+        let seed = self.transcript_voxels[t];
+        let vacant = |voxel: Voxel| self.voxel_cells.get(voxel) == BACKGROUND_CELL;
+        if params.cell_assignments[t] != BACKGROUND_CELL || !vacant(seed) {
+            return false;
+        }
+
+        let region = self.birth_region(seed, radius, vacant);
+        let region_set = region.iter().cloned().collect::<HashSet<_, FixedState>>();
+        let in_region = |voxel: Voxel| region_set.contains(&voxel);
+        if !self.within_perimeter_bounds(priors, &[], &region, in_region)
+            || !self.within_max_cell_radius(priors, &region)
+        {
+            return false;
+        }
+
+        let nseeds = self.birth_seed_count(&region, &region_set, radius, vacant, |t| {
+            params.cell_assignments[t] == BACKGROUND_CELL
+        });
+
+        let (transcripts, gene_count) = self.region_transcripts(params, &region, BACKGROUND_CELL);
+        let volume = region.len() as f32 * self.voxel_volume;
+
+        // The component is drawn from its prior, so the two cancel.
+        params.z[new_cell as usize] = params.random_component(rng);
+        let rates_log_ratio = params.draw_new_cell_rates(rng, new_cell, &gene_count, volume);
+
+        let δ = region_move_log_ratio(
+            priors,
+            params,
+            &transcripts,
+            &gene_count,
+            volume,
+            BACKGROUND_CELL,
+            new_cell,
+            false,
+            true,
+        ) + rates_log_ratio
+            // picking any transcript seeding the region, reversed by picking `new_cell`
+            + (self.transcript_voxels.len() as f32).ln()
+            - (nseeds as f32).ln()
+            - ((ncells + 1) as f32).ln()
+            - 0.5 * self.boundary_penalty(&region, in_region)
+            - self.perimeter_penalty(priors, || self.region_perimeter(&region, in_region));
+        if !accept(rng, δ) {
+            return false;
+        }
+
+        self.move_region(
+            priors,
+            params,
+            &region,
+            &transcripts,
+            volume,
+            BACKGROUND_CELL,
+            new_cell,
+            false,
+            true,
+        );
+        cell_voxels[new_cell as usize] = region;
+        self.recompute_region_perimeter(new_cell, &cell_voxels[new_cell as usize]);
+
+        true
+    }
+
+    // Try returning all of `cell`, one of `ncells` non-empty cells, to the
+    // background. Returns true if the death was accepted.
+    #[allow(clippy::too_many_arguments)]
+    fn propose_death(
+        &mut self,
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        rng: &mut SamplerRng,
+        cell_voxels: &mut [Vec<Voxel>],
+        ncells: usize,
+        cell: CellIndex,
+        radius: f32,
+        accept: impl FnOnce(&mut SamplerRng, f32) -> bool,
+    ) -> bool {
+        let voxels = &cell_voxels[cell as usize];
+        let in_cell = |voxel: Voxel| self.voxel_cells.get(voxel) == cell;
+        if !self.within_perimeter_bounds(priors, &[], voxels, in_cell)
+            || !self.within_max_cell_radius(priors, voxels)
+        {
+            return false;
+        }
+
+        // transcripts that could seed a birth of the cell, once it's gone
+        let voxel_set = voxels.iter().cloned().collect::<HashSet<_, FixedState>>();
+        let vacant = |voxel: Voxel| {
+            let voxel_cell = self.voxel_cells.get(voxel);
+            voxel_cell == BACKGROUND_CELL || voxel_cell == cell
+        };
+        let nseeds = self.birth_seed_count(voxels, &voxel_set, radius, vacant, |t| {
+            let t_cell = params.cell_assignments[t];
+            t_cell == BACKGROUND_CELL || t_cell == cell
+        });
+        if nseeds == 0 {
+            return false;
+        }
 
-        // 85.4s
-        // let t0 = Instant::now();
-        sampler.recompute_cell_perimeter();
-        // println!("recompute_cell_perimeter: {:?}", t0.elapsed());
+        let (transcripts, gene_count) = self.region_transcripts(params, voxels, cell);
+        let volume = voxels.len() as f32 * self.voxel_volume;
+
+        let δ = region_move_log_ratio(
+            priors,
+            params,
+            &transcripts,
+            &gene_count,
+            volume,
+            cell,
+            BACKGROUND_CELL,
+            true,
+            false,
+        ) - params.new_cell_rates_log_ratio(cell, &gene_count, volume)
+            // picking `cell`, reversed by picking any transcript seeding it
+            + (ncells as f32).ln()
+            + (nseeds as f32).ln()
+            - (self.transcript_voxels.len() as f32).ln()
+            + 0.5 * self.boundary_penalty(voxels, in_cell)
+            + self.perimeter_penalty(priors, || self.total_perimeter(cell));
+        if !accept(rng, δ) {
+            return false;
+        }
 
-        sampler.update_transcript_positions(
-            &vec![true; params.transcript_positions.len()],
-            &params.transcript_positions,
+        let voxels = std::mem::take(&mut cell_voxels[cell as usize]);
+        self.move_region(
+            priors,
+            params,
+            &voxels,
+            &transcripts,
+            volume,
+            cell,
+            BACKGROUND_CELL,
+            true,
+            false,
         );
+        self.recompute_region_perimeter(cell, &[]);
 
-        sampler
+        true
     }
 
-    fn recompute_cell_volume(&mut self, priors: &ModelPriors, params: &mut ModelParams) {
-        // recompute cell areas as the sum of rect areas
-        params.cell_volume.fill(0.0_f32);
-        for (_, &cell) in self.voxel_cells.iter() {
-            if cell == BACKGROUND_CELL {
+    // The region a birth starting from a transcript in `seed` takes: the
+    // `vacant` voxels connected to it within `radius` (in voxels), not growing
+    // past the z extents of the data.
+    fn birth_region(&self, seed: Voxel, radius: f32, vacant: impl Fn(Voxel) -> bool) -> Vec<Voxel> {
+        let in_reach = |voxel: Voxel| {
+            let (di, dj) = ((voxel.i - seed.i) as f32, (voxel.j - seed.j) as f32);
+            let (_, _, z0, _, _, z1) = self.chunkquad.layout.voxel_to_world_coords(voxel);
+            voxel.inbounds(self.voxel_layers)
+                && di * di + dj * dj <= radius * radius
+                && z1 >= self.zmin
+                && z0 <= self.zmax
+                && vacant(voxel)
+        };
+        let mut region_set = HashSet::<Voxel, FixedState>::default();
+        region_set.insert(seed);
+        let mut region = vec![seed];
+        let mut next = 0;
+        while next < region.len() {
+            for neighbor in region[next].von_neumann_neighborhood() {
+                if in_reach(neighbor) && region_set.insert(neighbor) {
+                    region.push(neighbor);
+                }
+            }
+            next += 1;
+        }
+        region
+    }
+
+    // Number of transcripts (those for which `seeds` is true) that would grow
+    // a birth of exactly `region`, given which voxels are `vacant`.
+    fn birth_seed_count(
+        &self,
+        region: &[Voxel],
+        region_set: &HashSet<Voxel, FixedState>,
+        radius: f32,
+        vacant: impl Fn(Voxel) -> bool,
+        seeds: impl Fn(usize) -> bool,
+    ) -> usize {
+        let mut count = 0;
+        for &voxel in region {
+            let nseeds = self.voxel_transcripts(voxel).filter(|&t| seeds(t)).count();
+            if nseeds == 0 {
                 continue;
             }
-            params.cell_volume[cell as usize] += self.voxel_volume;
+            let seeded = self.birth_region(voxel, radius, &vacant);
+            if seeded.len() == region.len() && seeded.iter().all(|voxel| region_set.contains(voxel)) {
+                count += nseeds;
+            }
         }
+        count
+    }
 
-        for cell_volume in params.cell_volume.iter_mut() {
-            assert!(*cell_volume > 0.0);
-            *cell_volume = cell_volume.max(priors.min_cell_volume);
+    // Transcripts in `voxel`.
+    fn voxel_transcripts(&self, voxel: Voxel) -> impl Iterator<Item = usize> + '_ {
+        let start = self
+            .transcript_voxel_ord
+            .partition_point(|&t| self.transcript_voxels[t] < voxel);
+        self.transcript_voxel_ord[start..]
+            .iter()
+            .cloned()
+            .take_while(move |&t| self.transcript_voxels[t] == voxel)
+    }
+
+    // Transcripts in `cell` within the given voxels, and their counts
+    // ([ngenes, nlayers]).
+    fn region_transcripts(
+        &self,
+        params: &ModelParams,
+        voxels: &[Voxel],
+        cell: CellIndex,
+    ) -> (Vec<usize>, Array2<u32>) {
+        let mut transcripts = Vec::new();
+        let mut gene_count = Array2::zeros(self.proposals[0].genepop.raw_dim());
+        for &voxel in voxels {
+            for t in self.voxel_transcripts(voxel) {
+                if params.cell_assignments[t] == cell {
+                    transcripts.push(t);
+                    let layer = self.transcript_layers[t] as usize;
+                    gene_count[[self.transcript_genes[t] as usize, layer]] += 1;
+                }
+            }
         }
+        (transcripts, gene_count)
     }
 
-    fn recompute_cell_population(&mut self) {
-        self.cell_population.fill(0.0_f32);
-        for (&voxel, &cell) in self.voxel_cells.iter() {
-            if cell == BACKGROUND_CELL {
-                continue;
+    // Whether `voxels` (for which `member` is true) form one connected component.
+    fn is_connected(&self, voxels: &[Voxel], member: impl Fn(Voxel) -> bool) -> bool {
+        let mut visited = HashSet::<Voxel, FixedState>::default();
+        let mut stack = vec![voxels[0]];
+        visited.insert(voxels[0]);
+        while let Some(voxel) = stack.pop() {
+            for neighbor in voxel.von_neumann_neighborhood() {
+                if neighbor.inbounds(self.voxel_layers)
+                    && member(neighbor)
+                    && visited.insert(neighbor)
+                {
+                    stack.push(neighbor);
+                }
             }
-            self.cell_population[[voxel.k as usize, cell as usize]] += 1.0_f32;
         }
+        visited.len() == voxels.len()
     }
 
-    fn recompute_cell_perimeter(&mut self) {
-        // TODO: This function is super expensive. It's hard to update in parallel
-        // though, each cell consists of many voxels. So we'd either need mutexes
-        // or more likelily, organize things by cell first.
+    // Whether a cell made up of `voxels` (for which `member` is true) is, in
+    // every layer, no further past the perimeter bound than the worst of
+    // `cells`, which it's made from.
+    fn within_perimeter_bounds(
+        &self,
+        priors: &ModelPriors,
+        cells: &[CellIndex],
+        voxels: &[Voxel],
+        member: impl Fn(Voxel) -> bool,
+    ) -> bool {
+        let bound_ratio = |population: f32, perimeter: f32| {
+            perimeter / perimeter_bound(priors.perimeter_eta, priors.perimeter_bound, population)
+        };
 
-        self.cell_perimeter.fill(0.0_f32);
-        for (&voxel, &cell) in self.voxel_cells.iter() {
-            if cell == BACKGROUND_CELL {
-                continue;
+        let mut max_bound_ratio = 1.0_f32;
+        for &cell in cells {
+            for (&population, &perimeter) in self
+                .cell_population
+                .column(cell as usize)
+                .iter()
+                .zip(self.cell_perimeter.column(cell as usize))
+            {
+                if population > 0.0 {
+                    max_bound_ratio = max_bound_ratio.max(bound_ratio(population, perimeter));
+                }
             }
+        }
+
+        self.perimeter_bound_ratio(priors, voxels, member) <= max_bound_ratio
+    }
 
+    // The largest ratio, over layers, of the perimeter of a cell made up of
+    // `voxels` (for which `member` is true) to the perimeter bound.
+    fn perimeter_bound_ratio(
+        &self,
+        priors: &ModelPriors,
+        voxels: &[Voxel],
+        member: impl Fn(Voxel) -> bool,
+    ) -> f32 {
+        let mut population = vec![0.0_f32; self.voxel_layers];
+        let mut perimeter = vec![0.0_f32; self.voxel_layers];
+        for &voxel in voxels {
+            population[voxel.k as usize] += 1.0;
             for neighbor in voxel.radius2_xy_neighborhood() {
-                let neighbor_cell = self.voxel_cells.get(neighbor);
-                if neighbor_cell != cell {
-                    self.cell_perimeter[[voxel.k as usize, cell as usize]] += 1.0_f32;
+                if !member(neighbor) {
+                    perimeter[voxel.k as usize] += 1.0;
                 }
             }
         }
+
+        population
+            .iter()
+            .zip(&perimeter)
+            .filter(|(&population, _)| population > 0.0)
+            .map(|(&population, &perimeter)| {
+                perimeter / perimeter_bound(priors.perimeter_eta, priors.perimeter_bound, population)
+            })
+            .fold(0.0, f32::max)
     }
 
-    fn populate_mismatches(&mut self) {
-        for (&voxel, &cell) in self.voxel_cells.iter() {
-            let (chunk, quad) = self.chunkquad.get(voxel);
-            for neighbor in voxel.von_neumann_neighborhood() {
-                // don't consider neighbors that are out of bounds on the z-axis
-                if neighbor.k < 0 || neighbor.k >= self.voxel_layers as i32 {
-                    continue;
-                }
+    // Move a whole region of voxels, and the transcripts in them, between cells
+    // (or the background).
+    #[allow(clippy::too_many_arguments)]
+    fn move_region(
+        &mut self,
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        voxels: &[Voxel],
+        transcripts: &[usize],
+        volume: f32,
+        from: CellIndex,
+        to: CellIndex,
+        from_emptied: bool,
+        to_created: bool,
+    ) {
+        params.move_cell_region(
+            priors,
+            &self.transcript_genes,
+            transcripts,
+            volume,
+            from,
+            to,
+            from_emptied,
+            to_created,
+        );
 
-                let neighbor_cell = self.voxel_cells.get(neighbor);
-                if cell != neighbor_cell {
-                    let (neighbor_chunk, neighbor_quad) = self.chunkquad.get(neighbor);
+        for &voxel in voxels {
+            self.voxel_cells.set(voxel, to);
+            if from != BACKGROUND_CELL {
+                self.cell_population[[voxel.k as usize, from as usize]] -= 1.0;
+            }
+            if to != BACKGROUND_CELL {
+                self.cell_population[[voxel.k as usize, to as usize]] += 1.0;
+            }
+        }
 
-                    let mismatch_edges = &self.mismatch_edges[quad as usize];
-                    if (chunk as usize) < mismatch_edges.len() {
-                        mismatch_edges[chunk as usize]
-                            .lock()
-                            .unwrap()
-                            .insert((voxel, neighbor));
-                    }
+        for &voxel in voxels {
+            self.update_mismatch_edges(voxel, to);
+        }
+    }
 
-                    let mismatch_edges = &self.mismatch_edges[neighbor_quad as usize];
-                    if (neighbor_chunk as usize) < mismatch_edges.len() {
-                        mismatch_edges[neighbor_chunk as usize]
-                            .lock()
-                            .unwrap()
-                            .insert((neighbor, voxel));
-                    }
+    // Recompute the perimeter of a single cell, consisting of `voxels`.
+    fn recompute_region_perimeter(&mut self, cell: CellIndex, voxels: &[Voxel]) {
+        self.cell_perimeter.column_mut(cell as usize).fill(0.0);
+        for &voxel in voxels {
+            for neighbor in voxel.radius2_xy_neighborhood() {
+                if self.voxel_cells.get(neighbor) != cell {
+                    self.cell_perimeter[[voxel.k as usize, cell as usize]] += 1.0;
                 }
             }
         }
     }
 
     pub fn voxels(&self) -> impl Iterator<Item = (CellIndex, (f32, f32, f32, f32, f32, f32))> + '_ {
-        return self
-            .voxel_cells
+        self.voxel_cells
             .iter()
             .filter(|(_, &cell)| cell != BACKGROUND_CELL)
-            .map(|(voxel, cell)| (*cell, self.chunkquad.layout.voxel_to_world_coords(*voxel)));
+            .map(|(voxel, cell)| (*cell, self.chunkquad.layout.voxel_to_world_coords(*voxel)))
+    }
+
+    // Every voxel that's assigned to a cell or contains transcripts, as (cell,
+    // world coordinates, number of transcripts), with BACKGROUND_CELL for
+    // unassigned voxels. Ordered by layer, then row, then column.
+    pub fn voxel_transcript_counts(&self) -> Vec<VoxelTranscriptCount> {
+        let mut counts: HashMap<Voxel, u32> = HashMap::new();
+        for &voxel in &self.transcript_voxels {
+            *counts.entry(voxel).or_insert(0) += 1;
+        }
+        for (&voxel, &cell) in self.voxel_cells.iter() {
+            if cell != BACKGROUND_CELL {
+                counts.entry(voxel).or_insert(0);
+            }
+        }
+
+        counts
+            .into_iter()
+            .sorted_by_key(|(voxel, _)| (voxel.k, voxel.j, voxel.i))
+            .map(|(voxel, count)| {
+                (
+                    self.voxel_cells.get(voxel),
+                    self.chunkquad.layout.voxel_to_world_coords(voxel),
+                    count,
+                )
+            })
+            .collect()
+    }
+
+    // Recompute each cell's anchor, from which it can't grow further than
+    // `max_cell_radius`. Empty cells are given no anchor.
+    pub fn update_cell_anchors(&mut self) {
+        let population = self.cell_population.sum_axis(Axis(0));
+        self.cell_anchors = self
+            .cell_centroids()
+            .iter()
+            .zip(&population)
+            .map(|(&(x, y, _), &population)| {
+                if population > 0.0 {
+                    (x, y)
+                } else {
+                    (f32::NAN, f32::NAN)
+                }
+            })
+            .collect();
+    }
+
+    // Whether a cell made up of `voxels` fits within `max_cell_radius` of its
+    // own centroid.
+    fn within_max_cell_radius(&self, priors: &ModelPriors, voxels: &[Voxel]) -> bool {
+        let max_radius = match priors.max_cell_radius {
+            Some(max_radius) => max_radius,
+            None => return true,
+        };
+        let positions: Vec<(f32, f32)> = voxels
+            .iter()
+            .map(|&voxel| {
+                let (x, y, _) = self.chunkquad.layout.voxel_to_world_pos(voxel);
+                (x, y)
+            })
+            .collect();
+        let n = positions.len().max(1) as f32;
+        let x = positions.iter().map(|p| p.0).sum::<f32>() / n;
+        let y = positions.iter().map(|p| p.1).sum::<f32>() / n;
+        positions
+            .iter()
+            .all(|&(vx, vy)| (vx - x).hypot(vy - y) <= max_radius)
+    }
+
+    // Whether each cell has no voxels.
+    pub fn empty_cells(&self) -> Vec<bool> {
+        self.cell_population
+            .columns()
+            .into_iter()
+            .map(|population| population.sum() == 0.0)
+            .collect()
     }
 
     pub fn cell_centroids(&self) -> Vec<(f32, f32, f32)> {
@@ -802,6 +2416,100 @@ impl VoxelSampler {
         centroids
     }
 
+    // Pairs of cells that share a boundary, as (cell_a, cell_b, length) with
+    // cell_a < cell_b, where length is that of the voxel edges between them,
+    // averaged over voxel layers. Cells touching only vertically aren't
+    // included.
+    pub fn cell_adjacency(&self) -> Vec<(CellIndex, CellIndex, f32)> {
+        let size = self.chunkquad.layout.size;
+        let mut lengths: HashMap<(CellIndex, CellIndex), f32> = HashMap::new();
+        for (&voxel, &cell) in self.voxel_cells.iter() {
+            if cell == BACKGROUND_CELL {
+                continue;
+            }
+
+            // count each edge once, from the voxel on its lower side
+            let neighbors = [
+                (Voxel::new(voxel.i + 1, voxel.j, voxel.k), size.1),
+                (Voxel::new(voxel.i, voxel.j + 1, voxel.k), size.0),
+            ];
+            for (neighbor, length) in neighbors {
+                let neighbor_cell = self.voxel_cells.get(neighbor);
+                if neighbor_cell != BACKGROUND_CELL && neighbor_cell != cell {
+                    *lengths
+                        .entry((cell.min(neighbor_cell), cell.max(neighbor_cell)))
+                        .or_insert(0.0) += length;
+                }
+            }
+        }
+
+        let mut adjacency: Vec<(CellIndex, CellIndex, f32)> = lengths
+            .into_iter()
+            .map(|((a, b), length)| (a, b, length / self.voxel_layers as f32))
+            .collect();
+        adjacency.sort_by_key(|&(a, b, _)| (a, b));
+        adjacency
+    }
+
+    pub fn cell_shapes(&self) -> Vec<CellShape> {
+        let mut cell_footprints = vec![HashSet::new(); self.ncells()];
+        let mut cell_layers = vec![HashSet::new(); self.ncells()];
+        for (voxel, &cell) in self.voxel_cells.iter() {
+            if cell != BACKGROUND_CELL {
+                cell_footprints[cell as usize].insert((voxel.i, voxel.j));
+                cell_layers[cell as usize].insert(voxel.k);
+            }
+        }
+
+        let (sx, sy) = (self.chunkquad.layout.size.0, self.chunkquad.layout.size.1);
+        cell_footprints
+            .par_iter()
+            .zip(cell_layers.par_iter())
+            .map(|(footprint, layers)| {
+                if footprint.is_empty() {
+                    return CellShape {
+                        elongation: 0.0,
+                        orientation: 0.0,
+                        solidity: 0.0,
+                        zlayers: 0,
+                    };
+                }
+
+                let n = footprint.len() as f32;
+                let (mi, mj) = footprint
+                    .iter()
+                    .fold((0.0, 0.0), |(mi, mj), &(i, j)| (mi + i as f32 / n, mj + j as f32 / n));
+
+                // every voxel adds the variance of a uniform square to that of
+                // the voxel centers
+                let (mut cxx, mut cyy, mut cxy) = (sx * sx / 12.0, sy * sy / 12.0, 0.0);
+                for &(i, j) in footprint {
+                    let dx = (i as f32 - mi) * sx;
+                    let dy = (j as f32 - mj) * sy;
+                    cxx += dx * dx / n;
+                    cyy += dy * dy / n;
+                    cxy += dx * dy / n;
+                }
+                let mean = (cxx + cyy) / 2.0;
+                let d = ((cxx - cyy) / 2.0).hypot(cxy);
+
+                let corners: MultiPoint<f32> = footprint
+                    .iter()
+                    .flat_map(|&(i, j)| [(i, j), (i + 1, j), (i, j + 1), (i + 1, j + 1)])
+                    .map(|(i, j)| Point::new((i as f32 - mi) * sx, (j as f32 - mj) * sy))
+                    .collect();
+                let hull_area = corners.convex_hull().unsigned_area();
+
+                CellShape {
+                    elongation: ((mean + d) / (mean - d)).sqrt(),
+                    orientation: 0.5 * (2.0 * cxy).atan2(cxx - cyy),
+                    solidity: (n * sx * sy / hull_area).min(1.0),
+                    zlayers: layers.len() as u32,
+                }
+            })
+            .collect()
+    }
+
     pub fn cell_polygons(&self) -> (Vec<CellPolygonLayers>, Vec<CellPolygon>) {
         // Build sets of voxels for each cell
         let mut cell_voxels = vec![HashSet::new(); self.ncells()];
@@ -924,7 +2632,7 @@ impl VoxelSampler {
             .collect();
         // println!("build polygons: {:?}", t0.elapsed());
 
-        return cell_polygons;
+        cell_polygons
     }
 
     // pub fn mismatch_edge_stats(&self) -> (usize, usize) {
@@ -1038,11 +2746,28 @@ impl Sampler<VoxelProposal> for VoxelSampler {
     fn repopulate_proposals(&mut self, priors: &ModelPriors, params: &ModelParams) {
         const UNASSIGNED_PROPOSAL_PROB: f64 = 0.01;
 
+        // Mismatching edges are chosen uniformly, then kept with probability
+        // given by their weight, so proposal probabilities below sum edge
+        // weights rather than counting edges.
+        let (lateral_weight, vertical_weight) = self.chunkquad.layout.edge_weights(priors.z_scale);
+        let edge_weight = |i: &Voxel, j: &Voxel| {
+            if i.k == j.k {
+                lateral_weight
+            } else {
+                vertical_weight
+            }
+        };
+
+        // log probability penalty per mismatching neighbor in a cell's perimeter
+        let perimeter_penalty = self.perimeter_penalty(priors, || 1.0);
+
+        let stream = rng::next_stream();
         self.proposals
             .par_iter_mut()
             // .iter_mut()
             .zip(&self.mismatch_edges[self.quad])
-            .for_each(|(proposal, mismatch_edges)| {
+            .enumerate()
+            .for_each(|(chunk, (proposal, mismatch_edges))| {
                 proposal.old_cell = BACKGROUND_CELL;
                 proposal.new_cell = BACKGROUND_CELL;
                 proposal.ignore = false;
@@ -1054,9 +2779,15 @@ impl Sampler<VoxelProposal> for VoxelSampler {
                     return;
                 }
 
-                let mut rng = thread_rng();
+                let mut rng = rng::stream_rng(stream, chunk);
                 let (i, j) = mismatch_edges.choose(&mut rng).unwrap();
 
+                let weight = edge_weight(i, j);
+                if weight < 1.0 && rng.gen::<f64>() >= weight {
+                    proposal.ignore = true;
+                    return;
+                }
+
                 let cell_from = self.voxel_cells.get(*i);
                 let mut cell_to = self.voxel_cells.get(*j);
                 assert!(cell_from != cell_to);
@@ -1079,6 +2810,18 @@ impl Sampler<VoxelProposal> for VoxelSampler {
                     cell_to = BACKGROUND_CELL;
                 }
 
+                // don't let cells balloon out across sparse regions, by
+                // keeping them within `max_cell_radius` of their anchor
+                if let (Some(max_radius), Some(&(x, y))) =
+                    (priors.max_cell_radius, self.cell_anchors.get(cell_to as usize))
+                {
+                    let (vx, vy, _) = self.chunkquad.layout.voxel_to_world_pos(*i);
+                    if (vx - x).hypot(vy - y) > max_radius {
+                        proposal.ignore = true;
+                        return;
+                    }
+                }
+
                 // Local connectivity condition: don't propose changes that render increase the
                 // number of connected components of either the cell_from or cell_to
                 // neighbors subgraphs.
@@ -1121,34 +2864,39 @@ impl Sampler<VoxelProposal> for VoxelSampler {
                 // compute the probability of selecting the proposal (k, c)
                 let num_mismatching_edges = mismatch_edges.len();
 
-                let num_new_state_neighbors = i
+                let new_state_neighbors = i
                     .von_neumann_neighborhood()
-                    .iter()
-                    .filter(|&&j| {
+                    .into_iter()
+                    .filter(|&j| {
                         j.inbounds(self.voxel_layers) && self.voxel_cells.get(j) == cell_to
-                    })
-                    .count();
+                    });
+                let num_new_state_neighbors = new_state_neighbors.clone().count();
+                let new_state_neighbors_weight: f64 =
+                    new_state_neighbors.map(|j| edge_weight(i, &j)).sum();
 
-                let num_prev_state_neighbors = i
+                let prev_state_neighbors = i
                     .von_neumann_neighborhood()
-                    .iter()
-                    .filter(|&&j| {
+                    .into_iter()
+                    .filter(|&j| {
                         j.inbounds(self.voxel_layers) && self.voxel_cells.get(j) == cell_from
-                    })
-                    .count();
+                    });
+                let num_prev_state_neighbors = prev_state_neighbors.clone().count();
+                let prev_state_neighbors_weight: f64 =
+                    prev_state_neighbors.map(|j| edge_weight(i, &j)).sum();
 
                 let mut proposal_prob = (1.0 - UNASSIGNED_PROPOSAL_PROB)
-                    * (num_new_state_neighbors as f64 / num_mismatching_edges as f64);
+                    * (new_state_neighbors_weight / num_mismatching_edges as f64);
 
                 // If this is an unassigned proposal, account for multiple ways of doing unassigned proposals
                 if to_unassigned {
-                    let num_mismatching_neighbors = i
+                    let mismatching_neighbors_weight: f64 = i
                         .von_neumann_neighborhood()
                         .iter()
                         .filter(|&&j| self.voxel_cells.get(j) != cell_from)
-                        .count();
+                        .map(|j| edge_weight(i, j))
+                        .sum();
                     proposal_prob += UNASSIGNED_PROPOSAL_PROB
-                        * (num_mismatching_neighbors as f64 / num_mismatching_edges as f64);
+                        * (mismatching_neighbors_weight / num_mismatching_edges as f64);
                 }
 
                 let new_num_mismatching_edges = num_mismatching_edges
@@ -1156,17 +2904,18 @@ impl Sampler<VoxelProposal> for VoxelSampler {
                     - 2*num_new_state_neighbors; // edges that are newly matching
 
                 let mut reverse_proposal_prob = (1.0 - UNASSIGNED_PROPOSAL_PROB)
-                    * (num_prev_state_neighbors as f64 / new_num_mismatching_edges as f64);
+                    * (prev_state_neighbors_weight / new_num_mismatching_edges as f64);
 
                 // If this is a proposal from unassigned, account for multiple ways of reversing it
                 if from_unassigned {
-                    let new_num_mismatching_neighbors = i
+                    let new_mismatching_neighbors_weight: f64 = i
                         .von_neumann_neighborhood()
                         .iter()
                         .filter(|&&j| self.voxel_cells.get(j) != cell_to)
-                        .count();
+                        .map(|j| edge_weight(i, j))
+                        .sum();
                     reverse_proposal_prob += UNASSIGNED_PROPOSAL_PROB
-                        * (new_num_mismatching_neighbors as f64 / new_num_mismatching_edges as f64);
+                        * (new_mismatching_neighbors_weight / new_num_mismatching_edges as f64);
                 }
 
                 // if proposal_prob > 0.5 || reverse_proposal_prob > 0.5 {
@@ -1190,37 +2939,18 @@ impl Sampler<VoxelProposal> for VoxelSampler {
                 proposal.old_cell = cell_from;
                 proposal.new_cell = cell_to;
                 proposal.log_weight = (reverse_proposal_prob.ln() - proposal_prob.ln()) as f32;
+                if params.background_grid.is_some() {
+                    let (x, y, _) = self.chunkquad.layout.voxel_to_world_pos(*i);
+                    proposal.background_scale = params.background_scale(x, y);
+                }
+
                 proposal.ignore = false;
                 proposal.accept = false;
                 proposal.old_cell_volume_delta = -self.voxel_volume;
                 proposal.new_cell_volume_delta = self.voxel_volume;
 
-                proposal.old_cell_perimeter_delta = 0.0;
-                proposal.new_cell_perimeter_delta = 0.0;
-
-                for neighbor in i.radius2_xy_neighborhood() {
-                    let neighbor_cell = self.voxel_cells.get(neighbor);
-
-                    // cube i's new mismatches
-                    if neighbor_cell != cell_to {
-                        proposal.new_cell_perimeter_delta += 1.0;
-                    }
-
-                    // neighbors for whom i is no longer a mismatch
-                    if neighbor_cell == cell_to {
-                        proposal.new_cell_perimeter_delta -= 1.0;
-                    }
-
-                    // neighbors for whom i is now a mismatch
-                    if neighbor_cell == cell_from {
-                        proposal.old_cell_perimeter_delta += 1.0;
-                    }
-
-                    // neighbors of i that were previously counted as a mismatch
-                    if neighbor_cell != cell_from {
-                        proposal.old_cell_perimeter_delta -= 1.0;
-                    }
-                }
+                (proposal.old_cell_perimeter_delta, proposal.new_cell_perimeter_delta) =
+                    self.voxel_cells.perimeter_deltas(*i, cell_from, cell_to);
 
                 // reject in advance if the perimeter for this layer surpases
                 // the limit.
@@ -1268,6 +2998,17 @@ impl Sampler<VoxelProposal> for VoxelSampler {
                     }
                 }
 
+                proposal.log_prior_delta = voxel_move_log_prior_delta(
+                    self.boundary.as_deref(),
+                    &self.chunkquad.layout,
+                    &self.voxel_cells,
+                    perimeter_penalty,
+                    *i,
+                    cell_from,
+                    cell_to,
+                    (proposal.old_cell_perimeter_delta, proposal.new_cell_perimeter_delta),
+                );
+
                 // find transcripts within the voxel
                 let transcript_range_start = self
                     .transcript_voxel_ord
@@ -1328,55 +3069,12 @@ impl Sampler<VoxelProposal> for VoxelSampler {
             }
         }
 
+        // Mismatch edges are updated serially, since the order they are inserted
+        // in determines which are later proposed.
         self.proposals
-            .par_iter()
+            .iter()
             .filter(|p| !p.ignore && p.accept)
-            .for_each(|proposal| {
-                let (chunk, quad) = self.chunkquad.get(proposal.voxel);
-
-                // update mismatch edges
-                for neighbor in proposal.voxel.von_neumann_neighborhood() {
-                    if neighbor.k < 0 || neighbor.k >= self.voxel_layers as i32 {
-                        continue;
-                    }
-
-                    let (neighbor_chunk, neighbor_quad) = self.chunkquad.get(neighbor);
-                    let neighbor_cell = self.voxel_cells.get(neighbor);
-                    if proposal.new_cell == neighbor_cell {
-                        let mismatch_edges = &self.mismatch_edges[quad as usize];
-                        if (chunk as usize) < mismatch_edges.len() {
-                            mismatch_edges[chunk as usize]
-                                .lock()
-                                .unwrap()
-                                .remove((proposal.voxel, neighbor));
-                        }
-
-                        let mismatch_edges = &self.mismatch_edges[neighbor_quad as usize];
-                        if (neighbor_chunk as usize) < mismatch_edges.len() {
-                            mismatch_edges[neighbor_chunk as usize]
-                                .lock()
-                                .unwrap()
-                                .remove((neighbor, proposal.voxel));
-                        }
-                    } else {
-                        let mismatch_edges = &self.mismatch_edges[quad as usize];
-                        if (chunk as usize) < mismatch_edges.len() {
-                            mismatch_edges[chunk as usize]
-                                .lock()
-                                .unwrap()
-                                .insert((proposal.voxel, neighbor));
-                        }
-
-                        let mismatch_edges = &self.mismatch_edges[neighbor_quad as usize];
-                        if (neighbor_chunk as usize) < mismatch_edges.len() {
-                            mismatch_edges[neighbor_chunk as usize]
-                                .lock()
-                                .unwrap()
-                                .insert((neighbor, proposal.voxel));
-                        }
-                    }
-                }
-            });
+            .for_each(|proposal| self.update_mismatch_edges(proposal.voxel, proposal.new_cell));
     }
 
     fn cell_at_position(&self, position: (f32, f32, f32)) -> u32 {
@@ -1385,6 +3083,12 @@ impl Sampler<VoxelProposal> for VoxelSampler {
         self.voxel_cells.get(cubindex)
     }
 
+    fn editable_position(&self, position: (f32, f32, f32)) -> bool {
+        self.editable_region
+            .as_ref()
+            .is_none_or(|region| region.contains(position.0, position.1))
+    }
+
     fn update_transcript_positions(&mut self, updated: &[bool], positions: &[(f32, f32, f32)]) {
         self.transcript_voxels
             .par_iter_mut()
@@ -1519,6 +3223,12 @@ pub struct VoxelProposal {
     // metroplis-hastings proposal weight weight
     log_weight: f32,
 
+    // change in log prior not computed by `Proposal::evaluate`
+    log_prior_delta: f32,
+
+    // background rate scale at the voxel
+    background_scale: f32,
+
     ignore: bool,
     accept: bool,
 
@@ -1539,6 +3249,8 @@ impl VoxelProposal {
             old_cell: 0,
             new_cell: 0,
             log_weight: 0.0,
+            log_prior_delta: 0.0,
+            background_scale: 1.0,
             ignore: false,
             accept: false,
             old_cell_volume_delta: 0.0,
@@ -1584,6 +3296,14 @@ impl Proposal for VoxelProposal {
         self.log_weight
     }
 
+    fn log_prior_delta(&self) -> f32 {
+        self.log_prior_delta
+    }
+
+    fn background_scale(&self) -> f32 {
+        self.background_scale
+    }
+
     fn transcripts<'b, 'c>(&'b self) -> &'c [usize]
     where
         'b: 'c,
@@ -1655,3 +3375,346 @@ pub fn filter_sparse_cells(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::CellQc;
+    use crate::sampler::boundary::BoundaryPrior;
+    use crate::sampler::transcripts::postprocess_cell_assignments;
+    use ndarray::s;
+
+    // Run `f` with its own seeded random streams, so tests running at once
+    // don't draw from each other's.
+    fn seeded<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+        rng::thread_pool(1).install(|| {
+            rng::set_seed(0);
+            f()
+        })
+    }
+
+    // A sampler over square clusters of 10x10 transcripts, centered at
+    // `centers`, each with a cell starting from the nucleus in its middle.
+    fn cluster_sampler(centers: &[(f32, f32)]) -> (ModelPriors, ModelParams, VoxelSampler) {
+        let mut transcripts = Vec::new();
+        let mut nucleus_assignments = Vec::new();
+        for (cell, &(cx, cy)) in centers.iter().enumerate() {
+            for i in 0..100 {
+                let (dx, dy) = ((i % 10) as f32 - 4.5, (i / 10) as f32 - 4.5);
+                transcripts.push(Transcript {
+                    transcript_id: transcripts.len() as u64,
+                    x: cx + dx,
+                    y: cy + dy,
+                    z: 0.0,
+                    gene: (i % 3) as u32,
+                    fov: 0,
+                });
+                let nuclear = dx.abs() < 3.0 && dy.abs() < 3.0;
+                nucleus_assignments.push(if nuclear { cell as CellIndex } else { BACKGROUND_CELL });
+            }
+        }
+        let mut cell_assignments = nucleus_assignments.clone();
+        let nucleus_population =
+            postprocess_cell_assignments(&mut nucleus_assignments, &mut cell_assignments);
+
+        let priors = ModelPriors::new(36.0, 0.0, 1.0);
+        let (xmin, xmax, ymin, ymax, _, _) = coordinate_span(&transcripts);
+        let mut params = ModelParams::new(
+            &priors,
+            (xmax - xmin) * (ymax - ymin),
+            0.0,
+            1.0,
+            &transcripts,
+            &nucleus_assignments,
+            &nucleus_population,
+            &cell_assignments,
+            2,
+            1,
+            centers.len(),
+            3,
+        );
+        let chunks = ChunkLayout::grid(xmin, xmax, ymin, ymax, 100.0);
+        let mut sampler =
+            VoxelSampler::new(&priors, &mut params, &transcripts, 3, 1, 1, 0.0, 1.0, 1.0, chunks);
+        sampler.initialize(&priors, &mut params);
+        (priors, params, sampler)
+    }
+
+    fn voxel_assignments(sampler: &VoxelSampler) -> Vec<(Voxel, CellIndex)> {
+        sampler
+            .voxel_cells
+            .iter()
+            .filter(|(_, &cell)| cell != BACKGROUND_CELL)
+            .map(|(&voxel, &cell)| (voxel, cell))
+            .sorted()
+            .collect()
+    }
+
+    #[test]
+    fn split_and_merge_are_reverses() {
+        seeded(|| {
+            let (priors, mut params, mut sampler) = cluster_sampler(&[(10.0, 10.0)]);
+            let voxels_before = voxel_assignments(&sampler);
+            let assignments_before = params.cell_assignments.clone();
+            let counts_before = params.counts.clone();
+            let perimeter_before = sampler.total_perimeter(0);
+
+            let mut regions = sampler.cell_regions();
+            let new_cell = sampler.free_cell(&priors, &mut params, &mut regions);
+            let mut rng = rng::rng();
+            let mut split_log_ratio = None;
+            for _ in 0..100 {
+                let split = sampler.propose_split(
+                    &priors,
+                    &mut params,
+                    &mut rng,
+                    &mut regions.voxels,
+                    1,
+                    0,
+                    new_cell,
+                    |_, δ| {
+                        split_log_ratio = Some(δ);
+                        true
+                    },
+                );
+                if split {
+                    break;
+                }
+            }
+            let split_log_ratio = split_log_ratio.expect("no split was possible");
+            assert!(params.cell_population[new_cell as usize] > 0);
+            assert_ne!(voxel_assignments(&sampler), voxels_before);
+
+            let mut merge_log_ratio = None;
+            let into = sampler.propose_merge(
+                &priors,
+                &mut params,
+                &mut rng,
+                &mut regions.voxels,
+                2,
+                new_cell,
+                |_, δ| {
+                    merge_log_ratio = Some(δ);
+                    true
+                },
+            );
+            assert_eq!(into, Some(0));
+            let merge_log_ratio = merge_log_ratio.unwrap();
+            assert!(
+                (split_log_ratio + merge_log_ratio).abs() <= 1e-3 * split_log_ratio.abs().max(1.0),
+                "split log ratio {} isn't the reverse of merge log ratio {}",
+                split_log_ratio,
+                merge_log_ratio
+            );
+
+            assert_eq!(voxel_assignments(&sampler), voxels_before);
+            assert_eq!(params.cell_assignments, assignments_before);
+            assert_eq!(params.counts.slice(s![.., 0..1, ..]), counts_before);
+            assert_eq!(params.cell_population[new_cell as usize], 0);
+            assert_eq!(sampler.total_perimeter(0), perimeter_before);
+            assert_eq!(sampler.total_perimeter(new_cell), 0.0);
+        });
+    }
+
+    // Create a cell by a birth move from the background, accepting the first
+    // birth proposed.
+    fn birth(
+        priors: &ModelPriors,
+        params: &mut ModelParams,
+        sampler: &mut VoxelSampler,
+        regions: &mut CellRegions,
+        rng: &mut SamplerRng,
+        radius: f32,
+    ) -> CellIndex {
+        let new_cell = sampler.free_cell(priors, params, regions);
+        for _ in 0..1000 {
+            if sampler.propose_birth(
+                priors,
+                params,
+                rng,
+                &mut regions.voxels,
+                regions.ncells,
+                new_cell,
+                radius,
+                |_, _| true,
+            ) {
+                regions.free.pop();
+                regions.ncells += 1;
+                return new_cell;
+            }
+        }
+        panic!("no birth was possible");
+    }
+
+    #[test]
+    fn birth_and_death_keep_cells_dense() {
+        seeded(|| {
+            let (priors, mut params, mut sampler) = cluster_sampler(&[(10.0, 10.0), (40.0, 10.0)]);
+            let mut rng = rng::rng();
+            let radius = 3.0;
+
+            let mut regions = sampler.cell_regions();
+            assert_eq!(birth(&priors, &mut params, &mut sampler, &mut regions, &mut rng, radius), 2);
+            assert_eq!(birth(&priors, &mut params, &mut sampler, &mut regions, &mut rng, radius), 3);
+
+            let died = sampler.propose_death(
+                &priors,
+                &mut params,
+                &mut rng,
+                &mut regions.voxels,
+                regions.ncells,
+                2,
+                radius,
+                |_, _| true,
+            );
+            assert!(died);
+            regions.remove(2, BACKGROUND_CELL);
+            regions.finish(&mut params);
+            assert!(params.cell_assignments.iter().all(|&cell| cell != 2));
+
+            // the dead cell is dropped from the output, and later cells renumbered
+            let empty = sampler.empty_cells();
+            assert_eq!(empty, vec![false, false, true, false]);
+            let counts = Array2::from_elem((3, 4), 10);
+            let qc = CellQc::new(&empty, &counts, &[100.0; 4], 0, 0.0);
+            assert_eq!(
+                (0..4).map(|cell| qc.renumber(cell)).collect::<Vec<_>>(),
+                vec![0, 1, BACKGROUND_CELL, 2]
+            );
+
+            // a new cell reuses the dead cell's index, rather than adding one
+            let mut regions = sampler.cell_regions();
+            assert_eq!(regions.ncells, 3);
+            assert_eq!(birth(&priors, &mut params, &mut sampler, &mut regions, &mut rng, radius), 2);
+            assert_eq!(params.ncells(), 4);
+            assert_eq!(sampler.empty_cells(), vec![false; 4]);
+        });
+    }
+
+    // Cell 0's voxels, with one removed from the middle of its left side to
+    // leave a notch, returning the notch and a voxel sticking out from the
+    // middle of its right side.
+    fn notch_and_protrusion(sampler: &mut VoxelSampler) -> (Voxel, Voxel) {
+        let voxels = sampler
+            .voxel_cells
+            .iter()
+            .filter(|(_, &cell)| cell == 0)
+            .map(|(&voxel, _)| voxel)
+            .collect::<Vec<_>>();
+        let imin = voxels.iter().map(|voxel| voxel.i).min().unwrap();
+        let imax = voxels.iter().map(|voxel| voxel.i).max().unwrap();
+        let jmin = voxels.iter().map(|voxel| voxel.j).min().unwrap();
+        let jmax = voxels.iter().map(|voxel| voxel.j).max().unwrap();
+        let jmid = (jmin + jmax) / 2;
+
+        let notch = Voxel::new(imin, jmid, 0);
+        sampler.voxel_cells.set(notch, BACKGROUND_CELL);
+        (notch, Voxel::new(imax + 1, jmid, 0))
+    }
+
+    // Log prior change from penalties for moving `voxel` from the background
+    // into cell 0.
+    fn growth_log_prior_delta(
+        sampler: &VoxelSampler,
+        boundary: Option<&BoundaryPrior>,
+        perimeter_penalty: f32,
+        voxel: Voxel,
+    ) -> f32 {
+        voxel_move_log_prior_delta(
+            boundary,
+            &sampler.chunkquad.layout,
+            &sampler.voxel_cells,
+            perimeter_penalty,
+            voxel,
+            BACKGROUND_CELL,
+            0,
+            sampler
+                .voxel_cells
+                .perimeter_deltas(voxel, BACKGROUND_CELL, 0),
+        )
+    }
+
+    #[test]
+    fn perimeter_penalty_favors_compact_shapes() {
+        seeded(|| {
+            let (_, _, mut sampler) = cluster_sampler(&[(10.0, 10.0)]);
+            let (notch, protrusion) = notch_and_protrusion(&mut sampler);
+
+            assert_eq!(growth_log_prior_delta(&sampler, None, 0.0, protrusion), 0.0);
+            let filling_notch = growth_log_prior_delta(&sampler, None, 1.0, notch);
+            let protruding = growth_log_prior_delta(&sampler, None, 1.0, protrusion);
+            assert!(protruding < 0.0);
+            assert!(filling_notch > protruding);
+
+            // and the reverse moves are penalized by the same amount
+            let cells = &mut sampler.voxel_cells;
+            let (from_delta, to_delta) = cells.perimeter_deltas(notch, BACKGROUND_CELL, 0);
+            cells.set(notch, 0);
+            assert_eq!(
+                cells.perimeter_deltas(notch, 0, BACKGROUND_CELL),
+                (-to_delta, -from_delta)
+            );
+        });
+    }
+
+    #[test]
+    fn boundary_penalty_favors_cells_not_crossing_boundaries() {
+        seeded(|| {
+            let (_, _, mut sampler) = cluster_sampler(&[(10.0, 10.0)]);
+            let (notch, protrusion) = notch_and_protrusion(&mut sampler);
+
+            // a vertical line of boundary, at ten pixels per unit, between the
+            // protruding voxel and the cell
+            let layout = &sampler.chunkquad.layout;
+            let (x, _, _) = layout.voxel_to_world_pos(protrusion);
+            let line = (10.0 * (x - 0.5 * layout.size.0)) as usize;
+            let (width, height) = (300, 300);
+            let values = (0..width * height)
+                .map(|k| if k % width == line { 1.0 } else { 0.0 })
+                .collect();
+            let weight = 2.0;
+            let transform = [10.0, 0.0, 0.0, 0.0, 10.0, 0.0];
+            let boundary = BoundaryPrior::new(width, height, values, transform, weight);
+
+            let boundary = Some(&boundary);
+            assert_eq!(growth_log_prior_delta(&sampler, boundary, 0.0, notch), 0.0);
+            assert_eq!(
+                growth_log_prior_delta(&sampler, boundary, 0.0, protrusion),
+                -weight
+            );
+        });
+    }
+
+    #[test]
+    fn penalties_are_tempered() {
+        seeded(|| {
+            let (priors, mut params, mut sampler) = cluster_sampler(&[(10.0, 10.0)]);
+            let (_, protrusion) = notch_and_protrusion(&mut sampler);
+            let mut rng = rng::rng();
+
+            // (background rates are only sampled once the chain runs)
+            params.λ_bg.fill(0.01);
+
+            // acceptances of growing into the protruding voxel, out of 100,
+            // leaving volumes unchanged so only the penalty matters
+            let mut accepted = |perimeter_penalty: f32, temperature: f32| {
+                let mut proposal = VoxelProposal::new(3, 1);
+                proposal.voxel = protrusion;
+                proposal.old_cell = BACKGROUND_CELL;
+                proposal.new_cell = 0;
+                proposal.log_prior_delta =
+                    growth_log_prior_delta(&sampler, None, perimeter_penalty, protrusion);
+                (0..100)
+                    .filter(|_| {
+                        proposal.evaluate(&priors, &params, temperature, &mut rng);
+                        proposal.accepted()
+                    })
+                    .count()
+            };
+
+            assert_eq!(accepted(0.0, 1.0), 100);
+            assert_eq!(accepted(10.0, 1.0), 0);
+            assert!(accepted(10.0, 100.0) > 50);
+        });
+    }
+}
@@ -15,9 +15,28 @@ pub fn transcript_metadata_schema() -> Schema {
         Field::new("gene", DataType::LargeUtf8, false),
         Field::new("qv", DataType::Float32, false),
         Field::new("fov", DataType::LargeUtf8, false),
+        Field::new("sample", DataType::LargeUtf8, false),
         Field::new("assignment", DataType::UInt32, false),
         Field::new("probability", DataType::Float32, false),
         Field::new("background", DataType::UInt8, false),
         Field::new("confusion", DataType::UInt8, false),
+        Field::new("is_noise_probability", DataType::Float32, false),
+        Field::new("compartment", DataType::LargeUtf8, true),
+        Field::new("qc_flag", DataType::LargeUtf8, true),
     ])
-}
\ No newline at end of file
+}
+pub fn chain_agreement_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("transcript_id", DataType::UInt64, false),
+        Field::new("assignment", DataType::UInt32, false),
+        Field::new("agreement", DataType::Float32, false),
+    ])
+}
+
+pub fn transcript_posterior_schema() -> Schema {
+    Schema::new(vec![
+        Field::new("transcript_id", DataType::UInt64, false),
+        Field::new("assignment", DataType::UInt32, false),
+        Field::new("probability", DataType::Float32, false),
+    ])
+}
@@ -0,0 +1,132 @@
+// Progress of a run, served as JSON over HTTP (`--status-port`) so long runs
+// on a cluster can be monitored without parsing the log. Every request, to any
+// path, gets the current status.
+
+use json::JsonValue;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::sampler::IterationDiagnostics;
+
+struct StatusState {
+    stage: &'static str,
+    // the latest iteration, and the number in the whole schedule
+    last: Option<IterationDiagnostics>,
+    nphases: usize,
+    total_iterations: usize,
+    // when the first iteration finished, and its index, for estimating the
+    // time remaining (iterations before it may have come from a checkpoint)
+    sampling_start: Option<(Instant, usize)>,
+}
+
+pub struct RunStatus {
+    start: Instant,
+    state: Mutex<StatusState>,
+}
+
+impl Default for RunStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RunStatus {
+    pub fn new() -> Self {
+        RunStatus {
+            start: Instant::now(),
+            state: Mutex::new(StatusState {
+                stage: "reading",
+                last: None,
+                nphases: 0,
+                total_iterations: 0,
+                sampling_start: None,
+            }),
+        }
+    }
+
+    // What the run is doing, e.g. "reading", "sampling", or "output".
+    pub fn set_stage(&self, stage: &'static str) {
+        self.state.lock().unwrap().stage = stage;
+    }
+
+    pub fn record_iteration(&self, diagnostics: &IterationDiagnostics, nphases: usize, total_iterations: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.stage = "sampling";
+        state.nphases = nphases;
+        state.total_iterations = total_iterations;
+        state
+            .sampling_start
+            .get_or_insert((Instant::now(), diagnostics.iteration));
+        state.last = Some(diagnostics.clone());
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let state = self.state.lock().unwrap();
+
+        let mut status = JsonValue::new_object();
+        status["stage"] = state.stage.into();
+        status["elapsed_seconds"] = self.start.elapsed().as_secs_f64().into();
+        if let Some(last) = &state.last {
+            status["chain"] = last.chain.into();
+            status["phase"] = (last.phase + 1).into();
+            status["nphases"] = state.nphases.into();
+            status["iteration"] = (last.iteration + 1).into();
+            status["total_iterations"] = state.total_iterations.into();
+            status["log_likelihood"] = last.log_likelihood.into();
+            status["ncells"] = last.ncells.into();
+            status["unassigned_fraction"] = last.unassigned_fraction.into();
+            status["background_fraction"] = last.background_fraction.into();
+            status["mean_compactness"] = last.mean_compactness.into();
+
+            // assuming the remaining iterations take as long as those so far,
+            // which overestimates when phases end early on convergence
+            if let Some((sampling_start, first_iteration)) = state.sampling_start {
+                let done = last.iteration.saturating_sub(first_iteration);
+                let remaining = state.total_iterations.saturating_sub(last.iteration + 1);
+                status["eta_seconds"] = if done > 0 {
+                    (sampling_start.elapsed().as_secs_f64() * remaining as f64 / done as f64).into()
+                } else {
+                    JsonValue::Null
+                };
+            }
+        }
+        status
+    }
+
+    // Answer requests on `port` of `address` from a background thread for the
+    // rest of the process's life.
+    pub fn serve(self: &Arc<Self>, address: IpAddr, port: u16) -> std::io::Result<()> {
+        let listener = TcpListener::bind((address, port))?;
+        let status = Arc::clone(self);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // a client that goes away mid-request doesn't matter
+                let _ = status.respond(stream);
+            }
+        });
+        Ok(())
+    }
+
+    fn respond(&self, mut stream: TcpStream) -> std::io::Result<()> {
+        // requests are answered one at a time, so don't wait long on any
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        // read the request line and headers, up to the blank line
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+            line.clear();
+        }
+
+        let body = self.to_json().pretty(2);
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        stream.flush()
+    }
+}
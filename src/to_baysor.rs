@@ -8,8 +8,8 @@ use clap::Parser;
 // use arrow2::io::parquet;
 // use csv::StringRecord;
 
-mod schemas;
-use crate::schemas::transcript_metadata_schema;
+use proseg::output::open_decompressed;
+use proseg::schemas::transcript_metadata_schema;
 
 use arrow::array::RecordBatch;
 use arrow::datatypes::{Schema, Field, DataType};
@@ -17,7 +17,6 @@ use arrow::error::ArrowError;
 use arrow::csv;
 use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
 
-use flate2::read::GzDecoder;
 use json::JsonValue;
 use std::cmp::Ordering;
 use std::fs::File;
@@ -88,7 +87,7 @@ fn determine_format(filename: &str, fmtstr: &Option<String>) -> OutputFormat {
 
     if filename.ends_with(".csv.gz") {
         OutputFormat::CsvGz
-    } else if filename.ends_with(".csv") {
+    } else if filename.ends_with(".csv") || filename.ends_with(".csv.zst") {
         OutputFormat::Csv
     } else if filename.ends_with(".parquet") {
         OutputFormat::Parquet
@@ -118,23 +117,16 @@ fn read_proseg_transcript_metadata(filename: String) -> TranscriptMetadata {
     let fmt = determine_format(&filename, &None);
 
     let schema = transcript_metadata_schema();
-    let input_file = File::open(&filename).expect(&format!("Unable to open '{}'.", &filename));
-
     match fmt {
-        OutputFormat::Csv => {
-            let rdr = csv::ReaderBuilder::new(Arc::new(schema.clone()))
-                .build(input_file)
-                .expect(&format!("Unable to construct CSV reader for '{}'", filename));
-            read_proseg_transcript_metadata_from_reader(rdr, &schema)
-        }
-        OutputFormat::CsvGz => {
-            let input_decoder = GzDecoder::new(input_file);
+        OutputFormat::Csv | OutputFormat::CsvGz => {
+            let input = open_decompressed(&filename).expect(&format!("Unable to open '{}'.", &filename));
             let rdr = csv::ReaderBuilder::new(Arc::new(schema.clone()))
-                .build(input_decoder)
+                .build(input)
                 .expect(&format!("Unable to construct CSV reader for '{}'", filename));
             read_proseg_transcript_metadata_from_reader(rdr, &schema)
         }
         OutputFormat::Parquet => {
+            let input_file = File::open(&filename).expect(&format!("Unable to open '{}'.", &filename));
             let rdr = ParquetRecordBatchReaderBuilder::try_new(input_file)
                 .unwrap()
                 .build()
@@ -357,9 +349,8 @@ fn polygon_area(vertices: &mut [(f32, f32)]) -> f32 {
 }
 
 fn read_cell_polygons_geojson(input_filename: String) -> (JsonValue, Vec<JsonValue>) {
-    let input =
-        File::open(input_filename).expect("Unable to open input cell polygon geojson file.");
-    let mut input = GzDecoder::new(input);
+    let mut input =
+        open_decompressed(&input_filename).expect("Unable to open input cell polygon geojson file.");
 
     let mut content = String::new();
     input
@@ -0,0 +1,265 @@
+// Checks on proseg's output, run with `proseg validate`, for catching
+// malformed polygons before they reach downstream tools.
+
+use geo::geometry::{MultiPolygon, Point, Rect};
+use geo::{Area, BooleanOps, BoundingRect, Intersects};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+
+use super::error::{Error, Result};
+use super::output::{infer_format_from_filename, open_decompressed, OutputFormat};
+use super::sampler::roi::{geojson_error, parse_polygon, read_geojson};
+use super::sampler::roi::RectIndex;
+use super::sampler::smoothing::{ring_self_intersects, to_f64, try_boolean_op};
+use super::sampler::transcripts::{
+    find_column, find_parquet_column, parquet_column, parse_field, BACKGROUND_CELL,
+};
+
+pub struct CellPolygons {
+    pub cells: Vec<u32>,
+    pub polygons: Vec<MultiPolygon<f32>>,
+}
+
+pub struct PolygonOverlap {
+    pub cell_a: u32,
+    pub cell_b: u32,
+    pub area: f32,
+}
+
+pub struct TranscriptCheck {
+    pub assigned: usize,
+    // assigned transcripts outside of their cell's polygon
+    pub outside: usize,
+    // assigned transcripts whose cell has no polygon at all
+    pub missing: usize,
+}
+
+// Read cell polygons from a GeoJSON FeatureCollection like that written with
+// --output-cell-polygons, taking cell ids from the "cell" property.
+pub fn read_cell_polygons(path: &str) -> Result<CellPolygons> {
+    let geojson = read_geojson(path)?;
+    let mut cells = Vec::new();
+    let mut polygons = Vec::new();
+    for (i, feature) in geojson["features"].members().enumerate() {
+        let cell = feature["properties"]["cell"].as_u32().unwrap_or(i as u32);
+        let geometry = &feature["geometry"];
+        let polygon = match geometry["type"].as_str() {
+            Some("Polygon") => {
+                MultiPolygon::new(vec![parse_polygon(path, &geometry["coordinates"])?])
+            }
+            Some("MultiPolygon") => MultiPolygon::new(
+                geometry["coordinates"]
+                    .members()
+                    .map(|coordinates| parse_polygon(path, coordinates))
+                    .collect::<Result<Vec<_>>>()?,
+            ),
+            _ => {
+                return Err(geojson_error(
+                    path,
+                    format!("feature {} is not a Polygon or MultiPolygon", i),
+                ))
+            }
+        };
+        cells.push(cell);
+        polygons.push(polygon);
+    }
+    Ok(CellPolygons { cells, polygons })
+}
+
+// Cells with any ring that crosses or touches itself.
+pub fn self_intersecting_cells(polygons: &CellPolygons) -> Vec<u32> {
+    polygons
+        .polygons
+        .par_iter()
+        .zip(&polygons.cells)
+        .filter(|(multipoly, _)| {
+            multipoly.iter().any(|poly| {
+                ring_self_intersects(poly.exterior())
+                    || poly.interiors().iter().any(ring_self_intersects)
+            })
+        })
+        .map(|(_, &cell)| cell)
+        .collect()
+}
+
+// Pairs of cells whose polygons overlap by more than `min_area`. Cells listed in
+// `skip` (e.g. those that self-intersect, for which the overlap isn't well
+// defined) are not checked.
+pub fn overlapping_cells(
+    polygons: &CellPolygons,
+    skip: &[u32],
+    min_area: f32,
+) -> Vec<PolygonOverlap> {
+    let skip: HashSet<u32> = skip.iter().cloned().collect();
+    let bounds: Vec<Option<Rect<f32>>> = polygons
+        .polygons
+        .iter()
+        .zip(&polygons.cells)
+        .map(|(multipoly, cell)| {
+            if skip.contains(cell) {
+                None
+            } else {
+                multipoly.bounding_rect()
+            }
+        })
+        .collect();
+    let index = RectIndex::new(&bounds);
+
+    (0..polygons.polygons.len())
+        .into_par_iter()
+        .flat_map_iter(|i| {
+            let mut overlaps = Vec::new();
+            if let Some(bounds_i) = bounds[i] {
+                let poly_i = to_f64(&polygons.polygons[i]);
+                for j in index.query(&bounds_i) {
+                    if j <= i || !bounds[j].is_some_and(|bounds_j| bounds_j.intersects(&bounds_i)) {
+                        continue;
+                    }
+                    // Overlaps that can't be computed are reported with a NaN area.
+                    let poly_j = to_f64(&polygons.polygons[j]);
+                    let area = try_boolean_op(|| poly_i.intersection(&poly_j).unsigned_area())
+                        .map_or(f32::NAN, |area| area as f32);
+                    if area.is_nan() || area > min_area {
+                        overlaps.push(PolygonOverlap {
+                            cell_a: polygons.cells[i],
+                            cell_b: polygons.cells[j],
+                            area,
+                        });
+                    }
+                }
+            }
+            overlaps
+        })
+        .collect()
+}
+
+// Count assigned transcripts falling outside their cell's polygon. Transcripts
+// on the boundary count as inside.
+pub fn check_transcripts(
+    polygons: &CellPolygons,
+    transcripts: &[(f32, f32, u32)],
+) -> TranscriptCheck {
+    let cell_index: HashMap<u32, usize> = polygons
+        .cells
+        .iter()
+        .enumerate()
+        .map(|(i, &cell)| (cell, i))
+        .collect();
+
+    let (outside, missing) = transcripts
+        .par_iter()
+        .filter(|(_, _, cell)| *cell != BACKGROUND_CELL)
+        .map(|&(x, y, cell)| match cell_index.get(&cell) {
+            Some(&i) if polygons.polygons[i].0.is_empty() => (0, 1),
+            Some(&i) => {
+                if polygons.polygons[i].intersects(&Point::new(x, y)) {
+                    (0, 0)
+                } else {
+                    (1, 0)
+                }
+            }
+            None => (0, 1),
+        })
+        .reduce(|| (0, 0), |a, b| (a.0 + b.0, a.1 + b.1));
+
+    TranscriptCheck {
+        assigned: transcripts
+            .iter()
+            .filter(|(_, _, cell)| *cell != BACKGROUND_CELL)
+            .count(),
+        outside,
+        missing,
+    }
+}
+
+// Read positions and cell assignments from a table written with
+// --output-transcript-metadata.
+pub fn read_transcript_assignments(path: &str, fmt: OutputFormat) -> Result<Vec<(f32, f32, u32)>> {
+    let fmt = match fmt {
+        OutputFormat::Infer => infer_format_from_filename(path),
+        _ => fmt,
+    };
+
+    match fmt {
+        OutputFormat::Csv | OutputFormat::CsvGz | OutputFormat::CsvZst => {
+            let file = open_decompressed(path).map_err(|source| Error::Io {
+                path: path.to_string(),
+                source,
+            })?;
+            read_transcript_assignments_csv(path, csv::Reader::from_reader(file))
+        }
+        OutputFormat::Parquet => read_transcript_assignments_parquet(path),
+        _ => Err(Error::UnsupportedFormat {
+            path: path.to_string(),
+            format: format!("{:?}", fmt).to_lowercase(),
+        }),
+    }
+}
+
+fn read_transcript_assignments_csv<T: std::io::Read>(
+    path: &str,
+    mut rdr: csv::Reader<T>,
+) -> Result<Vec<(f32, f32, u32)>> {
+    let csv_error = |source| Error::Csv {
+        path: path.to_string(),
+        source,
+    };
+    let headers = rdr.headers().map_err(csv_error)?.clone();
+    let x_col = find_column(path, &headers, "x")?;
+    let y_col = find_column(path, &headers, "y")?;
+    let cell_col = find_column(path, &headers, "assignment")?;
+
+    let mut transcripts = Vec::new();
+    let mut row = csv::StringRecord::new();
+    while rdr.read_record(&mut row).map_err(csv_error)? {
+        transcripts.push((
+            parse_field::<f32>(path, &headers, &row, x_col, "a number")?,
+            parse_field::<f32>(path, &headers, &row, y_col, "a number")?,
+            parse_field::<u32>(path, &headers, &row, cell_col, "a cell index")?,
+        ));
+    }
+    Ok(transcripts)
+}
+
+fn read_transcript_assignments_parquet(path: &str) -> Result<Vec<(f32, f32, u32)>> {
+    use arrow::array::{Array, Float32Array, UInt32Array};
+    use arrow::datatypes::DataType;
+
+    let parquet_error = |source| Error::Parquet {
+        path: path.to_string(),
+        source,
+    };
+    let file = File::open(path).map_err(|source| Error::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    let builder = ParquetRecordBatchReaderBuilder::try_new(file).map_err(parquet_error)?;
+    let schema = builder.schema().as_ref().clone();
+    let x_col_idx = find_parquet_column(path, &schema, "x")?;
+    let y_col_idx = find_parquet_column(path, &schema, "y")?;
+    let cell_col_idx = find_parquet_column(path, &schema, "assignment")?;
+    let rdr = builder.build().map_err(parquet_error)?;
+
+    let mut transcripts = Vec::new();
+    for rec_batch in rdr {
+        let rec_batch = rec_batch.map_err(|source| Error::Arrow {
+            path: path.to_string(),
+            source,
+        })?;
+        let x_col: Float32Array = parquet_column(path, &rec_batch, x_col_idx, &DataType::Float32)?;
+        let y_col: Float32Array = parquet_column(path, &rec_batch, y_col_idx, &DataType::Float32)?;
+        let cell_col: UInt32Array =
+            parquet_column(path, &rec_batch, cell_col_idx, &DataType::UInt32)?;
+        for i in 0..rec_batch.num_rows() {
+            let cell = if cell_col.is_null(i) {
+                BACKGROUND_CELL
+            } else {
+                cell_col.value(i)
+            };
+            transcripts.push((x_col.value(i), y_col.value(i), cell));
+        }
+    }
+    Ok(transcripts)
+}
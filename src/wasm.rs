@@ -0,0 +1,193 @@
+//! Running the sampler from JavaScript, with the `wasm32` feature, e.g. for an
+//! interactive playground on small regions. Build with
+//! `cargo rustc --lib --target wasm32-unknown-unknown --features wasm32 --crate-type cdylib`
+//! and generate bindings with `wasm-bindgen` (see the README).
+//!
+//! Transcripts are passed as arrays rather than read from files, and nothing is
+//! written. Without threads in the browser, rayon runs everything on the
+//! calling thread, so this is only practical for a few thousand cells.
+
+use wasm_bindgen::prelude::*;
+
+use crate::sampler::hull::compute_cell_areas;
+use crate::sampler::transcripts::{
+    coordinate_span, estimate_full_area, filter_cellfree_transcripts,
+    postprocess_cell_assignments, Transcript, TranscriptDataset, BACKGROUND_CELL,
+};
+use crate::sampler::voxelsampler::filter_sparse_cells;
+use crate::sampler::ModelPriors;
+use crate::Proseg;
+
+// Model settings, matching the command line defaults where there is one.
+const NCOMPONENTS: usize = 10;
+const NBGLAYERS: usize = 4;
+const MAX_TRANSCRIPT_NUCLEUS_DISTANCE: f32 = 60.0;
+
+/// Result of [`segment`]. Cells are renumbered from 0, dropping any with too
+/// few transcripts to initialize.
+#[wasm_bindgen]
+pub struct Segmentation {
+    cell_assignments: Vec<u32>,
+    probabilities: Vec<f32>,
+    centroids: Vec<f32>,
+}
+
+#[wasm_bindgen]
+impl Segmentation {
+    /// Cell of each transcript, or 4294967295 for unassigned transcripts.
+    /// Transcripts far from any cell are dropped before sampling, and are
+    /// reported as unassigned.
+    #[wasm_bindgen(getter)]
+    pub fn cell_assignments(&self) -> Vec<u32> {
+        self.cell_assignments.clone()
+    }
+
+    /// Posterior probability of each transcript's assignment.
+    #[wasm_bindgen(getter)]
+    pub fn probabilities(&self) -> Vec<f32> {
+        self.probabilities.clone()
+    }
+
+    /// Cell centroids, as consecutive x, y, z triples.
+    #[wasm_bindgen(getter)]
+    pub fn centroids(&self) -> Vec<f32> {
+        self.centroids.clone()
+    }
+}
+
+/// Segment transcripts given their positions, gene indices (from 0 to
+/// `ngenes - 1`), and preliminary cell assignments (4294967295 for
+/// unassigned), running the sampler for `iterations` iterations with the given
+/// initial voxel size.
+#[allow(clippy::too_many_arguments)]
+#[wasm_bindgen]
+pub fn segment(
+    x: &[f32],
+    y: &[f32],
+    z: &[f32],
+    gene: &[u32],
+    cell: &[u32],
+    ngenes: u32,
+    voxel_size: f32,
+    iterations: u32,
+    seed: u32,
+) -> Result<Segmentation, JsError> {
+    let ntranscripts = x.len();
+    if [y.len(), z.len(), gene.len(), cell.len()]
+        .iter()
+        .any(|&len| len != ntranscripts)
+    {
+        return Err(JsError::new("x, y, z, gene, and cell must have the same length"));
+    }
+    if gene.iter().any(|&gene| gene >= ngenes) {
+        return Err(JsError::new("gene indices must be less than ngenes"));
+    }
+    if iterations == 0 || voxel_size <= 0.0 {
+        return Err(JsError::new("iterations and voxel_size must be positive"));
+    }
+
+    let transcripts: Vec<Transcript> = (0..ntranscripts)
+        .map(|i| Transcript {
+            transcript_id: i as u64,
+            x: x[i],
+            y: y[i],
+            z: z[i],
+            gene: gene[i],
+            fov: 0,
+        })
+        .collect();
+
+    let mut nucleus_assignments = cell.to_vec();
+    let mut cell_assignments = cell.to_vec();
+    let nucleus_population =
+        postprocess_cell_assignments(&mut nucleus_assignments, &mut cell_assignments);
+    if nucleus_population.is_empty() {
+        return Err(JsError::new("at least one transcript must be assigned to a cell"));
+    }
+
+    let mut dataset = TranscriptDataset {
+        transcript_names: (0..ngenes).map(|gene| gene.to_string()).collect(),
+        transcripts,
+        nucleus_assignments,
+        cell_assignments,
+        nucleus_population,
+        fovs: vec![0; ntranscripts],
+        qvs: vec![f32::INFINITY; ntranscripts],
+        fov_names: vec![String::from("0")],
+        sample_names: vec![String::from("0")],
+        fov_samples: vec![0],
+        nuclear: vec![false; ntranscripts],
+        original_cell_ids: Vec::new(),
+        original_cell_assignments: vec![BACKGROUND_CELL; ntranscripts],
+        prior_source_names: vec![String::new()],
+        prior_sources: vec![0; ntranscripts],
+    };
+
+    // The same preparation as the command line tool, with default settings.
+    let (_, _, _, _, zmin, zmax) = coordinate_span(&dataset.transcripts);
+    let ncells = dataset.nucleus_population.len();
+    filter_cellfree_transcripts(&mut dataset, ncells, MAX_TRANSCRIPT_NUCLEUS_DISTANCE);
+    loop {
+        let prev_ncells = dataset.nucleus_population.len();
+        filter_sparse_cells(
+            voxel_size,
+            1,
+            &dataset.transcripts,
+            &mut dataset.nucleus_assignments,
+            &mut dataset.cell_assignments,
+            &mut dataset.nucleus_population,
+        );
+        if dataset.nucleus_population.len() == prev_ncells {
+            break;
+        }
+    }
+    let transcript_ids: Vec<u64> = dataset.transcripts.iter().map(|t| t.transcript_id).collect();
+
+    let ncells = dataset.nucleus_population.len();
+    let nucleus_areas =
+        compute_cell_areas(ncells, &dataset.transcripts, &dataset.nucleus_assignments);
+    let mean_nucleus_area = nucleus_areas.iter().sum::<f32>()
+        / nucleus_areas.iter().filter(|a| **a > 0.0).count().max(1) as f32;
+
+    let zspan = if zmax > zmin { zmax - zmin } else { 1.0 };
+    let layer_depth = 1.01 * zspan / NBGLAYERS as f32;
+    let full_area = estimate_full_area(&dataset.transcripts, mean_nucleus_area);
+    let full_layer_volume = full_area * zspan / NBGLAYERS as f32;
+
+    let priors = ModelPriors::new(mean_nucleus_area, zmin, zmax);
+
+    let iterations = iterations as usize;
+    let result = Proseg::new(&dataset, priors, full_layer_volume, layer_depth)
+        .ncomponents(NCOMPONENTS.min(ncells))
+        .nbglayers(NBGLAYERS)
+        .initial_voxel_size(voxel_size)
+        .schedule(vec![iterations])
+        .recorded_samples(iterations.div_ceil(2))
+        .seed(Some(seed as u64))
+        .progress(false)
+        .run()
+        .map_err(|err| JsError::new(&err.to_string()))?;
+
+    // Map back to the input transcripts, some of which may have been dropped.
+    let mut cell_assignments = vec![BACKGROUND_CELL; ntranscripts];
+    let mut probabilities = vec![0.0; ntranscripts];
+    for (&id, (cell, pr)) in transcript_ids.iter().zip(
+        result
+            .uncertainty
+            .max_posterior_cell_assignments(&result.params),
+    ) {
+        cell_assignments[id as usize] = cell;
+        probabilities[id as usize] = pr;
+    }
+
+    Ok(Segmentation {
+        cell_assignments,
+        probabilities,
+        centroids: result
+            .sampler
+            .cell_centroids()
+            .into_iter()
+            .flat_map(|(x, y, z)| [x, y, z])
+            .collect(),
+    })
+}